@@ -0,0 +1,22 @@
+// Compiler:
+// Runtime:
+//    status: error
+//    stderr:
+//      abort called
+//        backtrace:
+//          #0 fail (i32 3)
+//            `call @abort()` in block...
+//          #1 main ()
+//            `call @fail(i32 3)` in block %0
+#include <stdlib.h>
+
+void fail(int code) {
+    if (code) {
+        abort();
+    }
+}
+
+int main() {
+    fail(3);
+    return 0;
+}
@@ -0,0 +1,23 @@
+// Compiler:
+// Runtime:
+//    stdout: 5 8
+//            1 -1
+//            6 3
+#include <stdatomic.h>
+
+atomic_int counter = 5;
+
+int main() {
+    int old = atomic_fetch_add(&counter, 3);
+    printf("%i %i", old, atomic_load(&counter));
+
+    int expected = 8;
+    int ok = atomic_compare_exchange_strong(&counter, &expected, -1);
+    printf("%i %i", ok, counter);
+
+    _Atomic long local = 2;
+    local += 4;
+    long prev = atomic_exchange(&local, 3);
+    printf("%li %li", prev, atomic_load(&local));
+    return 0;
+}
@@ -0,0 +1,36 @@
+// Compiler:
+//    exec-arg: -g
+// Runtime:
+//    exec-arg: --coverage=-
+//    exec-arg: --coverage-summary
+//    stdout:
+//      1
+//      TN:
+//      SF:...
+//      FN:27,sign
+//      FN:33,main
+//      FNDA:1,sign
+//      FNDA:1,main
+//      FNF:2
+//      FNH:2
+//      BRDA:28,0,0,0
+//      BRDA:28,0,1,1
+//      BRF:2
+//      BRH:1
+//      ...
+//      end_of_record
+//    stderr:
+//      Coverage:
+//      function                      blocks       edges       lines
+//      sign                             3/4         2/4...
+//      main                             1/1         0/0...
+int sign(int x) {
+    if (x < 0)
+        return -1;
+    return 1;
+}
+
+int main() {
+    printf("%d", sign(5));
+    return 0;
+}
@@ -0,0 +1,24 @@
+// Compiler:
+// Runtime:
+//    status: error
+//    stderr:
+//      Deadlock: every thread is blocked
+//      ...
+#include <pthread.h>
+
+pthread_mutex_t a = PTHREAD_MUTEX_INITIALIZER;
+pthread_mutex_t b = PTHREAD_MUTEX_INITIALIZER;
+
+void *other(void *arg) {
+    pthread_mutex_lock(&b);
+    pthread_mutex_lock(&a);
+    return 0;
+}
+
+int main() {
+    pthread_t thread;
+    pthread_mutex_lock(&a);
+    pthread_create(&thread, 0, other, 0);
+    pthread_join(thread, 0);
+    return 0;
+}
@@ -0,0 +1,33 @@
+// Compiler:
+//    exec-arg: -O1
+// Runtime:
+//    exec-arg: --debug
+//    stdin:
+//      break add
+//      continue
+//      print %1
+//      backtrace
+//      finish
+//      delete
+//      continue
+//    stdout:
+//      Stopped at...
+//      Breakpoint 1 at add
+//      Breakpoint 1, `%3 = add...
+//      %1 = i32 3
+//          #0 add (i32 2, i32 3)
+//              ...in block %2
+//          #1 main ()
+//              ...in block %0
+//      Stopped at `%2 = ...
+//      9
+__attribute__((noinline)) int add(int x, int y) {
+    return x + y;
+}
+
+int main() {
+    int a = add(2, 3);
+    int b = add(a, 4);
+    printf("%d", b);
+    return 0;
+}
@@ -0,0 +1,15 @@
+// Compiler:
+// Runtime:
+//    exec-arg: --ub=poison
+//    status: error
+//    stderr:
+//      Division by zero in sdiv
+//        ...of @main
+//        backtrace:
+//          #0 main ()
+//            ...in block %0
+int main() {
+    int zero = 0;
+    printf("%i", 7 / zero);
+    return 0;
+}
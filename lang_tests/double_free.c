@@ -0,0 +1,15 @@
+// Compiler:
+// Runtime:
+//    exec-arg: --memcheck
+//    status: error
+//    stderr:
+//      Double free of address ..., a heap allocation of 1 bytes
+//      ...
+#include <stdlib.h>
+
+int main() {
+    char *p = malloc(1);
+    free(p);
+    free(p);
+    return 0;
+}
@@ -0,0 +1,17 @@
+// Compiler:
+// Runtime:
+//    status: 3
+//    stdout: before
+#include <stdio.h>
+#include <stdlib.h>
+
+void stop(int status) {
+    exit(status);
+}
+
+int main() {
+    printf("before");
+    stop(3);
+    printf("after");
+    return 0;
+}
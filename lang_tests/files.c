@@ -0,0 +1,33 @@
+// Compiler:
+// Runtime:
+//    exec-arg: --fs=memory
+//    stdout:
+//      42 hello
+//      second
+//      second
+//      16 7
+#include <fcntl.h>
+#include <stdio.h>
+#include <unistd.h>
+
+int main() {
+    FILE *f = fopen("/tmp/data.txt", "w");
+    fprintf(f, "%d %s\n", 42, "hello");
+    fputs("second\n", f);
+    fclose(f);
+
+    char line[64];
+    f = fopen("/tmp/data.txt", "r");
+    while (fgets(line, sizeof line, f))
+        fputs(line, stdout);
+    fclose(f);
+
+    int fd = open("/tmp/data.txt", O_RDONLY);
+    off_t end = lseek(fd, 0, SEEK_END);
+    lseek(fd, -7, SEEK_END);
+    ssize_t n = read(fd, line, sizeof line);
+    write(1, line, n);
+    close(fd);
+    printf("%ld %ld", (long)end, (long)n);
+    return 0;
+}
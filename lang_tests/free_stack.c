@@ -0,0 +1,13 @@
+// Compiler:
+// Runtime:
+//    status: error
+//    stderr:
+//      Invalid free of address ...
+//      ...
+#include <stdlib.h>
+
+int main() {
+    int x = 0;
+    free(&x);
+    return x;
+}
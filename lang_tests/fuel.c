@@ -0,0 +1,20 @@
+// Compiler:
+// Runtime:
+//    exec-arg: --max-instructions=1000
+//    status: error
+//    stderr:
+//      Out of fuel after 1000 instructions
+//        backtrace:
+//          #0 spin ()
+//      ...
+void spin() {
+    volatile int x = 0;
+    while (1) {
+        x++;
+    }
+}
+
+int main() {
+    spin();
+    return 0;
+}
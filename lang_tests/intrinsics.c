@@ -0,0 +1,19 @@
+#include <math.h>
+// Compiler:
+// Runtime:
+//    stdout: 4
+//            24
+//            4
+//            2018915346
+//            2.5
+int main() {
+    unsigned int u = 0xF0;
+    printf("%i", __builtin_popcount(u));
+    printf("%i", __builtin_clz(u));
+    printf("%i", __builtin_ctz(u));
+    unsigned int b = 0x12345678;
+    printf("%i", __builtin_bswap32(b));
+    double d = -2.5;
+    printf("%f", fabs(d));
+    return 0;
+}
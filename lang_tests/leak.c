@@ -0,0 +1,19 @@
+// Compiler:
+// Runtime:
+//    exec-arg: --memcheck
+//    status: error
+//    stdout: 7
+//    stderr:
+//      Leak of a heap allocation of 8 bytes at address ...
+//        allocated at:
+//          #0 main
+#include <stdlib.h>
+
+int main() {
+    long *kept = malloc(sizeof(long));
+    long *freed = malloc(sizeof(long));
+    *kept = 7;
+    printf("%li", *kept);
+    free(freed);
+    return 0;
+}
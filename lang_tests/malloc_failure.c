@@ -0,0 +1,18 @@
+// Compiler:
+// Runtime:
+//    stdout: 1 1 1 1
+#include <errno.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+int main() {
+    char *a = malloc(SIZE_MAX);
+    int enomem = errno == ENOMEM;
+    char *b = calloc(SIZE_MAX / 2, 4);
+    char *c = malloc(16);
+    char *d = realloc(c, SIZE_MAX);
+    printf("%d %d %d %d", a == NULL, enomem, b == NULL, d == NULL);
+    free(c);
+    return 0;
+}
@@ -0,0 +1,16 @@
+// Compiler:
+// Runtime:
+//    exec-arg: --memcheck
+//    status: error
+//    stderr:
+//      ...is 0 bytes past the end of a stack allocation of 16 bytes
+//        allocated at:
+//          #0 main
+//      ...
+int main() {
+    int xs[4];
+    for (int i = 0; i <= 4; i++) {
+        xs[i] = i;
+    }
+    return xs[0];
+}
@@ -0,0 +1,18 @@
+// Compiler:
+// Runtime:
+//    stdout: overflow
+//            3
+int main() {
+    int a = 2147483647;
+    int b = 1;
+    int r;
+    if (__builtin_add_overflow(a, b, &r)) {
+        printf("overflow");
+    }
+    if (__builtin_add_overflow(b, 2, &r)) {
+        printf("overflow");
+    } else {
+        printf("%i", r);
+    }
+    return 0;
+}
@@ -0,0 +1,34 @@
+// Compiler:
+// Runtime:
+//    stdout: hello 42
+//            10
+#include <stdlib.h>
+#include <string.h>
+
+struct point {
+    char tag;
+    int x;
+    long y;
+};
+
+int sum(int *xs, int n) {
+    int total = 0;
+    for (int i = 0; i < n; i++) {
+        total += xs[i];
+    }
+    return total;
+}
+
+int main() {
+    char *s = malloc(6);
+    memcpy(s, "hello", 6);
+    struct point p;
+    struct point *pp = &p;
+    pp->y = 42;
+    printf("%s %li", s, p.y);
+    free(s);
+
+    int xs[4] = {1, 2, 3, 4};
+    printf("%i", sum(xs, 4));
+    return 0;
+}
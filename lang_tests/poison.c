@@ -0,0 +1,19 @@
+// Compiler:
+//    exec-arg: -O1
+// Runtime:
+//    exec-arg: --ub=poison
+//    status: error
+//    stderr:
+//      Poison value used as a branch condition
+//        ...of @main
+//      ...
+__attribute__((noinline)) int next(int x) {
+    return x + 1;
+}
+
+int main() {
+    if (next(2147483647) > 0) {
+        printf("positive");
+    }
+    return 0;
+}
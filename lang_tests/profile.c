@@ -0,0 +1,18 @@
+// Compiler:
+//    exec-arg: -O1
+// Runtime:
+//    exec-arg: --profile=-
+//    stdout:
+//      5 9
+//      main 4
+//      main;add 4
+__attribute__((noinline)) int add(int x, int y) {
+    return x + y;
+}
+
+int main() {
+    int a = add(2, 3);
+    int b = add(a, 4);
+    printf("%d %d", a, b);
+    return 0;
+}
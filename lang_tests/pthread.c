@@ -0,0 +1,50 @@
+// Compiler:
+// Runtime:
+//    exec-arg: --sched=random
+//    exec-arg: --seed=7
+//    stdout: 400 42
+#include <pthread.h>
+
+int counter = 0;
+int ready = 0;
+pthread_mutex_t lock = PTHREAD_MUTEX_INITIALIZER;
+pthread_cond_t cond = PTHREAD_COND_INITIALIZER;
+
+void *work(void *arg) {
+    for (int i = 0; i < 100; i++) {
+        pthread_mutex_lock(&lock);
+        counter++;
+        pthread_mutex_unlock(&lock);
+    }
+    return arg;
+}
+
+void *produce(void *arg) {
+    pthread_mutex_lock(&lock);
+    ready = 42;
+    pthread_cond_signal(&cond);
+    pthread_mutex_unlock(&lock);
+    return 0;
+}
+
+int main() {
+    pthread_t threads[4];
+    for (int i = 0; i < 4; i++) {
+        pthread_create(&threads[i], 0, work, 0);
+    }
+    for (int i = 0; i < 4; i++) {
+        pthread_join(threads[i], 0);
+    }
+
+    pthread_t producer;
+    pthread_mutex_lock(&lock);
+    pthread_create(&producer, 0, produce, 0);
+    while (!ready) {
+        pthread_cond_wait(&cond, &lock);
+    }
+    pthread_mutex_unlock(&lock);
+    pthread_join(producer, 0);
+
+    printf("%i %i", counter, ready);
+    return 0;
+}
@@ -0,0 +1,35 @@
+// Compiler:
+// Runtime:
+//    exec-arg: --race
+//    status: error
+//    stderr:
+//      Data race on 4 bytes at address ...
+//      ...by thread ...
+//      ...#0 increment
+//      ...#1 work
+//      ...Previous...
+//      ...#0 increment
+//      ...#1 work
+//      ...
+#include <pthread.h>
+
+int counter = 0;
+
+void increment() {
+    counter++;
+}
+
+void *work(void *arg) {
+    increment();
+    return 0;
+}
+
+int main() {
+    pthread_t a, b;
+    pthread_create(&a, 0, work, 0);
+    pthread_create(&b, 0, work, 0);
+    pthread_join(a, 0);
+    pthread_join(b, 0);
+    printf("%i", counter);
+    return 0;
+}
@@ -0,0 +1,40 @@
+// Compiler:
+// Runtime:
+//    exec-arg: --race
+//    stdout: 42 2
+#include <pthread.h>
+#include <stdatomic.h>
+
+int data = 0;
+atomic_int ready = 0;
+int counter = 0;
+pthread_mutex_t lock = PTHREAD_MUTEX_INITIALIZER;
+
+void *produce(void *arg) {
+    data = 42;
+    atomic_store_explicit(&ready, 1, memory_order_release);
+    return 0;
+}
+
+void *work(void *arg) {
+    pthread_mutex_lock(&lock);
+    counter++;
+    pthread_mutex_unlock(&lock);
+    return 0;
+}
+
+int main() {
+    pthread_t producer, a, b;
+    pthread_create(&producer, 0, produce, 0);
+    while (!atomic_load_explicit(&ready, memory_order_acquire)) {
+    }
+    int seen = data;
+
+    pthread_create(&a, 0, work, 0);
+    pthread_create(&b, 0, work, 0);
+    pthread_join(a, 0);
+    pthread_join(b, 0);
+    pthread_join(producer, 0);
+    printf("%i %i", seen, counter);
+    return 0;
+}
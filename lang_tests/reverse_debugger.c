@@ -0,0 +1,34 @@
+// Compiler:
+//    exec-arg: -O1
+// Runtime:
+//    exec-arg: --debug
+//    stdin:
+//      break add
+//      continue
+//      continue
+//      reverse-continue
+//      print %1
+//      reverse-step
+//      delete
+//      continue
+//    stdout:
+//      Stopped at...
+//      Breakpoint 1 at add
+//      Breakpoint 1, `%3 = add...
+//      5
+//      Breakpoint 1, `%3 = add...
+//      Breakpoint 1, `%3 = add...
+//      %1 = i32 3
+//      Stopped at `%...
+//      9
+__attribute__((noinline)) int add(int x, int y) {
+    return x + y;
+}
+
+int main() {
+    int a = add(2, 3);
+    printf("%d", a);
+    int b = add(a, 4);
+    printf("%d", b);
+    return 0;
+}
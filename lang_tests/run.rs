@@ -7,7 +7,9 @@ const COMMENT: &str = "//";
 fn main() {
     LangTester::new()
         .test_dir("lang_tests")
-        .test_file_filter(|p| p.extension().map(|x| x.to_str().unwrap()) == Some("c"))
+        .test_path_filter(|p| {
+            p.is_file() && p.extension().map(|x| x.to_str().unwrap()) == Some("c")
+        })
         .test_extract(move |p| {
             read_to_string(p)
                 .unwrap()
@@ -22,9 +24,9 @@ fn main() {
             let temp_path = NamedTempFile::new().unwrap().into_temp_path();
             let temp_path_str = temp_path.to_str().unwrap();
             let mut compiler = Command::new("clang");
-            compiler.args(&["-emit-llvm", "-o", temp_path_str, "-c", p.to_str().unwrap()]);
+            compiler.args(["-emit-llvm", "-o", temp_path_str, "-c", p.to_str().unwrap()]);
             let mut runtime = Command::new("cargo");
-            runtime.args(&["run", "--release", temp_path_str]);
+            runtime.args(["run", "--release", "--", temp_path_str]);
             vec![("Compiler", compiler), ("Runtime", runtime)]
         })
         .run();
@@ -0,0 +1,22 @@
+// Compiler:
+// Runtime:
+//    exec-arg: --sandbox
+//    exec-arg: --allow-env=GREETING=hi
+//    stdout:
+//      hi 1
+//      1 1 1
+#include <errno.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <time.h>
+
+int main() {
+    printf("%s %d", getenv("GREETING"), getenv("HOME") == NULL);
+
+    FILE *f = fopen("/etc/passwd", "r");
+    int denied_open = f == NULL && errno == EACCES;
+    int denied_clock = time(NULL) == -1 && errno == EPERM;
+    int denied_spawn = system("true") == -1 && errno == EPERM;
+    printf("%d %d %d", denied_open, denied_clock, denied_spawn);
+    return 0;
+}
@@ -0,0 +1,15 @@
+// Compiler:
+// Runtime:
+//    status: error
+//    stdout: 256
+//    stderr:
+//      Shift amount 32 is too large for i32 in shl
+//      ...
+int main() {
+    int one = 1;
+    int amount = 8;
+    printf("%i", one << amount);
+    amount = 32;
+    printf("%i", one << amount);
+    return 0;
+}
@@ -0,0 +1,15 @@
+// Compiler:
+// Runtime:
+//    status: error
+//    stderr:
+//      Signed overflow in add nsw: 2147483647 and 1
+//        ...of @increment
+//      ...
+int increment(int x) {
+    return x + 1;
+}
+
+int main() {
+    printf("%i", increment(2147483647));
+    return 0;
+}
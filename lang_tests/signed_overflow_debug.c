@@ -0,0 +1,20 @@
+// Compiler:
+//    exec-arg: -g
+// Runtime:
+//    status: error
+//    stderr:
+//      Signed overflow in add nsw: 2147483647 and 1
+//        ...signed_overflow_debug.c:13:15 in increment)
+//      ...
+#include <stdio.h>
+
+int increment(int x) {
+    // The add follows the llvm.dbg.declare calls for x and y in its block.
+    int y = x + 1;
+    return y;
+}
+
+int main() {
+    printf("%i", increment(2147483647));
+    return 0;
+}
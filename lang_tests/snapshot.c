@@ -0,0 +1,39 @@
+// Compiler:
+// Runtime:
+//    exec-arg: --sched=random
+//    exec-arg: --seed=7
+//    exec-arg: --snapshot=/dev/null
+//    exec-arg: --snapshot-at=work
+//    stdout: 200 50
+#include <pthread.h>
+#include <stdlib.h>
+
+pthread_mutex_t lock = PTHREAD_MUTEX_INITIALIZER;
+
+struct totals {
+    int count;
+    double sum;
+};
+
+void *work(void *arg) {
+    struct totals *totals = arg;
+    for (int i = 0; i < 100; i++) {
+        pthread_mutex_lock(&lock);
+        totals->count++;
+        totals->sum += 0.25;
+        pthread_mutex_unlock(&lock);
+    }
+    return NULL;
+}
+
+int main() {
+    struct totals *totals = calloc(1, sizeof(struct totals));
+    pthread_t t1, t2;
+    pthread_create(&t1, NULL, work, totals);
+    pthread_create(&t2, NULL, work, totals);
+    pthread_join(t1, NULL);
+    pthread_join(t2, NULL);
+    printf("%d %f", totals->count, totals->sum);
+    free(totals);
+    return 0;
+}
@@ -0,0 +1,20 @@
+// Compiler:
+//    exec-arg: -g
+// Runtime:
+//    status: error
+//    stderr:
+//      Division by zero in sdiv
+//        ...source_location.c:9:14 in divide)
+//        backtrace:
+//          #0 divide (i32 1, i32 0) at...
+//            ...in block %2
+//          #1 main () at...
+//            ...in block %0
+int divide(int x, int y) {
+    return x / y;
+}
+
+int main() {
+    printf("%d", divide(1, 0));
+    return 0;
+}
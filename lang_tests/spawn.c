@@ -0,0 +1,13 @@
+// Compiler:
+// Runtime:
+//    stdout: 1 1
+#include <errno.h>
+#include <stdio.h>
+#include <stdlib.h>
+
+int main() {
+    // Without --allow-spawn there's no shell, sandboxed or not.
+    int denied = system("true") == -1 && errno == EPERM;
+    printf("%d %d", denied, system(NULL) == 0);
+    return 0;
+}
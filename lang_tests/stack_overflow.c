@@ -0,0 +1,18 @@
+// Compiler:
+// Runtime:
+//    exec-arg: --stack-size=4096
+//    status: error
+//    stderr:
+//      Stack overflow in recurse at depth ...
+//        backtrace:
+//          #0 recurse ...
+//      ...
+int recurse(int n) {
+    char buf[16];
+    buf[n % 16] = n;
+    return recurse(n + 1) + buf[0];
+}
+
+int main() {
+    return recurse(0);
+}
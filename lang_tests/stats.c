@@ -0,0 +1,27 @@
+// Compiler:
+//    exec-arg: -O1
+// Runtime:
+//    exec-arg: --stats
+//    stdout: 9
+//    stderr:
+//      Instructions executed: 8
+//      By opcode:
+//      call                       3
+//      ret                        3
+//      add                        2
+//      By function:
+//      function                       calls   inclusive   exclusive
+//      main                               1           8           4
+//      add                                2           4           4
+//      Peak stack usage: 0 bytes
+//      Peak heap usage: 0 bytes
+__attribute__((noinline)) int add(int x, int y) {
+    return x + y;
+}
+
+int main() {
+    int a = add(2, 3);
+    int b = add(a, 4);
+    printf("%d", b);
+    return 0;
+}
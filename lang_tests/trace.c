@@ -0,0 +1,22 @@
+// Compiler:
+//    exec-arg: -O1
+// Runtime:
+//    exec-arg: --trace
+//    exec-arg: --trace-func=add
+//    exec-arg: --trace-limit=3
+//    stdout: 5 9
+//    stderr:
+//      [0] @add %2 #0: %3 = add i32...
+//      [0] @add %2 #1: ret i32 %3 (i32 5)
+//      [0] @add %2 #0: %3 = add i32...
+//      (trace limit reached)
+__attribute__((noinline)) int add(int x, int y) {
+    return x + y;
+}
+
+int main() {
+    int a = add(2, 3);
+    int b = add(4, 5);
+    printf("%d %d", a, b);
+    return 0;
+}
@@ -0,0 +1,15 @@
+// Compiler:
+// Runtime:
+//    status: error
+//    stdout: before trap
+//    stderr:
+//      llvm.trap called
+//        backtrace:
+//          #0 main ()
+//            `call @llvm.trap()` in block %0
+int main() {
+    printf("before trap");
+    __builtin_trap();
+    printf("after trap");
+    return 0;
+}
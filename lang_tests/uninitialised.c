@@ -0,0 +1,19 @@
+// Compiler:
+// Runtime:
+//    exec-arg: --memcheck
+//    status: error
+//    stderr:
+//      Uninitialised value used as a branch condition
+//        ...of @main
+//        ...stack allocation of 4 bytes made by `%2 = alloca i32, align 4` in block %0 of @main
+//      ...
+int main() {
+    int total;
+    for (int i = 0; i < 3; i++) {
+        total += i;
+    }
+    if (total > 0) {
+        printf("positive");
+    }
+    return 0;
+}
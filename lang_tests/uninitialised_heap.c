@@ -0,0 +1,21 @@
+// Compiler:
+// Runtime:
+//    exec-arg: --memcheck
+//    status: error
+//    stdout: 1 2
+//    stderr:
+//      Uninitialised value passed as argument 2 of @printf
+//        ...
+//        ...heap allocation of 12 bytes made by `...
+//      ...
+#include <stdlib.h>
+
+int main() {
+    int *xs = malloc(3 * sizeof(int));
+    xs[0] = 1;
+    xs[1] = 2;
+    printf("%i %i", xs[0], xs[1]);
+    printf("%i", xs[2]);
+    free(xs);
+    return 0;
+}
@@ -0,0 +1,25 @@
+// Compiler:
+// Runtime:
+//    exec-arg: --memcheck
+//    status: error
+//    stderr:
+//      ...is 8 bytes inside a heap allocation of 16 bytes
+//        allocated at:
+//          #0 make
+//          #1 main
+//        freed at:
+//          #0 main
+//      ...
+#include <stdlib.h>
+
+int *make() {
+    return malloc(4 * sizeof(int));
+}
+
+int main() {
+    int *xs = make();
+    xs[2] = 3;
+    free(xs);
+    printf("%i", xs[2]);
+    return 0;
+}
@@ -0,0 +1,23 @@
+// Compiler:
+// Runtime:
+//    stdout: 11 -42 93 20
+//            -1 0
+//            1 10 -2 20
+//            0.5 -1
+typedef int v4si __attribute__((vector_size(16)));
+typedef float v4sf __attribute__((vector_size(16)));
+
+int main() {
+    v4si a = {1, -2, 3, 4};
+    v4si b = {10, 20, 30, 4};
+    v4si c = a * b + a;
+    printf("%i %i %i %i", c[0], c[1], c[2], c[3]);
+    v4si m = a < b;
+    printf("%i %i", m[0], m[3]);
+    v4si s = __builtin_shufflevector(a, b, 0, 4, 1, 5);
+    printf("%i %i %i %i", s[0], s[1], s[2], s[3]);
+    v4sf f = __builtin_convertvector(a, v4sf);
+    f = f * 0.5f;
+    printf("%f %f", f[0], f[1]);
+    return 0;
+}
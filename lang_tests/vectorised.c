@@ -0,0 +1,27 @@
+// Compiler:
+//    exec-arg: -O2
+// Runtime:
+//    stdout: 4950 328350
+#include <stdio.h>
+
+__attribute__((noinline)) int sum(int *a, int n) {
+    int sum = 0;
+    for (int i = 0; i < n; i++)
+        sum += a[i];
+    return sum;
+}
+
+__attribute__((noinline)) int sum_squares(int *a, int n) {
+    int sum = 0;
+    for (int i = 0; i < n; i++)
+        sum += a[i] * a[i];
+    return sum;
+}
+
+int main() {
+    int a[100];
+    for (int i = 0; i < 100; i++)
+        a[i] = i;
+    printf("%i %i", sum(a, 100), sum_squares(a, 100));
+    return 0;
+}
@@ -0,0 +1,239 @@
+use super::{debuginfo::source_path, LLVMIRInterpreter};
+use llvm_ir::{Function, HasDebugLoc, Name, Terminator};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+};
+
+/// The blocks a terminator can go to.
+fn successors(term: &Terminator) -> Vec<&Name> {
+    let mut dests = match term {
+        Terminator::Br(br) => vec![&br.dest],
+        Terminator::CondBr(condbr) => vec![&condbr.true_dest, &condbr.false_dest],
+        Terminator::Switch(switch) => {
+            let mut dests = switch
+                .dests
+                .iter()
+                .map(|(_, dest)| dest)
+                .collect::<Vec<_>>();
+            dests.push(&switch.default_dest);
+            dests
+        }
+        _ => Vec::new(),
+    };
+    let mut seen = Vec::new();
+    dests.retain(|dest| {
+        let new = !seen.contains(dest);
+        seen.push(*dest);
+        new
+    });
+    dests
+}
+
+/// Which blocks, and edges between them, a program executed.
+pub struct Coverage {
+    /// Where to write an lcov tracefile.
+    path: Option<String>,
+    summary: bool,
+    /// How often each block was entered, by function and block index.
+    blocks: HashMap<(String, usize), u64>,
+    edges: HashMap<(String, usize, usize), u64>,
+}
+
+/// What an lcov tracefile records about a source file.
+#[derive(Default)]
+struct FileCoverage {
+    funcs: Vec<(u32, String, u64)>,
+    /// The line of a conditional branch, its block, which of its successors and how often that
+    /// was taken, if the branch was reached at all.
+    branches: Vec<(u32, usize, usize, Option<u64>)>,
+    lines: BTreeMap<u32, u64>,
+}
+
+impl Coverage {
+    pub fn new(path: Option<String>, summary: bool) -> Coverage {
+        Coverage {
+            path,
+            summary,
+            blocks: HashMap::new(),
+            edges: HashMap::new(),
+        }
+    }
+
+    /// Coverage with nothing executed yet, for the program to run again.
+    pub(super) fn restarted(&self) -> Coverage {
+        Coverage::new(self.path.clone(), self.summary)
+    }
+
+    fn block(&self, func: &str, block: usize) -> u64 {
+        // PERF: this copies the function name just to look it up.
+        *self.blocks.get(&(func.to_owned(), block)).unwrap_or(&0)
+    }
+
+    fn edge(&self, func: &str, from: usize, to: usize) -> u64 {
+        *self.edges.get(&(func.to_owned(), from, to)).unwrap_or(&0)
+    }
+
+    /// The indices of the blocks `term` can go to.
+    fn successor_inds(func: &Function, term: &Terminator) -> Vec<usize> {
+        successors(term)
+            .into_iter()
+            .map(|dest| {
+                func.basic_blocks
+                    .iter()
+                    .position(|bb| bb.name == *dest)
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    fn summary(&self, funcs: &[Function]) -> String {
+        let mut summary = format!(
+            "Coverage:\n    {:<24}{:>12}{:>12}{:>12}",
+            "function", "blocks", "edges", "lines"
+        );
+        for func in funcs {
+            let blocks = func.basic_blocks.len();
+            let hit_blocks = (0..blocks)
+                .filter(|&bb| self.block(&func.name, bb) > 0)
+                .count();
+            let (mut edges, mut hit_edges) = (0, 0);
+            let mut lines = BTreeMap::new();
+            for (from, bb) in func.basic_blocks.iter().enumerate() {
+                for to in Coverage::successor_inds(func, &bb.term) {
+                    edges += 1;
+                    if self.edge(&func.name, from, to) > 0 {
+                        hit_edges += 1;
+                    }
+                }
+                let hit = self.block(&func.name, from) > 0;
+                let locs = bb.instrs.iter().map(|inst| inst.get_debug_loc());
+                for loc in locs.chain([bb.term.get_debug_loc()]).flatten() {
+                    *lines.entry(loc.line).or_insert(false) |= hit;
+                }
+            }
+            let lines = match lines.len() {
+                0 => "-".to_owned(),
+                n => format!("{}/{}", lines.values().filter(|&&hit| hit).count(), n),
+            };
+            summary.push_str(&format!(
+                "\n    {:<24}{:>12}{:>12}{:>12}",
+                func.name,
+                format!("{}/{}", hit_blocks, blocks),
+                format!("{}/{}", hit_edges, edges),
+                lines
+            ));
+        }
+        summary
+    }
+
+    fn lcov(&self, funcs: &[Function]) -> String {
+        let mut files = BTreeMap::<String, FileCoverage>::new();
+        for func in funcs {
+            let func_loc = match &func.debugloc {
+                Some(loc) => loc,
+                None => continue,
+            };
+            let file = files.entry(source_path(func_loc)).or_default();
+            let calls = self.block(&func.name, 0);
+            file.funcs.push((func_loc.line, func.name.clone(), calls));
+
+            for (from, bb) in func.basic_blocks.iter().enumerate() {
+                let count = self.block(&func.name, from);
+                let locs = bb.instrs.iter().map(|inst| inst.get_debug_loc());
+                for loc in locs.chain([bb.term.get_debug_loc()]).flatten() {
+                    let file = files.entry(source_path(loc)).or_default();
+                    let line = file.lines.entry(loc.line).or_insert(0);
+                    *line = (*line).max(count);
+                }
+                let to = Coverage::successor_inds(func, &bb.term);
+                if let (true, Some(loc)) = (to.len() > 1, bb.term.get_debug_loc()) {
+                    let file = files.entry(source_path(loc)).or_default();
+                    for (i, to) in to.into_iter().enumerate() {
+                        let taken = match count {
+                            0 => None,
+                            _ => Some(self.edge(&func.name, from, to)),
+                        };
+                        file.branches.push((loc.line, from, i, taken));
+                    }
+                }
+            }
+        }
+
+        let mut lcov = String::new();
+        for (path, mut file) in files {
+            lcov.push_str(&format!("TN:\nSF:{}\n", path));
+            file.funcs.sort();
+            for (line, name, _) in &file.funcs {
+                lcov.push_str(&format!("FN:{},{}\n", line, name));
+            }
+            for (_, name, calls) in &file.funcs {
+                lcov.push_str(&format!("FNDA:{},{}\n", calls, name));
+            }
+            let hit = file.funcs.iter().filter(|(_, _, calls)| *calls > 0);
+            lcov.push_str(&format!("FNF:{}\nFNH:{}\n", file.funcs.len(), hit.count()));
+            for (line, block, branch, taken) in &file.branches {
+                let taken = taken.map_or("-".to_owned(), |taken| taken.to_string());
+                lcov.push_str(&format!("BRDA:{},{},{},{}\n", line, block, branch, taken));
+            }
+            let hit = file
+                .branches
+                .iter()
+                .filter(|(_, _, _, taken)| taken > &Some(0));
+            lcov.push_str(&format!(
+                "BRF:{}\nBRH:{}\n",
+                file.branches.len(),
+                hit.count()
+            ));
+            for (line, count) in &file.lines {
+                lcov.push_str(&format!("DA:{},{}\n", line, count));
+            }
+            let hit = file.lines.values().filter(|&&count| count > 0);
+            lcov.push_str(&format!(
+                "LF:{}\nLH:{}\nend_of_record\n",
+                file.lines.len(),
+                hit.count()
+            ));
+        }
+        lcov
+    }
+}
+
+impl LLVMIRInterpreter {
+    /// Record a call of `func`.
+    pub(super) fn cover_entry(&mut self, func: &str) {
+        if let Some(coverage) = &mut self.coverage {
+            *coverage.blocks.entry((func.to_owned(), 0)).or_default() += 1;
+        }
+    }
+
+    /// Record a branch from one block of `func` to another.
+    pub(super) fn cover_edge(&mut self, func: &str, from: usize, to: usize) {
+        if let Some(coverage) = &mut self.coverage {
+            *coverage.blocks.entry((func.to_owned(), to)).or_default() += 1;
+            *coverage
+                .edges
+                .entry((func.to_owned(), from, to))
+                .or_default() += 1;
+        }
+    }
+
+    /// Write the lcov tracefile and print the summary that `--coverage` and `--coverage-summary`
+    /// ask for.
+    pub fn write_coverage(&self) -> Result<(), String> {
+        let coverage = match &self.coverage {
+            Some(coverage) => coverage,
+            None => return Ok(()),
+        };
+        if coverage.summary {
+            eprintln!("{}", coverage.summary(&self.module.functions));
+        }
+        match coverage.path.as_deref() {
+            Some("-") => print!("{}", coverage.lcov(&self.module.functions)),
+            Some(path) => fs::write(path, coverage.lcov(&self.module.functions))
+                .map_err(|err| format!("Can't write coverage to '{}': {}", path, err))?,
+            None => {}
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,828 @@
+use super::{
+    debugger::{parse_break_at, BreakAt, Debugger, Resume},
+    debuginfo::source_path,
+    trace::{json_string, value_string},
+    Frame, LLVMIRInterpreter,
+};
+use llvm_ir::{BasicBlock, HasDebugLoc, Instruction, Name};
+use std::{
+    fmt,
+    io::{self, BufRead, Read, Write},
+    path::Path,
+    sync::mpsc::{self, Receiver, TryRecvError},
+    thread,
+};
+
+/// How many instructions run between checks for requests from the client, such as `pause`.
+const POLL_INTERVAL: u32 = 1_000;
+
+/// The variables reference of the globals scope. Frame `n`'s locals are `2n + 2` and its
+/// registers `2n + 3`.
+const GLOBALS_REF: u64 = 1;
+
+/// Just enough JSON for the Debug Adapter Protocol.
+#[derive(Clone, PartialEq)]
+pub(super) enum Json {
+    Null,
+    Bool(bool),
+    Num(f64),
+    Str(String),
+    Arr(Vec<Json>),
+    Obj(Vec<(String, Json)>),
+}
+
+static NULL: Json = Json::Null;
+
+impl Json {
+    fn parse(text: &str) -> Option<Json> {
+        let mut parser = Parser {
+            text: text.as_bytes(),
+            pos: 0,
+        };
+        let json = parser.value()?;
+        parser.space();
+        match parser.pos == text.len() {
+            true => Some(json),
+            false => None,
+        }
+    }
+
+    fn get(&self, key: &str) -> &Json {
+        match self {
+            Json::Obj(fields) => fields
+                .iter()
+                .find(|(k, _)| k == key)
+                .map_or(&NULL, |(_, val)| val),
+            _ => &NULL,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn as_u64(&self) -> Option<u64> {
+        match self {
+            Json::Num(n) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as u64),
+            _ => None,
+        }
+    }
+
+    fn as_arr(&self) -> &[Json] {
+        match self {
+            Json::Arr(items) => items,
+            _ => &[],
+        }
+    }
+}
+
+fn obj(fields: Vec<(&str, Json)>) -> Json {
+    Json::Obj(fields.into_iter().map(|(k, v)| (k.to_owned(), v)).collect())
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Json {
+        Json::Str(s.to_owned())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Json {
+        Json::Str(s)
+    }
+}
+
+impl From<u64> for Json {
+    fn from(n: u64) -> Json {
+        Json::Num(n as f64)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Json {
+        Json::Bool(b)
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(items: Vec<Json>) -> Json {
+        Json::Arr(items)
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Num(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            Json::Num(n) => write!(f, "{}", n),
+            Json::Str(s) => write!(f, "{}", json_string(s)),
+            Json::Arr(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Obj(fields) => {
+                write!(f, "{{")?;
+                for (i, (key, val)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}:{}", json_string(key), val)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn space(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.text.get(self.pos) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, byte: u8) -> bool {
+        self.space();
+        let found = self.text.get(self.pos) == Some(&byte);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn value(&mut self) -> Option<Json> {
+        self.space();
+        match *self.text.get(self.pos)? {
+            b'{' => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                if self.eat(b'}') {
+                    return Some(Json::Obj(fields));
+                }
+                loop {
+                    self.space();
+                    let key = self.string()?;
+                    if !self.eat(b':') {
+                        return None;
+                    }
+                    fields.push((key, self.value()?));
+                    if !self.eat(b',') {
+                        return match self.eat(b'}') {
+                            true => Some(Json::Obj(fields)),
+                            false => None,
+                        };
+                    }
+                }
+            }
+            b'[' => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if self.eat(b']') {
+                    return Some(Json::Arr(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    if !self.eat(b',') {
+                        return match self.eat(b']') {
+                            true => Some(Json::Arr(items)),
+                            false => None,
+                        };
+                    }
+                }
+            }
+            b'"' => self.string().map(Json::Str),
+            _ => {
+                let start = self.pos;
+                while let Some(b'a'..=b'z' | b'0'..=b'9' | b'+' | b'-' | b'.' | b'E') =
+                    self.text.get(self.pos)
+                {
+                    self.pos += 1;
+                }
+                match std::str::from_utf8(&self.text[start..self.pos]).ok()? {
+                    "null" => Some(Json::Null),
+                    "true" => Some(Json::Bool(true)),
+                    "false" => Some(Json::Bool(false)),
+                    num => num.parse().ok().map(Json::Num),
+                }
+            }
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        if self.text.get(self.pos) != Some(&b'"') {
+            return None;
+        }
+        self.pos += 1;
+        let mut bytes = Vec::new();
+        loop {
+            let byte = *self.text.get(self.pos)?;
+            self.pos += 1;
+            match byte {
+                b'"' => return String::from_utf8(bytes).ok(),
+                b'\\' => {
+                    let escape = *self.text.get(self.pos)?;
+                    self.pos += 1;
+                    let ch = match escape {
+                        b'n' => '\n',
+                        b't' => '\t',
+                        b'r' => '\r',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'u' => {
+                            let hex = std::str::from_utf8(self.text.get(self.pos..self.pos + 4)?);
+                            self.pos += 4;
+                            // Surrogate pairs aren't put back together.
+                            char::from_u32(u32::from_str_radix(hex.ok()?, 16).ok()?)
+                                .unwrap_or('\u{fffd}')
+                        }
+                        byte => byte as char,
+                    };
+                    bytes.extend(ch.to_string().as_bytes());
+                }
+                byte => bytes.push(byte),
+            }
+        }
+    }
+}
+
+/// Read messages, each a `Content-Length` header and a JSON body, from stdin until it's closed.
+fn read_messages(requests: mpsc::Sender<Json>) {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    loop {
+        let mut len = None;
+        loop {
+            let mut line = String::new();
+            match input.read_line(&mut line) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => {}
+            }
+            let line = line.trim();
+            if line.is_empty() {
+                break;
+            }
+            if let Some(val) = line.strip_prefix("Content-Length:") {
+                len = val.trim().parse().ok();
+            }
+        }
+        let mut body = vec![0; len.unwrap_or(0)];
+        if input.read_exact(&mut body).is_err() {
+            return;
+        }
+        let request = String::from_utf8(body)
+            .ok()
+            .and_then(|body| Json::parse(&body));
+        if let Some(request) = request {
+            if requests.send(request).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// A connection to an editor, or another client, speaking the Debug Adapter Protocol over
+/// stdin and stdout.
+pub(crate) struct DapConnection {
+    requests: Receiver<Json>,
+    seq: u64,
+    poll_in: u32,
+    /// Whether the program has stopped before, so that the first stop is reported as its entry.
+    stopped_before: bool,
+    /// Set once the client has disconnected or gone away.
+    closed: bool,
+}
+
+enum Action {
+    Done,
+    Pause,
+    Resume(Resume, Option<u32>),
+}
+
+impl DapConnection {
+    pub(super) fn new() -> DapConnection {
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || read_messages(sender));
+        DapConnection {
+            requests,
+            seq: 1,
+            poll_in: POLL_INTERVAL,
+            stopped_before: false,
+            closed: false,
+        }
+    }
+
+    fn send(&mut self, mut message: Vec<(&str, Json)>) {
+        message.insert(0, ("seq", self.seq.into()));
+        self.seq += 1;
+        let body = obj(message).to_string();
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body).ok();
+        out.flush().ok();
+    }
+
+    fn respond(&mut self, request: &Json, body: Result<Json, String>) {
+        let mut message = vec![
+            ("type", "response".into()),
+            ("request_seq", request.get("seq").clone()),
+            ("command", request.get("command").clone()),
+        ];
+        match body {
+            Ok(body) => {
+                message.push(("success", true.into()));
+                message.push(("body", body));
+            }
+            Err(err) => {
+                message.push(("success", false.into()));
+                message.push(("message", err.into()));
+            }
+        }
+        self.send(message);
+    }
+
+    fn event(&mut self, event: &str, body: Json) {
+        self.send(vec![
+            ("type", "event".into()),
+            ("event", event.into()),
+            ("body", body),
+        ]);
+    }
+
+    /// Send the program's output to the client.
+    pub(super) fn output(&mut self, category: &str, text: &str) {
+        if !self.closed {
+            let body = obj(vec![("category", category.into()), ("output", text.into())]);
+            self.event("output", body);
+        }
+    }
+
+    /// Requests that have arrived while the program was running. The client is only looked at
+    /// every `POLL_INTERVAL` instructions.
+    fn poll(&mut self) -> Vec<Json> {
+        if self.closed {
+            return Vec::new();
+        }
+        self.poll_in -= 1;
+        if self.poll_in > 0 {
+            return Vec::new();
+        }
+        self.poll_in = POLL_INTERVAL;
+        let mut requests = Vec::new();
+        loop {
+            match self.requests.try_recv() {
+                Ok(request) => requests.push(request),
+                Err(TryRecvError::Empty) => return requests,
+                Err(TryRecvError::Disconnected) => {
+                    self.closed = true;
+                    return requests;
+                }
+            }
+        }
+    }
+
+    /// Tell the client how the program ended.
+    pub(super) fn end(&mut self, result: Result<i32, &String>) {
+        if self.closed {
+            return;
+        }
+        if let Err(err) = result {
+            self.output("stderr", &format!("{}\n", err));
+        }
+        let code = match result {
+            Ok(status) => status as u8 as u64,
+            Err(_) => 1,
+        };
+        self.event("exited", obj(vec![("exitCode", code.into())]));
+        self.event("terminated", obj(Vec::new()));
+    }
+}
+
+impl Debugger {
+    /// Answer the client's requests until it has launched a program and finished setting
+    /// breakpoints. Returns the path of the program.
+    pub(super) fn dap_configure(&mut self) -> Result<String, String> {
+        let mut program = None;
+        let mut configured = false;
+        while !configured || program.is_none() {
+            let conn = self.dap_connection().unwrap();
+            let request = conn
+                .requests
+                .recv()
+                .map_err(|_| "The client went away before launching a program".to_owned())?;
+            let body = match request.get("command").as_str().unwrap_or("") {
+                "initialize" => {
+                    let caps = obj(vec![
+                        ("supportsConfigurationDoneRequest", true.into()),
+                        ("supportsFunctionBreakpoints", true.into()),
+                        ("supportsSteppingGranularity", true.into()),
+                        ("supportsStepBack", true.into()),
+                        ("supportsTerminateRequest", true.into()),
+                    ]);
+                    conn.respond(&request, Ok(caps));
+                    conn.event("initialized", obj(Vec::new()));
+                    continue;
+                }
+                "launch" => {
+                    let args = request.get("arguments");
+                    match args.get("program").as_str() {
+                        Some(path) if Path::new(path).is_file() => {
+                            program = Some(path.to_owned());
+                            self.resume = match args.get("stopOnEntry") {
+                                Json::Bool(true) => Resume::Step,
+                                _ => Resume::Continue,
+                            };
+                            Ok(obj(Vec::new()))
+                        }
+                        Some(path) => Err(format!("No such file '{}'", path)),
+                        None => Err("No program to launch".to_owned()),
+                    }
+                }
+                "configurationDone" => {
+                    configured = true;
+                    Ok(obj(Vec::new()))
+                }
+                "threads" => Ok(obj(vec![(
+                    "threads",
+                    vec![obj(vec![("id", 1.into()), ("name", "thread 1".into())])].into(),
+                )])),
+                "disconnect" | "terminate" => {
+                    conn.respond(&request, Ok(obj(Vec::new())));
+                    return Err("The client disconnected before launching a program".to_owned());
+                }
+                command => self.dap_breakpoints(command, request.get("arguments")),
+            };
+            self.dap_connection().unwrap().respond(&request, body);
+        }
+        Ok(program.unwrap())
+    }
+
+    fn dap_breakpoints(&mut self, command: &str, args: &Json) -> Result<Json, String> {
+        let mut added = Vec::new();
+        match command {
+            "setBreakpoints" => {
+                let path = args
+                    .get("source")
+                    .get("path")
+                    .as_str()
+                    .ok_or("No source path")?
+                    .to_owned();
+                // The breakpoints given replace those in the same file.
+                self.breakpoints.retain(
+                    |(_, _, at)| !matches!(at, BreakAt::Line(Some(file), _) if *file == path),
+                );
+                for bp in args.get("breakpoints").as_arr() {
+                    let line = bp.get("line").as_u64().ok_or("No line for a breakpoint")?;
+                    let spec = format!("{}:{}", path, line);
+                    let at = BreakAt::Line(Some(path.clone()), line as u32);
+                    let id = self.add_breakpoint(&spec, at);
+                    added.push(obj(vec![
+                        ("id", (id as u64).into()),
+                        ("verified", true.into()),
+                        ("line", line.into()),
+                    ]));
+                }
+            }
+            "setFunctionBreakpoints" => {
+                self.breakpoints
+                    .retain(|(_, _, at)| !matches!(at, BreakAt::Func(_)));
+                for bp in args.get("breakpoints").as_arr() {
+                    let name = bp.get("name").as_str().ok_or("No name for a breakpoint")?;
+                    let at = parse_break_at(name)?;
+                    let id = self.add_breakpoint(name, at);
+                    added.push(obj(vec![
+                        ("id", (id as u64).into()),
+                        ("verified", true.into()),
+                    ]));
+                }
+            }
+            "setExceptionBreakpoints" => {}
+            _ => return Err(format!("Unsupported request '{}'", command)),
+        }
+        Ok(obj(vec![("breakpoints", added.into())]))
+    }
+}
+
+impl LLVMIRInterpreter {
+    /// Answer requests that came in while the program was running, and stop it if a breakpoint,
+    /// the last step or the client says so.
+    pub(super) fn dap_hook(
+        &mut self,
+        debugger: &mut Debugger,
+        bb: &BasicBlock,
+        index: usize,
+        frames: &[Frame],
+        hit: Option<usize>,
+        stop: bool,
+    ) -> Result<(), String> {
+        let mut paused = false;
+        for request in debugger.dap_connection().unwrap().poll() {
+            if let Action::Pause = self.dap_request(debugger, &request, bb, index, frames)? {
+                paused = true;
+            }
+        }
+        let conn = debugger.dap_connection().unwrap();
+        let reason = match hit {
+            Some(_) => "breakpoint",
+            None if paused => "pause",
+            None if !stop => return Ok(()),
+            None if !conn.stopped_before => "entry",
+            None => "step",
+        };
+        conn.stopped_before = true;
+        let mut body = vec![
+            ("reason", reason.into()),
+            ("threadId", ((self.thread + 1) as u64).into()),
+            ("allThreadsStopped", true.into()),
+        ];
+        if let Some(id) = hit {
+            body.push(("hitBreakpointIds", vec![(id as u64).into()].into()));
+        }
+        conn.event("stopped", obj(body));
+
+        loop {
+            let request = match debugger.dap_connection().unwrap().requests.recv() {
+                Ok(request) => request,
+                Err(_) => {
+                    // Without a client, let the program run to the end.
+                    debugger.dap_connection().unwrap().closed = true;
+                    debugger.breakpoints.clear();
+                    debugger.resume = Resume::Continue;
+                    debugger.step_line = None;
+                    return Ok(());
+                }
+            };
+            if let Action::Resume(resume, step_line) =
+                self.dap_request(debugger, &request, bb, index, frames)?
+            {
+                debugger.resume = resume;
+                debugger.step_line = step_line;
+                return Ok(());
+            }
+        }
+    }
+
+    fn dap_request(
+        &mut self,
+        debugger: &mut Debugger,
+        request: &Json,
+        bb: &BasicBlock,
+        index: usize,
+        frames: &[Frame],
+    ) -> Result<Action, String> {
+        let args = request.get("arguments");
+        let mut action = Action::Done;
+        let command = request.get("command").as_str().unwrap_or("");
+        let body = match command {
+            "threads" => {
+                let threads = self
+                    .live_threads()
+                    .into_iter()
+                    .map(|tid| {
+                        obj(vec![
+                            ("id", ((tid + 1) as u64).into()),
+                            ("name", format!("thread {}", tid + 1).into()),
+                        ])
+                    })
+                    .collect::<Vec<_>>();
+                Ok(obj(vec![("threads", threads.into())]))
+            }
+            "stackTrace" => Ok(self.dap_stack_trace(frames)),
+            "scopes" => {
+                let frame = args.get("frameId").as_u64().unwrap_or(0);
+                let scope = |name: &str, reference: u64| {
+                    obj(vec![
+                        ("name", name.into()),
+                        ("variablesReference", reference.into()),
+                        ("expensive", false.into()),
+                    ])
+                };
+                Ok(obj(vec![(
+                    "scopes",
+                    vec![
+                        scope("Locals", 2 * frame + 2),
+                        scope("Registers", 2 * frame + 3),
+                        scope("Globals", GLOBALS_REF),
+                    ]
+                    .into(),
+                )]))
+            }
+            "variables" => {
+                let reference = args.get("variablesReference").as_u64().unwrap_or(0);
+                let vars = self
+                    .dap_variables(reference, frames)
+                    .into_iter()
+                    .map(|(name, value)| {
+                        obj(vec![
+                            ("name", name.into()),
+                            ("value", value.into()),
+                            ("variablesReference", 0.into()),
+                        ])
+                    })
+                    .collect::<Vec<_>>();
+                Ok(obj(vec![("variables", vars.into())]))
+            }
+            "evaluate" => {
+                let expr = args.get("expression").as_str().unwrap_or("").trim();
+                let frame = args.get("frameId").as_u64().unwrap_or(0);
+                let local = self
+                    .dap_locals(frame as usize, frames)
+                    .into_iter()
+                    .find(|(name, _)| name == expr);
+                let result = match (local, self.debug_print(expr)) {
+                    (Some((_, val)), _) | (None, Ok(val)) => Ok(val),
+                    // The debug console takes the same commands as `--debug`.
+                    (None, Err(_)) if args.get("context").as_str() == Some("repl") => {
+                        let mut out = String::new();
+                        if self
+                            .debug_command(debugger, expr, bb, index, frames, &mut out)?
+                            .is_some()
+                        {
+                            out.push_str("Use the debugger's own controls to resume the program");
+                        }
+                        Ok(out.trim_end().to_owned())
+                    }
+                    (None, Err(err)) => Err(err),
+                };
+                result.map(|result| {
+                    obj(vec![
+                        ("result", result.into()),
+                        ("variablesReference", 0.into()),
+                    ])
+                })
+            }
+            "continue" => {
+                action = Action::Resume(Resume::Continue, None);
+                Ok(obj(vec![("allThreadsContinued", true.into())]))
+            }
+            "next" | "stepIn" | "stepOut" => {
+                let depth = self.stack.len();
+                let resume = match command {
+                    "next" => Resume::Next(self.thread, depth),
+                    "stepIn" => Resume::Step,
+                    _ => Resume::Finish(self.thread, depth),
+                };
+                let by_line =
+                    command != "stepOut" && args.get("granularity").as_str() != Some("instruction");
+                let line = match bb.instrs.get(index) {
+                    Some(inst) => inst.get_debug_loc().as_ref(),
+                    None => bb.term.get_debug_loc().as_ref(),
+                }
+                .map(|loc| loc.line);
+                action = Action::Resume(resume, line.filter(|_| by_line));
+                Ok(obj(Vec::new()))
+            }
+            "stepBack" => {
+                action = Action::Resume(Resume::ReverseStep, None);
+                Ok(obj(Vec::new()))
+            }
+            "reverseContinue" => {
+                action = Action::Resume(Resume::ReverseContinue, None);
+                Ok(obj(Vec::new()))
+            }
+            "pause" => {
+                action = Action::Pause;
+                Ok(obj(Vec::new()))
+            }
+            "disconnect" | "terminate" => {
+                let conn = debugger.dap_connection().unwrap();
+                conn.respond(request, Ok(obj(Vec::new())));
+                conn.closed = true;
+                return Err("Program stopped by the debugger".to_owned());
+            }
+            command => debugger.dap_breakpoints(command, args),
+        };
+        debugger.dap_connection().unwrap().respond(request, body);
+        Ok(action)
+    }
+
+    fn dap_stack_trace(&self, frames: &[Frame]) -> Json {
+        let stack = self
+            .stack_positions(frames)
+            .into_iter()
+            .enumerate()
+            .map(|(n, (func, pos))| {
+                let loc = pos.and_then(|(bb, inst)| {
+                    let bb = &func.basic_blocks[bb];
+                    match bb.instrs.get(inst) {
+                        Some(inst) => inst.get_debug_loc().as_ref(),
+                        None => bb.term.get_debug_loc().as_ref(),
+                    }
+                });
+                let mut frame = vec![
+                    ("id", (n as u64).into()),
+                    ("name", self.source_name(&func.name).into()),
+                    ("line", loc.map_or(0, |loc| loc.line.into()).into()),
+                    (
+                        "column",
+                        loc.and_then(|loc| loc.col).map_or(0, u64::from).into(),
+                    ),
+                ];
+                if let Some(loc) = loc {
+                    let source = obj(vec![
+                        ("name", file_name(&loc.filename).into()),
+                        ("path", source_path(loc).into()),
+                    ]);
+                    frame.push(("source", source));
+                }
+                obj(frame)
+            })
+            .collect::<Vec<_>>();
+        let total = stack.len() as u64;
+        obj(vec![
+            ("stackFrames", stack.into()),
+            ("totalFrames", total.into()),
+        ])
+    }
+
+    /// The source variables of frame `n` that have been given their stack slots so far, with
+    /// their values.
+    fn dap_locals(&self, n: usize, frames: &[Frame]) -> Vec<(String, String)> {
+        let func = match self.stack_positions(frames).get(n) {
+            Some((func, _)) => *func,
+            None => return Vec::new(),
+        };
+        let vars = match (self.frame_vars(n), self.local_vars.get(&func.name)) {
+            (Some(vars), Some(locals)) => (vars, locals),
+            _ => return Vec::new(),
+        };
+        let allocas = func
+            .basic_blocks
+            .iter()
+            .flat_map(|bb| &bb.instrs)
+            .filter_map(|inst| match inst {
+                Instruction::Alloca(alloca) => Some((&alloca.dest, &alloca.allocated_type)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let (vars, locals) = vars;
+        locals
+            .iter()
+            .filter_map(|(reg, name)| {
+                let addr = self.get_int_op(vars.get(reg)?).ok()?;
+                let ty = allocas.iter().find(|(dest, _)| *dest == reg)?.1;
+                let val = match self.load_val(addr, ty) {
+                    Ok(val) => value_string(&val, ty),
+                    Err(_) => "?".to_owned(),
+                };
+                Some((name.clone(), val))
+            })
+            .collect()
+    }
+
+    fn dap_variables(&self, reference: u64, frames: &[Frame]) -> Vec<(String, String)> {
+        if reference == GLOBALS_REF {
+            return self
+                .module
+                .global_vars
+                .iter()
+                .map(|gl_var| {
+                    let name = format!("@{}", name_string(&gl_var.name));
+                    let val = self.debug_print(&name).unwrap_or_else(|err| err);
+                    (name, val)
+                })
+                .collect();
+        }
+        let n = (reference.saturating_sub(2) / 2) as usize;
+        if reference.is_multiple_of(2) {
+            return self.dap_locals(n, frames);
+        }
+        let mut regs = self
+            .frame_vars(n)
+            .into_iter()
+            .flatten()
+            .map(|(name, val)| (name.to_string(), self.debug_value(val)))
+            .collect::<Vec<_>>();
+        regs.sort();
+        regs
+    }
+}
+
+fn file_name(path: &str) -> &str {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(path)
+}
+
+fn name_string(name: &Name) -> String {
+    match name {
+        Name::Name(name) => name.to_string(),
+        Name::Number(n) => n.to_string(),
+    }
+}
@@ -0,0 +1,584 @@
+use super::{
+    dap::DapConnection, debuginfo::format_loc, gdb::GdbConnection, without_debugloc, Frame,
+    LLVMIRInterpreter,
+};
+use llvm_ir::{
+    constant::Constant, name::Name, BasicBlock, DebugLoc, Function, HasDebugLoc, Operand, Type,
+};
+use std::io::{self, BufRead, IsTerminal, Write};
+
+const HELP: &str = "Commands:
+    break <where>       stop at <where>: a function `f`, a block `f:%bb`, an instruction
+                        `f:%bb:3`, or a source line `file.c:12` or `12`
+    delete [n]          delete breakpoint n, or all of them
+    info breakpoints    list breakpoints
+    info registers      print the registers of the current function
+    step                execute one instruction, entering calls
+    next                execute one instruction, stepping over calls
+    finish              run until the current function returns
+    continue            run until a breakpoint is hit
+    reverse-step        go back one instruction
+    reverse-continue    go back to the last breakpoint hit
+    print %reg|@global  print a register or a global
+    x <addr> [len]      print len bytes of memory (default: 16); addr may be a number, a register
+                        or a global
+    backtrace           print the call stack
+    snapshot <file>     write the program's state to a file, for `--restore`
+    list                print the current block
+    quit                stop the program";
+
+pub(super) enum BreakAt {
+    Func(String),
+    Block(String, Name),
+    Inst(String, Name, usize),
+    Line(Option<String>, u32),
+}
+
+impl BreakAt {
+    /// Whether the instruction at `index` in `bb` is here. A source line is only hit on entering
+    /// it from `last_line`.
+    pub(super) fn hit(
+        &self,
+        func: &Function,
+        source_name: &str,
+        bb: &BasicBlock,
+        index: usize,
+        last_line: Option<u32>,
+    ) -> bool {
+        let loc = match bb.instrs.get(index) {
+            Some(inst) => inst.get_debug_loc(),
+            None => bb.term.get_debug_loc(),
+        };
+        let is_func = |name: &str| name == func.name || name == source_name;
+        match self {
+            BreakAt::Func(name) => {
+                is_func(name) && bb.name == func.basic_blocks[0].name && index == 0
+            }
+            BreakAt::Block(name, block) => is_func(name) && bb.name == *block && index == 0,
+            BreakAt::Inst(name, block, i) => is_func(name) && bb.name == *block && index == *i,
+            BreakAt::Line(file, line) => match loc {
+                Some(loc) => {
+                    loc.line == *line
+                        && last_line != Some(*line)
+                        && file.as_ref().is_none_or(|file| {
+                            // Editors give absolute paths, while debug info has them relative
+                            // to where the program was compiled.
+                            loc.filename.ends_with(file.as_str())
+                                || file.ends_with(loc.filename.as_str())
+                        })
+                }
+                None => false,
+            },
+        }
+    }
+}
+
+pub(super) fn parse_name(name: &str) -> Name {
+    let name = name.trim_start_matches('%');
+    match name.parse() {
+        Ok(n) => Name::Number(n),
+        Err(_) => Name::from(name),
+    }
+}
+
+pub(super) fn parse_break_at(spec: &str) -> Result<BreakAt, String> {
+    let parts = spec.split(':').collect::<Vec<_>>();
+    let at = match parts.as_slice() {
+        [line] if line.parse::<u32>().is_ok() => BreakAt::Line(None, line.parse().unwrap()),
+        [file, line] if file.contains('.') && line.parse::<u32>().is_ok() => {
+            BreakAt::Line(Some(file.to_string()), line.parse().unwrap())
+        }
+        [func] => BreakAt::Func(func.trim_start_matches('@').to_owned()),
+        [func, block] => BreakAt::Block(func.trim_start_matches('@').to_owned(), parse_name(block)),
+        [func, block, index] => match index.parse() {
+            Ok(index) => BreakAt::Inst(
+                func.trim_start_matches('@').to_owned(),
+                parse_name(block),
+                index,
+            ),
+            Err(_) => return Err(format!("Invalid instruction index '{}'", index)),
+        },
+        _ => return Err(format!("Invalid breakpoint location '{}'", spec)),
+    };
+    Ok(at)
+}
+
+#[derive(Clone, Copy)]
+pub(super) enum Resume {
+    Step,
+    /// Stop once the thread is back at this call depth or above it.
+    Next(usize, usize),
+    /// Stop once the thread has returned from the function at this call depth.
+    Finish(usize, usize),
+    Continue,
+    ReverseStep,
+    ReverseContinue,
+}
+
+/// How far back in time the debugger is going, by running the program again from the start.
+#[derive(Clone, Copy)]
+enum Rewind {
+    /// Stop once the program gets to this point.
+    To(u64),
+    /// Find the last breakpoint hit before this point, and the point it was hit at so far.
+    LastHit(u64, Option<u64>),
+}
+
+/// Where a debugger's commands come from.
+enum Frontend {
+    Prompt {
+        input: Box<dyn BufRead>,
+        interactive: bool,
+        last_command: String,
+    },
+    Gdb(GdbConnection),
+    Dap(DapConnection),
+}
+
+/// A gdb-like command prompt that can stop the program between any two instructions.
+pub struct Debugger {
+    frontend: Frontend,
+    pub(super) breakpoints: Vec<(usize, String, BreakAt)>,
+    next_id: usize,
+    pub(super) resume: Resume,
+    /// The source line of the last instruction executed, so that line breakpoints only stop once
+    /// per visit to a line.
+    last_line: Option<u32>,
+    /// When stepping by source lines, the line being stepped off: instructions on it, or without
+    /// a line, don't stop the program.
+    pub(super) step_line: Option<u32>,
+    /// How many times the program has been checked for a stop, which is the point in time the
+    /// program is at.
+    clock: u64,
+    /// The furthest point the program has got to.
+    seen: u64,
+    rewind: Option<Rewind>,
+    /// Set when the program should be run again from the start.
+    restart: bool,
+}
+
+impl Debugger {
+    /// A debugger reading commands from stdin. It stops before the program's first instruction.
+    pub fn new() -> Debugger {
+        Debugger::with_frontend(Frontend::Prompt {
+            input: Box::new(io::BufReader::new(io::stdin())),
+            interactive: io::stdin().is_terminal(),
+            last_command: String::new(),
+        })
+    }
+
+    /// A debugger controlled by a gdb or lldb that connects to `port` on localhost, using the
+    /// GDB remote serial protocol. It waits for the connection before returning.
+    pub fn gdb(port: u16) -> Result<Debugger, String> {
+        Ok(Debugger::with_frontend(Frontend::Gdb(
+            GdbConnection::accept(port)?,
+        )))
+    }
+
+    /// A debugger controlled by an editor speaking the Debug Adapter Protocol on stdin and
+    /// stdout. It waits for the editor to launch a program, and returns that program's path.
+    pub fn dap() -> Result<(Debugger, String), String> {
+        let mut debugger = Debugger::with_frontend(Frontend::Dap(DapConnection::new()));
+        let program = debugger.dap_configure()?;
+        Ok((debugger, program))
+    }
+
+    fn with_frontend(frontend: Frontend) -> Debugger {
+        Debugger {
+            frontend,
+            breakpoints: Vec::new(),
+            next_id: 1,
+            resume: Resume::Step,
+            last_line: None,
+            step_line: None,
+            clock: 0,
+            seen: 0,
+            rewind: None,
+            restart: false,
+        }
+    }
+
+    /// Whether the program is to run again from the start, for the debugger to go back in time.
+    /// Readies the debugger for that.
+    pub(super) fn restarting(&mut self) -> bool {
+        if !self.restart {
+            return false;
+        }
+        self.restart = false;
+        self.seen = self.seen.max(self.clock);
+        self.clock = 0;
+        self.last_line = None;
+        true
+    }
+
+    /// Whether the program is running again through what it has done before.
+    pub(super) fn replaying(&self) -> bool {
+        self.clock < self.seen
+    }
+
+    /// Start going back in time if the last command said so. The program then stops with an
+    /// error, for it to be run again from the start.
+    fn reverse(&mut self) -> Result<(), String> {
+        let rewind = match self.resume {
+            Resume::ReverseStep => Rewind::To(self.clock - 1),
+            Resume::ReverseContinue => Rewind::LastHit(self.clock, None),
+            _ => return Ok(()),
+        };
+        self.rewind = Some(rewind);
+        self.resume = Resume::Continue;
+        self.step_line = None;
+        self.restart = true;
+        Err("Restarting the program".to_owned())
+    }
+
+    pub(super) fn gdb_connection(&mut self) -> Option<&mut GdbConnection> {
+        match &mut self.frontend {
+            Frontend::Gdb(conn) => Some(conn),
+            _ => None,
+        }
+    }
+
+    pub(super) fn dap_connection(&mut self) -> Option<&mut DapConnection> {
+        match &mut self.frontend {
+            Frontend::Dap(conn) => Some(conn),
+            _ => None,
+        }
+    }
+
+    fn read_command(&mut self) -> Option<String> {
+        let (input, interactive, last_command) = match &mut self.frontend {
+            Frontend::Prompt {
+                input,
+                interactive,
+                last_command,
+            } => (input, *interactive, last_command),
+            Frontend::Gdb(_) | Frontend::Dap(_) => return None,
+        };
+        if interactive {
+            print!("(bcvm) ");
+            io::stdout().flush().ok();
+        }
+        let mut line = String::new();
+        match input.read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) if line.trim().is_empty() => Some(last_command.clone()),
+            Ok(_) => {
+                *last_command = line.trim().to_owned();
+                Some(last_command.clone())
+            }
+        }
+    }
+
+    pub(super) fn add_breakpoint(&mut self, spec: &str, at: BreakAt) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.breakpoints.push((id, spec.to_owned(), at));
+        id
+    }
+
+    fn hit(
+        &self,
+        func: &Function,
+        source_name: &str,
+        bb: &BasicBlock,
+        index: usize,
+    ) -> Option<usize> {
+        self.breakpoints
+            .iter()
+            .find(|(_, _, at)| at.hit(func, source_name, bb, index, self.last_line))
+            .map(|(id, _, _)| *id)
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        Debugger::new()
+    }
+}
+
+impl LLVMIRInterpreter {
+    /// Stop before the instruction at `index` in `bb` if a breakpoint or the last command says
+    /// so, and take commands until the program is to carry on.
+    pub(super) fn debug_hook(
+        &mut self,
+        func: &Function,
+        bb: &BasicBlock,
+        index: usize,
+        frames: &[Frame],
+    ) -> Result<(), String> {
+        let mut debugger = match self.debugger.take() {
+            Some(debugger) => debugger,
+            None => return Ok(()),
+        };
+        debugger.clock += 1;
+        let result = self
+            .debug_stop(&mut debugger, func, bb, index, frames)
+            .and_then(|()| debugger.reverse());
+        let loc: Option<&DebugLoc> = match bb.instrs.get(index) {
+            Some(inst) => inst.get_debug_loc().as_ref(),
+            None => bb.term.get_debug_loc().as_ref(),
+        };
+        debugger.last_line = loc.map(|loc| loc.line);
+        self.debugger = Some(debugger);
+        result
+    }
+
+    fn debug_stop(
+        &mut self,
+        debugger: &mut Debugger,
+        func: &Function,
+        bb: &BasicBlock,
+        index: usize,
+        frames: &[Frame],
+    ) -> Result<(), String> {
+        let depth = self.stack.len();
+        let hit = debugger.hit(func, self.source_name(&func.name), bb, index);
+        let clock = debugger.clock;
+        match debugger.rewind {
+            Some(Rewind::To(target)) if clock < target => return Ok(()),
+            Some(Rewind::To(_)) => {
+                debugger.rewind = None;
+                debugger.resume = Resume::Step;
+            }
+            Some(Rewind::LastHit(limit, _)) if clock < limit => {
+                if hit.is_some() {
+                    debugger.rewind = Some(Rewind::LastHit(limit, Some(clock)));
+                }
+                return Ok(());
+            }
+            // Without a breakpoint hit, go back to the start.
+            Some(Rewind::LastHit(_, last)) => {
+                debugger.rewind = Some(Rewind::To(last.unwrap_or(1)));
+                debugger.restart = true;
+                return Err("Restarting the program".to_owned());
+            }
+            None => {}
+        }
+        let stop = match debugger.resume {
+            Resume::Step => true,
+            Resume::Next(thread, d) => self.thread == thread && depth <= d,
+            Resume::Finish(thread, d) => self.thread == thread && depth < d,
+            Resume::Continue | Resume::ReverseStep | Resume::ReverseContinue => false,
+        };
+        let stop = stop
+            && match debugger.step_line {
+                Some(step_line) => {
+                    let loc = match bb.instrs.get(index) {
+                        Some(inst) => inst.get_debug_loc(),
+                        None => bb.term.get_debug_loc(),
+                    };
+                    loc.as_ref().is_some_and(|loc| loc.line != step_line)
+                }
+                None => true,
+            };
+        if debugger.dap_connection().is_some() {
+            return self.dap_hook(debugger, bb, index, frames, hit, stop);
+        }
+        if let Some(conn) = debugger.gdb_connection() {
+            if hit.is_some() || stop || conn.interrupted() {
+                self.gdb_stop(debugger, func, bb, index, frames)?;
+            }
+            return Ok(());
+        }
+        match hit {
+            Some(id) => println!("Breakpoint {}, {}", id, self.location()),
+            None if stop => println!("Stopped at {}", self.location()),
+            None => return Ok(()),
+        }
+
+        loop {
+            let command = match debugger.read_command() {
+                Some(command) => command,
+                None => {
+                    // Without more commands, let the program run to the end.
+                    debugger.breakpoints.clear();
+                    debugger.resume = Resume::Continue;
+                    return Ok(());
+                }
+            };
+            let mut out = String::new();
+            let resume = self.debug_command(debugger, &command, bb, index, frames, &mut out)?;
+            print!("{}", out);
+            if let Some(resume) = resume {
+                debugger.resume = resume;
+                return Ok(());
+            }
+        }
+    }
+
+    /// Run a debugger command, adding what it prints to `out`. Commands that let the program
+    /// carry on return how it should.
+    pub(super) fn debug_command(
+        &mut self,
+        debugger: &mut Debugger,
+        command: &str,
+        bb: &BasicBlock,
+        index: usize,
+        frames: &[Frame],
+        out: &mut String,
+    ) -> Result<Option<Resume>, String> {
+        let depth = self.stack.len();
+        let mut words = command.split_whitespace();
+        let (cmd, args) = (words.next().unwrap_or(""), words.collect::<Vec<_>>());
+        match (cmd, args.as_slice()) {
+            ("s" | "step", []) => return Ok(Some(Resume::Step)),
+            ("n" | "next", []) => return Ok(Some(Resume::Next(self.thread, depth))),
+            ("finish", []) => return Ok(Some(Resume::Finish(self.thread, depth))),
+            ("c" | "continue", []) => return Ok(Some(Resume::Continue)),
+            ("rs" | "reverse-step", []) => return Ok(Some(Resume::ReverseStep)),
+            ("rc" | "reverse-continue", []) => return Ok(Some(Resume::ReverseContinue)),
+            ("q" | "quit", []) => return Err("Program stopped by the debugger".to_owned()),
+            ("b" | "break", [spec]) => match parse_break_at(spec) {
+                Ok(at) => {
+                    let id = debugger.add_breakpoint(spec, at);
+                    out.push_str(&format!("Breakpoint {} at {}\n", id, spec));
+                }
+                Err(err) => out.push_str(&format!("{}\n", err)),
+            },
+            ("d" | "delete", []) => debugger.breakpoints.clear(),
+            ("d" | "delete", [id]) => {
+                let len = debugger.breakpoints.len();
+                debugger
+                    .breakpoints
+                    .retain(|(bp_id, _, _)| bp_id.to_string() != *id);
+                if debugger.breakpoints.len() == len {
+                    out.push_str(&format!("No breakpoint {}\n", id));
+                }
+            }
+            ("info", ["b" | "breakpoints"]) => {
+                for (id, spec, _) in &debugger.breakpoints {
+                    out.push_str(&format!("{} {}\n", id, spec));
+                }
+            }
+            ("info", ["registers" | "locals"]) => {
+                let mut vars = self
+                    .vars
+                    .iter()
+                    .map(|(name, val)| format!("{} = {}\n", name, self.debug_value(val)))
+                    .collect::<Vec<_>>();
+                vars.sort();
+                out.push_str(&vars.concat());
+            }
+            ("p" | "print", [what]) => match self.debug_print(what) {
+                Ok(val) => out.push_str(&format!("{} = {}\n", what, val)),
+                Err(err) => out.push_str(&format!("{}\n", err)),
+            },
+            ("x", [addr]) | ("x", [addr, _]) => {
+                let len = match args.get(1).map(|len| len.parse::<u64>()) {
+                    Some(Ok(len)) => len,
+                    Some(Err(_)) => {
+                        out.push_str(&format!("Invalid length '{}'\n", args[1]));
+                        return Ok(None);
+                    }
+                    None => 16,
+                };
+                match self.debug_examine(addr, len) {
+                    Ok(dump) => out.push_str(&format!("{}\n", dump)),
+                    Err(err) => out.push_str(&format!("{}\n", err)),
+                }
+            }
+            ("bt" | "backtrace", []) => {
+                let backtrace = self.call_backtrace(frames);
+                out.push_str(&format!("{}\n", backtrace.trim_start_matches('\n')));
+            }
+            ("l" | "list", []) => {
+                let lines = bb
+                    .instrs
+                    .iter()
+                    .map(|inst| (inst.to_string(), inst.get_debug_loc().as_ref()));
+                let term = (bb.term.to_string(), bb.term.get_debug_loc().as_ref());
+                out.push_str(&format!("{}:\n", bb.name));
+                for (i, (text, loc)) in lines.chain([term]).enumerate() {
+                    let marker = match i == index {
+                        true => "=>",
+                        false => "  ",
+                    };
+                    let loc = loc.map_or(String::new(), |loc| format!("  ; {}", format_loc(loc)));
+                    out.push_str(&format!(
+                        "{} {:>3}  {}{}\n",
+                        marker,
+                        i,
+                        without_debugloc(&text),
+                        loc
+                    ));
+                }
+            }
+            ("snapshot", [path]) => match self.save_snapshot(path, &bb.name, index, frames) {
+                Ok(()) => out.push_str(&format!("Snapshot written to {}\n", path)),
+                Err(err) => out.push_str(&format!("{}\n", err)),
+            },
+            ("h" | "help", []) => out.push_str(&format!("{}\n", HELP)),
+            _ => out.push_str(&format!("Unknown command '{}'; try 'help'\n", command)),
+        }
+        Ok(None)
+    }
+
+    pub(super) fn debug_value(&self, val: &Operand) -> String {
+        match val {
+            Operand::ConstantOperand(val) => val.to_string(),
+            _ => "?".to_owned(),
+        }
+    }
+
+    pub(super) fn debug_print(&self, what: &str) -> Result<String, String> {
+        if what.starts_with('%') {
+            return match self.vars.get(&parse_name(what)) {
+                Some(val) => Ok(self.debug_value(val)),
+                None => Err(format!("No register {} in this function", what)),
+            };
+        }
+        let name = Name::from(what.trim_start_matches('@'));
+        let gl_var = self
+            .module
+            .global_vars
+            .iter()
+            .find(|gl_var| gl_var.name == name);
+        match (gl_var, self.gl_vars.get(&name)) {
+            (Some(gl_var), Some(&addr)) => {
+                let ty = match gl_var.ty.as_ref() {
+                    Type::PointerType { pointee_type, .. } => pointee_type.clone(),
+                    _ => gl_var.ty.clone(),
+                };
+                Ok(format!("{} at {:#x}", self.load_val(addr, &ty)?, addr))
+            }
+            (None, Some(&addr)) => Ok(format!("function at {:#x}", addr)),
+            _ => Err(format!("No register or global named {}", what)),
+        }
+    }
+
+    fn debug_examine(&self, addr: &str, len: u64) -> Result<String, String> {
+        let addr = match addr.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).ok(),
+            None if addr.starts_with('%') => match self.vars.get(&parse_name(addr)) {
+                Some(op) => match self.eval_op(op)?.as_ref() {
+                    Constant::Int { value, .. } => Some(*value),
+                    val => return Err(format!("{} is {}, not an address", addr, val)),
+                },
+                None => None,
+            },
+            None if addr.starts_with('@') => self.gl_vars.get(&Name::from(&addr[1..])).copied(),
+            None => addr.parse().ok(),
+        }
+        .ok_or_else(|| format!("Invalid address '{}'", addr))?;
+        let bytes = self.memory.read(addr, len)?;
+        let rows = bytes.chunks(16).enumerate().map(|(i, row)| {
+            let row = row.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>();
+            format!("{:#x}: {}", addr + 16 * i as u64, row.join(" "))
+        });
+        Ok(rows.collect::<Vec<_>>().join("\n"))
+    }
+
+    /// Tell a connected gdb or editor how the program ended.
+    pub fn end_debugging(&mut self, result: &Result<(), String>) {
+        let result = result.as_ref().map(|_| self.exit_status.unwrap_or(0));
+        match self
+            .debugger
+            .as_mut()
+            .map(|debugger| &mut debugger.frontend)
+        {
+            Some(Frontend::Gdb(conn)) => conn.end(result),
+            Some(Frontend::Dap(conn)) => conn.end(result),
+            _ => {}
+        }
+    }
+}
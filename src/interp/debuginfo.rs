@@ -0,0 +1,131 @@
+use super::{trace::value_string, without_debugloc, Frame, LLVMIRInterpreter};
+use llvm_ir::{name::Name, DebugLoc, Function, HasDebugLoc, Operand};
+use std::collections::HashMap;
+
+/// A source location as compilers print them, e.g. `main.c:12:5`.
+pub(super) fn format_loc(loc: &DebugLoc) -> String {
+    match loc.col {
+        Some(col) => format!("{}:{}:{}", loc.filename, loc.line, col),
+        None => format!("{}:{}", loc.filename, loc.line),
+    }
+}
+
+/// The path of a location's source file, relative to the directory it was compiled in.
+pub(super) fn source_path(loc: &DebugLoc) -> String {
+    match &loc.directory {
+        Some(dir) if !dir.is_empty() && !loc.filename.starts_with('/') => {
+            format!("{}/{}", dir.trim_end_matches('/'), loc.filename)
+        }
+        _ => loc.filename.clone(),
+    }
+}
+
+impl LLVMIRInterpreter {
+    /// The name `func` has in the source, if the program has debug info for it.
+    pub(super) fn source_name<'a>(&'a self, func: &'a str) -> &'a str {
+        self.source_names
+            .get(func)
+            .map_or(func, |name| name.as_str())
+    }
+
+    /// Where the instruction being executed comes from in the source, e.g. `main.c:12:5 in
+    /// main`, if the program was compiled with debug info.
+    pub(super) fn source_location(&self) -> Option<String> {
+        let func = self.module.get_func_by_name(self.stack.last()?)?;
+        let (bb, inst) = self.pc;
+        let bb = &func.basic_blocks[bb];
+        let loc = match bb.instrs.get(inst) {
+            Some(inst) => inst.get_debug_loc(),
+            None => bb.term.get_debug_loc(),
+        };
+        Some(format!(
+            "{} in {}",
+            format_loc(loc.as_ref()?),
+            self.source_name(&func.name)
+        ))
+    }
+
+    /// The functions on the running thread's stack, innermost first, with the block and
+    /// instruction index each is at. Callers are at the call before the instruction they continue
+    /// from; `frames` are those instructions.
+    pub(super) fn stack_positions(
+        &self,
+        frames: &[Frame],
+    ) -> Vec<(&Function, Option<(usize, usize)>)> {
+        let depth = self.stack.len();
+        let mut positions = Vec::new();
+        for (i, func) in self.stack.iter().enumerate().rev() {
+            let func = self.module.get_func_by_name(func).unwrap();
+            let pos = match (i + 1 == depth, frames.get(i)) {
+                (true, _) => Some(self.pc),
+                (false, Some((_, bb_name, inst, _))) => {
+                    let bb = func
+                        .basic_blocks
+                        .iter()
+                        .position(|bb| bb.name == *bb_name)
+                        .unwrap();
+                    Some((bb, inst - 1))
+                }
+                (false, None) => None,
+            };
+            positions.push((func, pos));
+        }
+        positions
+    }
+
+    /// The registers of the `n`th frame from the innermost one on the running thread's stack.
+    pub(super) fn frame_vars(&self, n: usize) -> Option<&HashMap<Name, Operand>> {
+        match n {
+            0 => Some(&self.vars),
+            _ => self.callstack.get((self.stack.len() - 1).checked_sub(n)?),
+        }
+    }
+
+    /// The running thread's call stack, innermost frame first, with each frame's arguments and
+    /// the instruction it's at. `frames` are where each caller continues once its callee returns.
+    pub(super) fn call_backtrace(&self, frames: &[Frame]) -> String {
+        let mut backtrace = String::new();
+        for (n, (func, pos)) in self.stack_positions(frames).into_iter().enumerate() {
+            let vars = self.frame_vars(n);
+            let args = func
+                .parameters
+                .iter()
+                .map(|param| {
+                    let val = match vars.and_then(|vars| vars.get(&param.name)) {
+                        Some(Operand::ConstantOperand(val)) => value_string(val, &param.ty),
+                        _ => "?".to_owned(),
+                    };
+                    match &param.name {
+                        Name::Name(name) => format!("{}={}", name, val),
+                        Name::Number(_) => val,
+                    }
+                })
+                .collect::<Vec<_>>();
+            backtrace.push_str(&format!(
+                "\n    #{} {} ({})",
+                n,
+                self.source_name(&func.name),
+                args.join(", ")
+            ));
+
+            let (bb, inst) = match pos {
+                Some(pos) => pos,
+                None => continue,
+            };
+            let bb = &func.basic_blocks[bb];
+            let (text, loc) = match bb.instrs.get(inst) {
+                Some(inst) => (inst.to_string(), inst.get_debug_loc()),
+                None => (bb.term.to_string(), bb.term.get_debug_loc()),
+            };
+            if let Some(loc) = loc {
+                backtrace.push_str(&format!(" at {}", format_loc(loc)));
+            }
+            backtrace.push_str(&format!(
+                "\n        `{}` in block {}",
+                without_debugloc(&text),
+                bb.name
+            ));
+        }
+        backtrace
+    }
+}
@@ -0,0 +1,739 @@
+use super::{
+    memory::ptr,
+    ops::{get_int, int},
+    snapshot::{Reader, Writer},
+    Debugger, LLVMIRInterpreter,
+};
+use llvm_ir::{instruction::Call, name::Name, ConstantRef};
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+// Flags of `open` and whences of `lseek`, as on Linux.
+const O_ACCMODE: u64 = 0o3;
+const O_RDONLY: u64 = 0o0;
+const O_WRONLY: u64 = 0o1;
+const O_RDWR: u64 = 0o2;
+const O_CREAT: u64 = 0o100;
+const O_EXCL: u64 = 0o200;
+const O_TRUNC: u64 = 0o1000;
+const O_APPEND: u64 = 0o2000;
+const O_NOFOLLOW: u64 = 0o400000;
+const SEEK_SET: u64 = 0;
+const SEEK_CUR: u64 = 1;
+const SEEK_END: u64 = 2;
+
+// The most a single read takes from a file, however much the program asks for.
+const READ_CHUNK: u64 = 64 << 10;
+// The biggest a file in memory may grow, as big as the biggest allocation.
+const MAX_FILE_SIZE: u64 = 1 << 32;
+
+/// Where the files a program opens live.
+#[derive(Clone)]
+pub enum FileSystem {
+    /// The host's files. Given a directory, the program can only open files under it, and sees it
+    /// as `/`.
+    Host(Option<String>),
+    /// Files kept in memory by path, which are gone once the program ends.
+    Memory(HashMap<String, Vec<u8>>),
+}
+
+enum Backing {
+    Stdin,
+    Stdout,
+    Stderr,
+    Host { file: File, path: PathBuf },
+    Memory { path: String, pos: u64 },
+}
+
+struct OpenFile {
+    backing: Backing,
+    readable: bool,
+    writable: bool,
+    append: bool,
+}
+
+/// The program's file descriptors, and the `FILE *` streams on top of them.
+pub(super) struct Files {
+    /// The file system as it was when the program started, for it to start again.
+    initial: FileSystem,
+    fs: FileSystem,
+    fds: BTreeMap<i32, OpenFile>,
+    /// The descriptors of the open streams, by the address of their `FILE`.
+    streams: HashMap<u64, i32>,
+}
+
+/// `path` without `.` or `..`, relative to the root of the file system, which it can't get out of.
+fn normalise(path: &str) -> String {
+    let mut parts = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+/// Read up to `len` bytes, stopping after a newline if `line`. A failed read reads as the end of
+/// the file.
+fn read_from(reader: &mut impl Read, len: u64, line: bool) -> Vec<u8> {
+    let len = len.min(READ_CHUNK);
+    if !line {
+        let mut data = vec![0; len as usize];
+        let read = reader.read(&mut data).unwrap_or(0);
+        data.truncate(read);
+        return data;
+    }
+    let mut data = Vec::new();
+    let mut byte = [0];
+    while (data.len() as u64) < len && data.last() != Some(&b'\n') {
+        match reader.read(&mut byte) {
+            Ok(1) => data.push(byte[0]),
+            _ => break,
+        }
+    }
+    data
+}
+
+/// The `open` flags for an `fopen` mode.
+fn fopen_flags(mode: &str) -> Option<u64> {
+    Some(match mode.replace('b', "").as_str() {
+        "r" => O_RDONLY,
+        "r+" => O_RDWR,
+        "w" => O_WRONLY | O_CREAT | O_TRUNC,
+        "w+" => O_RDWR | O_CREAT | O_TRUNC,
+        "a" => O_WRONLY | O_CREAT | O_APPEND,
+        "a+" => O_RDWR | O_CREAT | O_APPEND,
+        _ => return None,
+    })
+}
+
+impl Files {
+    pub(super) fn new(fs: FileSystem) -> Files {
+        let fs = match fs {
+            FileSystem::Memory(files) => FileSystem::Memory(
+                files
+                    .into_iter()
+                    .map(|(path, contents)| (normalise(&path), contents))
+                    .collect(),
+            ),
+            fs => fs,
+        };
+        let std = |backing, readable| OpenFile {
+            backing,
+            readable,
+            writable: !readable,
+            append: false,
+        };
+        let fds = vec![
+            (0, std(Backing::Stdin, true)),
+            (1, std(Backing::Stdout, false)),
+            (2, std(Backing::Stderr, false)),
+        ];
+        Files {
+            initial: fs.clone(),
+            fs,
+            fds: fds.into_iter().collect(),
+            streams: HashMap::new(),
+        }
+    }
+
+    /// The files as they were when the program started, for it to run again.
+    pub(super) fn restarted(&self) -> Files {
+        Files::new(self.initial.clone())
+    }
+
+    /// Where `path` is on the host, if the files are the host's.
+    fn host_path(&self, path: &str) -> Option<PathBuf> {
+        match &self.fs {
+            FileSystem::Host(Some(root)) => Some(Path::new(root).join(normalise(path))),
+            FileSystem::Host(None) => Some(PathBuf::from(path)),
+            FileSystem::Memory(_) => None,
+        }
+    }
+
+    /// Open `path`, which is at `host_path` if the files are the host's.
+    fn open(&mut self, path: &str, host_path: Option<PathBuf>, flags: u64) -> Result<i32, i32> {
+        let (readable, writable) = match flags & O_ACCMODE {
+            O_RDONLY => (true, false),
+            O_WRONLY => (false, true),
+            O_RDWR => (true, true),
+            _ => return Err(libc::EINVAL),
+        };
+        let (create, excl, trunc) = (
+            flags & O_CREAT != 0,
+            flags & O_EXCL != 0,
+            flags & O_TRUNC != 0,
+        );
+        let backing = match (&mut self.fs, host_path) {
+            (_, Some(path)) => {
+                let file = OpenOptions::new()
+                    .read(readable)
+                    .write(writable)
+                    .append(flags & O_APPEND != 0)
+                    .truncate(trunc)
+                    .create(create && !excl)
+                    .create_new(create && excl)
+                    .custom_flags((flags & O_NOFOLLOW) as i32)
+                    .open(&path)
+                    .map_err(|err| err.raw_os_error().unwrap_or(libc::EINVAL))?;
+                Backing::Host { file, path }
+            }
+            (FileSystem::Memory(files), None) => {
+                let path = normalise(path);
+                match files.get_mut(&path) {
+                    Some(_) if create && excl => return Err(libc::EEXIST),
+                    Some(contents) if trunc => contents.clear(),
+                    Some(_) => {}
+                    None if create => {
+                        files.insert(path.clone(), Vec::new());
+                    }
+                    None => return Err(libc::ENOENT),
+                }
+                Backing::Memory { path, pos: 0 }
+            }
+            (FileSystem::Host(_), None) => unreachable!(),
+        };
+        let fd = (0..).find(|fd| !self.fds.contains_key(fd)).unwrap();
+        let file = OpenFile {
+            backing,
+            readable,
+            writable,
+            append: flags & O_APPEND != 0,
+        };
+        self.fds.insert(fd, file);
+        Ok(fd)
+    }
+
+    fn close(&mut self, fd: i32) -> Result<(), i32> {
+        self.fds.remove(&fd).map(drop).ok_or(libc::EBADF)
+    }
+
+    /// Read from a file in memory.
+    fn read(&mut self, fd: i32, len: u64, line: bool) -> Result<Vec<u8>, i32> {
+        let file = self
+            .fds
+            .get_mut(&fd)
+            .filter(|file| file.readable)
+            .ok_or(libc::EBADF)?;
+        let (contents, pos) = match (&mut file.backing, &self.fs) {
+            (Backing::Memory { path, pos }, FileSystem::Memory(files)) => {
+                (files.get(path).map_or(&[][..], Vec::as_slice), pos)
+            }
+            _ => return Err(libc::EBADF),
+        };
+        let start = (*pos).min(contents.len() as u64) as usize;
+        let mut data = &contents[start..];
+        data = &data[..data.len().min(len as usize)];
+        if line {
+            if let Some(end) = data.iter().position(|&b| b == b'\n') {
+                data = &data[..=end];
+            }
+        }
+        *pos = (start + data.len()) as u64;
+        Ok(data.to_vec())
+    }
+
+    /// Write to a file that isn't stdout or stderr.
+    fn write(&mut self, fd: i32, bytes: &[u8]) -> Result<u64, i32> {
+        let file = self
+            .fds
+            .get_mut(&fd)
+            .filter(|file| file.writable)
+            .ok_or(libc::EBADF)?;
+        match (&mut file.backing, &mut self.fs) {
+            (Backing::Host { file, .. }, _) => file
+                .write_all(bytes)
+                .map_err(|err| err.raw_os_error().unwrap_or(libc::EIO))?,
+            (Backing::Memory { path, pos }, FileSystem::Memory(files)) => {
+                let contents = files.entry(path.clone()).or_default();
+                if file.append {
+                    *pos = contents.len() as u64;
+                }
+                let end = pos
+                    .checked_add(bytes.len() as u64)
+                    .filter(|&end| end <= MAX_FILE_SIZE)
+                    .ok_or(libc::EFBIG)? as usize;
+                if contents.len() < end {
+                    contents.resize(end, 0);
+                }
+                contents[*pos as usize..end].copy_from_slice(bytes);
+                *pos = end as u64;
+            }
+            _ => return Err(libc::EBADF),
+        }
+        Ok(bytes.len() as u64)
+    }
+
+    fn seek(&mut self, fd: i32, offset: i64, whence: u64) -> Result<u64, i32> {
+        let file = self.fds.get_mut(&fd).ok_or(libc::EBADF)?;
+        match (&mut file.backing, &self.fs) {
+            (Backing::Host { file, .. }, _) => {
+                let pos = match whence {
+                    SEEK_SET => SeekFrom::Start(u64::try_from(offset).map_err(|_| libc::EINVAL)?),
+                    SEEK_CUR => SeekFrom::Current(offset),
+                    SEEK_END => SeekFrom::End(offset),
+                    _ => return Err(libc::EINVAL),
+                };
+                file.seek(pos)
+                    .map_err(|err| err.raw_os_error().unwrap_or(libc::EINVAL))
+            }
+            (Backing::Memory { path, pos }, FileSystem::Memory(files)) => {
+                let base = match whence {
+                    SEEK_SET => 0,
+                    SEEK_CUR => *pos,
+                    SEEK_END => files.get(path).map_or(0, Vec::len) as u64,
+                    _ => return Err(libc::EINVAL),
+                };
+                let new_pos = (base as i64).checked_add(offset).ok_or(libc::EOVERFLOW)?;
+                *pos = u64::try_from(new_pos).map_err(|_| libc::EINVAL)?;
+                Ok(*pos)
+            }
+            // The standard streams are pipes or terminals as far as the program knows.
+            _ => Err(libc::ESPIPE),
+        }
+    }
+
+    pub(super) fn save(&self, out: &mut Writer) -> Result<(), String> {
+        match &self.fs {
+            FileSystem::Host(_) => out.bool(false),
+            FileSystem::Memory(files) => {
+                out.bool(true);
+                out.u64(files.len() as u64);
+                for (path, contents) in files {
+                    out.str(path);
+                    out.bytes(contents);
+                }
+            }
+        }
+        out.u64(self.fds.len() as u64);
+        for (fd, file) in &self.fds {
+            out.u64(*fd as u64);
+            match &file.backing {
+                Backing::Stdin => out.u64(0),
+                Backing::Stdout => out.u64(1),
+                Backing::Stderr => out.u64(2),
+                Backing::Host { file, path } => {
+                    out.u64(3);
+                    let name = path.to_str().ok_or_else(|| {
+                        format!("Can't take a snapshot of open file '{}'", path.display())
+                    })?;
+                    out.str(name);
+                    let mut file = file;
+                    out.u64(file.stream_position().map_err(|err| err.to_string())?);
+                }
+                Backing::Memory { path, pos } => {
+                    out.u64(4);
+                    out.str(path);
+                    out.u64(*pos);
+                }
+            }
+            out.bool(file.readable);
+            out.bool(file.writable);
+            out.bool(file.append);
+        }
+        out.u64(self.streams.len() as u64);
+        for (stream, fd) in &self.streams {
+            out.u64(*stream);
+            out.u64(*fd as u64);
+        }
+        Ok(())
+    }
+
+    /// Open the files a snapshot had open again. Files on the host are reopened, so they must
+    /// still be there.
+    pub(super) fn load(&mut self, input: &mut Reader) -> Result<(), String> {
+        match (input.bool()?, &mut self.fs) {
+            (false, FileSystem::Host(_)) => {}
+            (true, FileSystem::Memory(files)) => {
+                *files = (0..input.u64()?)
+                    .map(|_| Ok((input.string()?, input.bytes()?)))
+                    .collect::<Result<_, String>>()?;
+            }
+            (in_memory, _) => {
+                return Err(format!(
+                    "The snapshot must be restored with{} --fs=memory",
+                    if in_memory { "" } else { "out" }
+                ))
+            }
+        }
+        self.fds.clear();
+        for _ in 0..input.u64()? {
+            let fd = input.u64()? as i32;
+            let kind = input.u64()?;
+            let (path, pos) = match kind {
+                0..=2 => (String::new(), 0),
+                3 | 4 => (input.string()?, input.u64()?),
+                _ => return Err("The snapshot is corrupt".to_owned()),
+            };
+            let (readable, writable, append) = (input.bool()?, input.bool()?, input.bool()?);
+            let backing = match kind {
+                0 => Backing::Stdin,
+                1 => Backing::Stdout,
+                2 => Backing::Stderr,
+                3 => {
+                    let path = PathBuf::from(path);
+                    let reopen = || {
+                        let mut file = OpenOptions::new()
+                            .read(readable)
+                            .write(writable)
+                            .append(append)
+                            .open(&path)?;
+                        file.seek(SeekFrom::Start(pos))?;
+                        Ok(file)
+                    };
+                    let file = reopen().map_err(|err: io::Error| {
+                        format!(
+                            "Can't reopen '{}' for the snapshot: {}",
+                            path.display(),
+                            err
+                        )
+                    })?;
+                    Backing::Host { file, path }
+                }
+                _ => Backing::Memory { path, pos },
+            };
+            let file = OpenFile {
+                backing,
+                readable,
+                writable,
+                append,
+            };
+            self.fds.insert(fd, file);
+        }
+        self.streams = (0..input.u64()?)
+            .map(|_| Ok((input.u64()?, input.u64()? as i32)))
+            .collect::<Result<_, String>>()?;
+        Ok(())
+    }
+}
+
+impl LLVMIRInterpreter {
+    /// Keep the files the program opens on the host, the default, or in memory.
+    pub fn set_file_system(&mut self, fs: FileSystem) {
+        self.files = Files::new(fs);
+    }
+
+    /// Make the `stdin`, `stdout` and `stderr` globals the program declares point to streams.
+    pub(super) fn open_std_streams(&mut self) -> Result<(), String> {
+        for (fd, name) in ["stdin", "stdout", "stderr"].iter().enumerate() {
+            let stream = self.open_stream(fd as i32);
+            if let Some(&addr) = self.gl_vars.get(&Name::from(*name)) {
+                self.memory.write(addr, &stream.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// A `FILE` for `fd`. Its address can't be read from or written to, so the program can only
+    /// use it through the stdio functions.
+    fn open_stream(&mut self, fd: i32) -> u64 {
+        let stream = self.memory.reserve(1, 8);
+        self.files.streams.insert(stream, fd);
+        stream
+    }
+
+    fn stream_fd(&self, func_name: &str, stream: u64) -> Result<i32, String> {
+        match self.files.streams.get(&stream) {
+            Some(&fd) => Ok(fd),
+            None => Err(format!(
+                "@{} called with {:#x}, which isn't an open FILE *",
+                func_name, stream
+            )),
+        }
+    }
+
+    /// Write what the program prints to stdout or stderr.
+    pub(super) fn output(&mut self, stream: &str, bytes: &[u8]) {
+        // Output isn't repeated when the debugger runs the program again to go back in time.
+        if self.replaying() {
+            return;
+        }
+        // Under an editor stdout carries the Debug Adapter Protocol, so output goes to the editor.
+        if let Some(conn) = self.debugger.as_mut().and_then(Debugger::dap_connection) {
+            conn.output(stream, &String::from_utf8_lossy(bytes));
+            return;
+        }
+        let _ = match stream {
+            "stdout" => io::stdout()
+                .write_all(bytes)
+                .and_then(|_| io::stdout().flush()),
+            _ => io::stderr().write_all(bytes),
+        };
+    }
+
+    /// Open `path`, on the host if the sandbox allows it.
+    fn open_file(&mut self, path: &str, flags: u64) -> Result<i32, i32> {
+        let mut flags = flags;
+        let host_path = match self.files.host_path(path) {
+            Some(host_path) => {
+                let (host_path, nofollow) = self.open_path(host_path)?;
+                if nofollow {
+                    flags |= O_NOFOLLOW;
+                }
+                // The file was created and truncated when the program first got here.
+                if self.replaying() {
+                    flags &= !(O_TRUNC | O_EXCL);
+                }
+                Some(host_path)
+            }
+            None => None,
+        };
+        self.files.open(path, host_path, flags)
+    }
+
+    /// Read up to `len` bytes from `fd`, or up to the end of a line if `line`, for `what`, or
+    /// fail with an errno. What's read from the host is an input to the program, so it's
+    /// recorded.
+    fn read_fd(
+        &mut self,
+        what: &str,
+        fd: i32,
+        len: u64,
+        line: bool,
+    ) -> Result<Result<Vec<u8>, i32>, String> {
+        let data = match self.files.fds.get_mut(&fd).filter(|file| file.readable) {
+            Some(OpenFile {
+                backing: Backing::Stdin,
+                ..
+            }) => {
+                return self
+                    .input(what, || read_from(&mut io::stdin(), len, line))
+                    .map(Ok)
+            }
+            Some(OpenFile {
+                backing: Backing::Host { file, .. },
+                ..
+            }) => read_from(file, len, line),
+            _ => return Ok(self.files.read(fd, len, line)),
+        };
+        self.input(what, || data).map(Ok)
+    }
+
+    fn write_fd(&mut self, fd: i32, bytes: &[u8]) -> Result<u64, i32> {
+        let stream = match self.files.fds.get(&fd) {
+            Some(OpenFile {
+                backing: Backing::Stdout,
+                writable: true,
+                ..
+            }) => "stdout",
+            Some(OpenFile {
+                backing: Backing::Stderr,
+                writable: true,
+                ..
+            }) => "stderr",
+            Some(OpenFile {
+                backing: Backing::Host { .. },
+                writable: true,
+                ..
+            }) if self.replaying() => return Ok(bytes.len() as u64),
+            _ => return self.files.write(fd, bytes),
+        };
+        self.output(stream, bytes);
+        Ok(bytes.len() as u64)
+    }
+
+    /// The `len` bytes at `addr` that the program passes to `func_name` to write.
+    fn bytes_to_write(&self, func_name: &str, addr: u64, len: u64) -> Result<Vec<u8>, String> {
+        if len == 0 {
+            return Ok(Vec::new());
+        }
+        let bytes = self.memory.read(addr, len)?.to_vec();
+        if let Some(origin) = self.memory.undefined(addr, len) {
+            let what = format!("written by @{}", func_name);
+            return Err(self.uninit_error(&what, &origin));
+        }
+        Ok(bytes)
+    }
+
+    /// Call a function of stdio or POSIX that works with files. Functions that fail set `errno`
+    /// and return what tells the program so.
+    pub(super) fn call_file(
+        &mut self,
+        func_name: &str,
+        call: &Call,
+    ) -> Result<Option<ConstantRef>, String> {
+        let args = call
+            .arguments
+            .iter()
+            .map(|(arg, _)| self.eval_op(arg))
+            .collect::<Result<Vec<_>, _>>()?;
+        let arg = |i: usize| get_int(&args[i]).map(|(_, val)| val);
+        let fd = |i: usize| arg(i).map(|fd| fd as u32 as i32);
+        let ret = match func_name {
+            "open" => {
+                let path = self.memory.read_c_string(arg(0)?)?;
+                match self.open_file(&path, arg(1)?) {
+                    Ok(fd) => int(32, fd as u64),
+                    Err(errno) => return self.fail(32, errno),
+                }
+            }
+            "close" => match self.files.close(fd(0)?) {
+                Ok(()) => int(32, 0),
+                Err(errno) => return self.fail(32, errno),
+            },
+            "read" => match self.read_fd(func_name, fd(0)?, arg(2)?, false)? {
+                Ok(data) => {
+                    if !data.is_empty() {
+                        self.memory.write(arg(1)?, &data)?;
+                    }
+                    int(64, data.len() as u64)
+                }
+                Err(errno) => return self.fail(64, errno),
+            },
+            "write" => {
+                let bytes = self.bytes_to_write(func_name, arg(1)?, arg(2)?)?;
+                match self.write_fd(fd(0)?, &bytes) {
+                    Ok(written) => int(64, written),
+                    Err(errno) => return self.fail(64, errno),
+                }
+            }
+            "lseek" => match self.files.seek(fd(0)?, arg(1)? as i64, arg(2)?) {
+                Ok(pos) => int(64, pos),
+                Err(errno) => return self.fail(64, errno),
+            },
+            "fopen" => {
+                let path = self.memory.read_c_string(arg(0)?)?;
+                let mode = self.memory.read_c_string(arg(1)?)?;
+                let fd = fopen_flags(&mode)
+                    .ok_or(libc::EINVAL)
+                    .and_then(|flags| self.open_file(&path, flags));
+                match fd {
+                    Ok(fd) => return Ok(Some(ptr(self.open_stream(fd)))),
+                    Err(errno) => {
+                        self.set_errno(errno)?;
+                        return Ok(Some(ptr(0)));
+                    }
+                }
+            }
+            "fclose" => {
+                let fd = self.stream_fd(func_name, arg(0)?)?;
+                self.files.streams.remove(&arg(0)?);
+                match self.files.close(fd) {
+                    Ok(()) => int(32, 0),
+                    Err(errno) => return self.fail(32, errno),
+                }
+            }
+            "fread" => {
+                let fd = self.stream_fd(func_name, arg(3)?)?;
+                let size = arg(1)?;
+                let len = match size.checked_mul(arg(2)?) {
+                    Some(len) => len,
+                    None => {
+                        self.set_errno(libc::EOVERFLOW)?;
+                        return Ok(Some(ConstantRef::new(int(64, 0))));
+                    }
+                };
+                let mut data = Vec::new();
+                while (data.len() as u64) < len {
+                    let left = len - data.len() as u64;
+                    match self.read_fd(func_name, fd, left, false)? {
+                        Ok(chunk) if !chunk.is_empty() => data.extend(chunk),
+                        Ok(_) => break,
+                        Err(errno) => {
+                            self.set_errno(errno)?;
+                            break;
+                        }
+                    }
+                }
+                if !data.is_empty() {
+                    self.memory.write(arg(0)?, &data)?;
+                }
+                int(64, (data.len() as u64).checked_div(size).unwrap_or(0))
+            }
+            "fwrite" => {
+                let fd = self.stream_fd(func_name, arg(3)?)?;
+                let size = arg(1)?;
+                let len = match size.checked_mul(arg(2)?) {
+                    Some(len) => len,
+                    None => {
+                        self.set_errno(libc::EOVERFLOW)?;
+                        return Ok(Some(ConstantRef::new(int(64, 0))));
+                    }
+                };
+                let bytes = self.bytes_to_write(func_name, arg(0)?, len)?;
+                let written = match self.write_fd(fd, &bytes) {
+                    Ok(written) => written,
+                    Err(errno) => {
+                        self.set_errno(errno)?;
+                        0
+                    }
+                };
+                int(64, written.checked_div(size).unwrap_or(0))
+            }
+            "fgets" => {
+                let fd = self.stream_fd(func_name, arg(2)?)?;
+                let len = (arg(1)? as u32 as i32).max(1) as u64 - 1;
+                match self.read_fd(func_name, fd, len, true)? {
+                    Ok(mut line) if !line.is_empty() => {
+                        line.push(0);
+                        self.memory.write(arg(0)?, &line)?;
+                        return Ok(Some(ptr(arg(0)?)));
+                    }
+                    Ok(_) => return Ok(Some(ptr(0))),
+                    Err(errno) => {
+                        self.set_errno(errno)?;
+                        return Ok(Some(ptr(0)));
+                    }
+                }
+            }
+            "fgetc" | "getc" => {
+                let fd = self.stream_fd(func_name, arg(0)?)?;
+                match self.read_fd(func_name, fd, 1, false)? {
+                    Ok(byte) => int(32, byte.first().map_or(u64::MAX, |&b| b as u64)),
+                    Err(errno) => return self.fail(32, errno),
+                }
+            }
+            "fputc" | "putc" => {
+                let fd = self.stream_fd(func_name, arg(1)?)?;
+                match self.write_fd(fd, &[arg(0)? as u8]) {
+                    Ok(_) => int(32, arg(0)? as u8 as u64),
+                    Err(errno) => return self.fail(32, errno),
+                }
+            }
+            "fputs" => {
+                let fd = self.stream_fd(func_name, arg(1)?)?;
+                let string = self.memory.read_c_string(arg(0)?)?;
+                let len = string.chars().count() as u64;
+                let bytes = self.bytes_to_write(func_name, arg(0)?, len)?;
+                match self.write_fd(fd, &bytes) {
+                    Ok(_) => int(32, 0),
+                    Err(errno) => return self.fail(32, errno),
+                }
+            }
+            "fprintf" => {
+                let fd = self.stream_fd(func_name, arg(0)?)?;
+                let mut bytes = self.format(func_name, call, 1)?;
+                let len = bytes.len() as u64;
+                // Like printf, each call's output to the terminal ends a line.
+                if let Some(Backing::Stdout | Backing::Stderr) =
+                    self.files.fds.get(&fd).map(|file| &file.backing)
+                {
+                    bytes.push(b'\n');
+                }
+                match self.write_fd(fd, &bytes) {
+                    Ok(_) => int(32, len),
+                    Err(errno) => return self.fail(32, errno),
+                }
+            }
+            // Streams aren't buffered.
+            "fflush" => {
+                if arg(0)? != 0 {
+                    self.stream_fd(func_name, arg(0)?)?;
+                }
+                int(32, 0)
+            }
+            _ => unreachable!(),
+        };
+        Ok(Some(ConstantRef::new(ret)))
+    }
+}
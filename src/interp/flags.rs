@@ -0,0 +1,200 @@
+use super::debugger::parse_name as parse_register;
+use llvm_ir::{Module, Name};
+use llvm_sys::{
+    bit_reader::LLVMParseBitcodeInContext2,
+    core::{
+        LLVMContextCreate, LLVMContextDispose, LLVMCreateMemoryBufferWithContentsOfFile,
+        LLVMDisposeMemoryBuffer, LLVMDisposeMessage, LLVMDisposeModule, LLVMGetCalledValue,
+        LLVMGetFirstBasicBlock, LLVMGetFirstFunction, LLVMGetFirstInstruction,
+        LLVMGetInstructionOpcode, LLVMGetNextBasicBlock, LLVMGetNextFunction,
+        LLVMGetNextInstruction, LLVMGetOperand, LLVMGetValueName2, LLVMMetadataAsValue,
+        LLVMPrintValueToString,
+    },
+    debuginfo::LLVMGetSubprogram,
+    prelude::LLVMValueRef,
+    LLVMOpcode,
+};
+use std::{
+    collections::HashMap,
+    ffi::{CStr, CString},
+    ptr, slice,
+};
+
+pub(super) const NSW: u8 = 1;
+pub(super) const NUW: u8 = 2;
+pub(super) const EXACT: u8 = 4;
+
+/// The `nsw`, `nuw` and `exact` flags of every instruction, by function, block index and
+/// instruction index.
+pub(super) type WrapFlags = HashMap<String, Vec<Vec<u8>>>;
+
+/// The names functions have in the source, by their names in the module, for functions with debug
+/// info.
+pub(super) type SourceNames = HashMap<String, String>;
+
+/// The source variables `llvm.dbg.declare` says live in `alloca`s, by function: the register
+/// holding each variable's address, and the variable's name.
+pub(super) type LocalVars = HashMap<String, Vec<(Name, String)>>;
+
+// llvm-ir doesn't expose these flags, the names in `DISubprogram`s or the metadata arguments of
+// `llvm.dbg.declare`, and LLVM 12's C API has no getters for them either, so we load the module a
+// second time and read them off the textual form of each instruction and piece of metadata.
+pub(super) fn read_bitcode_info(path: &str) -> Result<(WrapFlags, SourceNames, LocalVars), String> {
+    let c_path = CString::new(path).map_err(|e| e.to_string())?;
+    let mut flags = HashMap::new();
+    let mut names = HashMap::new();
+    let mut locals = HashMap::new();
+    unsafe {
+        let mut buf = ptr::null_mut();
+        let mut err = ptr::null_mut();
+        if LLVMCreateMemoryBufferWithContentsOfFile(c_path.as_ptr(), &mut buf, &mut err) != 0 {
+            let msg = CStr::from_ptr(err).to_string_lossy().into_owned();
+            LLVMDisposeMessage(err);
+            return Err(msg);
+        }
+        let ctx = LLVMContextCreate();
+        let mut module = ptr::null_mut();
+        let failed = LLVMParseBitcodeInContext2(ctx, buf, &mut module) != 0;
+        LLVMDisposeMemoryBuffer(buf);
+        if failed {
+            LLVMContextDispose(ctx);
+            return Err(format!("Failed to parse {}", path));
+        }
+
+        let mut func = LLVMGetFirstFunction(module);
+        while !func.is_null() {
+            let mut len = 0;
+            let name = LLVMGetValueName2(func, &mut len);
+            let name = String::from_utf8_lossy(slice::from_raw_parts(name as *const u8, len));
+            let subprogram = LLVMGetSubprogram(func);
+            if !subprogram.is_null() {
+                let text = LLVMPrintValueToString(LLVMMetadataAsValue(ctx, subprogram));
+                if let Some(source_name) = parse_name(&CStr::from_ptr(text).to_string_lossy()) {
+                    names.insert(name.clone().into_owned(), source_name);
+                }
+                LLVMDisposeMessage(text);
+            }
+            let mut blocks = Vec::new();
+            let mut vars = Vec::new();
+            let mut bb = LLVMGetFirstBasicBlock(func);
+            while !bb.is_null() {
+                let mut insts = Vec::new();
+                let mut inst = LLVMGetFirstInstruction(bb);
+                while !inst.is_null() {
+                    insts.push(match LLVMGetInstructionOpcode(inst) {
+                        LLVMOpcode::LLVMAdd
+                        | LLVMOpcode::LLVMSub
+                        | LLVMOpcode::LLVMMul
+                        | LLVMOpcode::LLVMShl
+                        | LLVMOpcode::LLVMUDiv
+                        | LLVMOpcode::LLVMSDiv
+                        | LLVMOpcode::LLVMLShr
+                        | LLVMOpcode::LLVMAShr => {
+                            let text = LLVMPrintValueToString(inst);
+                            let inst_flags = parse_flags(&CStr::from_ptr(text).to_string_lossy());
+                            LLVMDisposeMessage(text);
+                            inst_flags
+                        }
+                        LLVMOpcode::LLVMCall => {
+                            if let Some(var) = declared_var(inst) {
+                                vars.push(var);
+                            }
+                            0
+                        }
+                        _ => 0,
+                    });
+                    inst = LLVMGetNextInstruction(inst);
+                }
+                blocks.push(insts);
+                bb = LLVMGetNextBasicBlock(bb);
+            }
+            if !vars.is_empty() {
+                locals.insert(name.clone().into_owned(), vars);
+            }
+            flags.insert(name.into_owned(), blocks);
+            func = LLVMGetNextFunction(func);
+        }
+
+        LLVMDisposeModule(module);
+        LLVMContextDispose(ctx);
+    }
+    Ok((flags, names, locals))
+}
+
+unsafe fn print_value(val: LLVMValueRef) -> String {
+    let text = LLVMPrintValueToString(val);
+    let string = CStr::from_ptr(text).to_string_lossy().into_owned();
+    LLVMDisposeMessage(text);
+    string
+}
+
+// e.g. `call void @llvm.dbg.declare(metadata i32* %3, metadata !9, metadata !DIExpression())`,
+// whose first two operands print as `i32* %3` and `!9 = !DILocalVariable(name: "x", ...)`.
+unsafe fn declared_var(call: LLVMValueRef) -> Option<(Name, String)> {
+    let mut len = 0;
+    let callee = LLVMGetValueName2(LLVMGetCalledValue(call), &mut len);
+    if slice::from_raw_parts(callee as *const u8, len) != b"llvm.dbg.declare" {
+        return None;
+    }
+    let addr = print_value(LLVMGetOperand(call, 0));
+    let reg = addr.rsplit(' ').next().filter(|reg| reg.starts_with('%'))?;
+    let var = parse_name(&print_value(LLVMGetOperand(call, 1)))?;
+    Some((parse_register(reg), var))
+}
+
+// e.g. `<0x55d0> = distinct !DISubprogram(name: "sign", scope: <0x55e0>, ...)`
+fn parse_name(text: &str) -> Option<String> {
+    let start = text.find("name: \"")? + "name: \"".len();
+    let len = text[start..].find('"')?;
+    Some(text[start..start + len].to_owned())
+}
+
+/// Fail unless `flags` has an entry for every instruction of `module`, as it does when both were
+/// loaded from the same file: the flags are looked up by position, so a block that the two parses
+/// see differently would give its instructions the flags of others.
+pub(super) fn check_counts(module: &Module, flags: &WrapFlags, path: &str) -> Result<(), String> {
+    for func in &module.functions {
+        let blocks = flags.get(&func.name).map_or(&[][..], Vec::as_slice);
+        if blocks.len() != func.basic_blocks.len() {
+            return Err(format!(
+                "{} has {} blocks in @{}, not the {} that were loaded",
+                path,
+                blocks.len(),
+                func.name,
+                func.basic_blocks.len()
+            ));
+        }
+        for (insts, bb) in blocks.iter().zip(&func.basic_blocks) {
+            // LLVM counts the terminator as an instruction.
+            if insts.len() != bb.instrs.len() + 1 {
+                return Err(format!(
+                    "{} has {} instructions in block {} of @{}, not the {} that were loaded",
+                    path,
+                    insts.len(),
+                    bb.name,
+                    func.name,
+                    bb.instrs.len() + 1
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// e.g. `%5 = add nsw i32 %3, %4`
+fn parse_flags(text: &str) -> u8 {
+    let mut words = text
+        .split_whitespace()
+        .skip_while(|word| *word != "=")
+        .skip(2);
+    let mut flags = 0;
+    for word in &mut words {
+        flags |= match word {
+            "nsw" => NSW,
+            "nuw" => NUW,
+            "exact" => EXACT,
+            _ => break,
+        };
+    }
+    flags
+}
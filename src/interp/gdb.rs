@@ -0,0 +1,451 @@
+use super::{
+    debugger::{BreakAt, Debugger, Resume},
+    Frame, LLVMIRInterpreter,
+};
+use llvm_ir::{constant::Constant, name::Name, BasicBlock, Function, Operand};
+use std::{
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+};
+
+/// Where instructions are taken to be, so that gdb has addresses to put breakpoints on and to
+/// report as the program counter. Each function gets `FUNC_SIZE` bytes, one per instruction.
+const CODE_BASE: u64 = 0x4000_0000_0000;
+const FUNC_SIZE: u64 = 1 << 20;
+
+/// The registers gdb is shown, in the order of the amd64 `g` packet. Only the program counter and
+/// the System V argument registers, which hold the current function's first six integer and
+/// pointer arguments, have values; the rest are zero.
+const REGISTERS: [&str; 18] = [
+    "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15", "rip", "eflags",
+];
+const ARG_REGISTERS: [usize; 6] = [5, 4, 3, 2, 8, 9];
+const PC: usize = 16;
+const FLAGS: usize = 17;
+
+/// How many instructions run between checks for gdb asking to interrupt the program.
+const POLL_INTERVAL: u32 = 10_000;
+/// The most bytes an `m` reply holds: two hex digits each, within the advertised `PacketSize`.
+const MAX_READ: u64 = 0x2000;
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, b| sum.wrapping_add(*b))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Parse the `addr,len` that memory and breakpoint packets start with.
+fn parse_addr_len(s: &str) -> Option<(u64, u64)> {
+    let (addr, len) = s.split_once(',')?;
+    Some((
+        u64::from_str_radix(addr, 16).ok()?,
+        u64::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+/// A connection to gdb, or to lldb, speaking the GDB remote serial protocol.
+pub(crate) struct GdbConnection {
+    stream: TcpStream,
+    no_ack: bool,
+    /// Whether gdb is waiting for the program to stop.
+    running: bool,
+    poll_in: u32,
+    /// Set once gdb has detached or gone away.
+    closed: bool,
+}
+
+enum Action {
+    Reply(String),
+    Resume(Resume),
+    Detach,
+}
+
+impl GdbConnection {
+    pub(super) fn accept(port: u16) -> Result<GdbConnection, String> {
+        let listener = TcpListener::bind(("127.0.0.1", port))
+            .map_err(|err| format!("Can't listen on port {}: {}", port, err))?;
+        eprintln!("Waiting for gdb to connect to 127.0.0.1:{}", port);
+        let (stream, _) = listener
+            .accept()
+            .map_err(|err| format!("Can't accept a connection from gdb: {}", err))?;
+        stream.set_nodelay(true).ok();
+        Ok(GdbConnection {
+            stream,
+            no_ack: false,
+            running: false,
+            poll_in: POLL_INTERVAL,
+            closed: false,
+        })
+    }
+
+    /// Whether gdb has sent an interrupt (Ctrl-C) while the program was running. The connection
+    /// is only looked at every `POLL_INTERVAL` instructions.
+    pub(super) fn interrupted(&mut self) -> bool {
+        if self.closed {
+            return false;
+        }
+        self.poll_in -= 1;
+        if self.poll_in > 0 {
+            return false;
+        }
+        self.poll_in = POLL_INTERVAL;
+        let mut byte = [0];
+        self.stream.set_nonblocking(true).ok();
+        let read = self.stream.read(&mut byte);
+        self.stream.set_nonblocking(false).ok();
+        match read {
+            Ok(1) => byte[0] == 0x03,
+            Ok(_) => {
+                self.closed = true;
+                false
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn read_byte(&mut self) -> Option<u8> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte) {
+            Ok(1) => Some(byte[0]),
+            _ => None,
+        }
+    }
+
+    /// The next packet from gdb, or `None` once the connection is closed.
+    fn read_packet(&mut self) -> Option<String> {
+        loop {
+            // Acks, and interrupts that arrive once the program has already stopped, are
+            // skipped.
+            if self.read_byte()? != b'$' {
+                continue;
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+            let sum = [self.read_byte()?, self.read_byte()?];
+            if !self.no_ack {
+                let sum = std::str::from_utf8(&sum).ok().and_then(unhex);
+                if sum != Some(vec![checksum(&data)]) {
+                    self.stream.write_all(b"-").ok()?;
+                    continue;
+                }
+                self.stream.write_all(b"+").ok()?;
+            }
+            return Some(String::from_utf8_lossy(&data).into_owned());
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            if self.no_ack {
+                return Ok(());
+            }
+            match self.read_byte() {
+                Some(b'-') => continue,
+                Some(_) => return Ok(()),
+                None => return Err(io::ErrorKind::UnexpectedEof.into()),
+            }
+        }
+    }
+
+    /// Tell gdb how the program ended.
+    pub(super) fn end(&mut self, result: Result<i32, &String>) {
+        if self.closed {
+            return;
+        }
+        let status = match result {
+            Ok(status) => format!("W{:02x}", status as u8),
+            Err(err) => {
+                self.console(&format!("{}\n", err)).ok();
+                "W01".to_owned()
+            }
+        };
+        self.write_packet(&status).ok();
+    }
+
+    /// Show `text` on gdb's console.
+    fn console(&mut self, text: &str) -> io::Result<()> {
+        match text.is_empty() {
+            true => Ok(()),
+            false => self.write_packet(&format!("O{}", hex(text.as_bytes()))),
+        }
+    }
+}
+
+impl LLVMIRInterpreter {
+    fn code_addr(&self, func: &Function, bb: &BasicBlock, index: usize) -> u64 {
+        let func_ind = self
+            .module
+            .functions
+            .iter()
+            .position(|f| f.name == func.name)
+            .unwrap();
+        let offset = func
+            .basic_blocks
+            .iter()
+            .take_while(|b| b.name != bb.name)
+            .map(|b| b.instrs.len() + 1)
+            .sum::<usize>();
+        CODE_BASE + func_ind as u64 * FUNC_SIZE + (offset + index) as u64
+    }
+
+    /// The function, block and instruction index at a code address.
+    fn code_location(&self, addr: u64) -> Option<(String, Name, usize)> {
+        let offset = addr.checked_sub(CODE_BASE)?;
+        let func = self.module.functions.get((offset / FUNC_SIZE) as usize)?;
+        let mut index = (offset % FUNC_SIZE) as usize;
+        for bb in &func.basic_blocks {
+            if index <= bb.instrs.len() {
+                return Some((func.name.clone(), bb.name.clone(), index));
+            }
+            index -= bb.instrs.len() + 1;
+        }
+        None
+    }
+
+    fn gdb_registers(&self, func: &Function, pc: u64) -> [u64; 18] {
+        let mut regs = [0; 18];
+        for (reg, param) in ARG_REGISTERS.iter().zip(&func.parameters) {
+            if let Some(Operand::ConstantOperand(val)) = self.vars.get(&param.name) {
+                if let Constant::Int { value, .. } = val.as_ref() {
+                    regs[*reg] = *value;
+                }
+            }
+        }
+        regs[PC] = pc;
+        regs
+    }
+
+    /// The bytes gdb asked for, stopping at the first one that can't be read.
+    fn gdb_read_memory(&self, addr: u64, len: u64) -> Vec<u8> {
+        let len = len.min(MAX_READ);
+        if let Ok(bytes) = self.memory.read(addr, len) {
+            return bytes.to_vec();
+        }
+        (addr..addr.saturating_add(len))
+            .map_while(|addr| self.memory.read(addr, 1).ok().map(|bytes| bytes[0]))
+            .collect()
+    }
+
+    /// Tell gdb that the program has stopped and answer its packets until it resumes the
+    /// program or detaches.
+    pub(super) fn gdb_stop(
+        &mut self,
+        debugger: &mut Debugger,
+        func: &Function,
+        bb: &BasicBlock,
+        index: usize,
+        frames: &[Frame],
+    ) -> Result<(), String> {
+        let stop_reply = format!("T05thread:{:x};", self.thread + 1);
+        let conn = debugger.gdb_connection().unwrap();
+        if conn.running {
+            conn.running = false;
+            if conn.write_packet(&stop_reply).is_err() {
+                return self.gdb_detach(debugger);
+            }
+        }
+        loop {
+            let packet = match debugger.gdb_connection().unwrap().read_packet() {
+                Some(packet) => packet,
+                None => return self.gdb_detach(debugger),
+            };
+            let action = match packet.as_str() {
+                "?" => Action::Reply(stop_reply.clone()),
+                "QStartNoAckMode" => {
+                    let conn = debugger.gdb_connection().unwrap();
+                    if conn.write_packet("OK").is_err() {
+                        return self.gdb_detach(debugger);
+                    }
+                    conn.no_ack = true;
+                    continue;
+                }
+                _ => self.gdb_packet(debugger, &packet, func, bb, index, frames)?,
+            };
+            let conn = debugger.gdb_connection().unwrap();
+            match action {
+                Action::Reply(reply) => {
+                    if conn.write_packet(&reply).is_err() {
+                        return self.gdb_detach(debugger);
+                    }
+                }
+                Action::Resume(resume) => {
+                    conn.running = true;
+                    debugger.resume = resume;
+                    return Ok(());
+                }
+                Action::Detach => {
+                    conn.write_packet("OK").ok();
+                    return self.gdb_detach(debugger);
+                }
+            }
+        }
+    }
+
+    fn gdb_detach(&mut self, debugger: &mut Debugger) -> Result<(), String> {
+        debugger.gdb_connection().unwrap().closed = true;
+        debugger.breakpoints.clear();
+        debugger.resume = Resume::Continue;
+        Ok(())
+    }
+
+    fn gdb_packet(
+        &mut self,
+        debugger: &mut Debugger,
+        packet: &str,
+        func: &Function,
+        bb: &BasicBlock,
+        index: usize,
+        frames: &[Frame],
+    ) -> Result<Action, String> {
+        let pc = self.code_addr(func, bb, index);
+        let (kind, args) = packet.split_at(packet.len().min(1));
+        let reply = match (kind, args) {
+            _ if packet.starts_with("qSupported") => {
+                "PacketSize=4000;QStartNoAckMode+;ReverseStep+;ReverseContinue+".to_owned()
+            }
+            _ if packet.starts_with("qHostInfo") => format!(
+                "triple:{};ptrsize:8;endian:little;",
+                hex(b"x86_64-unknown-linux-gnu")
+            ),
+            _ if packet.starts_with("qProcessInfo") => "pid:1;ptrsize:8;endian:little;".to_owned(),
+            _ if packet.starts_with("qRegisterInfo") => {
+                match usize::from_str_radix(&packet["qRegisterInfo".len()..], 16) {
+                    Ok(reg) if reg < REGISTERS.len() => format!(
+                        "name:{};bitsize:{};offset:{};encoding:uint;format:hex;set:General Purpose Registers;{}",
+                        REGISTERS[reg],
+                        if reg == FLAGS { 32 } else { 64 },
+                        reg * 8,
+                        match reg {
+                            PC => "generic:pc;",
+                            FLAGS => "generic:flags;",
+                            _ => "",
+                        }
+                    ),
+                    _ => "E45".to_owned(),
+                }
+            }
+            _ if packet.starts_with("qAttached") => "1".to_owned(),
+            ("q", "C") => format!("QC{:x}", self.thread + 1),
+            ("q", "fThreadInfo") => {
+                let tids = self
+                    .live_threads()
+                    .into_iter()
+                    .map(|tid| format!("{:x}", tid + 1))
+                    .collect::<Vec<_>>();
+                format!("m{}", tids.join(","))
+            }
+            ("q", "sThreadInfo") => "l".to_owned(),
+            ("q", "Symbol::") => "OK".to_owned(),
+            _ if packet.starts_with("qRcmd,") => {
+                let command = unhex(&packet["qRcmd,".len()..])
+                    .map(|command| String::from_utf8_lossy(&command).into_owned())
+                    .unwrap_or_default();
+                let mut out = String::new();
+                let resume = self.debug_command(debugger, &command, bb, index, frames, &mut out)?;
+                if resume.is_some() {
+                    out.push_str("Use gdb's own commands to resume the program\n");
+                }
+                if debugger.gdb_connection().unwrap().console(&out).is_err() {
+                    return Ok(Action::Detach);
+                }
+                "OK".to_owned()
+            }
+            ("c" | "C", _) => return Ok(Action::Resume(Resume::Continue)),
+            ("s" | "S", _) => return Ok(Action::Resume(Resume::Step)),
+            ("b", "s") => return Ok(Action::Resume(Resume::ReverseStep)),
+            ("b", "c") => return Ok(Action::Resume(Resume::ReverseContinue)),
+            ("D", _) => return Ok(Action::Detach),
+            ("k", _) => {
+                // gdb doesn't wait for a reply to a kill.
+                debugger.gdb_connection().unwrap().closed = true;
+                return Err("Program killed by the debugger".to_owned());
+            }
+            ("H", _) => "OK".to_owned(),
+            ("T", tid) => match usize::from_str_radix(tid, 16) {
+                Ok(tid) if self.live_threads().contains(&tid.wrapping_sub(1)) => "OK".to_owned(),
+                _ => "E01".to_owned(),
+            },
+            ("g", "") => {
+                let regs = self.gdb_registers(func, pc);
+                let mut bytes = regs[..FLAGS]
+                    .iter()
+                    .flat_map(|reg| reg.to_le_bytes())
+                    .collect::<Vec<_>>();
+                bytes.extend((regs[FLAGS] as u32).to_le_bytes());
+                hex(&bytes)
+            }
+            ("p", reg) => match usize::from_str_radix(reg, 16) {
+                Ok(FLAGS) => hex(&0u32.to_le_bytes()),
+                Ok(reg) if reg < REGISTERS.len() => {
+                    hex(&self.gdb_registers(func, pc)[reg].to_le_bytes())
+                }
+                _ => "E45".to_owned(),
+            },
+            ("m", args) => match parse_addr_len(args) {
+                Some((addr, len)) => match self.gdb_read_memory(addr, len) {
+                    bytes if bytes.is_empty() => "E01".to_owned(),
+                    bytes => hex(&bytes),
+                },
+                None => "E01".to_owned(),
+            },
+            ("M", args) => {
+                let write = args.split_once(':').and_then(|(addr_len, data)| {
+                    let (addr, len) = parse_addr_len(addr_len)?;
+                    Some((addr, unhex(data).filter(|data| data.len() as u64 == len)?))
+                });
+                match write.map(|(addr, data)| self.memory.write(addr, &data)) {
+                    Some(Ok(())) => "OK".to_owned(),
+                    _ => "E01".to_owned(),
+                }
+            }
+            ("Z" | "z", args) if args.starts_with('0') || args.starts_with('1') => {
+                let addr = args
+                    .get(2..)
+                    .and_then(|args| args.split(',').next())
+                    .and_then(|addr| u64::from_str_radix(addr, 16).ok());
+                match addr.and_then(|addr| Some((addr, self.code_location(addr)?))) {
+                    Some((addr, (func, block, index))) => {
+                        let spec = format!("*{:#x}", addr);
+                        debugger.breakpoints.retain(|(_, s, _)| *s != spec);
+                        if packet.starts_with('Z') {
+                            debugger.add_breakpoint(&spec, BreakAt::Inst(func, block, index));
+                        }
+                        "OK".to_owned()
+                    }
+                    None => "E01".to_owned(),
+                }
+            }
+            ("v", "Cont?") => "vCont;c;C;s;S".to_owned(),
+            ("v", args) if args.starts_with("Cont;") => {
+                return Ok(match args.as_bytes().get(5) {
+                    Some(b'c' | b'C') => Action::Resume(Resume::Continue),
+                    Some(b's' | b'S') => Action::Resume(Resume::Step),
+                    _ => Action::Reply("E01".to_owned()),
+                });
+            }
+            // An empty reply tells gdb that a packet isn't supported.
+            _ => String::new(),
+        };
+        Ok(Action::Reply(reply))
+    }
+}
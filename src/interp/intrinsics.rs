@@ -0,0 +1,513 @@
+use super::{
+    ops::{self, get_int, int, mask, sext},
+    BinOps, LLVMIRInterpreter,
+};
+use llvm_ir::{
+    constant::{Constant, Float},
+    instruction::Call,
+    ConstantRef, IntPredicate,
+    Operand::{self, ConstantOperand},
+    TypeRef,
+};
+
+// Overloaded intrinsics carry their types as a mangled suffix (e.g. `llvm.ctpop.i32`,
+// `llvm.lifetime.start.p0i8`). We strip those suffixes and dispatch on what remains; the
+// concrete types are then taken from the arguments themselves.
+fn intrinsic_base(name: &str) -> &str {
+    let mut base = name;
+    while let Some(i) = base.rfind('.') {
+        if !is_type_suffix(&base[i + 1..]) {
+            break;
+        }
+        base = &base[..i];
+    }
+    base
+}
+
+fn is_type_suffix(s: &str) -> bool {
+    let digits = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+    match s.chars().next() {
+        Some('i') | Some('f') => digits(&s[1..]),
+        Some('p') | Some('v') => s[1..].starts_with(|c: char| c.is_ascii_digit()),
+        _ => s == "bf16" || s == "ppcf128" || s == "isVoid",
+    }
+}
+
+fn fl_unary(
+    con: &Constant,
+    single: fn(f32) -> f32,
+    double: fn(f64) -> f64,
+) -> Result<Constant, String> {
+    match con {
+        Constant::Float(Float::Single(v)) => Ok(Constant::Float(Float::Single(single(*v)))),
+        Constant::Float(Float::Double(v)) => Ok(Constant::Float(Float::Double(double(*v)))),
+        _ => Err(ops::unsupported(con)),
+    }
+}
+
+fn fl_binary(
+    con0: &Constant,
+    con1: &Constant,
+    single: fn(f32, f32) -> f32,
+    double: fn(f64, f64) -> f64,
+) -> Result<Constant, String> {
+    match (con0, con1) {
+        (Constant::Float(Float::Single(v0)), Constant::Float(Float::Single(v1))) => {
+            Ok(Constant::Float(Float::Single(single(*v0, *v1))))
+        }
+        (Constant::Float(Float::Double(v0)), Constant::Float(Float::Double(v1))) => {
+            Ok(Constant::Float(Float::Double(double(*v0, *v1))))
+        }
+        _ => Err(ops::unsupported(con0)),
+    }
+}
+
+// `minnum`/`maxnum` return the non-NaN operand when only one is NaN, which is what Rust's
+// `min`/`max` already do. `minimum`/`maximum` instead propagate NaN and order -0.0 < +0.0.
+fn minimum<T: num::Float>(a: T, b: T) -> T {
+    if a.is_nan() || b.is_nan() {
+        T::nan()
+    } else if a == b {
+        if a.is_sign_negative() {
+            a
+        } else {
+            b
+        }
+    } else {
+        a.min(b)
+    }
+}
+
+fn maximum<T: num::Float>(a: T, b: T) -> T {
+    if a.is_nan() || b.is_nan() {
+        T::nan()
+    } else if a == b {
+        if a.is_sign_positive() {
+            a
+        } else {
+            b
+        }
+    } else {
+        a.max(b)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum OverflowOp {
+    SAdd,
+    UAdd,
+    SSub,
+    USub,
+    SMul,
+    UMul,
+}
+
+// Computes `op` on two `bits`-wide integers, returning the wrapped result and whether the
+// operation overflowed when its operands are interpreted as signed or unsigned as appropriate.
+fn overflow_op(op: OverflowOp, bits: u32, val0: u64, val1: u64) -> (u64, bool) {
+    let (s0, s1) = (i128::from(sext(bits, val0)), i128::from(sext(bits, val1)));
+    let (u0, u1) = (u128::from(val0), u128::from(val1));
+    let min = -(1i128 << (bits - 1));
+    let signed = |res: i128| (res as u64 & mask(bits), res < min || res > -min - 1);
+    let unsigned = |res: u128| (res as u64 & mask(bits), res > u128::from(mask(bits)));
+    match op {
+        OverflowOp::SAdd => signed(s0 + s1),
+        OverflowOp::SSub => signed(s0 - s1),
+        OverflowOp::SMul => signed(s0 * s1),
+        OverflowOp::UAdd => unsigned(u0 + u1),
+        OverflowOp::USub => (val0.wrapping_sub(val1) & mask(bits), val1 > val0),
+        OverflowOp::UMul => unsigned(u0 * u1),
+    }
+}
+
+fn saturate(op: OverflowOp, bits: u32, val0: u64, val1: u64) -> u64 {
+    let (res, overflowed) = overflow_op(op, bits, val0, val1);
+    if !overflowed {
+        return res;
+    }
+    let signed_min = 1u64 << (bits - 1);
+    match op {
+        OverflowOp::UAdd => mask(bits),
+        OverflowOp::USub => 0,
+        // Signed overflow saturates towards the sign of the first operand for subtraction and
+        // towards the sign shared by both operands for addition.
+        OverflowOp::SAdd | OverflowOp::SSub => {
+            if sext(bits, val0) < 0 {
+                signed_min
+            } else {
+                signed_min - 1
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn funnel_shift(left: bool, bits: u32, val0: u64, val1: u64, amt: u64) -> u64 {
+    let sh = (amt % u64::from(bits)) as u32;
+    if sh == 0 {
+        return if left { val0 } else { val1 };
+    }
+    let res = if left {
+        (val0 << sh) | (val1 >> (bits - sh))
+    } else {
+        (val1 >> sh) | (val0 << (bits - sh))
+    };
+    res & mask(bits)
+}
+
+impl LLVMIRInterpreter {
+    pub(super) fn call_intrinsic(
+        &mut self,
+        name: &str,
+        call: &Call,
+    ) -> Result<Option<Operand>, String> {
+        // Arguments are only evaluated on demand: debug intrinsics take metadata operands, and
+        // the pointers passed to e.g. `llvm.lifetime.*` need not hold a value yet.
+        let arg = |i: usize| self.eval_op(&call.arguments[i].0);
+        let defined_arg =
+            |i: usize, what: &str| self.get_defined_int_op(&call.arguments[i].0, what);
+
+        let val = match intrinsic_base(name) {
+            "llvm.dbg.declare" | "llvm.dbg.value" | "llvm.dbg.label" | "llvm.dbg.addr" => None,
+            "llvm.lifetime.start" | "llvm.lifetime.end" => None,
+            "llvm.invariant.end" | "llvm.assume" | "llvm.donothing" | "llvm.sideeffect" => None,
+            "llvm.invariant.start" => Some(Constant::Null(self.module.type_of(call))),
+            "llvm.expect" | "llvm.expect.with.probability" => Some(arg(0)?.as_ref().clone()),
+            // The saved "stack pointer" is the number of allocas in the current frame; restoring
+            // it frees everything allocated since.
+            "llvm.stacksave" => Some(int(64, self.allocas.len() as u64)),
+            "llvm.stackrestore" => {
+                let depth = defined_arg(0, "a stack pointer")? as usize;
+                self.free_allocas(depth);
+                None
+            }
+            "llvm.memcpy" | "llvm.memcpy.inline" | "llvm.memmove" => {
+                let (dst, src, len) = (
+                    defined_arg(0, "an address")?,
+                    defined_arg(1, "an address")?,
+                    defined_arg(2, "a length")?,
+                );
+                if len != 0 {
+                    self.race_read(src, len, None)?;
+                    self.race_write(dst, len, None)?;
+                    self.memory.copy(dst, src, len)?;
+                }
+                None
+            }
+            "llvm.memset" => {
+                let (dst, val, len) = (
+                    defined_arg(0, "an address")?,
+                    get_int(&ops::freeze(arg(1)?.as_ref()))?.1,
+                    defined_arg(2, "a length")?,
+                );
+                if len != 0 {
+                    self.race_write(dst, len, None)?;
+                    self.memory.fill(dst, val as u8, len)?;
+                }
+                None
+            }
+            "llvm.trap" | "llvm.debugtrap" | "llvm.ubsantrap" => {
+                return Err(format!("{} called", name));
+            }
+
+            // Everything below computes a value from its arguments, which is `poison` if any of
+            // them is.
+            _ if (0..call.arguments.len())
+                .any(|i| arg(i).is_ok_and(|arg| ops::is_poison(&arg))) =>
+            {
+                Some(Constant::Poison(self.module.type_of(call)))
+            }
+            base if base.starts_with("llvm.vector.reduce.")
+                || base.starts_with("llvm.experimental.vector.reduce.") =>
+            {
+                let op = &base[base.find("reduce.").unwrap() + 7..];
+                let ty = self.module.type_of(call);
+                Some(match op {
+                    "fadd" | "fmul" | "v2.fadd" | "v2.fmul" => {
+                        reduce(op, arg(1)?.as_ref(), Some(arg(0)?.as_ref().clone()), &ty)?
+                    }
+                    _ => reduce(op, arg(0)?.as_ref(), None, &ty)?,
+                })
+            }
+            base => {
+                let args = (0..call.arguments.len())
+                    .map(arg)
+                    .collect::<Result<Vec<_>, _>>()?;
+                Some(lift_elementwise(name, base, &args)?)
+            }
+        };
+
+        Ok(val.map(|con| ConstantOperand(ConstantRef::new(con))))
+    }
+}
+
+// Vector versions of the element-wise intrinsics apply the scalar version to each lane. Some
+// arguments (e.g. `llvm.ctlz`'s `is_zero_poison` flag) remain scalars in the vector versions.
+fn lift_elementwise(name: &str, base: &str, args: &[ConstantRef]) -> Result<Constant, String> {
+    let lanes = args
+        .iter()
+        .map(|arg| ops::vector_lanes(arg))
+        .collect::<Vec<_>>();
+    let num_lanes = match lanes.iter().flatten().next() {
+        Some(lane) => lane.len(),
+        None => return elementwise(name, base, args),
+    };
+    let mut results = Vec::with_capacity(num_lanes);
+    for i in 0..num_lanes {
+        let lane_args = args
+            .iter()
+            .zip(lanes.iter())
+            .map(|(arg, lanes)| match lanes {
+                Some(lanes) => lanes[i].clone(),
+                None => arg.clone(),
+            })
+            .collect::<Vec<_>>();
+        results.push(elementwise(name, base, &lane_args)?);
+    }
+    // `*.with.overflow` return a struct of two vectors rather than a vector of structs.
+    match &results[0] {
+        Constant::Struct { .. } => {
+            let (mut vals, mut flags) = (Vec::new(), Vec::new());
+            for res in results {
+                match res {
+                    Constant::Struct { mut values, .. } => {
+                        flags.push(values.pop().unwrap());
+                        vals.push(values.pop().unwrap());
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            Ok(Constant::Struct {
+                name: None,
+                values: vec![
+                    ConstantRef::new(Constant::Vector(vals)),
+                    ConstantRef::new(Constant::Vector(flags)),
+                ],
+                is_packed: false,
+            })
+        }
+        _ => Ok(Constant::Vector(
+            results.into_iter().map(ConstantRef::new).collect(),
+        )),
+    }
+}
+
+fn elementwise(name: &str, base: &str, args: &[ConstantRef]) -> Result<Constant, String> {
+    let arg = |i: usize| args[i].clone();
+    let int_arg = |i: usize| get_int(&args[i]);
+    Ok(match base {
+        "llvm.ctpop" => {
+            let (bits, val) = int_arg(0)?;
+            int(bits, val.count_ones().into())
+        }
+        "llvm.ctlz" => {
+            let (bits, val) = int_arg(0)?;
+            int(bits, (val.leading_zeros() - (64 - bits)).into())
+        }
+        "llvm.cttz" => {
+            let (bits, val) = int_arg(0)?;
+            int(bits, val.trailing_zeros().min(bits).into())
+        }
+        "llvm.bswap" => {
+            let (bits, val) = int_arg(0)?;
+            int(bits, val.swap_bytes() >> (64 - bits))
+        }
+        "llvm.bitreverse" => {
+            let (bits, val) = int_arg(0)?;
+            int(bits, val.reverse_bits() >> (64 - bits))
+        }
+        "llvm.abs" => {
+            let (bits, val) = int_arg(0)?;
+            int(bits, sext(bits, val).wrapping_abs() as u64)
+        }
+        "llvm.smax" | "llvm.smin" | "llvm.umax" | "llvm.umin" => {
+            let ((bits, val0), (_, val1)) = (int_arg(0)?, int_arg(1)?);
+            let first = match base {
+                "llvm.smax" => sext(bits, val0) >= sext(bits, val1),
+                "llvm.smin" => sext(bits, val0) <= sext(bits, val1),
+                "llvm.umax" => val0 >= val1,
+                _ => val0 <= val1,
+            };
+            int(bits, if first { val0 } else { val1 })
+        }
+        "llvm.fshl" | "llvm.fshr" => {
+            let ((bits, val0), (_, val1), (_, amt)) = (int_arg(0)?, int_arg(1)?, int_arg(2)?);
+            let left = base == "llvm.fshl";
+            int(bits, funnel_shift(left, bits, val0, val1, amt))
+        }
+        base @ ("llvm.sadd.with.overflow"
+        | "llvm.uadd.with.overflow"
+        | "llvm.ssub.with.overflow"
+        | "llvm.usub.with.overflow"
+        | "llvm.smul.with.overflow"
+        | "llvm.umul.with.overflow") => {
+            let op = match &base[5..9] {
+                "sadd" => OverflowOp::SAdd,
+                "uadd" => OverflowOp::UAdd,
+                "ssub" => OverflowOp::SSub,
+                "usub" => OverflowOp::USub,
+                "smul" => OverflowOp::SMul,
+                _ => OverflowOp::UMul,
+            };
+            let ((bits, val0), (_, val1)) = (int_arg(0)?, int_arg(1)?);
+            let (res, overflowed) = overflow_op(op, bits, val0, val1);
+            Constant::Struct {
+                name: None,
+                values: vec![
+                    ConstantRef::new(int(bits, res)),
+                    ConstantRef::new(int(1, overflowed.into())),
+                ],
+                is_packed: false,
+            }
+        }
+        base @ ("llvm.sadd.sat" | "llvm.uadd.sat" | "llvm.ssub.sat" | "llvm.usub.sat") => {
+            let op = match &base[5..9] {
+                "sadd" => OverflowOp::SAdd,
+                "uadd" => OverflowOp::UAdd,
+                "ssub" => OverflowOp::SSub,
+                _ => OverflowOp::USub,
+            };
+            let ((bits, val0), (_, val1)) = (int_arg(0)?, int_arg(1)?);
+            int(bits, saturate(op, bits, val0, val1))
+        }
+
+        "llvm.fabs" => fl_unary(&arg(0), f32::abs, f64::abs)?,
+        "llvm.sqrt" => fl_unary(&arg(0), f32::sqrt, f64::sqrt)?,
+        "llvm.floor" => fl_unary(&arg(0), f32::floor, f64::floor)?,
+        "llvm.ceil" => fl_unary(&arg(0), f32::ceil, f64::ceil)?,
+        "llvm.trunc" => fl_unary(&arg(0), f32::trunc, f64::trunc)?,
+        "llvm.round" => fl_unary(&arg(0), f32::round, f64::round)?,
+        // We only ever run in the default round-to-nearest-even mode.
+        "llvm.rint" | "llvm.nearbyint" | "llvm.roundeven" => {
+            fl_unary(&arg(0), f32::round_ties_even, f64::round_ties_even)?
+        }
+        "llvm.sin" => fl_unary(&arg(0), f32::sin, f64::sin)?,
+        "llvm.cos" => fl_unary(&arg(0), f32::cos, f64::cos)?,
+        "llvm.exp" => fl_unary(&arg(0), f32::exp, f64::exp)?,
+        "llvm.exp2" => fl_unary(&arg(0), f32::exp2, f64::exp2)?,
+        "llvm.log" => fl_unary(&arg(0), f32::ln, f64::ln)?,
+        "llvm.log2" => fl_unary(&arg(0), f32::log2, f64::log2)?,
+        "llvm.log10" => fl_unary(&arg(0), f32::log10, f64::log10)?,
+        "llvm.pow" => fl_binary(&arg(0), &arg(1), f32::powf, f64::powf)?,
+        "llvm.copysign" => fl_binary(&arg(0), &arg(1), f32::copysign, f64::copysign)?,
+        "llvm.minnum" => fl_binary(&arg(0), &arg(1), f32::min, f64::min)?,
+        "llvm.maxnum" => fl_binary(&arg(0), &arg(1), f32::max, f64::max)?,
+        "llvm.minimum" => fl_binary(&arg(0), &arg(1), minimum, minimum)?,
+        "llvm.maximum" => fl_binary(&arg(0), &arg(1), maximum, maximum)?,
+        "llvm.powi" => {
+            let exp = sext(32, int_arg(1)?.1) as i32;
+            match arg(0).as_ref() {
+                Constant::Float(Float::Single(v)) => Constant::Float(Float::Single(v.powi(exp))),
+                Constant::Float(Float::Double(v)) => Constant::Float(Float::Double(v.powi(exp))),
+                con => return Err(ops::unsupported(con)),
+            }
+        }
+        "llvm.fma" | "llvm.fmuladd" => match (arg(0).as_ref(), arg(1).as_ref(), arg(2).as_ref()) {
+            (
+                Constant::Float(Float::Single(v0)),
+                Constant::Float(Float::Single(v1)),
+                Constant::Float(Float::Single(v2)),
+            ) => Constant::Float(Float::Single(v0.mul_add(*v1, *v2))),
+            (
+                Constant::Float(Float::Double(v0)),
+                Constant::Float(Float::Double(v1)),
+                Constant::Float(Float::Double(v2)),
+            ) => Constant::Float(Float::Double(v0.mul_add(*v1, *v2))),
+            (con, _, _) => return Err(ops::unsupported(con)),
+        },
+        _ => return Err(format!("Unsupported intrinsic {}", name)),
+    })
+}
+
+fn reduce(
+    op: &str,
+    vector: &Constant,
+    start: Option<Constant>,
+    ty: &TypeRef,
+) -> Result<Constant, String> {
+    let lanes = ops::vector_lanes(vector).unwrap();
+    let mut acc = start.unwrap_or_else(|| lanes[0].as_ref().clone());
+    let rest = if op.ends_with("fadd") || op.ends_with("fmul") {
+        &lanes[..]
+    } else {
+        &lanes[1..]
+    };
+    for lane in rest {
+        let lane = lane.as_ref();
+        acc = match op {
+            "add" => ops::int_bin_op(&acc, lane, BinOps::Add)?,
+            "mul" => ops::int_bin_op(&acc, lane, BinOps::Mul)?,
+            "and" => ops::int_bin_op(&acc, lane, BinOps::And)?,
+            "or" => ops::int_bin_op(&acc, lane, BinOps::Or)?,
+            "xor" => ops::int_bin_op(&acc, lane, BinOps::Xor)?,
+            "smax" | "smin" | "umax" | "umin" => {
+                let pred = match op {
+                    "smax" => IntPredicate::SGE,
+                    "smin" => IntPredicate::SLE,
+                    "umax" => IntPredicate::UGE,
+                    _ => IntPredicate::ULE,
+                };
+                ops::select(&ops::icmp(pred, &acc, lane)?, &acc, lane, ty)?
+            }
+            "fadd" | "v2.fadd" => ops::fl_bin_op(&acc, lane, BinOps::Add)?,
+            "fmul" | "v2.fmul" => ops::fl_bin_op(&acc, lane, BinOps::Mul)?,
+            "fmax" => fl_binary(&acc, lane, f32::max, f64::max)?,
+            "fmin" => fl_binary(&acc, lane, f32::min, f64::min)?,
+            "fmaximum" => fl_binary(&acc, lane, maximum, maximum)?,
+            "fminimum" => fl_binary(&acc, lane, minimum, minimum)?,
+            _ => return Err(format!("Unsupported reduction {}", op)),
+        };
+    }
+    Ok(acc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overflow() {
+        assert_eq!(overflow_op(OverflowOp::SAdd, 8, 127, 1), (0x80, true));
+        assert_eq!(overflow_op(OverflowOp::SAdd, 8, 0xff, 1), (0, false));
+        assert_eq!(overflow_op(OverflowOp::UAdd, 8, 0xff, 1), (0, true));
+        assert_eq!(overflow_op(OverflowOp::SSub, 8, 0x80, 1), (0x7f, true));
+        assert_eq!(overflow_op(OverflowOp::USub, 8, 0, 1), (0xff, true));
+        assert_eq!(
+            overflow_op(OverflowOp::SMul, 32, 1 << 16, 1 << 16),
+            (0, true)
+        );
+        assert_eq!(
+            overflow_op(OverflowOp::SMul, 32, 0xffff_ffff, 2),
+            (0xffff_fffe, false)
+        );
+        assert_eq!(
+            overflow_op(OverflowOp::UMul, 64, u64::MAX, 2),
+            (u64::MAX - 1, true)
+        );
+    }
+
+    #[test]
+    fn saturation() {
+        assert_eq!(saturate(OverflowOp::SAdd, 8, 100, 100), 0x7f);
+        assert_eq!(saturate(OverflowOp::SAdd, 8, 0x9c, 0x9c), 0x80);
+        assert_eq!(saturate(OverflowOp::SAdd, 8, 100, 0x9c), 0);
+        assert_eq!(saturate(OverflowOp::SSub, 8, 0x9c, 100), 0x80);
+        assert_eq!(saturate(OverflowOp::SSub, 8, 100, 0x9c), 0x7f);
+        assert_eq!(saturate(OverflowOp::UAdd, 8, 200, 100), 0xff);
+        assert_eq!(saturate(OverflowOp::USub, 8, 5, 10), 0);
+        assert_eq!(saturate(OverflowOp::USub, 8, 10, 5), 5);
+    }
+
+    #[test]
+    fn funnel_shifts() {
+        assert_eq!(funnel_shift(true, 8, 0x12, 0x34, 4), 0x23);
+        assert_eq!(funnel_shift(false, 8, 0x12, 0x34, 4), 0x23);
+        // The shift amount is taken modulo the width.
+        assert_eq!(funnel_shift(true, 8, 0x12, 0x34, 8), 0x12);
+        assert_eq!(funnel_shift(false, 8, 0x12, 0x34, 12), 0x23);
+        assert_eq!(funnel_shift(false, 8, 0x12, 0x34, 16), 0x34);
+        // With both operands the same it's a rotate.
+        assert_eq!(funnel_shift(true, 32, 0x8000_0001, 0x8000_0001, 1), 3);
+        assert_eq!(funnel_shift(false, 64, 1, 1, 1), 1 << 63);
+    }
+}
@@ -0,0 +1,288 @@
+use super::{Frame, LLVMIRInterpreter};
+use llvm_ir::{BasicBlock, Function};
+use std::time::{Duration, Instant};
+
+// Looking at the clock on every instruction would slow everything down.
+const CLOCK_INTERVAL: u64 = 4096;
+
+/// How big each thread's stack is unless it's set otherwise, as on Linux.
+const STACK_SIZE: u64 = 8 << 20;
+/// The stack a call takes besides its allocas: about what a return address, a saved frame
+/// pointer and a few spilled registers take on x86-64.
+pub(super) const FRAME_SIZE: u64 = 64;
+
+/// Bounds on what a program may use, so that a runaway program can't hang bcvm.
+pub(super) struct Limits {
+    /// How many more instructions the program may execute.
+    fuel: Option<u64>,
+    /// How many instructions the program has executed under a fuel budget.
+    burned: u64,
+    max_depth: Option<usize>,
+    max_heap: Option<u64>,
+    stack_size: Option<u64>,
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    ticks: u64,
+    /// Where the program stopped when it ran out of fuel or time, for it to carry on from there.
+    suspended: Option<Vec<Frame>>,
+}
+
+impl Limits {
+    pub(super) fn new() -> Limits {
+        Limits {
+            fuel: None,
+            burned: 0,
+            max_depth: None,
+            max_heap: None,
+            stack_size: Some(STACK_SIZE),
+            timeout: None,
+            deadline: None,
+            ticks: 0,
+            suspended: None,
+        }
+    }
+}
+
+impl LLVMIRInterpreter {
+    /// Let the program execute only this many more instructions. Once they've run out,
+    /// [`interpret`](Self::interpret) fails, and can be called again to carry on after
+    /// [`add_fuel`](Self::add_fuel).
+    pub fn set_fuel(&mut self, fuel: Option<u64>) {
+        self.limits.fuel = fuel;
+    }
+
+    /// How many more instructions the program may execute, if that's limited.
+    pub fn fuel(&self) -> Option<u64> {
+        self.limits.fuel
+    }
+
+    pub fn add_fuel(&mut self, fuel: u64) {
+        self.limits.fuel = Some(self.limits.fuel.unwrap_or(0).saturating_add(fuel));
+    }
+
+    /// Fail once the program has more than this many calls on a thread's stack.
+    pub fn set_max_call_depth(&mut self, depth: Option<usize>) {
+        self.limits.max_depth = depth;
+    }
+
+    /// Fail once the program has more than this many bytes allocated on the heap.
+    pub fn set_max_heap(&mut self, bytes: Option<u64>) {
+        self.limits.max_heap = bytes;
+    }
+
+    /// How many bytes of stack each thread has for its calls and allocas, 8 MiB by default, or
+    /// `None` for as many as they like.
+    pub fn set_stack_size(&mut self, bytes: Option<u64>) {
+        self.limits.stack_size = bytes;
+    }
+
+    /// Stop the program once it has run for this long. Like running out of fuel, the program
+    /// can be carried on with another call to [`interpret`](Self::interpret), which gets as long
+    /// again.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.limits.timeout = timeout;
+    }
+
+    /// Whether the program stopped because it ran out of fuel or time, and can be carried on.
+    pub fn suspended(&self) -> bool {
+        self.limits.suspended.is_some()
+    }
+
+    pub(super) fn start_clock(&mut self) {
+        self.limits.deadline = self.limits.timeout.map(|timeout| Instant::now() + timeout);
+        self.limits.ticks = 0;
+    }
+
+    /// Give back the fuel the program has burned and start the clock again, for it to run again
+    /// from the start.
+    pub(super) fn restart_limits(&mut self) {
+        let limits = &mut self.limits;
+        if let Some(fuel) = &mut limits.fuel {
+            *fuel = fuel.saturating_add(limits.burned);
+        }
+        limits.burned = 0;
+        limits.suspended = None;
+        self.start_clock();
+    }
+
+    /// The frames to carry on from, if the program was suspended.
+    pub(super) fn take_suspended(&mut self) -> Option<Vec<Frame>> {
+        self.limits.suspended.take()
+    }
+
+    /// Stop before the instruction at `index` in `bb` if the program has run out of fuel or time.
+    pub(super) fn limit_hook(
+        &mut self,
+        func: &Function,
+        bb: &BasicBlock,
+        bb_ind: usize,
+        index: usize,
+        frames: &[Frame],
+    ) -> Result<(), String> {
+        let limits = &self.limits;
+        let err = match limits.fuel {
+            Some(0) => format!("Out of fuel after {} instructions", limits.burned),
+            _ => return self.check_clock(func, bb, bb_ind, index, frames),
+        };
+        self.suspend(err, func, bb, bb_ind, index, frames)
+    }
+
+    /// Take the fuel for an instruction that is about to be executed.
+    pub(super) fn burn_fuel(&mut self) {
+        if let Some(fuel) = &mut self.limits.fuel {
+            *fuel -= 1;
+            self.limits.burned += 1;
+        }
+    }
+
+    fn check_clock(
+        &mut self,
+        func: &Function,
+        bb: &BasicBlock,
+        bb_ind: usize,
+        index: usize,
+        frames: &[Frame],
+    ) -> Result<(), String> {
+        let limits = &mut self.limits;
+        let deadline = match limits.deadline {
+            Some(deadline) => deadline,
+            None => return Ok(()),
+        };
+        limits.ticks += 1;
+        if !limits.ticks.is_multiple_of(CLOCK_INTERVAL) || Instant::now() < deadline {
+            return Ok(());
+        }
+        let err = format!("Timed out after {:?}", limits.timeout.unwrap());
+        self.suspend(err, func, bb, bb_ind, index, frames)
+    }
+
+    fn suspend(
+        &mut self,
+        err: String,
+        func: &Function,
+        bb: &BasicBlock,
+        bb_ind: usize,
+        index: usize,
+        frames: &[Frame],
+    ) -> Result<(), String> {
+        self.pc = (bb_ind, index);
+        let mut frames = frames.to_vec();
+        frames.push((func.name.clone(), bb.name.clone(), index, None));
+        self.limits.suspended = Some(frames);
+        Err(err)
+    }
+
+    /// Fail if a call would take the running thread deeper than it may go.
+    pub(super) fn check_call_depth(&self) -> Result<(), String> {
+        match self.limits.max_depth {
+            Some(max) if self.stack.len() >= max => {
+                Err(format!("Call depth limit of {} reached", max))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Fail if taking `bytes` more of the running thread's stack for `func`, `depth` calls deep,
+    /// would overflow it.
+    pub(super) fn check_stack(&self, bytes: u64, func: &str, depth: usize) -> Result<(), String> {
+        let size = match self.limits.stack_size {
+            Some(size) => size,
+            None => return Ok(()),
+        };
+        match self.stack_used.checked_add(bytes) {
+            Some(used) if used <= size => Ok(()),
+            _ => Err(format!(
+                "Stack overflow in {} at depth {}: the stack is {} bytes",
+                self.source_name(func),
+                depth,
+                size
+            )),
+        }
+    }
+
+    /// Fail if allocating `bytes` more on the heap would take the program over what it may have.
+    pub(super) fn check_heap(&self, bytes: u64) -> Result<(), String> {
+        let heap = self.memory.usage().1;
+        match self.limits.max_heap {
+            Some(max) if heap.saturating_add(bytes) > max => Err(format!(
+                "Heap limit of {} bytes exceeded: {} bytes are allocated and {} more were asked for",
+                max, heap, bytes
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interp::memory::AllocKind;
+    use llvm_ir::{module::DataLayout, types::Types, Module};
+    use std::rc::Rc;
+
+    fn interpreter() -> LLVMIRInterpreter {
+        LLVMIRInterpreter::new(Module {
+            name: String::new(),
+            source_file_name: String::new(),
+            data_layout: DataLayout::default(),
+            target_triple: None,
+            functions: Vec::new(),
+            global_vars: Vec::new(),
+            global_aliases: Vec::new(),
+            inline_assembly: String::new(),
+            types: Types::blank_for_testing(),
+        })
+    }
+
+    #[test]
+    fn fuel() {
+        let mut lii = interpreter();
+        lii.set_fuel(Some(2));
+        lii.burn_fuel();
+        lii.add_fuel(3);
+        lii.burn_fuel();
+        assert_eq!(lii.fuel(), Some(3));
+        // What was burned is given back for the program to run again.
+        lii.restart_limits();
+        assert_eq!(lii.fuel(), Some(5));
+    }
+
+    #[test]
+    fn call_depth() {
+        let mut lii = interpreter();
+        lii.stack = Rc::new(vec!["main".to_owned(), "f".to_owned()]);
+        assert!(lii.check_call_depth().is_ok());
+        lii.set_max_call_depth(Some(3));
+        assert!(lii.check_call_depth().is_ok());
+        lii.set_max_call_depth(Some(2));
+        assert!(lii.check_call_depth().is_err());
+    }
+
+    #[test]
+    fn heap() {
+        let mut lii = interpreter();
+        lii.set_max_heap(Some(100));
+        let addr = lii.memory.alloc(60, 16, AllocKind::Heap, &lii.stack);
+        // Only the heap counts.
+        lii.memory.alloc(60, 16, AllocKind::Stack, &lii.stack);
+        assert!(lii.check_heap(40).is_ok());
+        assert!(lii.check_heap(41).is_err());
+        assert!(lii.check_heap(u64::MAX).is_err());
+        lii.memory.free(addr, AllocKind::Heap, &lii.stack).unwrap();
+        assert!(lii.check_heap(100).is_ok());
+        lii.set_max_heap(None);
+        assert!(lii.check_heap(u64::MAX).is_ok());
+    }
+
+    #[test]
+    fn stack() {
+        let mut lii = interpreter();
+        lii.stack_used = STACK_SIZE - 10;
+        assert!(lii.check_stack(10, "main", 1).is_ok());
+        assert!(lii.check_stack(11, "main", 1).is_err());
+        lii.set_stack_size(Some(STACK_SIZE + 1));
+        assert!(lii.check_stack(11, "main", 1).is_ok());
+        lii.set_stack_size(None);
+        assert!(lii.check_stack(u64::MAX, "main", 1).is_ok());
+    }
+}
@@ -0,0 +1,1201 @@
+use super::{
+    backtrace,
+    ops::{self, get_int, int, sext},
+    snapshot::{Reader, Writer},
+    LLVMIRInterpreter,
+};
+use llvm_ir::{
+    constant::Constant,
+    instruction::{AtomicRMW, Atomicity, CmpXchg, GetElementPtr, RMWBinOp},
+    name,
+    types::NamedStructDef,
+    ConstantRef, IntPredicate, Operand, Type, TypeRef,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    mem,
+    rc::Rc,
+};
+
+// Allocations are never placed back to back, so that running off the end of one doesn't silently
+// land in the next.
+const ALLOC_GAP: u64 = 16;
+/// The most bytes one allocation may have. Bigger ones fail as they would on a host that's out
+/// of memory, rather than taking bcvm down with them.
+const MAX_ALLOC: u64 = 1 << 32;
+/// The most host memory all live allocations together may take, counting the shadow every byte
+/// has in checked mode.
+const MAX_FOOTPRINT: u64 = 16 << 30;
+
+#[derive(Clone, PartialEq)]
+pub(super) enum AllocKind {
+    Stack,
+    Heap,
+    Global(String),
+}
+
+/// Where an allocation came from and, once it's gone, where it was freed. Only kept in checked
+/// mode.
+struct Site {
+    kind: AllocKind,
+    size: u64,
+    stack: Rc<Vec<String>>,
+    freed: Option<Rc<Vec<String>>>,
+}
+
+impl Site {
+    fn describe(&self) -> String {
+        match &self.kind {
+            AllocKind::Stack => format!("stack allocation of {} bytes", self.size),
+            AllocKind::Heap => format!("heap allocation of {} bytes", self.size),
+            AllocKind::Global(name) => format!("global {} of {} bytes", name, self.size),
+        }
+    }
+
+    fn history(&self) -> String {
+        let mut history = String::new();
+        if !self.stack.is_empty() {
+            history.push_str(&format!("\n  allocated at:{}", backtrace(&self.stack)));
+        }
+        if let (AllocKind::Heap, Some(freed)) = (&self.kind, &self.freed) {
+            history.push_str(&format!("\n  freed at:{}", backtrace(freed)));
+        }
+        history
+    }
+}
+
+/// A flat, byte-addressable address space. Addresses are handed out in increasing order and never
+/// reused.
+pub(super) struct Memory {
+    allocs: BTreeMap<u64, Vec<u8>>,
+    /// Which of `allocs` are on the heap, so that nothing else can be freed as if it were.
+    heap: BTreeSet<u64>,
+    next_addr: u64,
+    /// Every allocation ever made, live or not, when in checked mode.
+    sites: Option<BTreeMap<u64, Site>>,
+    /// For every byte of each live allocation, where it came from if it holds no defined value.
+    /// Only kept in checked mode.
+    shadow: Option<BTreeMap<u64, Vec<Option<Rc<String>>>>>,
+    /// Bytes of all live allocations.
+    live: u64,
+    /// Bytes of live stack and heap allocations, and the most there have ever been.
+    stack_in_use: u64,
+    heap_in_use: u64,
+    peak_stack: u64,
+    peak_heap: u64,
+}
+
+impl Memory {
+    pub(super) fn new() -> Memory {
+        Memory {
+            allocs: BTreeMap::new(),
+            heap: BTreeSet::new(),
+            next_addr: 0x1000,
+            sites: None,
+            shadow: None,
+            live: 0,
+            stack_in_use: 0,
+            heap_in_use: 0,
+            peak_stack: 0,
+            peak_heap: 0,
+        }
+    }
+
+    pub(super) fn save(&self, out: &mut Writer) {
+        out.u64(self.allocs.len() as u64);
+        for (addr, bytes) in &self.allocs {
+            out.u64(*addr);
+            out.bytes(bytes);
+        }
+        out.u64(self.heap.len() as u64);
+        for addr in &self.heap {
+            out.u64(*addr);
+        }
+        out.u64(self.next_addr);
+        out.bool(self.sites.is_some());
+        for (addr, site) in self.sites.iter().flatten() {
+            out.u64(*addr);
+            match &site.kind {
+                AllocKind::Stack => out.u64(0),
+                AllocKind::Heap => out.u64(1),
+                AllocKind::Global(name) => {
+                    out.u64(2);
+                    out.str(name);
+                }
+            }
+            out.u64(site.size);
+            out.strings(&site.stack);
+            out.bool(site.freed.is_some());
+            out.strings(site.freed.as_deref().map_or(&[], Vec::as_slice));
+        }
+        out.u64(u64::MAX);
+        // Runs of bytes share where they came from.
+        for (addr, origins) in self.shadow.iter().flatten() {
+            out.u64(*addr);
+            let runs = origins.chunk_by(|a, b| match (a, b) {
+                (Some(a), Some(b)) => Rc::ptr_eq(a, b),
+                (a, b) => a.is_none() && b.is_none(),
+            });
+            out.u64(runs.clone().count() as u64);
+            for run in runs {
+                out.u64(run.len() as u64);
+                out.bool(run[0].is_some());
+                out.str(run[0].as_deref().map_or("", String::as_str));
+            }
+        }
+        out.u64(u64::MAX);
+        for n in [
+            self.stack_in_use,
+            self.heap_in_use,
+            self.peak_stack,
+            self.peak_heap,
+        ] {
+            out.u64(n);
+        }
+    }
+
+    pub(super) fn load(input: &mut Reader) -> Result<Memory, String> {
+        let mut memory = Memory::new();
+        for _ in 0..input.u64()? {
+            let (addr, bytes) = (input.u64()?, input.bytes()?);
+            memory.live += bytes.len() as u64;
+            memory.allocs.insert(addr, bytes);
+        }
+        for _ in 0..input.u64()? {
+            memory.heap.insert(input.u64()?);
+        }
+        memory.next_addr = input.u64()?;
+        memory.set_checked(input.bool()?);
+        loop {
+            let addr = input.u64()?;
+            if addr == u64::MAX {
+                break;
+            }
+            let kind = match input.u64()? {
+                0 => AllocKind::Stack,
+                1 => AllocKind::Heap,
+                _ => AllocKind::Global(input.string()?),
+            };
+            let size = input.u64()?;
+            let stack = Rc::new(input.strings()?);
+            let freed = input.bool()?;
+            let freed_at = Rc::new(input.strings()?);
+            let site = Site {
+                kind,
+                size,
+                stack,
+                freed: Some(freed_at).filter(|_| freed),
+            };
+            if let Some(sites) = &mut memory.sites {
+                sites.insert(addr, site);
+            }
+        }
+        loop {
+            let addr = input.u64()?;
+            if addr == u64::MAX {
+                break;
+            }
+            let mut origins = Vec::new();
+            for _ in 0..input.u64()? {
+                let len = input.usize()?;
+                let defined = !input.bool()?;
+                let origin = Rc::new(input.string()?);
+                origins.extend((0..len).map(|_| Some(Rc::clone(&origin)).filter(|_| !defined)));
+            }
+            if let Some(shadow) = &mut memory.shadow {
+                shadow.insert(addr, origins);
+            }
+        }
+        memory.stack_in_use = input.u64()?;
+        memory.heap_in_use = input.u64()?;
+        memory.peak_stack = input.u64()?;
+        memory.peak_heap = input.u64()?;
+        Ok(memory)
+    }
+
+    /// Track where every allocation comes from so that bad accesses and frees can be reported in
+    /// detail, and leaks found. Also track which bytes hold undefined values.
+    pub(super) fn set_checked(&mut self, checked: bool) {
+        (self.sites, self.shadow) = match checked {
+            true => (Some(BTreeMap::new()), Some(BTreeMap::new())),
+            false => (None, None),
+        };
+    }
+
+    pub(super) fn alloc(
+        &mut self,
+        size: u64,
+        align: u64,
+        kind: AllocKind,
+        stack: &Rc<Vec<String>>,
+    ) -> u64 {
+        let addr = self.reserve(size, align);
+        self.allocs.insert(addr, vec![0; size as usize]);
+        self.live += size;
+        if kind == AllocKind::Heap {
+            self.heap.insert(addr);
+        }
+        if let Some((in_use, peak)) = self.in_use(&kind) {
+            *in_use += size;
+            *peak = (*peak).max(*in_use);
+        }
+        if let Some(shadow) = &mut self.shadow {
+            shadow.insert(addr, vec![None; size as usize]);
+        }
+        if let Some(sites) = &mut self.sites {
+            let site = Site {
+                kind,
+                size,
+                stack: Rc::clone(stack),
+                freed: None,
+            };
+            sites.insert(addr, site);
+        }
+        addr
+    }
+
+    /// Like [`alloc`](Self::alloc), but fails if `size` is more than an allocation may have, or
+    /// would take more host memory than all allocations may.
+    pub(super) fn try_alloc(
+        &mut self,
+        size: u64,
+        align: u64,
+        kind: AllocKind,
+        stack: &Rc<Vec<String>>,
+    ) -> Option<u64> {
+        let per_byte = match self.shadow {
+            Some(_) => 1 + mem::size_of::<Option<Rc<String>>>() as u64,
+            None => 1,
+        };
+        let footprint = self.live.saturating_add(size).saturating_mul(per_byte);
+        match size <= MAX_ALLOC && footprint <= MAX_FOOTPRINT {
+            true => Some(self.alloc(size, align, kind, stack)),
+            false => None,
+        }
+    }
+
+    /// Reserve a range of addresses without backing it with memory. This gives functions an
+    /// address that can't be read from or written to.
+    pub(super) fn reserve(&mut self, size: u64, align: u64) -> u64 {
+        let align = align.max(1);
+        let addr = self.next_addr.div_ceil(align) * align;
+        self.next_addr = addr + size.max(1) + ALLOC_GAP;
+        addr
+    }
+
+    pub(super) fn free(
+        &mut self,
+        addr: u64,
+        kind: AllocKind,
+        stack: &Rc<Vec<String>>,
+    ) -> Result<(), String> {
+        let sites = match &mut self.sites {
+            Some(sites) => sites,
+            None => {
+                let is_heap = self.heap.contains(&addr);
+                if !self.allocs.contains_key(&addr) || is_heap != (kind == AllocKind::Heap) {
+                    return Err(format!("Invalid free of address {:#x}", addr));
+                }
+                let size = self.allocs.remove(&addr).unwrap().len() as u64;
+                self.live -= size;
+                self.heap.remove(&addr);
+                if let Some((in_use, _)) = self.in_use(&kind) {
+                    *in_use -= size;
+                }
+                return Ok(());
+            }
+        };
+        match sites.get_mut(&addr) {
+            Some(site) if site.freed.is_some() => Err(format!(
+                "Double free of address {:#x}, a {}{}",
+                addr,
+                site.describe(),
+                site.history()
+            )),
+            Some(site) if site.kind != kind => Err(format!(
+                "Invalid free of address {:#x}, a {}{}",
+                addr,
+                site.describe(),
+                site.history()
+            )),
+            Some(site) => {
+                site.freed = Some(Rc::clone(stack));
+                self.allocs.remove(&addr);
+                self.heap.remove(&addr);
+                if let Some(shadow) = &mut self.shadow {
+                    shadow.remove(&addr);
+                }
+                let size = site.size;
+                self.live -= size;
+                if let Some((in_use, _)) = self.in_use(&kind) {
+                    *in_use -= size;
+                }
+                Ok(())
+            }
+            None => Err(format!(
+                "Invalid free of address {:#x}, which was never allocated",
+                addr
+            )),
+        }
+    }
+
+    fn in_use(&mut self, kind: &AllocKind) -> Option<(&mut u64, &mut u64)> {
+        match kind {
+            AllocKind::Stack => Some((&mut self.stack_in_use, &mut self.peak_stack)),
+            AllocKind::Heap => Some((&mut self.heap_in_use, &mut self.peak_heap)),
+            AllocKind::Global(_) => None,
+        }
+    }
+
+    /// The bytes of stack and heap allocations that are live.
+    pub(super) fn usage(&self) -> (u64, u64) {
+        (self.stack_in_use, self.heap_in_use)
+    }
+
+    /// The most bytes of stack and heap allocations that have been live at once.
+    pub(super) fn peak_usage(&self) -> (u64, u64) {
+        (self.peak_stack, self.peak_heap)
+    }
+
+    pub(super) fn alloc_size(&self, addr: u64) -> Option<u64> {
+        self.allocs.get(&addr).map(|bytes| bytes.len() as u64)
+    }
+
+    /// Describe the allocation starting at `addr`, in checked mode.
+    pub(super) fn describe(&self, addr: u64) -> Option<String> {
+        Some(self.sites.as_ref()?.get(&addr)?.describe())
+    }
+
+    /// Heap allocations that are still live, in checked mode.
+    pub(super) fn leaks(&self) -> Vec<String> {
+        let sites = match &self.sites {
+            Some(sites) => sites,
+            None => return Vec::new(),
+        };
+        sites
+            .iter()
+            .filter(|(_, site)| site.kind == AllocKind::Heap && site.freed.is_none())
+            .map(|(addr, site)| {
+                format!(
+                    "Leak of a {} at address {:#x}{}",
+                    site.describe(),
+                    addr,
+                    site.history()
+                )
+            })
+            .collect()
+    }
+
+    fn find(&self, addr: u64, len: u64, write: bool) -> Result<(u64, &Vec<u8>), String> {
+        match self.allocs.range(..=addr).next_back() {
+            Some((base, bytes)) if matches!(addr.checked_add(len), Some(end) if end <= base + bytes.len() as u64) => {
+                Ok((*base, bytes))
+            }
+            _ => Err(self.diagnose(addr, len, write)),
+        }
+    }
+
+    fn diagnose(&self, addr: u64, len: u64, write: bool) -> String {
+        let sites = match &self.sites {
+            Some(sites) => sites,
+            None => {
+                return format!(
+                    "Invalid memory access of {} bytes at address {:#x}",
+                    len, addr
+                )
+            }
+        };
+        let access = format!(
+            "{}-byte {} at address {:#x}",
+            len,
+            if write { "write" } else { "read" },
+            addr
+        );
+        let below = sites.range(..=addr).next_back();
+        let above = sites.range(addr.saturating_add(1)..).next();
+        match (below, above) {
+            (Some((base, site)), _) if addr < base + site.size && site.freed.is_some() => {
+                let what = match site.kind {
+                    AllocKind::Stack => "Use after return",
+                    _ => "Use after free",
+                };
+                format!(
+                    "{}: {} is {} bytes inside a {}{}",
+                    what,
+                    access,
+                    addr - base,
+                    site.describe(),
+                    site.history()
+                )
+            }
+            (Some((base, site)), _) if addr < base + site.size + ALLOC_GAP => {
+                let end = base + site.size;
+                let (verb, past) = match addr >= end {
+                    true => ("is", addr - end),
+                    false => ("runs", addr.saturating_add(len) - end),
+                };
+                format!(
+                    "Out-of-bounds access: {} {} {} bytes past the end of a {}{}",
+                    access,
+                    verb,
+                    past,
+                    site.describe(),
+                    site.history()
+                )
+            }
+            (_, Some((base, site)))
+                if addr.saturating_add(len) > *base || base - addr <= ALLOC_GAP =>
+            {
+                format!(
+                    "Out-of-bounds access: {} is {} bytes before the start of a {}{}",
+                    access,
+                    base - addr,
+                    site.describe(),
+                    site.history()
+                )
+            }
+            _ => format!("Wild access: {} is outside any allocation", access),
+        }
+    }
+
+    pub(super) fn read(&self, addr: u64, len: u64) -> Result<&[u8], String> {
+        let (base, bytes) = self.find(addr, len, false)?;
+        let off = (addr - base) as usize;
+        Ok(&bytes[off..off + len as usize])
+    }
+
+    pub(super) fn write(&mut self, addr: u64, val: &[u8]) -> Result<(), String> {
+        let base = self.find(addr, val.len() as u64, true)?.0;
+        let off = (addr - base) as usize;
+        let bytes = self.allocs.get_mut(&base).unwrap();
+        bytes[off..off + val.len()].copy_from_slice(val);
+        if let Some(shadow) = self.shadow_mut(addr, val.len() as u64) {
+            shadow.fill(None);
+        }
+        Ok(())
+    }
+
+    /// Set `len` bytes at `addr` to `val`.
+    pub(super) fn fill(&mut self, addr: u64, val: u8, len: u64) -> Result<(), String> {
+        let base = self.find(addr, len, true)?.0;
+        let off = (addr - base) as usize;
+        let bytes = self.allocs.get_mut(&base).unwrap();
+        bytes[off..off + len as usize].fill(val);
+        if let Some(shadow) = self.shadow_mut(addr, len) {
+            shadow.fill(None);
+        }
+        Ok(())
+    }
+
+    /// Copy `len` bytes, and whether they're defined, from `src` to `dst`.
+    pub(super) fn copy(&mut self, dst: u64, src: u64, len: u64) -> Result<(), String> {
+        let bytes = self.read(src, len)?.to_vec();
+        let shadow = self.shadow(src, len).map(<[_]>::to_vec);
+        self.write(dst, &bytes)?;
+        if let (Some(shadow), Some(dst)) = (shadow, self.shadow_mut(dst, len)) {
+            dst.clone_from_slice(&shadow);
+        }
+        Ok(())
+    }
+
+    /// The shadow of `len` bytes at `addr`, which must be valid, in checked mode.
+    pub(super) fn shadow(&self, addr: u64, len: u64) -> Option<&[Option<Rc<String>>]> {
+        let (base, shadow) = self.shadow.as_ref()?.range(..=addr).next_back()?;
+        let off = (addr - base) as usize;
+        Some(&shadow[off..off + len as usize])
+    }
+
+    fn shadow_mut(&mut self, addr: u64, len: u64) -> Option<&mut [Option<Rc<String>>]> {
+        let (base, shadow) = self.shadow.as_mut()?.range_mut(..=addr).next_back()?;
+        let off = (addr - base) as usize;
+        Some(&mut shadow[off..off + len as usize])
+    }
+
+    /// Where the first undefined byte of the `len` bytes at `addr` came from, if any of them is.
+    pub(super) fn undefined(&self, addr: u64, len: u64) -> Option<Rc<String>> {
+        self.shadow(addr, len)?.iter().flatten().next().cloned()
+    }
+
+    /// Mark `len` bytes at `addr`, which must be valid, as holding no defined value because of
+    /// `origin`. Only has an effect in checked mode.
+    pub(super) fn undefine(&mut self, addr: u64, len: u64, origin: &Rc<String>) {
+        if let Some(shadow) = self.shadow_mut(addr, len) {
+            shadow.fill(Some(Rc::clone(origin)));
+        }
+    }
+
+    pub(super) fn read_c_string(&self, addr: u64) -> Result<String, String> {
+        let mut string = String::new();
+        let mut addr = addr;
+        loop {
+            match self.read(addr, 1)?[0] {
+                0 => return Ok(string),
+                ch => string.push(ch as char),
+            }
+            addr += 1;
+        }
+    }
+}
+
+pub(super) fn ptr(addr: u64) -> ConstantRef {
+    ConstantRef::new(int(64, addr))
+}
+
+impl LLVMIRInterpreter {
+    fn resolve_type(&self, ty: &TypeRef) -> Result<TypeRef, String> {
+        match ty.as_ref() {
+            Type::NamedStructType { name } => match self.module.types.named_struct_def(name) {
+                Some(NamedStructDef::Defined(def)) => Ok(def.clone()),
+                _ => Err(format!("Unsupported opaque struct %{}", name)),
+            },
+            _ => Ok(ty.clone()),
+        }
+    }
+
+    pub(super) fn align_of(&self, ty: &TypeRef) -> Result<u64, String> {
+        Ok(match self.resolve_type(ty)?.as_ref() {
+            Type::StructType {
+                element_types,
+                is_packed,
+            } => match is_packed {
+                true => 1,
+                false => element_types
+                    .iter()
+                    .map(|ty| self.align_of(ty))
+                    .collect::<Result<Vec<_>, _>>()?
+                    .into_iter()
+                    .max()
+                    .unwrap_or(1),
+            },
+            Type::ArrayType { element_type, .. } => self.align_of(element_type)?,
+            Type::VectorType { .. } => self.store_size(ty)?.next_power_of_two(),
+            ty => {
+                let align = self.module.data_layout.alignments.type_alignment(ty).abi / 8;
+                u64::from(align.max(1))
+            }
+        })
+    }
+
+    /// The number of bytes a load or store of `ty` touches.
+    pub(super) fn store_size(&self, ty: &TypeRef) -> Result<u64, String> {
+        Ok(match self.resolve_type(ty)?.as_ref() {
+            Type::IntegerType { bits } => u64::from(*bits).div_ceil(8),
+            Type::FPType(_) => self.module.data_layout.alignments.type_alignment(ty).abi as u64 / 8,
+            Type::PointerType { .. } => 8,
+            Type::VectorType {
+                element_type,
+                num_elements,
+                ..
+            } => match element_type.as_ref() {
+                // Lanes that aren't whole bytes are packed together.
+                Type::IntegerType { bits } => (u64::from(*bits) * *num_elements as u64).div_ceil(8),
+                _ => self.store_size(element_type)? * *num_elements as u64,
+            },
+            Type::StructType { .. } | Type::ArrayType { .. } => self.size_of(ty)?,
+            _ => return Err(format!("Unsupported type {} in memory", ty)),
+        })
+    }
+
+    /// The distance between successive values of `ty` in memory, including padding.
+    pub(super) fn size_of(&self, ty: &TypeRef) -> Result<u64, String> {
+        Ok(match self.resolve_type(ty)?.as_ref() {
+            Type::StructType {
+                element_types,
+                is_packed,
+            } => {
+                let offsets = self.field_offsets(element_types, *is_packed)?;
+                let end = match (offsets.last(), element_types.last()) {
+                    (Some(off), Some(ty)) => off + self.size_of(ty)?,
+                    _ => 0,
+                };
+                let align = self.align_of(ty)?;
+                end.div_ceil(align) * align
+            }
+            Type::ArrayType {
+                element_type,
+                num_elements,
+            } => self.size_of(element_type)? * *num_elements as u64,
+            _ => {
+                let align = self.align_of(ty)?;
+                self.store_size(ty)?.div_ceil(align) * align
+            }
+        })
+    }
+
+    fn field_offsets(
+        &self,
+        element_types: &[TypeRef],
+        is_packed: bool,
+    ) -> Result<Vec<u64>, String> {
+        let mut offsets = Vec::with_capacity(element_types.len());
+        let mut off = 0u64;
+        for ty in element_types {
+            if !is_packed {
+                let align = self.align_of(ty)?;
+                off = off.div_ceil(align) * align;
+            }
+            offsets.push(off);
+            off += self.size_of(ty)?;
+        }
+        Ok(offsets)
+    }
+
+    /// Evaluate constant expressions that denote addresses (globals, `null`, constant GEPs and
+    /// casts thereof) into pointer values. Those we can't evaluate are left as they are, to be
+    /// reported where they're used.
+    pub(super) fn eval_const(&self, con: &ConstantRef) -> ConstantRef {
+        let val = match con.as_ref() {
+            Constant::GlobalReference { name, .. } => Ok(ptr(*self.gl_vars.get(name).unwrap())),
+            Constant::Null(_) => Ok(ptr(0)),
+            Constant::GetElementPtr(gep) => {
+                get_int(&self.eval_const(&gep.address)).and_then(|(_, addr)| {
+                    let indices = gep
+                        .indices
+                        .iter()
+                        .map(|ind| self.eval_const(ind))
+                        .collect::<Vec<_>>();
+                    let ty = self.module.type_of(&gep.address);
+                    Ok(ptr(self.gep_addr(addr, &ty, &indices)?))
+                })
+            }
+            Constant::BitCast(bc) => match bc.to_type.as_ref() {
+                Type::PointerType { .. } => Ok(self.eval_const(&bc.operand)),
+                _ => ops::cast(
+                    ops::CastOps::BitCast,
+                    &self.eval_const(&bc.operand),
+                    &bc.to_type,
+                )
+                .map(ConstantRef::new),
+            },
+            Constant::AddrSpaceCast(asc) => Ok(self.eval_const(&asc.operand)),
+            Constant::PtrToInt(pti) => get_int(&self.eval_const(&pti.operand)).map(|(_, addr)| {
+                match pti.to_type.as_ref() {
+                    Type::IntegerType { bits } => ConstantRef::new(int(*bits, addr)),
+                    _ => unreachable!(),
+                }
+            }),
+            Constant::IntToPtr(itp) => {
+                get_int(&self.eval_const(&itp.operand)).map(|(_, addr)| ptr(addr))
+            }
+            _ => Err(String::new()),
+        };
+        val.unwrap_or_else(|_| con.clone())
+    }
+
+    fn gep_addr(
+        &self,
+        addr: u64,
+        ptr_ty: &TypeRef,
+        indices: &[ConstantRef],
+    ) -> Result<u64, String> {
+        let mut ty = match ptr_ty.as_ref() {
+            Type::PointerType { pointee_type, .. } => pointee_type.clone(),
+            _ => return Err(format!("Unsupported GEP on {}", ptr_ty)),
+        };
+        let ind = |con: &ConstantRef| {
+            let (bits, val) = get_int(con)?;
+            Ok::<_, String>(sext(bits, val) as u64)
+        };
+        let mut addr = addr.wrapping_add(ind(&indices[0])?.wrapping_mul(self.size_of(&ty)?));
+        for index in &indices[1..] {
+            ty = match self.resolve_type(&ty)?.as_ref() {
+                Type::StructType {
+                    element_types,
+                    is_packed,
+                } => {
+                    let field = ind(index)? as usize;
+                    addr += self.field_offsets(element_types, *is_packed)?[field];
+                    element_types[field].clone()
+                }
+                Type::ArrayType { element_type, .. } | Type::VectorType { element_type, .. } => {
+                    addr = addr.wrapping_add(ind(index)?.wrapping_mul(self.size_of(element_type)?));
+                    element_type.clone()
+                }
+                _ => unreachable!(),
+            };
+        }
+        Ok(addr)
+    }
+
+    pub(super) fn val_to_bytes(&self, con: &Constant, ty: &TypeRef) -> Result<Vec<u8>, String> {
+        match con {
+            Constant::Undef(_) | Constant::Poison(_) => {
+                return Ok(vec![0; self.store_size(ty)? as usize])
+            }
+            Constant::AggregateZero(_) => return Ok(vec![0; self.store_size(ty)? as usize]),
+            _ => {}
+        }
+        Ok(match self.resolve_type(ty)?.as_ref() {
+            Type::StructType {
+                element_types,
+                is_packed,
+            } => {
+                let mut bytes = vec![0; self.size_of(ty)? as usize];
+                let values = match con {
+                    Constant::Struct { values, .. } => values,
+                    _ => unreachable!(),
+                };
+                let offsets = self.field_offsets(element_types, *is_packed)?;
+                for ((val, ty), off) in values.iter().zip(element_types).zip(offsets) {
+                    let val = self.val_to_bytes(val, ty)?;
+                    bytes[off as usize..off as usize + val.len()].copy_from_slice(&val);
+                }
+                bytes
+            }
+            Type::ArrayType { element_type, .. } => {
+                let elements = match con {
+                    Constant::Array { elements, .. } => elements,
+                    _ => unreachable!(),
+                };
+                let stride = self.size_of(element_type)? as usize;
+                let mut bytes = vec![0; stride * elements.len()];
+                for (i, elem) in elements.iter().enumerate() {
+                    let elem = self.val_to_bytes(elem, element_type)?;
+                    bytes[i * stride..i * stride + elem.len()].copy_from_slice(&elem);
+                }
+                bytes
+            }
+            _ => ops::to_bytes(&self.eval_const(&ConstantRef::new(con.clone())))?,
+        })
+    }
+
+    pub(super) fn bytes_to_val(&self, ty: &TypeRef, bytes: &[u8]) -> Result<Constant, String> {
+        let (name, resolved) = match ty.as_ref() {
+            Type::NamedStructType { name } => (Some(name.clone()), self.resolve_type(ty)?),
+            _ => (None, ty.clone()),
+        };
+        match resolved.as_ref() {
+            Type::StructType {
+                element_types,
+                is_packed,
+            } => {
+                let offsets = self.field_offsets(element_types, *is_packed)?;
+                Ok(Constant::Struct {
+                    name,
+                    values: element_types
+                        .iter()
+                        .zip(offsets)
+                        .map(|(ty, off)| {
+                            let off = off as usize;
+                            let size = self.store_size(ty)? as usize;
+                            self.bytes_to_val(ty, &bytes[off..off + size])
+                                .map(ConstantRef::new)
+                        })
+                        .collect::<Result<_, _>>()?,
+                    is_packed: *is_packed,
+                })
+            }
+            Type::ArrayType {
+                element_type,
+                num_elements,
+            } => {
+                let stride = self.size_of(element_type)? as usize;
+                let size = self.store_size(element_type)? as usize;
+                Ok(Constant::Array {
+                    element_type: element_type.clone(),
+                    elements: (0..*num_elements)
+                        .map(|i| {
+                            let elem = &bytes[i * stride..i * stride + size];
+                            self.bytes_to_val(element_type, elem).map(ConstantRef::new)
+                        })
+                        .collect::<Result<_, _>>()?,
+                })
+            }
+            _ => ops::from_bytes(ty, bytes),
+        }
+    }
+
+    /// Load a value of type `ty`. Parts of it loaded from undefined bytes are `poison`.
+    pub(super) fn load_val(&self, addr: u64, ty: &TypeRef) -> Result<Constant, String> {
+        let bytes = self.memory.read(addr, self.store_size(ty)?)?;
+        let val = self.bytes_to_val(ty, bytes)?;
+        Ok(match self.memory.shadow(addr, bytes.len() as u64) {
+            Some(shadow) => self.poison_undefined(val, ty, shadow)?,
+            None => val,
+        })
+    }
+
+    fn poison_undefined(
+        &self,
+        val: Constant,
+        ty: &TypeRef,
+        shadow: &[Option<Rc<String>>],
+    ) -> Result<Constant, String> {
+        if shadow.iter().all(Option::is_none) {
+            return Ok(val);
+        }
+        let poison_all = |vals: Vec<ConstantRef>, tys: &dyn Fn(usize) -> (TypeRef, u64)| {
+            vals.into_iter()
+                .enumerate()
+                .map(|(i, val)| {
+                    let (ty, off) = tys(i);
+                    let (off, size) = (off as usize, self.store_size(&ty)? as usize);
+                    let val = val.as_ref().clone();
+                    let val = self.poison_undefined(val, &ty, &shadow[off..off + size])?;
+                    Ok(ConstantRef::new(val))
+                })
+                .collect::<Result<_, String>>()
+        };
+        Ok(match (val, self.resolve_type(ty)?.as_ref()) {
+            (
+                Constant::Struct { name, values, .. },
+                Type::StructType {
+                    element_types,
+                    is_packed,
+                },
+            ) => {
+                let offsets = self.field_offsets(element_types, *is_packed)?;
+                Constant::Struct {
+                    name,
+                    values: poison_all(values, &|i| (element_types[i].clone(), offsets[i]))?,
+                    is_packed: *is_packed,
+                }
+            }
+            (
+                Constant::Array {
+                    element_type,
+                    elements,
+                },
+                _,
+            ) => {
+                let stride = self.size_of(&element_type)?;
+                Constant::Array {
+                    elements: poison_all(elements, &|i| (element_type.clone(), i as u64 * stride))?,
+                    element_type,
+                }
+            }
+            // Lanes narrower than a byte share the shadow of the byte they're in.
+            (Constant::Vector(lanes), Type::VectorType { element_type, .. }) => {
+                let n = lanes.len();
+                Constant::Vector(poison_all(lanes, &|i| {
+                    (element_type.clone(), (i * shadow.len() / n) as u64)
+                })?)
+            }
+            _ => Constant::Poison(ty.clone()),
+        })
+    }
+
+    /// Store a value of type `ty`. In checked mode, the bytes of any `poison` parts of it are
+    /// marked as undefined.
+    pub(super) fn store_val_at(
+        &mut self,
+        addr: u64,
+        con: &Constant,
+        ty: &TypeRef,
+    ) -> Result<(), String> {
+        let bytes = self.val_to_bytes(con, ty)?;
+        self.memory.write(addr, &bytes)?;
+        if self.checked && ops::is_poison(con) {
+            let origin = self
+                .origin_of(con)
+                .unwrap_or_else(|| Rc::new(format!("poison value stored by {}", self.location())));
+            self.undefine_poison(addr, con, ty, &origin)?;
+        }
+        Ok(())
+    }
+
+    fn undefine_poison(
+        &mut self,
+        addr: u64,
+        con: &Constant,
+        ty: &TypeRef,
+        origin: &Rc<String>,
+    ) -> Result<(), String> {
+        let parts = match (con, self.resolve_type(ty)?.as_ref()) {
+            (Constant::Poison(_), _) => {
+                self.memory.undefine(addr, self.store_size(ty)?, origin);
+                return Ok(());
+            }
+            (
+                Constant::Struct { values, .. },
+                Type::StructType {
+                    element_types,
+                    is_packed,
+                },
+            ) => {
+                let offsets = self.field_offsets(element_types, *is_packed)?;
+                values
+                    .iter()
+                    .zip(element_types.iter().cloned().zip(offsets))
+                    .collect::<Vec<_>>()
+            }
+            (Constant::Array { elements, .. }, Type::ArrayType { element_type, .. }) => {
+                let stride = self.size_of(element_type)?;
+                elements
+                    .iter()
+                    .enumerate()
+                    .map(|(i, elem)| (elem, (element_type.clone(), i as u64 * stride)))
+                    .collect()
+            }
+            (Constant::Vector(lanes), Type::VectorType { element_type, .. }) => {
+                let (size, n) = (self.store_size(ty)?, lanes.len() as u64);
+                lanes
+                    .iter()
+                    .enumerate()
+                    .map(|(i, lane)| (lane, (element_type.clone(), i as u64 * size / n)))
+                    .collect()
+            }
+            _ => return Ok(()),
+        };
+        for (part, (ty, off)) in parts {
+            self.undefine_poison(addr + off, part, &ty, origin)?;
+        }
+        Ok(())
+    }
+
+    // Globals are laid out in two passes since initialisers may refer to the addresses of other
+    // globals and functions.
+    pub(super) fn store_gl_var(&mut self) -> Result<(), String> {
+        for func in self.module.functions.iter() {
+            let addr = self.memory.reserve(1, 16);
+            self.gl_vars
+                .insert(name::Name::from(func.name.as_str()), addr);
+            self.fn_ptrs.insert(addr, func.name.clone());
+        }
+        let mut inits = Vec::new();
+        for gl_var in self.module.global_vars.iter() {
+            let ty = match gl_var.ty.as_ref() {
+                Type::PointerType { pointee_type, .. } => pointee_type.clone(),
+                _ => gl_var.ty.clone(),
+            };
+            let align = self.align_of(&ty)?.max(u64::from(gl_var.alignment));
+            let kind = AllocKind::Global(gl_var.name.to_string());
+            let addr = self
+                .memory
+                .alloc(self.size_of(&ty)?, align, kind, &self.stack);
+            self.gl_vars.insert(gl_var.name.clone(), addr);
+            if let Some(init) = &gl_var.initializer {
+                inits.push((addr, init.clone(), ty));
+            }
+        }
+        for (addr, init, ty) in inits {
+            self.store_val_at(addr, &init, &ty)?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn alloca(
+        &mut self,
+        ty: &TypeRef,
+        num_elements: &Operand,
+        align: u32,
+        dest: &name::Name,
+    ) -> Result<(), String> {
+        let size = self
+            .size_of(ty)?
+            .saturating_mul(self.get_defined_int_op(num_elements, "an allocation size")?);
+        let align = self.align_of(ty)?.max(align.into());
+        self.check_stack(size, self.stack.last().unwrap(), self.stack.len())?;
+        let addr = match self
+            .memory
+            .try_alloc(size, align, AllocKind::Stack, &self.stack)
+        {
+            Some(addr) => addr,
+            None => return Err(format!("Out of memory for an alloca of {} bytes", size)),
+        };
+        self.stack_used += size;
+        self.undefine_alloc(addr, addr, size);
+        self.allocas.push(addr);
+        self.vars
+            .insert(dest.clone(), Operand::ConstantOperand(ptr(addr)));
+        Ok(())
+    }
+
+    pub(super) fn free_allocas(&mut self, from: usize) {
+        for addr in self.allocas.drain(from..) {
+            self.stack_used -= self.memory.alloc_size(addr).unwrap_or(0);
+            self.memory
+                .free(addr, AllocKind::Stack, &self.stack)
+                .unwrap();
+        }
+    }
+
+    pub(super) fn load(
+        &mut self,
+        address: &Operand,
+        ty: &TypeRef,
+        atomicity: &Option<Atomicity>,
+        dest: &name::Name,
+    ) -> Result<(), String> {
+        let addr = self.get_defined_int_op(address, "an address")?;
+        let ordering = atomicity.as_ref().map(|a| a.mem_ordering);
+        self.race_read(addr, self.store_size(ty)?, ordering)?;
+        let val = ConstantRef::new(self.load_val(addr, ty)?);
+        if ops::is_poison(&val) {
+            if let Some(origin) = self.memory.undefined(addr, self.store_size(ty)?) {
+                self.set_origin(&val, origin);
+            }
+        }
+        self.vars
+            .insert(dest.clone(), Operand::ConstantOperand(val));
+        Ok(())
+    }
+
+    pub(super) fn store(
+        &mut self,
+        address: &Operand,
+        value: &Operand,
+        atomicity: &Option<Atomicity>,
+    ) -> Result<(), String> {
+        let addr = self.get_defined_int_op(address, "an address")?;
+        let ty = self.module.type_of(value);
+        let ordering = atomicity.as_ref().map(|a| a.mem_ordering);
+        self.race_write(addr, self.store_size(&ty)?, ordering)?;
+        let val = self.eval_op(value)?;
+        self.store_val_at(addr, &val, &ty)
+    }
+
+    pub(super) fn gep(&mut self, gep: &GetElementPtr) -> Result<(), String> {
+        let addr = self.eval_op(&gep.address)?;
+        let indices = gep
+            .indices
+            .iter()
+            .map(|ind| self.eval_op(ind))
+            .collect::<Result<Vec<_>, _>>()?;
+        let val = match ops::is_poison(&addr) || indices.iter().any(|ind| ops::is_poison(ind)) {
+            true => ConstantRef::new(Constant::Poison(self.module.type_of(gep))),
+            false => {
+                let ty = self.module.type_of(&gep.address);
+                ptr(self.gep_addr(get_int(&addr)?.1, &ty, &indices)?)
+            }
+        };
+        self.vars
+            .insert(gep.dest.clone(), Operand::ConstantOperand(val));
+        Ok(())
+    }
+
+    // Threads are only ever switched between instructions, so every atomic operation is
+    // trivially sequentially consistent and the orderings only matter to the race detector.
+    pub(super) fn atomicrmw(&mut self, rmw: &AtomicRMW) -> Result<(), String> {
+        let addr = self.get_defined_int_op(&rmw.address, "an address")?;
+        let ty = self.module.type_of(&rmw.value);
+        self.race_rmw(addr, self.store_size(&ty)?, rmw.atomicity.mem_ordering)?;
+        let old = self.load_val(addr, &ty)?;
+        let val = ConstantRef::new(ops::freeze(self.eval_op(&rmw.value)?.as_ref()));
+        let new = match rmw.operation {
+            RMWBinOp::Xchg => val.as_ref().clone(),
+            RMWBinOp::Add => ops::int_bin_op(&old, &val, super::BinOps::Add)?,
+            RMWBinOp::Sub => ops::int_bin_op(&old, &val, super::BinOps::Sub)?,
+            RMWBinOp::And => ops::int_bin_op(&old, &val, super::BinOps::And)?,
+            RMWBinOp::Nand => {
+                let and = ops::int_bin_op(&old, &val, super::BinOps::And)?;
+                ops::int_bin_op(&and, &int(get_int(&and)?.0, u64::MAX), super::BinOps::Xor)?
+            }
+            RMWBinOp::Or => ops::int_bin_op(&old, &val, super::BinOps::Or)?,
+            RMWBinOp::Xor => ops::int_bin_op(&old, &val, super::BinOps::Xor)?,
+            RMWBinOp::Max | RMWBinOp::Min | RMWBinOp::UMax | RMWBinOp::UMin => {
+                let pred = match rmw.operation {
+                    RMWBinOp::Max => IntPredicate::SGE,
+                    RMWBinOp::Min => IntPredicate::SLE,
+                    RMWBinOp::UMax => IntPredicate::UGE,
+                    _ => IntPredicate::ULE,
+                };
+                ops::select(&ops::icmp(pred, &old, &val)?, &old, &val, &ty)?
+            }
+            RMWBinOp::FAdd => ops::fl_bin_op(&old, &val, super::BinOps::Add)?,
+            RMWBinOp::FSub => ops::fl_bin_op(&old, &val, super::BinOps::Sub)?,
+        };
+        self.store_val_at(addr, &new, &ty)?;
+        self.store_val(&rmw.dest, old);
+        Ok(())
+    }
+
+    pub(super) fn cmpxchg(&mut self, cmpxchg: &CmpXchg) -> Result<(), String> {
+        let addr = self.get_defined_int_op(&cmpxchg.address, "an address")?;
+        let ty = self.module.type_of(&cmpxchg.expected);
+        let old = self.load_val(addr, &ty)?;
+        let expected = self.eval_op(&cmpxchg.expected)?;
+        let success = self.val_to_bytes(&old, &ty)? == self.val_to_bytes(&expected, &ty)?;
+        match success {
+            true => self.race_rmw(addr, self.store_size(&ty)?, cmpxchg.atomicity.mem_ordering)?,
+            false => self.race_read(
+                addr,
+                self.store_size(&ty)?,
+                Some(cmpxchg.failure_memory_ordering),
+            )?,
+        }
+        if success {
+            let replacement = self.eval_op(&cmpxchg.replacement)?;
+            self.store_val_at(addr, &replacement, &ty)?;
+        }
+        let val = Constant::Struct {
+            name: None,
+            values: vec![
+                ConstantRef::new(old),
+                ConstantRef::new(int(1, success.into())),
+            ],
+            is_packed: false,
+        };
+        self.store_val(&cmpxchg.dest, val);
+        Ok(())
+    }
+
+    pub(super) fn call_heap(
+        &mut self,
+        func_name: &str,
+        args: &[ConstantRef],
+    ) -> Result<Option<ConstantRef>, String> {
+        let arg = |i: usize| get_int(&args[i]).map(|(_, val)| val);
+        let (heap, stack) = (AllocKind::Heap, &self.stack);
+        match func_name {
+            "malloc" | "calloc" => {
+                let size = match func_name {
+                    "malloc" => Some(arg(0)?),
+                    _ => arg(0)?.checked_mul(arg(1)?),
+                };
+                if let Some(size) = size {
+                    self.check_heap(size)?;
+                }
+                let (size, addr) = match size {
+                    Some(size) => (size, self.memory.try_alloc(size, 16, heap, stack)),
+                    None => (0, None),
+                };
+                let addr = match addr {
+                    Some(addr) => addr,
+                    None => return self.out_of_memory(),
+                };
+                if func_name == "malloc" {
+                    self.undefine_alloc(addr, addr, size);
+                }
+                Ok(Some(ptr(addr)))
+            }
+            "realloc" => {
+                let (old, size) = (arg(0)?, arg(1)?);
+                if old != 0 && self.memory.alloc_size(old).is_none() {
+                    // Produces the same diagnostic as freeing the pointer would.
+                    self.memory.free(old, AllocKind::Heap, stack)?;
+                }
+                let old_size = match old {
+                    0 => 0,
+                    _ => self.memory.alloc_size(old).unwrap(),
+                };
+                self.check_heap(size.saturating_sub(old_size))?;
+                // The old allocation is left as it was if there's no room for the new one.
+                let new = match self.memory.try_alloc(size, 16, AllocKind::Heap, stack) {
+                    Some(new) => new,
+                    None => return self.out_of_memory(),
+                };
+                let mut copied = 0;
+                if old != 0 {
+                    copied = old_size.min(size);
+                    self.memory.copy(new, old, copied)?;
+                    self.memory.free(old, AllocKind::Heap, &self.stack)?;
+                }
+                self.undefine_alloc(new, new + copied, size - copied);
+                Ok(Some(ptr(new)))
+            }
+            "free" => {
+                if arg(0)? != 0 {
+                    self.memory.free(arg(0)?, heap, stack)?;
+                }
+                Ok(None)
+            }
+            _ => unreachable!(),
+        }
+    }
+    /// Fail an allocation as the C library does when there's no memory for it.
+    fn out_of_memory(&mut self) -> Result<Option<ConstantRef>, String> {
+        self.set_errno(libc::ENOMEM)?;
+        Ok(Some(ptr(0)))
+    }
+}
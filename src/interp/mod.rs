@@ -1,160 +1,591 @@
-use core::panic;
 use either::Either::{Left, Right};
 use llvm_ir::{
-    constant::{Constant, Float, GetElementPtr},
+    constant::{Constant, Float},
     instruction::{Call, Instruction},
     name::{
         self,
         Name::{Name, Number},
     },
-    types::FPType,
-    ConstantRef, IntPredicate, Module,
+    types::{FPType, NamedStructDef},
+    BasicBlock, ConstantRef, FPPredicate, IntPredicate, Module,
     Operand::{self, ConstantOperand, LocalOperand, MetadataOperand},
     Terminator, Type, TypeRef,
 };
-use std::{collections::HashMap, convert::TryInto, mem};
+use std::{collections::HashMap, mem, rc::Rc};
+
+mod coverage;
+mod dap;
+mod debugger;
+mod debuginfo;
+mod files;
+mod flags;
+mod gdb;
+mod intrinsics;
+mod limits;
+mod memory;
+mod ops;
+mod profile;
+mod race;
+mod record;
+mod sandbox;
+mod snapshot;
+mod stats;
+mod threads;
+mod trace;
+mod ub;
+mod uninit;
+pub use coverage::Coverage;
+pub use debugger::Debugger;
+pub use files::FileSystem;
+use files::Files;
+use flags::{LocalVars, SourceNames, WrapFlags};
+use limits::{Limits, FRAME_SIZE};
+use memory::Memory;
+use ops::CastOps;
+pub use profile::{ProfileFormat, Profiler};
+use race::RaceDetector;
+pub use record::Recorder;
+pub use sandbox::Sandbox;
+pub use snapshot::SnapshotTrigger;
+use stats::Stats;
+pub use threads::{Policy, Scheduler};
+use threads::{Thread, ThreadOp};
+pub use trace::{Trace, TraceFormat};
+pub use ub::UbAction;
+
+/// Format an interpreted call stack, innermost function first.
+fn backtrace(stack: &[String]) -> String {
+    stack
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, func)| format!("\n    #{} {}", i, func))
+        .collect()
+}
+
+// llvm-ir appends this to instructions that carry a debug location.
+fn without_debugloc(text: &str) -> &str {
+    text.trim_end_matches(" (with debugloc)")
+}
+
+/// The operands of an instruction.
+fn operands(inst: &Instruction) -> Vec<&Operand> {
+    macro_rules! operands {
+        ($($binary:ident),*; $($unary:ident),*) => {
+            match inst {
+                $(Instruction::$binary(inst) => return vec![&inst.operand0, &inst.operand1],)*
+                $(Instruction::$unary(inst) => return vec![&inst.operand],)*
+                _ => {}
+            }
+        };
+    }
+    operands!(
+        Add, Sub, Mul, UDiv, SDiv, URem, SRem, And, Or, Xor, Shl, LShr, AShr, FAdd, FSub, FMul,
+        FDiv, FRem, ICmp, FCmp, ShuffleVector;
+        FNeg, Trunc, ZExt, SExt, FPTrunc, FPExt, FPToUI, FPToSI, UIToFP, SIToFP, PtrToInt,
+        IntToPtr, BitCast, AddrSpaceCast, Freeze
+    );
+    match inst {
+        Instruction::Select(inst) => vec![&inst.condition, &inst.true_value, &inst.false_value],
+        Instruction::ExtractElement(inst) => vec![&inst.vector, &inst.index],
+        Instruction::InsertElement(inst) => vec![&inst.vector, &inst.element, &inst.index],
+        Instruction::ExtractValue(inst) => vec![&inst.aggregate],
+        Instruction::InsertValue(inst) => vec![&inst.aggregate, &inst.element],
+        Instruction::GetElementPtr(inst) => {
+            let mut ops = vec![&inst.address];
+            ops.extend(&inst.indices);
+            ops
+        }
+        Instruction::Alloca(inst) => vec![&inst.num_elements],
+        Instruction::Load(inst) => vec![&inst.address],
+        Instruction::Store(inst) => vec![&inst.address, &inst.value],
+        Instruction::AtomicRMW(inst) => vec![&inst.address, &inst.value],
+        Instruction::CmpXchg(inst) => vec![&inst.address, &inst.expected, &inst.replacement],
+        Instruction::Call(inst) => inst.arguments.iter().map(|(arg, _)| arg).collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[derive(Clone, Copy)]
 enum BinOps {
     Add,
     Sub,
     Mul,
     Div,
     Rem,
+    UDiv,
+    SDiv,
+    URem,
+    SRem,
+    And,
+    Or,
+    Xor,
+    Shl,
+    LShr,
+    AShr,
 }
 enum BbReturn {
     Return(Option<Operand>),
     Call(Call),
+    /// The thread was preempted and should be rescheduled.
+    Yield,
+    /// Continue after the innermost frame's call with the call's result.
+    Resume(Option<Operand>),
 }
-pub(crate) struct LLVMIRInterpreter {
-    module: Module,
+/// Where to continue a function: its name, block, instruction and the call's destination.
+type Frame = (String, name::Name, usize, Option<name::Name>);
+pub struct LLVMIRInterpreter {
+    module: Rc<Module>,
     callstack: Vec<HashMap<name::Name, Operand>>,
     vars: HashMap<name::Name, Operand>,
-    gl_vars: HashMap<name::Name, Constant>,
+    gl_vars: HashMap<name::Name, u64>,
+    memory: Memory,
+    fn_ptrs: HashMap<u64, String>,
+    allocas: Vec<u64>,
+    alloca_stack: Vec<Vec<u64>>,
+    /// How many bytes of the running thread's stack its calls and allocas take.
+    stack_used: u64,
+    threads: Vec<Thread>,
+    thread: usize,
+    mutexes: HashMap<u64, usize>,
+    scheduler: Scheduler,
+    race: Option<RaceDetector>,
+    checked: bool,
+    /// The names of the functions on the running thread's call stack.
+    stack: Rc<Vec<String>>,
+    /// The block and instruction index of the instruction being executed.
+    pc: (usize, usize),
+    wrap_flags: Option<WrapFlags>,
+    source_names: SourceNames,
+    local_vars: LocalVars,
+    ub: UbAction,
+    /// Where `poison` loaded from undefined memory came from, in checked mode.
+    origins: HashMap<usize, (ConstantRef, Rc<String>)>,
+    /// How big `origins` may grow before the origins of values that have gone are dropped.
+    origins_limit: usize,
+    trace: Option<Trace>,
+    stats: Option<Stats>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    debugger: Option<Debugger>,
+    recorder: Option<Recorder>,
+    snapshot_trigger: Option<SnapshotTrigger>,
+    /// The snapshot to resume the program from, if it isn't started from `main`.
+    restore: Option<Rc<Vec<u8>>>,
+    limits: Limits,
+    files: Files,
+    sandbox: Option<Sandbox>,
+    allow_spawn: bool,
+    /// Where each thread's `errno` is, once it has one.
+    errno: HashMap<usize, u64>,
+    /// The status the program exited with, once it has.
+    exit_status: Option<i32>,
 }
 
 impl LLVMIRInterpreter {
+    /// Load the bitcode file at `path` together with the information `new` can't get from the
+    /// module: see `read_bitcode_info`.
+    pub fn from_bc_path(path: &str) -> Result<LLVMIRInterpreter, String> {
+        let mut lii = LLVMIRInterpreter::new(Module::from_bc_path(path)?);
+        lii.read_bitcode_info(path)?;
+        Ok(lii)
+    }
+
+    /// Interpret an already loaded module. `llvm_ir` drops the `nsw`, `nuw` and `exact` flags, so
+    /// until `read_bitcode_info` is called no overflow or inexact division is reported.
     pub fn new(module: Module) -> LLVMIRInterpreter {
         LLVMIRInterpreter {
-            module,
+            module: Rc::new(module),
             callstack: Vec::new(),
             vars: HashMap::new(),
             gl_vars: HashMap::new(),
+            memory: Memory::new(),
+            fn_ptrs: HashMap::new(),
+            allocas: Vec::new(),
+            alloca_stack: Vec::new(),
+            stack_used: 0,
+            threads: Vec::new(),
+            thread: 0,
+            mutexes: HashMap::new(),
+            scheduler: Scheduler::default(),
+            race: None,
+            checked: false,
+            stack: Rc::new(Vec::new()),
+            pc: (0, 0),
+            wrap_flags: None,
+            source_names: SourceNames::new(),
+            local_vars: LocalVars::new(),
+            ub: UbAction::Trap,
+            origins: HashMap::new(),
+            origins_limit: 0,
+            trace: None,
+            stats: None,
+            profiler: None,
+            coverage: None,
+            debugger: None,
+            recorder: None,
+            snapshot_trigger: None,
+            restore: None,
+            limits: Limits::new(),
+            files: Files::new(FileSystem::Host(None)),
+            sandbox: None,
+            allow_spawn: false,
+            errno: HashMap::new(),
+            exit_status: None,
         }
     }
 
-    pub fn interpret(&mut self) -> Result<(), &'static str> {
-        self.store_gl_var();
+    pub fn set_scheduler(&mut self, scheduler: Scheduler) {
+        self.scheduler = scheduler;
+    }
+
+    /// Report memory errors in detail, with where the memory involved was allocated and freed,
+    /// report uses of uninitialised memory, and report leaks when the program ends.
+    pub fn set_memory_check(&mut self, checked: bool) {
+        self.checked = checked;
+        self.memory.set_checked(checked);
+    }
 
-        let main_bb1 = match self.module.get_func_by_name("main") {
-            Some(main) => main.basic_blocks[0].name.clone(),
-            None => return Err("No main function"),
+    /// Read the `nsw`, `nuw` and `exact` flags that overflow checks need, and the source names of
+    /// functions and their local variables, from the bitcode file the module was loaded from.
+    /// `from_bc_path` does this already.
+    pub fn read_bitcode_info(&mut self, path: &str) -> Result<(), String> {
+        let (wrap_flags, source_names, local_vars) = flags::read_bitcode_info(path)?;
+        flags::check_counts(&self.module, &wrap_flags, path)?;
+        self.wrap_flags = Some(wrap_flags);
+        self.source_names = source_names;
+        self.local_vars = local_vars;
+        Ok(())
+    }
+
+    pub fn set_ub_action(&mut self, ub: UbAction) {
+        self.ub = ub;
+    }
+
+    pub fn set_trace(&mut self, trace: Option<Trace>) {
+        self.trace = trace;
+    }
+
+    pub fn set_stats(&mut self, enabled: bool) {
+        self.stats = match enabled {
+            true => Some(Stats::default()),
+            false => None,
         };
+    }
 
-        self.it_funcs("main", main_bb1);
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+    }
 
-        Ok(())
+    pub fn set_coverage(&mut self, coverage: Option<Coverage>) {
+        self.coverage = coverage;
+    }
+
+    pub fn set_debugger(&mut self, debugger: Option<Debugger>) {
+        self.debugger = debugger;
+    }
+
+    pub fn set_race_detection(&mut self, enabled: bool) {
+        self.race = match enabled {
+            true => Some(RaceDetector::new()),
+            false => None,
+        };
+    }
+
+    /// The status the program exited with, by returning from `main` or calling `exit`, once it
+    /// has.
+    pub fn exit_status(&self) -> Option<i32> {
+        self.exit_status
     }
 
-    fn store_gl_var(&mut self) {
-        for gl_var in self.module.global_vars.iter() {
-            match &gl_var.initializer {
-                Some(init) => {
-                    self.gl_vars
-                        .insert(gl_var.name.clone(), init.as_ref().clone());
+    /// Run the program, or carry on with it if it ran out of fuel or time.
+    pub fn interpret(&mut self) -> Result<(), String> {
+        self.start_clock();
+        // The debugger goes back in time by running the program again with the same inputs.
+        if self.debugger.is_some() && self.recorder.is_none() {
+            self.recorder = Some(Recorder::in_memory());
+        }
+        loop {
+            let result = self.run();
+            match self.debugger.as_mut().map(Debugger::restarting) {
+                Some(true) => self.restart(),
+                _ => return result,
+            }
+        }
+    }
+
+    fn run(&mut self) -> Result<(), String> {
+        let mut frames = Vec::new();
+        let result = match (self.take_suspended(), self.restore.clone()) {
+            (Some(suspended), _) => {
+                frames = suspended;
+                self.it_loop(BbReturn::Resume(None), &mut frames)
+            }
+            (None, Some(snapshot)) => {
+                let next = self.restore_snapshot(&snapshot, &mut frames)?;
+                self.it_loop(next, &mut frames)
+            }
+            (None, None) => {
+                self.store_gl_var()?;
+                self.open_std_streams()?;
+
+                let main_bb1 = match self.module.get_func_by_name("main") {
+                    Some(main) => main.basic_blocks[0].name.clone(),
+                    None => return Err("No main function".to_owned()),
+                };
+                self.it_funcs("main", main_bb1, &mut frames)
+            }
+        };
+        if let Err(mut err) = result {
+            if let Some(source) = self.source_location() {
+                if !err.contains(&source) {
+                    err = format!("{}\n  at {}", err, source);
                 }
-                None => todo!(),
             }
+            return Err(format!(
+                "{}\n  backtrace:{}",
+                err,
+                self.call_backtrace(&frames)
+            ));
+        }
+        match self.memory.leaks().as_slice() {
+            [] => Ok(()),
+            leaks => Err(leaks.join("\n")),
         }
     }
 
-    fn it_funcs(&mut self, main_name: &str, main_bb1_name: name::Name) {
-        let mut it_bb_params = Vec::new();
-        let mut value = self.it_bb(main_name, main_bb1_name, 0, &mut it_bb_params);
-        while !it_bb_params.is_empty() {
-            match value {
-                BbReturn::Call(c) => {
-                    self.callstack.push(self.vars.clone());
+    fn it_funcs(
+        &mut self,
+        main_name: &str,
+        main_bb1_name: name::Name,
+        it_bb_params: &mut Vec<Frame>,
+    ) -> Result<(), String> {
+        self.threads.push(Thread::new());
+        self.stack = Rc::new(vec![main_name.to_owned()]);
+        if let Some(stats) = &mut self.stats {
+            stats.enter(0, main_name);
+        }
+        self.cover_entry(main_name);
+        let value = self.it_bb(main_name, main_bb1_name, 0, it_bb_params)?;
+        self.it_loop(value, it_bb_params)
+    }
 
-                    let func_name = self.call_func(&c);
+    /// Carry on until the program ends from where a function's blocks left off with `value`.
+    fn it_loop(
+        &mut self,
+        mut value: BbReturn,
+        it_bb_params: &mut Vec<Frame>,
+    ) -> Result<(), String> {
+        loop {
+            value = match value {
+                BbReturn::Call(c) => {
+                    let func_name = self.call_func(&c)?;
 
                     match self.module.get_func_by_name(&func_name) {
                         Some(func) => {
+                            self.check_call_depth()?;
+                            self.check_stack(FRAME_SIZE, &func.name, self.stack.len() + 1)?;
+                            self.stack_used += FRAME_SIZE;
+                            self.callstack.push(self.vars.clone());
+                            self.alloca_stack.push(mem::take(&mut self.allocas));
+
+                            // Arguments must be evaluated in the caller's frame before we switch
+                            // to the callee's.
+                            let args = c
+                                .arguments
+                                .iter()
+                                .map(|(arg, _)| self.eval_op(arg).map(ConstantOperand))
+                                .collect::<Result<Vec<_>, _>>()?;
                             self.vars.clear();
 
-                            for (par, arg) in func.parameters.iter().zip(c.arguments.iter()) {
-                                self.vars.insert(par.name.clone(), arg.0.clone());
+                            for (par, arg) in func.parameters.iter().zip(args) {
+                                self.vars.insert(par.name.clone(), arg);
                             }
 
                             let func_name = func.name.clone();
                             let bb_name = func.basic_blocks[0].name.clone();
+                            Rc::make_mut(&mut self.stack).push(func_name.clone());
+                            if let Some(stats) = &mut self.stats {
+                                stats.enter(self.thread, &func_name);
+                            }
+                            self.cover_entry(&func_name);
 
-                            value = self.it_bb(&func_name, bb_name, 0, &mut it_bb_params);
+                            self.it_bb(&func_name, bb_name, 0, it_bb_params)?
+                        }
+                        None if func_name.starts_with("pthread_") => {
+                            match self.call_pthread(&func_name, &c)? {
+                                ThreadOp::Done(r) => {
+                                    if self.trace.is_some() {
+                                        self.trace_call(it_bb_params, r.as_ref());
+                                    }
+                                    BbReturn::Resume(r)
+                                }
+                                // The call is retried once the thread is woken up.
+                                ThreadOp::Block => {
+                                    match self.schedule(Some(BbReturn::Call(c)), it_bb_params)? {
+                                        Some(next) => next,
+                                        None => return Ok(()),
+                                    }
+                                }
+                                ThreadOp::Exit(r) => {
+                                    self.finish_thread(r, it_bb_params);
+                                    match self.schedule(None, it_bb_params)? {
+                                        Some(next) => next,
+                                        None => return Ok(()),
+                                    }
+                                }
+                            }
                         }
                         None => {
-                            if func_name == "printf" {
-                                self.printf(c);
+                            let r = self.call_external(&func_name, &c)?;
+                            if self.exit_status.is_some() {
+                                return Ok(());
+                            }
+                            if self.trace.is_some() {
+                                self.trace_call(it_bb_params, r.as_ref());
                             }
-                            value = BbReturn::Return(None)
+                            BbReturn::Resume(r)
                         }
                     }
                 }
                 BbReturn::Return(r) => {
-                    let (func_name, bb_name, inst_ind, call_dest) = it_bb_params.pop().unwrap();
-                    if !self.callstack.is_empty() {
+                    if it_bb_params.is_empty() {
+                        // Returning from `main` ends the program, whatever other threads are
+                        // doing.
+                        if self.thread == 0 {
+                            let status = r.map_or(Ok(0), |r| self.get_int_op(&r));
+                            self.exit_status = Some(status.unwrap_or(0) as i32);
+                            return Ok(());
+                        }
+                        self.finish_thread(r, it_bb_params);
+                        match self.schedule(None, it_bb_params)? {
+                            Some(next) => next,
+                            None => return Ok(()),
+                        }
+                    } else {
+                        self.free_allocas(0);
+                        self.stack_used -= FRAME_SIZE;
+                        self.allocas = self.alloca_stack.pop().unwrap();
                         self.vars.clear();
-                        self.vars.extend(self.callstack.pop().unwrap().into_iter());
+                        self.vars.extend(self.callstack.pop().unwrap());
+                        Rc::make_mut(&mut self.stack).pop();
+                        if let Some(stats) = &mut self.stats {
+                            stats.leave(self.thread);
+                        }
+                        if self.trace.is_some() {
+                            self.trace_call(it_bb_params, r.as_ref());
+                        }
+                        BbReturn::Resume(r)
                     }
-                    match r {
-                        Some(v) => match call_dest {
-                            Some(dest) => {
-                                self.vars.insert(dest.clone(), v);
-                            }
-                            None => todo!(),
-                        },
-                        None => {}
+                }
+                BbReturn::Yield => {
+                    match self.schedule(Some(BbReturn::Resume(None)), it_bb_params)? {
+                        Some(next) => next,
+                        None => return Ok(()),
                     }
-
-                    value = self.it_bb(func_name.as_str(), bb_name, inst_ind, &mut it_bb_params);
+                }
+                BbReturn::Resume(r) => {
+                    let (func_name, bb_name, inst_ind, call_dest) = it_bb_params.pop().unwrap();
+                    if let (Some(v), Some(dest)) = (r, call_dest) {
+                        self.vars.insert(dest, v);
+                    }
+                    self.it_bb(func_name.as_str(), bb_name, inst_ind, it_bb_params)?
                 }
             }
         }
     }
 
-    fn call_func(&mut self, call: &Call) -> String {
+    fn call_func(&mut self, call: &Call) -> Result<String, String> {
         match &call.function {
-            Left(_) => todo!(),
+            Left(_) => Err("Unsupported inline assembly".to_owned()),
             Right(op) => match op {
-                LocalOperand { .. } => todo!(),
+                LocalOperand { .. } => self.fn_ptr(op),
                 ConstantOperand(con_op) => match con_op.as_ref() {
                     Constant::GlobalReference { name, .. } => match name {
-                        Name(n) => {
-                            return n.as_str().to_owned();
-                        }
-                        Number(_) => todo!(),
+                        Name(n) => Ok(n.as_str().to_owned()),
+                        Number(n) => Err(format!("Unsupported call to unnamed function @{}", n)),
                     },
-                    _ => todo!(),
+                    // Calls to functions without a prototype cast the callee first.
+                    Constant::BitCast(_) => self.fn_ptr(op),
+                    con => Err(format!("Unsupported callee {}", con)),
                 },
-                MetadataOperand => todo!(),
+                MetadataOperand => Err("Unsupported metadata callee".to_owned()),
             },
         }
     }
 
+    fn fn_ptr(&self, op: &Operand) -> Result<String, String> {
+        let addr = self.get_defined_int_op(op, "a function pointer")?;
+        match self.fn_ptrs.get(&addr) {
+            Some(func) => Ok(func.clone()),
+            None => Err(format!("Call through {:#x}, which isn't a function", addr)),
+        }
+    }
+
     fn it_bb(
         &mut self,
         func_name: &str,
         bb_name: name::Name,
         mut inst_ind: usize,
-        it_bb_params: &mut Vec<(String, llvm_ir::Name, usize, Option<name::Name>)>,
-    ) -> BbReturn {
-        let func = self.module.get_func_by_name(func_name).unwrap().clone();
+        it_bb_params: &mut Vec<Frame>,
+    ) -> Result<BbReturn, String> {
+        let module = Rc::clone(&self.module);
+        let func = module.get_func_by_name(func_name).unwrap();
         let mut bb_name_option = Some(bb_name);
+        let mut prev_bb_ind = None;
         while let Some(bb_name) = bb_name_option {
-            //PERF: get_bb_by_name function is inefficient.
-            let bb = func.get_bb_by_name(&bb_name).unwrap();
+            //PERF: looking blocks up by name is inefficient.
+            let bb_ind = func
+                .basic_blocks
+                .iter()
+                .position(|bb| bb.name == bb_name)
+                .unwrap();
+            let bb = &func.basic_blocks[bb_ind];
+            if let Some(prev_bb_ind) = prev_bb_ind {
+                self.cover_edge(func_name, prev_bb_ind, bb_ind);
+                let pred = &func.basic_blocks[prev_bb_ind].name;
+                inst_ind = self.phis(func_name, bb, pred)?;
+            }
             for (new_inst_ind, inst) in bb.instrs[inst_ind..].iter().enumerate() {
+                if self.snapshot_trigger.is_some() {
+                    self.snapshot_hook(func, bb, inst_ind + new_inst_ind, it_bb_params)?;
+                }
+                self.limit_hook(func, bb, bb_ind, inst_ind + new_inst_ind, it_bb_params)?;
+                if self.preempt() {
+                    it_bb_params.push((
+                        func_name.to_owned(),
+                        bb_name,
+                        inst_ind + new_inst_ind,
+                        None,
+                    ));
+                    return Ok(BbReturn::Yield);
+                }
+                self.burn_fuel();
+                self.pc = (bb_ind, inst_ind + new_inst_ind);
+                self.count_inst(inst);
+                if self.debugger.is_some() {
+                    self.debug_hook(func, bb, inst_ind + new_inst_ind, it_bb_params)?;
+                }
                 match inst {
-                    Instruction::Alloca(_) => {}
-                    Instruction::Store(store) => self.store_var(&store.address, &store.value),
-                    Instruction::Load(load) => self.load(&load.address, &load.dest),
+                    Instruction::Alloca(alloca) => self.alloca(
+                        &alloca.allocated_type,
+                        &alloca.num_elements,
+                        alloca.alignment,
+                        &alloca.dest,
+                    )?,
+                    Instruction::Store(store) => {
+                        self.store(&store.address, &store.value, &store.atomicity)?
+                    }
+                    Instruction::Load(load) => self.load(
+                        &load.address,
+                        &self.module.type_of(load),
+                        &load.atomicity,
+                        &load.dest,
+                    )?,
+                    Instruction::GetElementPtr(gep) => self.gep(gep)?,
+                    Instruction::AtomicRMW(rmw) => self.atomicrmw(rmw)?,
+                    Instruction::CmpXchg(cmpxchg) => self.cmpxchg(cmpxchg)?,
+                    Instruction::Fence(_) => {}
                     Instruction::Call(call) => {
                         it_bb_params.push((
                             func_name.to_owned(),
@@ -162,91 +593,239 @@ impl LLVMIRInterpreter {
                             inst_ind + new_inst_ind + 1,
                             call.dest.clone(),
                         ));
-                        return BbReturn::Call(call.clone());
+                        return Ok(BbReturn::Call(call.clone()));
                     }
                     Instruction::Add(add) => self.int_bin_operations(
                         &add.operand0,
                         &add.operand1,
                         &add.dest,
                         BinOps::Add,
-                    ),
-                    Instruction::FAdd(fadd) => self.fl_bin_operations(
-                        &fadd.operand0,
-                        &fadd.operand1,
-                        &fadd.dest,
-                        BinOps::Add,
-                    ),
-                    Instruction::FPExt(fpext) => {
-                        self.fpext(&fpext.operand, &fpext.to_type, &fpext.dest)
-                    }
+                    )?,
                     Instruction::Sub(sub) => self.int_bin_operations(
                         &sub.operand0,
                         &sub.operand1,
                         &sub.dest,
                         BinOps::Sub,
-                    ),
+                    )?,
                     Instruction::Mul(mul) => self.int_bin_operations(
                         &mul.operand0,
                         &mul.operand1,
                         &mul.dest,
                         BinOps::Mul,
-                    ),
+                    )?,
                     Instruction::UDiv(udiv) => self.int_bin_operations(
                         &udiv.operand0,
                         &udiv.operand1,
                         &udiv.dest,
-                        BinOps::Div,
-                    ),
+                        BinOps::UDiv,
+                    )?,
                     Instruction::SDiv(sdiv) => self.int_bin_operations(
                         &sdiv.operand0,
                         &sdiv.operand1,
                         &sdiv.dest,
-                        BinOps::Div,
-                    ),
+                        BinOps::SDiv,
+                    )?,
                     Instruction::URem(urem) => self.int_bin_operations(
                         &urem.operand0,
                         &urem.operand1,
                         &urem.dest,
-                        BinOps::Rem,
-                    ),
+                        BinOps::URem,
+                    )?,
                     Instruction::SRem(srem) => self.int_bin_operations(
                         &srem.operand0,
                         &srem.operand1,
                         &srem.dest,
-                        BinOps::Rem,
-                    ),
+                        BinOps::SRem,
+                    )?,
+                    Instruction::And(and_) => self.int_bin_operations(
+                        &and_.operand0,
+                        &and_.operand1,
+                        &and_.dest,
+                        BinOps::And,
+                    )?,
+                    Instruction::Or(or_) => self.int_bin_operations(
+                        &or_.operand0,
+                        &or_.operand1,
+                        &or_.dest,
+                        BinOps::Or,
+                    )?,
+                    Instruction::Xor(xor) => self.int_bin_operations(
+                        &xor.operand0,
+                        &xor.operand1,
+                        &xor.dest,
+                        BinOps::Xor,
+                    )?,
+                    Instruction::Shl(shl) => self.int_bin_operations(
+                        &shl.operand0,
+                        &shl.operand1,
+                        &shl.dest,
+                        BinOps::Shl,
+                    )?,
+                    Instruction::LShr(lshr) => self.int_bin_operations(
+                        &lshr.operand0,
+                        &lshr.operand1,
+                        &lshr.dest,
+                        BinOps::LShr,
+                    )?,
+                    Instruction::AShr(ashr) => self.int_bin_operations(
+                        &ashr.operand0,
+                        &ashr.operand1,
+                        &ashr.dest,
+                        BinOps::AShr,
+                    )?,
+                    Instruction::FAdd(fadd) => self.fl_bin_operations(
+                        &fadd.operand0,
+                        &fadd.operand1,
+                        &fadd.dest,
+                        BinOps::Add,
+                    )?,
                     Instruction::FSub(fsub) => self.fl_bin_operations(
                         &fsub.operand0,
                         &fsub.operand1,
                         &fsub.dest,
                         BinOps::Sub,
-                    ),
-                    Instruction::SExt(sext) => self.szext(&sext.operand, &sext.to_type, &sext.dest),
+                    )?,
                     Instruction::FMul(fmul) => self.fl_bin_operations(
                         &fmul.operand0,
                         &fmul.operand1,
                         &fmul.dest,
                         BinOps::Mul,
-                    ),
+                    )?,
                     Instruction::FDiv(fdiv) => self.fl_bin_operations(
                         &fdiv.operand0,
                         &fdiv.operand1,
                         &fdiv.dest,
                         BinOps::Div,
-                    ),
+                    )?,
+                    Instruction::FRem(frem) => self.fl_bin_operations(
+                        &frem.operand0,
+                        &frem.operand1,
+                        &frem.dest,
+                        BinOps::Rem,
+                    )?,
+                    Instruction::FNeg(fneg) => self.fneg(&fneg.operand, &fneg.dest)?,
+                    Instruction::Trunc(trunc) => {
+                        self.cast(&trunc.operand, &trunc.to_type, &trunc.dest, CastOps::Trunc)?
+                    }
+                    Instruction::ZExt(zext) => {
+                        self.cast(&zext.operand, &zext.to_type, &zext.dest, CastOps::ZExt)?
+                    }
+                    Instruction::SExt(sext) => {
+                        self.cast(&sext.operand, &sext.to_type, &sext.dest, CastOps::SExt)?
+                    }
+                    Instruction::FPTrunc(fptrunc) => self.cast(
+                        &fptrunc.operand,
+                        &fptrunc.to_type,
+                        &fptrunc.dest,
+                        CastOps::FPTrunc,
+                    )?,
+                    Instruction::FPExt(fpext) => {
+                        self.cast(&fpext.operand, &fpext.to_type, &fpext.dest, CastOps::FPExt)?
+                    }
+                    Instruction::FPToUI(fptoui) => self.cast(
+                        &fptoui.operand,
+                        &fptoui.to_type,
+                        &fptoui.dest,
+                        CastOps::FPToUI,
+                    )?,
+                    Instruction::FPToSI(fptosi) => self.cast(
+                        &fptosi.operand,
+                        &fptosi.to_type,
+                        &fptosi.dest,
+                        CastOps::FPToSI,
+                    )?,
+                    Instruction::UIToFP(uitofp) => self.cast(
+                        &uitofp.operand,
+                        &uitofp.to_type,
+                        &uitofp.dest,
+                        CastOps::UIToFP,
+                    )?,
+                    Instruction::SIToFP(sitofp) => self.cast(
+                        &sitofp.operand,
+                        &sitofp.to_type,
+                        &sitofp.dest,
+                        CastOps::SIToFP,
+                    )?,
+                    Instruction::BitCast(bitcast) => self.cast(
+                        &bitcast.operand,
+                        &bitcast.to_type,
+                        &bitcast.dest,
+                        CastOps::BitCast,
+                    )?,
+                    Instruction::PtrToInt(ptrtoint) => self.cast(
+                        &ptrtoint.operand,
+                        &ptrtoint.to_type,
+                        &ptrtoint.dest,
+                        CastOps::ZExt,
+                    )?,
+                    Instruction::IntToPtr(inttoptr) => self.cast(
+                        &inttoptr.operand,
+                        &inttoptr.to_type,
+                        &inttoptr.dest,
+                        CastOps::ZExt,
+                    )?,
+                    Instruction::AddrSpaceCast(asc) => {
+                        self.cast(&asc.operand, &asc.to_type, &asc.dest, CastOps::BitCast)?
+                    }
                     Instruction::ICmp(icmp) => {
-                        self.icmp(icmp.predicate, &icmp.operand0, &icmp.operand1, &icmp.dest)
+                        self.icmp(icmp.predicate, &icmp.operand0, &icmp.operand1, &icmp.dest)?
+                    }
+                    Instruction::FCmp(fcmp) => {
+                        self.fcmp(fcmp.predicate, &fcmp.operand0, &fcmp.operand1, &fcmp.dest)?
+                    }
+                    Instruction::Select(select) => self.select(
+                        &select.condition,
+                        &select.true_value,
+                        &select.false_value,
+                        &select.dest,
+                    )?,
+                    Instruction::ExtractElement(ee) => {
+                        self.extract_element(&ee.vector, &ee.index, &ee.dest)?
+                    }
+                    Instruction::InsertElement(ie) => {
+                        self.insert_element(&ie.vector, &ie.element, &ie.index, &ie.dest)?
+                    }
+                    Instruction::ShuffleVector(sv) => {
+                        self.shuffle_vector(&sv.operand0, &sv.operand1, &sv.mask, &sv.dest)?
                     }
-                    Instruction::ZExt(zext) => self.szext(&zext.operand, &zext.to_type, &zext.dest),
-                    _ => todo!(),
+                    Instruction::ExtractValue(ev) => {
+                        self.extract_value(&ev.aggregate, &ev.indices, &ev.dest)?
+                    }
+                    Instruction::InsertValue(iv) => {
+                        self.insert_value(&iv.aggregate, &iv.element, &iv.indices, &iv.dest)?
+                    }
+                    Instruction::Freeze(freeze) => {
+                        let val = ops::freeze(self.eval_op(&freeze.operand)?.as_ref());
+                        self.store_val(&freeze.dest, val);
+                    }
+                    _ => return Err(format!("Unsupported instruction {}", inst)),
+                }
+                if self.checked {
+                    self.propagate_origin(inst);
+                }
+                if self.trace.is_some() {
+                    self.trace_inst(func_name, &bb_name, inst_ind + new_inst_ind, inst);
                 }
             }
 
+            if self.snapshot_trigger.is_some() {
+                self.snapshot_hook(func, bb, bb.instrs.len(), it_bb_params)?;
+            }
+            self.limit_hook(func, bb, bb_ind, bb.instrs.len(), it_bb_params)?;
+            self.burn_fuel();
+            self.pc = (bb_ind, bb.instrs.len());
+            self.count_term(&bb.term);
+            if self.debugger.is_some() {
+                self.debug_hook(func, bb, bb.instrs.len(), it_bb_params)?;
+            }
+            prev_bb_ind = Some(bb_ind);
+            if self.trace.is_some() {
+                self.trace_term(func_name, &bb_name, bb.instrs.len(), &bb.term);
+            }
             match &bb.term {
                 Terminator::Ret(ret) => {
-                    match ret.return_operand.as_ref() {
-                        Some(op) => return BbReturn::Return(Some(op.clone())),
-                        None => {}
+                    if let Some(op) = ret.return_operand.as_ref() {
+                        return Ok(BbReturn::Return(Some(ConstantOperand(self.eval_op(op)?))));
                     }
                     bb_name_option = None;
                 }
@@ -255,149 +834,153 @@ impl LLVMIRInterpreter {
                     inst_ind = 0;
                 }
                 Terminator::CondBr(condbr) => {
-                    bb_name_option =
-                        Some(self.condbr(&condbr.condition, &condbr.true_dest, &condbr.false_dest));
+                    bb_name_option = Some(self.condbr(
+                        &condbr.condition,
+                        &condbr.true_dest,
+                        &condbr.false_dest,
+                    )?);
                     inst_ind = 0;
                 }
                 Terminator::Switch(switch) => {
                     bb_name_option =
-                        Some(self.switch(&switch.operand, &switch.dests, &switch.default_dest));
+                        Some(self.switch(&switch.operand, &switch.dests, &switch.default_dest)?);
                     inst_ind = 0;
                 }
-                _ => todo!(),
+                Terminator::Unreachable(_) => {
+                    return Err(format!("Reached unreachable in @{}", func_name));
+                }
+                _ => return Err(format!("Unsupported terminator {}", bb.term)),
             }
         }
-        BbReturn::Return(None)
+        Ok(BbReturn::Return(None))
     }
 
-    fn store_var(&mut self, op: &Operand, val: &Operand) {
+    fn eval_op(&self, op: &Operand) -> Result<ConstantRef, String> {
         match op {
-            LocalOperand { name: op_name, .. } => {
-                match val {
-                    LocalOperand { name, .. } => self
-                        .vars
-                        .insert(op_name.clone(), self.vars.get(name).unwrap().clone()),
-                    ConstantOperand(..) => self.vars.insert(op_name.clone(), val.clone()),
-                    MetadataOperand => todo!(),
-                };
+            LocalOperand { name, .. } => self.eval_op(self.vars.get(name).unwrap()),
+            ConstantOperand(con_op) => Ok(self.eval_const(con_op)),
+            MetadataOperand => Err("Unsupported metadata operand".to_owned()),
+        }
+    }
+
+    // Functions we don't interpret are outside the program, so `poison` mustn't escape to them.
+    fn check_external_args(&self, func_name: &str, call: &Call) -> Result<(), String> {
+        for (i, (arg, _)) in call.arguments.iter().enumerate() {
+            let arg = self.eval_op(arg)?;
+            if ops::is_poison(&arg) {
+                let what = format!("passed as argument {} of @{}", i + 1, func_name);
+                return Err(self.poison_error(&arg, &what));
             }
-            ConstantOperand(_) => todo!(),
-            MetadataOperand => todo!(),
         }
+        Ok(())
     }
 
-    fn load(&mut self, op: &Operand, dest: &name::Name) {
-        match op {
-            LocalOperand { name, .. } => self.load_loc_op(name, dest),
-            ConstantOperand(_) => todo!(),
-            MetadataOperand => todo!(),
+    fn call_external(&mut self, func_name: &str, call: &Call) -> Result<Option<Operand>, String> {
+        if !func_name.starts_with("llvm.") {
+            self.check_external_args(func_name, call)?;
         }
+        let ret = match func_name {
+            "printf" => {
+                self.printf(call)?;
+                None
+            }
+            "abort" => return Err("abort called".to_owned()),
+            "exit" | "_exit" => {
+                self.exit_status = Some(self.get_int_op(&call.arguments[0].0)? as i32);
+                None
+            }
+            "getchar" | "time" | "clock_gettime" | "gettimeofday" => {
+                self.call_input(func_name, call)?
+            }
+            "__errno_location" | "getenv" | "system" | "fork" | "execv" | "execvp" | "wait"
+            | "waitpid" => self.call_host(func_name, call)?,
+            "open" | "close" | "read" | "write" | "lseek" | "fopen" | "fclose" | "fread"
+            | "fwrite" | "fgets" | "fgetc" | "getc" | "fputc" | "putc" | "fputs" | "fprintf"
+            | "fflush" => self.call_file(func_name, call)?,
+            "malloc" | "calloc" | "realloc" | "free" => {
+                let args = call
+                    .arguments
+                    .iter()
+                    .map(|(arg, _)| self.eval_op(arg))
+                    .collect::<Result<Vec<_>, _>>()?;
+                self.call_heap(func_name, &args)?
+            }
+            _ if func_name.starts_with("llvm.") => {
+                return self.call_intrinsic(func_name, call);
+            }
+            _ => return Err(format!("Unsupported external function {}", func_name)),
+        };
+        Ok(ret.map(ConstantOperand))
     }
 
-    fn load_loc_op(&mut self, name: &name::Name, dest: &name::Name) {
-        self.vars
-            .insert(dest.clone(), self.vars.get(name).unwrap().clone());
+    fn printf(&mut self, call: &Call) -> Result<(), String> {
+        let mut bytes = self.format("printf", call, 0)?;
+        bytes.push(b'\n');
+        self.output("stdout", &bytes);
+        Ok(())
     }
 
-    fn printf(&mut self, call: Call) {
-        let constant = match &call.arguments[0].0 {
-            LocalOperand { .. } => todo!(),
-            ConstantOperand(const_op) => match const_op.as_ref() {
-                Constant::GetElementPtr(GetElementPtr { address, .. }) => match address.as_ref() {
-                    Constant::GlobalReference { name, .. } => self.gl_vars.get(name).unwrap(),
-                    _ => todo!(),
-                },
-                _ => todo!(),
-            },
-            MetadataOperand => todo!(),
-        };
+    /// The bytes `func_name` prints, given the format string in argument `index` of `call` and
+    /// the arguments after it.
+    fn format(&self, func_name: &str, call: &Call, index: usize) -> Result<Vec<u8>, String> {
+        let format = self
+            .memory
+            .read_c_string(self.get_int_op(&call.arguments[index].0)?)?;
 
-        let mut string = String::new();
-        let mut arg_it = call.arguments[1..].into_iter();
-        match constant {
-            Constant::Array { elements, .. } => {
-                let mut i = 0;
-                while i < elements.len() {
-                    match elements[i].as_ref() {
-                        Constant::Int { value, .. } => {
-                            let ch = *value as u8 as char;
-                            if ch == '%' {
-                                string.push_str(
-                                    self.arg_to_string(&arg_it.next().unwrap().0).as_str(),
-                                );
-                                i += 2;
-                                continue;
-                            } else if value == &0u64 {
-                                i += 1;
-                                continue;
-                            }
-                            string.push(ch);
-                        }
-                        _ => todo!(),
-                    }
-                    i += 1;
+        // `read_c_string` gives each byte a char of its own, so they narrow back losslessly.
+        let mut string = Vec::new();
+        let mut arg_it = call.arguments[index + 1..].iter();
+        let mut chars = format.chars();
+        while let Some(ch) = chars.next() {
+            if ch == '%' {
+                // Skip any length modifiers up to the conversion character; the argument's type
+                // says everything we need.
+                if chars.find(|c| !"hlLqjzt".contains(*c)) == Some('%') {
+                    string.push(b'%');
+                } else {
+                    let arg = match arg_it.next() {
+                        Some((arg, _)) => arg,
+                        None => return Err(format!("Too few arguments to @{}", func_name)),
+                    };
+                    let arg = self.arg_to_string(func_name, arg)?;
+                    string.extend(arg.chars().map(|ch| ch as u8));
                 }
+                continue;
             }
-            _ => todo!(),
+            string.push(ch as u8);
         }
+        Ok(string)
+    }
 
-        println!("{}", string);
-    }
-
-    fn arg_to_string(&self, arg: &Operand) -> String {
-        match arg {
-            LocalOperand { name, ty } => {
-                let op = self.vars.get(name).unwrap();
-                match ty.as_ref() {
-                    Type::IntegerType { bits } => match bits {
-                        8 => todo!(),
-                        16 => todo!(),
-                        32 => (self.get_int_op(op) as i32).to_string(),
-                        64 => (self.get_int_op(op) as i64).to_string(),
-                        128 => todo!(),
-                        _ => todo!(),
-                    },
-                    Type::FPType(fptype) => match fptype {
-                        FPType::Single => self.get_single_fl_op(op).to_string(),
-                        FPType::Double => self.get_double_fl_op(op).to_string(),
-                        _ => todo!(),
-                    },
-                    _ => todo!(),
+    fn arg_to_string(&self, func_name: &str, arg: &Operand) -> Result<String, String> {
+        let ty = self.module.type_of(arg);
+        let unsupported = || format!("Unsupported argument of type {} to @{}", ty, func_name);
+        Ok(match ty.as_ref() {
+            Type::IntegerType { bits } => match bits {
+                8 | 16 | 32 | 64 => ops::sext(*bits, self.get_int_op(arg)?).to_string(),
+                _ => return Err(unsupported()),
+            },
+            Type::FPType(fptype) => match fptype {
+                FPType::Single => self.get_single_fl_op(arg)?.to_string(),
+                FPType::Double => self.get_double_fl_op(arg)?.to_string(),
+                _ => return Err(unsupported()),
+            },
+            Type::PointerType { .. } => {
+                let addr = self.get_int_op(arg)?;
+                let string = self.memory.read_c_string(addr)?;
+                if let Some(origin) = self.memory.undefined(addr, string.len() as u64 + 1) {
+                    let what = format!("passed to @{} as a string", func_name);
+                    return Err(self.uninit_error(&what, &origin));
                 }
+                string
             }
-            ConstantOperand(con_op) => match con_op.as_ref() {
-                Constant::Int { value, .. } => value.to_string(),
-                Constant::GetElementPtr(GetElementPtr { address, .. }) => match address.as_ref() {
-                    Constant::GlobalReference { name, .. } => {
-                        match self.gl_vars.get(name).unwrap() {
-                            Constant::Array { elements, .. } => {
-                                assert!(&name.to_string()[1..5] == ".str");
-                                self.arr_to_string(elements)
-                            }
-                            _ => todo!(),
-                        }
-                    }
-                    _ => todo!(),
-                },
-                _ => todo!(),
-            },
-            MetadataOperand => todo!(),
-        }
+            _ => return Err(unsupported()),
+        })
     }
 
-    fn arr_to_string(&self, elements: &Vec<ConstantRef>) -> String {
-        let mut string = String::new();
-        for elem in elements.iter() {
-            match elem.as_ref() {
-                Constant::Int { value, .. } => {
-                    if value != &0u64 {
-                        string.push(*value as u8 as char)
-                    }
-                }
-                _ => todo!(),
-            }
-        }
-        string
+    fn store_val(&mut self, dest: &name::Name, val: Constant) {
+        self.vars
+            .insert(dest.clone(), ConstantOperand(ConstantRef::new(val)));
     }
 
     fn int_bin_operations(
@@ -406,82 +989,52 @@ impl LLVMIRInterpreter {
         op1: &Operand,
         dest: &name::Name,
         operation_type: BinOps,
-    ) {
-        match op0 {
-            LocalOperand { name, ty } => match ty.as_ref() {
-                Type::IntegerType { bits } => match bits {
-                    8 => todo!(),
-                    16 => todo!(),
-                    32 => {
-                        let op0 = self.get_int_op(self.vars.get(name).unwrap()) as i32;
-                        let op1 = self.get_int_op(op1) as i32;
-                        self.int_bin_operation(op0, op1, dest, operation_type)
-                    }
-                    64 => {
-                        let op0 = self.get_int_op(self.vars.get(name).unwrap()) as i64;
-                        let op1 = self.get_int_op(op1) as i64;
-                        self.int_bin_operation(op0, op1, dest, operation_type)
-                    }
-                    128 => todo!(),
-                    _ => todo!(),
-                },
-                _ => todo!(),
-            },
-            ConstantOperand(con_op) => match con_op.as_ref() {
-                Constant::Int { .. } => todo!(),
-                Constant::Vector(_) => todo!(),
-                _ => todo!(),
-            },
-            MetadataOperand => todo!(),
-        }
+    ) -> Result<(), String> {
+        let val = self.checked_int_bin_op(
+            self.eval_op(op0)?.as_ref(),
+            self.eval_op(op1)?.as_ref(),
+            operation_type,
+        )?;
+        self.store_val(dest, val);
+        Ok(())
     }
 
-    fn get_int_op(&self, op: &Operand) -> u64 {
-        match op {
-            LocalOperand { name, .. } => self.get_int_op(self.vars.get(name).unwrap()),
-            ConstantOperand(con_op) => match con_op.as_ref() {
-                Constant::Int { value, .. } => *value,
-                Constant::Undef(..) => panic!(),
-                _ => unreachable!(),
-            },
-            MetadataOperand => todo!(),
+    /// Describe the instruction being executed and where it is.
+    fn location(&self) -> String {
+        let func = match self.stack.last() {
+            Some(func) => self.module.get_func_by_name(func).unwrap(),
+            None => return "program start".to_owned(),
+        };
+        let (bb, inst) = self.pc;
+        let bb = &func.basic_blocks[bb];
+        let text = match bb.instrs.get(inst) {
+            Some(inst) => inst.to_string(),
+            None => bb.term.to_string(),
+        };
+        let location = format!(
+            "`{}` in block {} of @{}",
+            without_debugloc(&text),
+            bb.name,
+            func.name
+        );
+        match self.source_location() {
+            Some(source) => format!("{} ({})", location, source),
+            None => location,
         }
     }
 
-    fn int_bin_operation<
-        T: std::ops::Add<Output = T>
-            + std::ops::Sub<Output = T>
-            + std::ops::Mul<Output = T>
-            + std::ops::Div<Output = T>
-            + std::ops::Rem<Output = T>
-            + num::cast::AsPrimitive<u64>,
-    >(
-        &mut self,
-        op0: T,
-        op1: T,
-        dest: &name::Name,
-        operation_type: BinOps,
-    ) {
-        let val;
-        match operation_type {
-            BinOps::Add => val = op0 + op1,
-            BinOps::Sub => val = op0 - op1,
-            BinOps::Mul => val = op0 * op1,
-            BinOps::Div => val = op0 / op1,
-            BinOps::Rem => val = op0 % op1,
-        };
-        let val = val.as_();
-        let bytes: u32 = match mem::size_of::<T>().try_into() {
-            Ok(by) => by,
-            Err(_) => unreachable!(),
-        };
+    fn get_int_op(&self, op: &Operand) -> Result<u64, String> {
+        Ok(ops::get_int(self.eval_op(op)?.as_ref())?.1)
+    }
 
-        let constant = Constant::Int {
-            bits: bytes * 8,
-            value: val,
-        };
-        self.vars
-            .insert(dest.clone(), ConstantOperand(ConstantRef::new(constant)));
+    /// Evaluate an integer or pointer whose value must be defined, such as an address or a branch
+    /// condition. `undef` is taken to be zero, but `poison` is an error.
+    fn get_defined_int_op(&self, op: &Operand, what: &str) -> Result<u64, String> {
+        let val = self.eval_op(op)?;
+        match val.as_ref() {
+            Constant::Poison(_) => Err(self.poison_error(&val, &format!("used as {}", what))),
+            _ => Ok(ops::get_int(&val)?.1),
+        }
     }
 
     fn fl_bin_operations(
@@ -490,199 +1043,205 @@ impl LLVMIRInterpreter {
         op1: &Operand,
         dest: &name::Name,
         operation_type: BinOps,
-    ) {
-        match op0 {
-            LocalOperand { name, ty } => match ty.as_ref() {
-                Type::FPType(fptype) => match fptype {
-                    FPType::Single => {
-                        let op0 = self.get_single_fl_op(self.vars.get(name).unwrap());
-                        let op1 = self.get_single_fl_op(op1);
-                        let val = self.fl_bin_operation(op0, op1, operation_type);
-                        self.vars.insert(
-                            dest.clone(),
-                            ConstantOperand(ConstantRef::new(Constant::Float(Float::Single(val)))),
-                        );
-                    }
-                    FPType::Double => {
-                        let op0 = self.get_double_fl_op(self.vars.get(name).unwrap());
-                        let op1 = self.get_double_fl_op(op1);
-                        let val = self.fl_bin_operation(op0, op1, operation_type);
-                        self.vars.insert(
-                            dest.clone(),
-                            ConstantOperand(ConstantRef::new(Constant::Float(Float::Double(val)))),
-                        );
-                    }
-                    _ => todo!(),
-                },
-                _ => todo!(),
-            },
-            ConstantOperand(..) => todo!(),
-            MetadataOperand => todo!(),
-        }
+    ) -> Result<(), String> {
+        let val = ops::fl_bin_op(
+            self.eval_op(op0)?.as_ref(),
+            self.eval_op(op1)?.as_ref(),
+            operation_type,
+        )?;
+        self.store_val(dest, val);
+        Ok(())
     }
 
-    fn get_single_fl_op(&self, op: &Operand) -> f32 {
-        match op {
-            LocalOperand { name, .. } => self.get_single_fl_op(self.vars.get(name).unwrap()),
-            ConstantOperand(con_op) => match con_op.as_ref() {
-                Constant::Vector(_) => todo!(),
-                Constant::Float(float) => match float {
-                    Float::Single(val) => *val,
-                    _ => todo!(),
-                },
-                Constant::Undef(..) => panic!(),
-                _ => todo!(),
-            },
-            MetadataOperand => todo!(),
+    fn fneg(&mut self, op: &Operand, dest: &name::Name) -> Result<(), String> {
+        let val = ops::fneg(self.eval_op(op)?.as_ref())?;
+        self.store_val(dest, val);
+        Ok(())
+    }
+
+    fn get_single_fl_op(&self, op: &Operand) -> Result<f32, String> {
+        match self.eval_op(op)?.as_ref() {
+            Constant::Float(Float::Single(val)) => Ok(*val),
+            Constant::Undef(..) => Ok(0.0),
+            con => Err(ops::unsupported(con)),
         }
     }
 
-    fn get_double_fl_op(&self, op: &Operand) -> f64 {
-        match op {
-            LocalOperand { name, .. } => self.get_double_fl_op(self.vars.get(name).unwrap()),
-            ConstantOperand(con_op) => match con_op.as_ref() {
-                Constant::Vector(_) => todo!(),
-                Constant::Float(float) => match float {
-                    Float::Double(val) => *val,
-                    _ => todo!(),
-                },
-                Constant::Undef(..) => panic!(),
-                _ => todo!(),
-            },
-            MetadataOperand => todo!(),
+    fn get_double_fl_op(&self, op: &Operand) -> Result<f64, String> {
+        match self.eval_op(op)?.as_ref() {
+            Constant::Float(Float::Double(val)) => Ok(*val),
+            Constant::Undef(..) => Ok(0.0),
+            con => Err(ops::unsupported(con)),
         }
     }
 
-    fn fl_bin_operation<
-        T: std::ops::Add<Output = T>
-            + std::ops::Sub<Output = T>
-            + std::ops::Mul<Output = T>
-            + std::ops::Div<Output = T>
-            + std::ops::Rem<Output = T>,
-    >(
+    fn cast(
         &mut self,
-        op0: T,
-        op1: T,
-        operation_type: BinOps,
-    ) -> T {
-        let val;
-        match operation_type {
-            BinOps::Add => val = op0 + op1,
-            BinOps::Sub => val = op0 - op1,
-            BinOps::Mul => val = op0 * op1,
-            BinOps::Div => val = op0 / op1,
-            BinOps::Rem => todo!(),
-        };
-        val
+        op: &Operand,
+        to_type: &TypeRef,
+        dest: &name::Name,
+        cast_op: CastOps,
+    ) -> Result<(), String> {
+        let val = ops::cast(cast_op, self.eval_op(op)?.as_ref(), to_type)?;
+        self.store_val(dest, val);
+        Ok(())
     }
 
-    fn fpext(&mut self, op: &Operand, to_type: &TypeRef, dest: &name::Name) {
-        match op {
-            LocalOperand { name, ty } => match ty.as_ref() {
-                Type::FPType(fptype) => match fptype {
-                    FPType::Single => match to_type.as_ref() {
-                        Type::FPType(fptype) => {
-                            match fptype {
-                                FPType::Double => self.vars.insert(
-                                    dest.clone(),
-                                    ConstantOperand(ConstantRef::new(Constant::Float(
-                                        Float::Double(
-                                            self.get_single_fl_op(self.vars.get(name).unwrap())
-                                                as f64,
-                                        ),
-                                    ))),
-                                ),
-                                _ => todo!(),
-                            };
-                        }
-                        _ => todo!(),
-                    },
-                    _ => todo!(),
-                },
-                _ => todo!(),
+    fn icmp(
+        &mut self,
+        pred: IntPredicate,
+        op0: &Operand,
+        op1: &Operand,
+        dest: &name::Name,
+    ) -> Result<(), String> {
+        let bool_ty = self.module.types.bool();
+        let val = ops::lift2(
+            self.eval_op(op0)?.as_ref(),
+            self.eval_op(op1)?.as_ref(),
+            |con0, con1| match ops::propagate(&[con0, con1], Some(&bool_ty)) {
+                Some(res) => Ok(res),
+                None => ops::icmp(pred, con0, con1),
             },
-            ConstantOperand(_) => todo!(),
-            MetadataOperand => todo!(),
-        }
+        )?;
+        self.store_val(dest, val);
+        Ok(())
     }
 
-    fn szext(&mut self, op: &Operand, to_type: &TypeRef, dest: &name::Name) {
-        match op {
-            LocalOperand { name, ty } => match ty.as_ref() {
-                Type::IntegerType { bits: _ } => match to_type.as_ref() {
-                    Type::IntegerType { bits } => {
-                        match bits {
-                            32 => {
-                                let constant = Constant::Int {
-                                    bits: 32,
-                                    value: self.get_int_op(self.vars.get(name).unwrap()),
-                                };
-                                self.vars.insert(
-                                    dest.clone(),
-                                    ConstantOperand(ConstantRef::new(constant)),
-                                );
-                            }
-                            64 => {
-                                let constant = Constant::Int {
-                                    bits: 64,
-                                    value: self.get_int_op(self.vars.get(name).unwrap()),
-                                };
-                                self.vars.insert(
-                                    dest.clone(),
-                                    ConstantOperand(ConstantRef::new(constant)),
-                                );
-                            }
-                            _ => todo!(),
-                        };
-                    }
-                    _ => todo!(),
-                },
-                _ => todo!(),
+    fn fcmp(
+        &mut self,
+        pred: FPPredicate,
+        op0: &Operand,
+        op1: &Operand,
+        dest: &name::Name,
+    ) -> Result<(), String> {
+        let bool_ty = self.module.types.bool();
+        let val = ops::lift2(
+            self.eval_op(op0)?.as_ref(),
+            self.eval_op(op1)?.as_ref(),
+            |con0, con1| match ops::propagate(&[con0, con1], Some(&bool_ty)) {
+                Some(res) => Ok(res),
+                None => ops::fcmp(pred, con0, con1),
             },
-            ConstantOperand(_) => todo!(),
-            MetadataOperand => todo!(),
-        }
+        )?;
+        self.store_val(dest, val);
+        Ok(())
     }
 
-    fn icmp(&mut self, pred: IntPredicate, op0: &Operand, op1: &Operand, dest: &name::Name) {
-        match op0 {
-            LocalOperand { name, ty } => match ty.as_ref() {
-                Type::IntegerType { bits: _ } => {
-                    let op0 = self.get_int_op(self.vars.get(name).unwrap());
-                    let op1 = self.get_int_op(op1);
-                    self.store_comp(pred, op0, op1, dest);
-                }
-                _ => todo!(),
-            },
-            ConstantOperand(con_op) => match con_op.as_ref() {
-                Constant::Int { .. } => todo!(),
-                Constant::Vector(_) => todo!(),
-                _ => todo!(),
-            },
-            MetadataOperand => todo!(),
-        }
+    fn select(
+        &mut self,
+        cond: &Operand,
+        true_val: &Operand,
+        false_val: &Operand,
+        dest: &name::Name,
+    ) -> Result<(), String> {
+        let val = ops::select(
+            self.eval_op(cond)?.as_ref(),
+            self.eval_op(true_val)?.as_ref(),
+            self.eval_op(false_val)?.as_ref(),
+            &self.module.type_of(true_val),
+        )?;
+        self.store_val(dest, val);
+        Ok(())
     }
 
-    fn store_comp(&mut self, pred: IntPredicate, op0: u64, op1: u64, dest: &name::Name) {
-        let is_true = match pred {
-            IntPredicate::EQ => op0 == op1,
-            IntPredicate::NE => op0 != op1,
-            IntPredicate::UGT => op0 > op1,
-            IntPredicate::UGE => op0 >= op1,
-            IntPredicate::ULT => op0 < op1,
-            IntPredicate::ULE => op0 <= op1,
-            IntPredicate::SGT => op0 > op1,
-            IntPredicate::SGE => op0 >= op1,
-            IntPredicate::SLT => op0 < op1,
-            IntPredicate::SLE => op0 <= op1,
+    // An index past the end of the vector gives `poison`.
+    fn extract_element(
+        &mut self,
+        vector: &Operand,
+        index: &Operand,
+        dest: &name::Name,
+    ) -> Result<(), String> {
+        let lanes = ops::vector_lanes(self.eval_op(vector)?.as_ref()).unwrap();
+        let index = match self.eval_op(index)?.as_ref() {
+            Constant::Poison(_) => None,
+            index => lanes.get(ops::get_int(index)?.1 as usize),
         };
+        let val = match index {
+            Some(lane) => lane.clone(),
+            None => ConstantRef::new(Constant::Poison(self.module.type_of(&lanes[0]))),
+        };
+        self.vars.insert(dest.clone(), ConstantOperand(val));
+        Ok(())
+    }
 
-        let constant = Constant::Int {
-            bits: 8,
-            value: is_true.into(),
+    fn insert_element(
+        &mut self,
+        vector: &Operand,
+        element: &Operand,
+        index: &Operand,
+        dest: &name::Name,
+    ) -> Result<(), String> {
+        let mut lanes = ops::vector_lanes(self.eval_op(vector)?.as_ref()).unwrap();
+        let index = match self.eval_op(index)?.as_ref() {
+            Constant::Poison(_) => None,
+            index => Some(ops::get_int(index)?.1 as usize).filter(|&i| i < lanes.len()),
         };
-        self.vars
-            .insert(dest.clone(), ConstantOperand(ConstantRef::new(constant)));
+        let val = match index {
+            Some(index) => {
+                lanes[index] = self.eval_op(element)?;
+                Constant::Vector(lanes)
+            }
+            None => Constant::Poison(self.module.type_of(vector)),
+        };
+        self.store_val(dest, val);
+        Ok(())
+    }
+
+    fn shuffle_vector(
+        &mut self,
+        op0: &Operand,
+        op1: &Operand,
+        mask: &ConstantRef,
+        dest: &name::Name,
+    ) -> Result<(), String> {
+        let mut lanes = ops::vector_lanes(self.eval_op(op0)?.as_ref()).unwrap();
+        lanes.extend(ops::vector_lanes(self.eval_op(op1)?.as_ref()).unwrap());
+        let lane_ty = self.module.type_of(&lanes[0]);
+        let val = ops::vector_lanes(mask)
+            .unwrap()
+            .iter()
+            .map(|ind| match ind.as_ref() {
+                Constant::Int { value, .. } => lanes[*value as usize].clone(),
+                _ => ConstantRef::new(Constant::Undef(lane_ty.clone())),
+            })
+            .collect();
+        self.store_val(dest, Constant::Vector(val));
+        Ok(())
+    }
+
+    /// Give the phis at the start of `bb` their values for coming from `pred`, all at once as
+    /// their incoming values may be each other, and return how many there are.
+    fn phis(
+        &mut self,
+        func_name: &str,
+        bb: &BasicBlock,
+        pred: &name::Name,
+    ) -> Result<usize, String> {
+        let phis = bb
+            .instrs
+            .iter()
+            .map_while(|inst| match inst {
+                Instruction::Phi(phi) => Some(phi),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let vals = phis
+            .iter()
+            .map(
+                |phi| match phi.incoming_values.iter().find(|(_, from)| from == pred) {
+                    Some((op, _)) => self.eval_op(op),
+                    None => Err(format!("`{}` has no value for coming from {}", phi, pred)),
+                },
+            )
+            .collect::<Result<Vec<_>, _>>()?;
+        for (index, (phi, val)) in phis.iter().zip(vals).enumerate() {
+            self.count_inst(&bb.instrs[index]);
+            self.vars.insert(phi.dest.clone(), ConstantOperand(val));
+            if self.trace.is_some() {
+                self.trace_inst(func_name, &bb.name, index, &bb.instrs[index]);
+            }
+        }
+        Ok(phis.len())
     }
 
     fn condbr(
@@ -690,15 +1249,11 @@ impl LLVMIRInterpreter {
         cond: &Operand,
         true_dest: &name::Name,
         false_dest: &name::Name,
-    ) -> name::Name {
-        match cond {
-            LocalOperand { name, .. } => match self.get_int_op(self.vars.get(name).unwrap()) {
-                0 => false_dest.clone(),
-                1 => true_dest.clone(),
-                _ => unreachable!(),
-            },
-            ConstantOperand(_) => todo!(),
-            MetadataOperand => todo!(),
+    ) -> Result<name::Name, String> {
+        match self.get_defined_int_op(cond, "a branch condition")? {
+            0 => Ok(false_dest.clone()),
+            1 => Ok(true_dest.clone()),
+            _ => unreachable!(),
         }
     }
 
@@ -707,24 +1262,117 @@ impl LLVMIRInterpreter {
         op: &Operand,
         dests: &Vec<(ConstantRef, name::Name)>,
         default_dest: &name::Name,
-    ) -> name::Name {
-        match op {
-            LocalOperand { name, .. } => {
-                let op = self.get_int_op(self.vars.get(name).unwrap());
-                for dest in dests {
-                    match dest.0.as_ref() {
-                        Constant::Int { bits: _, value } => {
-                            if value == &op {
-                                return dest.1.clone();
-                            }
-                        }
-                        _ => todo!(),
+    ) -> Result<name::Name, String> {
+        let op = self.get_defined_int_op(op, "a switch condition")?;
+        for dest in dests {
+            match dest.0.as_ref() {
+                Constant::Int { bits: _, value } => {
+                    if value == &op {
+                        return Ok(dest.1.clone());
                     }
                 }
-                return default_dest.clone();
+                con => return Err(format!("Unsupported switch case {}", con)),
+            }
+        }
+        Ok(default_dest.clone())
+    }
+
+    fn extract_value(
+        &mut self,
+        aggregate: &Operand,
+        indices: &[u32],
+        dest: &name::Name,
+    ) -> Result<(), String> {
+        let mut val = self.eval_op(aggregate)?;
+        for ind in indices {
+            val = match self.expand_aggregate(&val)? {
+                Constant::Struct { mut values, .. } => values.swap_remove(*ind as usize),
+                Constant::Array { mut elements, .. } => elements.swap_remove(*ind as usize),
+                _ => unreachable!(),
+            };
+        }
+        self.vars.insert(dest.clone(), ConstantOperand(val));
+        Ok(())
+    }
+
+    fn insert_value(
+        &mut self,
+        aggregate: &Operand,
+        element: &Operand,
+        indices: &[u32],
+        dest: &name::Name,
+    ) -> Result<(), String> {
+        let val = self.insert_into(
+            self.eval_op(aggregate)?.as_ref(),
+            self.eval_op(element)?,
+            indices,
+        )?;
+        self.vars.insert(dest.clone(), ConstantOperand(val));
+        Ok(())
+    }
+
+    fn insert_into(
+        &self,
+        aggregate: &Constant,
+        element: ConstantRef,
+        indices: &[u32],
+    ) -> Result<ConstantRef, String> {
+        let (ind, rest) = match indices.split_first() {
+            Some((ind, rest)) => (*ind as usize, rest),
+            None => return Ok(element),
+        };
+        let mut aggregate = self.expand_aggregate(aggregate)?;
+        match &mut aggregate {
+            Constant::Struct {
+                values: elements, ..
+            }
+            | Constant::Array { elements, .. } => {
+                elements[ind] = self.insert_into(&elements[ind], element, rest)?;
             }
-            ConstantOperand(_) => todo!(),
-            MetadataOperand => todo!(),
+            _ => unreachable!(),
         }
+        Ok(ConstantRef::new(aggregate))
+    }
+
+    // `zeroinitializer`, `undef` and `poison` aggregates don't list their elements, so we
+    // materialise one level of them as an explicit `Struct` or `Array`.
+    fn expand_aggregate(&self, aggregate: &Constant) -> Result<Constant, String> {
+        let ty = match aggregate {
+            Constant::Struct { .. } | Constant::Array { .. } => return Ok(aggregate.clone()),
+            Constant::AggregateZero(ty) | Constant::Undef(ty) | Constant::Poison(ty) => ty,
+            _ => return Err(format!("Unsupported aggregate {}", aggregate)),
+        };
+        let elem = |ty: &TypeRef| {
+            ConstantRef::new(match aggregate {
+                Constant::AggregateZero(_) => ops::zero_value(ty),
+                Constant::Undef(_) => Constant::Undef(ty.clone()),
+                _ => Constant::Poison(ty.clone()),
+            })
+        };
+        let (name, ty) = match ty.as_ref() {
+            Type::NamedStructType { name } => match self.module.types.named_struct_def(name) {
+                Some(NamedStructDef::Defined(def)) => (Some(name.clone()), def.clone()),
+                _ => return Err(format!("Unsupported opaque struct %{}", name)),
+            },
+            _ => (None, ty.clone()),
+        };
+        Ok(match ty.as_ref() {
+            Type::StructType {
+                element_types,
+                is_packed,
+            } => Constant::Struct {
+                name,
+                values: element_types.iter().map(elem).collect(),
+                is_packed: *is_packed,
+            },
+            Type::ArrayType {
+                element_type,
+                num_elements,
+            } => Constant::Array {
+                element_type: element_type.clone(),
+                elements: vec![elem(element_type); *num_elements],
+            },
+            _ => return Err(format!("Unsupported aggregate type {}", ty)),
+        })
     }
 }
@@ -0,0 +1,549 @@
+use super::BinOps;
+use llvm_ir::{
+    constant::{Constant, Float},
+    types::FPType,
+    ConstantRef, FPPredicate, IntPredicate, Type, TypeRef,
+};
+
+#[derive(Clone, Copy)]
+pub(super) enum CastOps {
+    Trunc,
+    ZExt,
+    SExt,
+    FPTrunc,
+    FPExt,
+    FPToUI,
+    FPToSI,
+    UIToFP,
+    SIToFP,
+    BitCast,
+}
+
+pub(super) fn mask(bits: u32) -> u64 {
+    if bits >= 64 {
+        u64::MAX
+    } else {
+        (1 << bits) - 1
+    }
+}
+
+pub(super) fn sext(bits: u32, val: u64) -> i64 {
+    let shift = 64 - bits.min(64);
+    ((val << shift) as i64) >> shift
+}
+
+pub(super) fn int(bits: u32, value: u64) -> Constant {
+    Constant::Int {
+        bits,
+        value: value & mask(bits),
+    }
+}
+
+pub(super) fn unsupported(con: &Constant) -> String {
+    format!("Unsupported value {}", con)
+}
+
+// `undef` may be any value each time it's used; we always pick zero. `poison` must be caught
+// before it gets this far.
+pub(super) fn get_int(con: &Constant) -> Result<(u32, u64), String> {
+    match con {
+        Constant::Int { bits, value } => Ok((*bits, *value & mask(*bits))),
+        Constant::Null(_) => Ok((64, 0)),
+        Constant::Undef(ty) => match ty.as_ref() {
+            Type::IntegerType { bits } => Ok((*bits, 0)),
+            Type::PointerType { .. } => Ok((64, 0)),
+            _ => Err(unsupported(con)),
+        },
+        _ => Err(unsupported(con)),
+    }
+}
+
+fn get_fl(con: &Constant) -> Result<f64, String> {
+    match con {
+        Constant::Float(Float::Single(val)) => Ok((*val).into()),
+        Constant::Float(Float::Double(val)) => Ok(*val),
+        Constant::Undef(_) => Ok(0.0),
+        _ => Err(unsupported(con)),
+    }
+}
+
+/// Whether `con` is or contains `poison`.
+pub(super) fn is_poison(con: &Constant) -> bool {
+    match con {
+        Constant::Poison(_) => true,
+        Constant::Vector(elements) | Constant::Array { elements, .. } => {
+            elements.iter().any(|elem| is_poison(elem))
+        }
+        Constant::Struct { values, .. } => values.iter().any(|val| is_poison(val)),
+        _ => false,
+    }
+}
+
+/// The result of an operation on `cons` if any of them is `poison` or `undef`: `poison` if any
+/// of them is, `undef` otherwise. It has type `ty`, or else the type of the offending operand.
+pub(super) fn propagate(cons: &[&Constant], ty: Option<&TypeRef>) -> Option<Constant> {
+    let poison = cons.iter().find(|con| matches!(con, Constant::Poison(_)));
+    let undef = cons.iter().find(|con| matches!(con, Constant::Undef(_)));
+    match poison.or(undef) {
+        Some(Constant::Poison(con_ty)) => Some(Constant::Poison(ty.unwrap_or(con_ty).clone())),
+        Some(Constant::Undef(con_ty)) => Some(Constant::Undef(ty.unwrap_or(con_ty).clone())),
+        _ => None,
+    }
+}
+
+/// Replace any `undef` or `poison` in `con` with an arbitrary but fixed value, as `freeze` does.
+pub(super) fn freeze(con: &Constant) -> Constant {
+    let freeze_all = |cons: &[ConstantRef]| {
+        cons.iter()
+            .map(|con| ConstantRef::new(freeze(con)))
+            .collect::<Vec<_>>()
+    };
+    match con {
+        Constant::Undef(ty) | Constant::Poison(ty) => zero_value(ty),
+        Constant::Vector(lanes) => Constant::Vector(freeze_all(lanes)),
+        Constant::Struct {
+            name,
+            values,
+            is_packed,
+        } => Constant::Struct {
+            name: name.clone(),
+            values: freeze_all(values),
+            is_packed: *is_packed,
+        },
+        Constant::Array {
+            element_type,
+            elements,
+        } => Constant::Array {
+            element_type: element_type.clone(),
+            elements: freeze_all(elements),
+        },
+        _ => con.clone(),
+    }
+}
+
+fn fl(ty: &Type, val: f64) -> Result<Constant, String> {
+    match ty {
+        Type::FPType(FPType::Single) => Ok(Constant::Float(Float::Single(val as f32))),
+        Type::FPType(FPType::Double) => Ok(Constant::Float(Float::Double(val))),
+        _ => Err(format!("Unsupported type {}", ty)),
+    }
+}
+
+pub(super) fn zero_value(ty: &TypeRef) -> Constant {
+    match ty.as_ref() {
+        Type::IntegerType { bits } => Constant::Int {
+            bits: *bits,
+            value: 0,
+        },
+        // llvm-ir has no values for the other floating-point types, and nor do we.
+        Type::FPType(fptype) => Constant::Float(match fptype {
+            FPType::Single => Float::Single(0.0),
+            FPType::Double => Float::Double(0.0),
+            FPType::Half => Float::Half,
+            FPType::BFloat => Float::BFloat,
+            FPType::FP128 => Float::Quadruple,
+            FPType::X86_FP80 => Float::X86_FP80,
+            FPType::PPC_FP128 => Float::PPC_FP128,
+        }),
+        Type::PointerType { .. } => Constant::Null(ty.clone()),
+        _ => Constant::AggregateZero(ty.clone()),
+    }
+}
+
+/// If `con` is of vector type, return its lanes, materialising `zeroinitializer`, `undef` and
+/// `poison` vectors as needed.
+pub(super) fn vector_lanes(con: &Constant) -> Option<Vec<ConstantRef>> {
+    let ty = match con {
+        Constant::Vector(lanes) => return Some(lanes.clone()),
+        Constant::AggregateZero(ty) | Constant::Undef(ty) | Constant::Poison(ty) => ty,
+        _ => return None,
+    };
+    match ty.as_ref() {
+        Type::VectorType {
+            element_type,
+            num_elements,
+            ..
+        } => {
+            let lane = match con {
+                Constant::AggregateZero(_) => zero_value(element_type),
+                Constant::Undef(_) => Constant::Undef(element_type.clone()),
+                _ => Constant::Poison(element_type.clone()),
+            };
+            Some(vec![ConstantRef::new(lane); *num_elements])
+        }
+        _ => None,
+    }
+}
+
+/// Apply `f` to `con`, or to each of its lanes if it is a vector.
+pub(super) fn lift1(
+    con: &Constant,
+    f: impl Fn(&Constant) -> Result<Constant, String>,
+) -> Result<Constant, String> {
+    match vector_lanes(con) {
+        Some(lanes) => Ok(Constant::Vector(
+            lanes
+                .iter()
+                .map(|lane| f(lane).map(ConstantRef::new))
+                .collect::<Result<_, _>>()?,
+        )),
+        None => f(con),
+    }
+}
+
+/// Apply `f` to `con0` and `con1`, or lane-wise if they are vectors.
+pub(super) fn lift2(
+    con0: &Constant,
+    con1: &Constant,
+    f: impl Fn(&Constant, &Constant) -> Result<Constant, String>,
+) -> Result<Constant, String> {
+    match (vector_lanes(con0), vector_lanes(con1)) {
+        (Some(lanes0), Some(lanes1)) => Ok(Constant::Vector(
+            lanes0
+                .iter()
+                .zip(lanes1.iter())
+                .map(|(lane0, lane1)| f(lane0, lane1).map(ConstantRef::new))
+                .collect::<Result<_, _>>()?,
+        )),
+        (None, None) => f(con0, con1),
+        _ => unreachable!(),
+    }
+}
+
+pub(super) fn int_bin_op(
+    con0: &Constant,
+    con1: &Constant,
+    operation_type: BinOps,
+) -> Result<Constant, String> {
+    lift2(con0, con1, |con0, con1| {
+        if let Some(res) = propagate(&[con0, con1], None) {
+            // Some operations give the same result whatever value `undef` takes.
+            let absorbing = match operation_type {
+                BinOps::And | BinOps::Mul => Some(0),
+                BinOps::Or => Some(u64::MAX),
+                _ => None,
+            };
+            return Ok(match (&res, absorbing) {
+                (Constant::Undef(_), Some(absorbing)) => [con0, con1]
+                    .iter()
+                    .find(|con| match con {
+                        Constant::Int { bits, value } => *value == absorbing & mask(*bits),
+                        _ => false,
+                    })
+                    .map_or(res.clone(), |con| (*con).clone()),
+                _ => res,
+            });
+        }
+        let ((bits, op0), (_, op1)) = (get_int(con0)?, get_int(con1)?);
+        let (sop0, sop1) = (sext(bits, op0), sext(bits, op1));
+        let val = match operation_type {
+            BinOps::Add => op0.wrapping_add(op1),
+            BinOps::Sub => op0.wrapping_sub(op1),
+            BinOps::Mul => op0.wrapping_mul(op1),
+            BinOps::UDiv => op0 / op1,
+            BinOps::SDiv => sop0.wrapping_div(sop1) as u64,
+            BinOps::URem => op0 % op1,
+            BinOps::SRem => sop0.wrapping_rem(sop1) as u64,
+            BinOps::And => op0 & op1,
+            BinOps::Or => op0 | op1,
+            BinOps::Xor => op0 ^ op1,
+            BinOps::Shl => op0.checked_shl(op1 as u32).unwrap_or(0),
+            BinOps::LShr => op0.checked_shr(op1 as u32).unwrap_or(0),
+            BinOps::AShr => (sop0 >> op1.min(63)) as u64,
+            BinOps::Div | BinOps::Rem => unreachable!(),
+        };
+        Ok(int(bits, val))
+    })
+}
+
+pub(super) fn fl_bin_op(
+    con0: &Constant,
+    con1: &Constant,
+    operation_type: BinOps,
+) -> Result<Constant, String> {
+    lift2(con0, con1, |con0, con1| {
+        if let Some(res) = propagate(&[con0, con1], None) {
+            return Ok(res);
+        }
+        match (con0, con1) {
+            (Constant::Float(Float::Single(op0)), Constant::Float(Float::Single(op1))) => Ok(
+                Constant::Float(Float::Single(fl_bin_operation(*op0, *op1, operation_type))),
+            ),
+            (Constant::Float(Float::Double(op0)), Constant::Float(Float::Double(op1))) => Ok(
+                Constant::Float(Float::Double(fl_bin_operation(*op0, *op1, operation_type))),
+            ),
+            _ => Err(unsupported(con0)),
+        }
+    })
+}
+
+fn fl_bin_operation<T: num::Float>(op0: T, op1: T, operation_type: BinOps) -> T {
+    match operation_type {
+        BinOps::Add => op0 + op1,
+        BinOps::Sub => op0 - op1,
+        BinOps::Mul => op0 * op1,
+        BinOps::Div => op0 / op1,
+        BinOps::Rem => op0 % op1,
+        _ => unreachable!(),
+    }
+}
+
+pub(super) fn fneg(con: &Constant) -> Result<Constant, String> {
+    lift1(con, |con| match con {
+        Constant::Undef(_) | Constant::Poison(_) => Ok(con.clone()),
+        Constant::Float(Float::Single(val)) => Ok(Constant::Float(Float::Single(-val))),
+        Constant::Float(Float::Double(val)) => Ok(Constant::Float(Float::Double(-val))),
+        _ => Err(unsupported(con)),
+    })
+}
+
+pub(super) fn icmp(
+    pred: IntPredicate,
+    con0: &Constant,
+    con1: &Constant,
+) -> Result<Constant, String> {
+    lift2(con0, con1, |con0, con1| {
+        let ((bits, op0), (_, op1)) = (get_int(con0)?, get_int(con1)?);
+        let (sop0, sop1) = (sext(bits, op0), sext(bits, op1));
+        let is_true = match pred {
+            IntPredicate::EQ => op0 == op1,
+            IntPredicate::NE => op0 != op1,
+            IntPredicate::UGT => op0 > op1,
+            IntPredicate::UGE => op0 >= op1,
+            IntPredicate::ULT => op0 < op1,
+            IntPredicate::ULE => op0 <= op1,
+            IntPredicate::SGT => sop0 > sop1,
+            IntPredicate::SGE => sop0 >= sop1,
+            IntPredicate::SLT => sop0 < sop1,
+            IntPredicate::SLE => sop0 <= sop1,
+        };
+        Ok(int(1, is_true.into()))
+    })
+}
+
+pub(super) fn fcmp(
+    pred: FPPredicate,
+    con0: &Constant,
+    con1: &Constant,
+) -> Result<Constant, String> {
+    lift2(con0, con1, |con0, con1| {
+        let (op0, op1) = (get_fl(con0)?, get_fl(con1)?);
+        let unordered = op0.is_nan() || op1.is_nan();
+        let is_true = match pred {
+            FPPredicate::False => false,
+            FPPredicate::OEQ => op0 == op1,
+            FPPredicate::OGT => op0 > op1,
+            FPPredicate::OGE => op0 >= op1,
+            FPPredicate::OLT => op0 < op1,
+            FPPredicate::OLE => op0 <= op1,
+            FPPredicate::ONE => !unordered && op0 != op1,
+            FPPredicate::ORD => !unordered,
+            FPPredicate::UNO => unordered,
+            FPPredicate::UEQ => unordered || op0 == op1,
+            FPPredicate::UGT => unordered || op0 > op1,
+            FPPredicate::UGE => unordered || op0 >= op1,
+            FPPredicate::ULT => unordered || op0 < op1,
+            FPPredicate::ULE => unordered || op0 <= op1,
+            FPPredicate::UNE => op0 != op1,
+            FPPredicate::True => true,
+        };
+        Ok(int(1, is_true.into()))
+    })
+}
+
+/// Pick between `true_val` and `false_val`, both of type `ty`. A `poison` condition gives
+/// `poison`; an `undef` one picks `false_val`.
+pub(super) fn select(
+    cond: &Constant,
+    true_val: &Constant,
+    false_val: &Constant,
+    ty: &TypeRef,
+) -> Result<Constant, String> {
+    match vector_lanes(cond) {
+        Some(conds) => {
+            let lane_ty = match ty.as_ref() {
+                Type::VectorType { element_type, .. } => element_type,
+                _ => unreachable!(),
+            };
+            let (trues, falses) = (
+                vector_lanes(true_val).unwrap(),
+                vector_lanes(false_val).unwrap(),
+            );
+            Ok(Constant::Vector(
+                conds
+                    .iter()
+                    .zip(trues.into_iter().zip(falses))
+                    .map(|(cond, (t, f))| match cond.as_ref() {
+                        Constant::Poison(_) => {
+                            Ok(ConstantRef::new(Constant::Poison(lane_ty.clone())))
+                        }
+                        _ if get_int(cond)?.1 == 1 => Ok(t),
+                        _ => Ok(f),
+                    })
+                    .collect::<Result<_, String>>()?,
+            ))
+        }
+        None if matches!(cond, Constant::Poison(_)) => Ok(Constant::Poison(ty.clone())),
+        None if get_int(cond)?.1 == 1 => Ok(true_val.clone()),
+        None => Ok(false_val.clone()),
+    }
+}
+
+pub(super) fn cast(
+    cast_op: CastOps,
+    con: &Constant,
+    to_type: &TypeRef,
+) -> Result<Constant, String> {
+    let to_elem_type = match to_type.as_ref() {
+        Type::VectorType { element_type, .. } => element_type,
+        _ => to_type,
+    };
+    match cast_op {
+        // Any `poison` bit makes the whole result `poison`.
+        CastOps::BitCast if is_poison(con) => Ok(Constant::Poison(to_type.clone())),
+        CastOps::BitCast => match con {
+            Constant::Undef(_) => Ok(Constant::Undef(to_type.clone())),
+            _ => from_bytes(to_type, &to_bytes(con)?),
+        },
+        _ => lift1(con, |con| scalar_cast(cast_op, con, to_elem_type)),
+    }
+}
+
+fn scalar_cast(cast_op: CastOps, con: &Constant, to_type: &TypeRef) -> Result<Constant, String> {
+    let to_bits = match to_type.as_ref() {
+        Type::IntegerType { bits } => *bits,
+        Type::PointerType { .. } => 64,
+        _ => 0,
+    };
+    if let Some(res) = propagate(&[con], Some(to_type)) {
+        return Ok(res);
+    }
+    match cast_op {
+        CastOps::Trunc | CastOps::ZExt => Ok(int(to_bits, get_int(con)?.1)),
+        CastOps::SExt => {
+            let (bits, val) = get_int(con)?;
+            Ok(int(to_bits, sext(bits, val) as u64))
+        }
+        CastOps::FPTrunc | CastOps::FPExt => fl(to_type, get_fl(con)?),
+        CastOps::FPToUI => Ok(int(to_bits, get_fl(con)? as u64)),
+        CastOps::FPToSI => Ok(int(to_bits, get_fl(con)? as i64 as u64)),
+        // Converting via `f64` could round twice on the way to `f32`, so go there directly.
+        CastOps::UIToFP => match to_type.as_ref() {
+            Type::FPType(FPType::Single) => {
+                Ok(Constant::Float(Float::Single(get_int(con)?.1 as f32)))
+            }
+            _ => fl(to_type, get_int(con)?.1 as f64),
+        },
+        CastOps::SIToFP => {
+            let (bits, val) = get_int(con)?;
+            match to_type.as_ref() {
+                Type::FPType(FPType::Single) => {
+                    Ok(Constant::Float(Float::Single(sext(bits, val) as f32)))
+                }
+                _ => fl(to_type, sext(bits, val) as f64),
+            }
+        }
+        CastOps::BitCast => unreachable!(),
+    }
+}
+
+/// The width of `con` if it is an integer, defined or not.
+fn int_bits(con: &Constant) -> Option<u32> {
+    match con {
+        Constant::Int { bits, .. } => Some(*bits),
+        Constant::Undef(ty) | Constant::Poison(ty) => match ty.as_ref() {
+            Type::IntegerType { bits } => Some(*bits),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// The in-memory (little-endian) representation of a first-class value.
+pub(super) fn to_bytes(con: &Constant) -> Result<Vec<u8>, String> {
+    match con {
+        Constant::Int { bits, value } => {
+            Ok(value.to_le_bytes()[..(*bits as usize).div_ceil(8).min(8)].to_vec())
+        }
+        Constant::Float(Float::Single(val)) => Ok(val.to_bits().to_le_bytes().to_vec()),
+        Constant::Float(Float::Double(val)) => Ok(val.to_bits().to_le_bytes().to_vec()),
+        Constant::Null(_) => Ok(vec![0; 8]),
+        // Undefined lanes of a vector are stored as zeros.
+        Constant::Undef(ty) | Constant::Poison(ty) if vector_lanes(con).is_none() => {
+            to_bytes(&zero_value(ty))
+        }
+        _ => match vector_lanes(con) {
+            // Lanes of `i1` and other widths that aren't whole bytes are packed together.
+            Some(lanes) => match int_bits(&lanes[0]) {
+                Some(bits) if bits % 8 != 0 => {
+                    let bits = bits as usize;
+                    let mut bytes = vec![0; (bits * lanes.len()).div_ceil(8)];
+                    for (i, lane) in lanes.iter().enumerate() {
+                        let val = match lane.as_ref() {
+                            Constant::Int { value, .. } => *value,
+                            _ => 0,
+                        };
+                        for bit in (0..bits).filter(|bit| val >> bit & 1 == 1) {
+                            let pos = i * bits + bit;
+                            bytes[pos / 8] |= 1 << (pos % 8);
+                        }
+                    }
+                    Ok(bytes)
+                }
+                _ => Ok(lanes
+                    .iter()
+                    .map(|lane| to_bytes(lane))
+                    .collect::<Result<Vec<_>, _>>()?
+                    .concat()),
+            },
+            None => Err(unsupported(con)),
+        },
+    }
+}
+
+/// Rebuild a value of type `ty` from its in-memory representation.
+pub(super) fn from_bytes(ty: &TypeRef, bytes: &[u8]) -> Result<Constant, String> {
+    let read = |bytes: &[u8]| {
+        let mut buf = [0; 8];
+        buf[..bytes.len()].copy_from_slice(bytes);
+        u64::from_le_bytes(buf)
+    };
+    Ok(match ty.as_ref() {
+        Type::IntegerType { bits } => int(*bits, read(bytes)),
+        Type::PointerType { .. } => int(64, read(bytes)),
+        Type::FPType(FPType::Single) => {
+            Constant::Float(Float::Single(f32::from_bits(read(&bytes[..4]) as u32)))
+        }
+        Type::FPType(FPType::Double) => Constant::Float(Float::Double(f64::from_bits(read(bytes)))),
+        Type::VectorType {
+            element_type,
+            num_elements,
+            ..
+        } => match element_type.as_ref() {
+            Type::IntegerType { bits } if bits % 8 != 0 => {
+                let bits = *bits as usize;
+                Constant::Vector(
+                    (0..*num_elements)
+                        .map(|i| {
+                            let val = (0..bits)
+                                .map(|bit| (i * bits + bit, bit))
+                                .filter(|(pos, _)| bytes[pos / 8] >> (pos % 8) & 1 == 1)
+                                .fold(0, |val, (_, bit)| val | 1 << bit);
+                            ConstantRef::new(int(bits as u32, val))
+                        })
+                        .collect(),
+                )
+            }
+            _ => {
+                let size = bytes.len() / num_elements;
+                Constant::Vector(
+                    bytes
+                        .chunks(size)
+                        .map(|chunk| from_bytes(element_type, chunk).map(ConstantRef::new))
+                        .collect::<Result<_, _>>()?,
+                )
+            }
+        },
+        _ => return Err(format!("Unsupported type {}", ty)),
+    })
+}
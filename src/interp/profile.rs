@@ -0,0 +1,153 @@
+use super::{trace::json_string, LLVMIRInterpreter};
+use std::{collections::HashMap, fs, rc::Rc};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum ProfileFormat {
+    /// One line per distinct call stack, `main;f;g <instructions>`, as flame graph tools expect.
+    Folded,
+    /// Chrome's trace event JSON, with one instruction as one microsecond.
+    Chrome,
+}
+
+/// Counts the instructions executed under each interpreted call stack.
+pub struct Profiler {
+    format: ProfileFormat,
+    path: String,
+    /// Instructions executed so far.
+    clock: u64,
+    /// The thread and stack the last instruction was executed on, and how many instructions have
+    /// been executed there since it was last recorded. Holding on to the stack means that it's
+    /// copied rather than changed in place, so a change is seen as a different `Rc`.
+    current: Option<(usize, Rc<Vec<String>>)>,
+    pending: u64,
+    folded: HashMap<String, u64>,
+    /// For each thread, the stack it was last seen with.
+    stacks: Vec<Rc<Vec<String>>>,
+    events: Vec<String>,
+}
+
+impl Profiler {
+    pub fn new(format: ProfileFormat, path: String) -> Profiler {
+        Profiler {
+            format,
+            path,
+            clock: 0,
+            current: None,
+            pending: 0,
+            folded: HashMap::new(),
+            stacks: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    /// A profiler with nothing counted yet, for the program to run again.
+    pub(super) fn restarted(&self) -> Profiler {
+        Profiler::new(self.format, self.path.clone())
+    }
+
+    fn tick(&mut self, tid: usize, stack: &Rc<Vec<String>>) {
+        match &self.current {
+            Some((cur, cur_stack)) if *cur == tid && Rc::ptr_eq(cur_stack, stack) => {}
+            _ => {
+                self.flush();
+                self.switch(tid, stack);
+                self.current = Some((tid, Rc::clone(stack)));
+            }
+        }
+        self.pending += 1;
+        self.clock += 1;
+    }
+
+    fn flush(&mut self) {
+        if let Some((_, stack)) = &self.current {
+            if self.pending > 0 {
+                *self.folded.entry(stack.join(";")).or_default() += self.pending;
+            }
+        }
+        self.pending = 0;
+    }
+
+    /// Record that thread `tid` is now running with `stack`, ending the functions it has left and
+    /// starting those it has entered.
+    fn switch(&mut self, tid: usize, stack: &Rc<Vec<String>>) {
+        if self.format != ProfileFormat::Chrome {
+            return;
+        }
+        if tid >= self.stacks.len() {
+            self.stacks.resize_with(tid + 1, Rc::default);
+        }
+        let old = Rc::clone(&self.stacks[tid]);
+        let common = old
+            .iter()
+            .zip(stack.iter())
+            .take_while(|(a, b)| a == b)
+            .count();
+        for func in old[common..].iter().rev() {
+            self.event(tid, func, "E");
+        }
+        for func in &stack[common..] {
+            self.event(tid, func, "B");
+        }
+        self.stacks[tid] = Rc::clone(stack);
+    }
+
+    fn event(&mut self, tid: usize, func: &str, phase: &str) {
+        self.events.push(format!(
+            "{{\"name\":{},\"ph\":\"{}\",\"ts\":{},\"pid\":1,\"tid\":{}}}",
+            json_string(func),
+            phase,
+            self.clock,
+            tid
+        ));
+    }
+
+    pub(super) fn finish_thread(&mut self, tid: usize) {
+        self.flush();
+        self.current = None;
+        self.switch(tid, &Rc::default());
+    }
+
+    fn write(&mut self) -> Result<(), String> {
+        self.flush();
+        self.current = None;
+        for tid in 0..self.stacks.len() {
+            self.switch(tid, &Rc::default());
+        }
+        let out = match self.format {
+            ProfileFormat::Folded => {
+                let mut lines = self
+                    .folded
+                    .iter()
+                    .map(|(stack, count)| format!("{} {}\n", stack, count))
+                    .collect::<Vec<_>>();
+                lines.sort();
+                lines.concat()
+            }
+            ProfileFormat::Chrome => {
+                format!("{{\"traceEvents\":[\n{}\n]}}\n", self.events.join(",\n"))
+            }
+        };
+        if self.path == "-" {
+            print!("{}", out);
+            return Ok(());
+        }
+        fs::write(&self.path, out)
+            .map_err(|err| format!("Can't write profile to '{}': {}", self.path, err))
+    }
+}
+
+impl LLVMIRInterpreter {
+    pub(super) fn profile_tick(&mut self) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.tick(self.thread, &self.stack);
+        }
+    }
+
+    /// Write the profile out, if `--profile` is on.
+    pub fn write_profile(&mut self) -> Result<(), String> {
+        match &mut self.profiler {
+            Some(profiler) => profiler.write(),
+            None => Ok(()),
+        }
+    }
+}
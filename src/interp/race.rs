@@ -0,0 +1,231 @@
+use super::{backtrace, LLVMIRInterpreter};
+use llvm_ir::instruction::MemoryOrdering;
+use std::{collections::HashMap, rc::Rc};
+
+type VClock = Vec<u64>;
+
+fn join(clock: &mut VClock, other: &VClock) {
+    if clock.len() < other.len() {
+        clock.resize(other.len(), 0);
+    }
+    for (c, o) in clock.iter_mut().zip(other) {
+        *c = (*c).max(*o);
+    }
+}
+
+#[derive(Clone)]
+struct Access {
+    tid: usize,
+    epoch: u64,
+    write: bool,
+    atomic: bool,
+    stack: Rc<Vec<String>>,
+}
+
+#[derive(Default)]
+struct Shadow {
+    write: Option<Access>,
+    reads: Vec<Access>,
+}
+
+/// A vector-clock race detector in the style of ThreadSanitizer. Every byte of memory remembers
+/// the last write to it and the reads since; an access races with one of those if the two don't
+/// both come from atomics and neither happens before the other.
+///
+/// Happens-before edges come from thread creation and joining, mutexes, and atomics with acquire
+/// and release orderings. Fences add no edges.
+pub(super) struct RaceDetector {
+    clocks: Vec<VClock>,
+    /// The clocks released by the last unlock of each mutex.
+    locks: HashMap<u64, VClock>,
+    /// The clocks released by atomic writes to each address.
+    releases: HashMap<u64, VClock>,
+    shadow: HashMap<u64, Shadow>,
+}
+
+impl RaceDetector {
+    pub(super) fn new() -> RaceDetector {
+        RaceDetector {
+            clocks: vec![vec![1]],
+            locks: HashMap::new(),
+            releases: HashMap::new(),
+            shadow: HashMap::new(),
+        }
+    }
+
+    fn tick(&mut self, tid: usize) {
+        self.clocks[tid][tid] += 1;
+    }
+
+    pub(super) fn fork(&mut self, parent: usize, child: usize) {
+        let mut clock = self.clocks[parent].clone();
+        clock.resize(child + 1, 0);
+        clock[child] = 1;
+        self.clocks.push(clock);
+        self.tick(parent);
+    }
+
+    pub(super) fn join(&mut self, tid: usize, child: usize) {
+        let child = self.clocks[child].clone();
+        join(&mut self.clocks[tid], &child);
+    }
+
+    pub(super) fn lock(&mut self, tid: usize, mutex: u64) {
+        if let Some(clock) = self.locks.get(&mutex) {
+            join(&mut self.clocks[tid], clock);
+        }
+    }
+
+    pub(super) fn unlock(&mut self, tid: usize, mutex: u64) {
+        self.locks.insert(mutex, self.clocks[tid].clone());
+        self.tick(tid);
+    }
+
+    fn acquire(&mut self, tid: usize, addr: u64) {
+        if let Some(clock) = self.releases.get(&addr) {
+            join(&mut self.clocks[tid], clock);
+        }
+    }
+
+    // Read-modify-write operations continue the release sequence of the write they read from;
+    // any other write starts a new one.
+    fn release(&mut self, tid: usize, addr: u64, rmw: bool) {
+        let clock = &self.clocks[tid];
+        match (rmw, self.releases.get_mut(&addr)) {
+            (true, Some(released)) => join(released, clock),
+            _ => {
+                self.releases.insert(addr, clock.clone());
+            }
+        }
+        self.tick(tid);
+    }
+
+    fn access(
+        &mut self,
+        tid: usize,
+        addr: u64,
+        len: u64,
+        write: bool,
+        atomic: bool,
+        stack: &Rc<Vec<String>>,
+    ) -> Result<(), String> {
+        let access = Access {
+            tid,
+            epoch: self.clocks[tid][tid],
+            write,
+            atomic,
+            stack: Rc::clone(stack),
+        };
+        let clock = &self.clocks[tid];
+        for byte in addr..addr + len {
+            let shadow = self.shadow.entry(byte).or_default();
+            let prev = shadow.write.iter().chain(match write {
+                true => shadow.reads.iter(),
+                false => [].iter(),
+            });
+            for prev in prev {
+                if prev.tid != tid
+                    && !(prev.atomic && atomic)
+                    && prev.epoch > clock.get(prev.tid).copied().unwrap_or(0)
+                {
+                    return Err(report(addr, len, &access, prev));
+                }
+            }
+
+            match write {
+                true => {
+                    shadow.write = Some(access.clone());
+                    shadow.reads.clear();
+                }
+                false => {
+                    shadow.reads.retain(|read| read.tid != tid);
+                    shadow.reads.push(access.clone());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn report(addr: u64, len: u64, access: &Access, prev: &Access) -> String {
+    let describe = |access: &Access| {
+        format!(
+            "{}{} by thread {}:{}",
+            if access.atomic { "atomic " } else { "" },
+            if access.write { "write" } else { "read" },
+            access.tid,
+            backtrace(&access.stack)
+        )
+    };
+    format!(
+        "Data race on {} bytes at address {:#x}\n  {}\n  Previous {}",
+        len,
+        addr,
+        describe(access),
+        describe(prev)
+    )
+}
+
+impl LLVMIRInterpreter {
+    pub(super) fn race_read(
+        &mut self,
+        addr: u64,
+        len: u64,
+        ordering: Option<MemoryOrdering>,
+    ) -> Result<(), String> {
+        let (tid, stack) = (self.thread, &self.stack);
+        if let Some(race) = &mut self.race {
+            race.access(tid, addr, len, false, ordering.is_some(), stack)?;
+            if let Some(MemoryOrdering::Acquire)
+            | Some(MemoryOrdering::AcquireRelease)
+            | Some(MemoryOrdering::SequentiallyConsistent) = ordering
+            {
+                race.acquire(tid, addr);
+            }
+        }
+        Ok(())
+    }
+
+    /// Record a write. Writes by read-modify-write operations are recorded by `race_rmw`.
+    pub(super) fn race_write(
+        &mut self,
+        addr: u64,
+        len: u64,
+        ordering: Option<MemoryOrdering>,
+    ) -> Result<(), String> {
+        self.race_write_inner(addr, len, ordering, false)
+    }
+
+    pub(super) fn race_rmw(
+        &mut self,
+        addr: u64,
+        len: u64,
+        ordering: MemoryOrdering,
+    ) -> Result<(), String> {
+        self.race_read(addr, len, Some(ordering))?;
+        self.race_write_inner(addr, len, Some(ordering), true)
+    }
+
+    fn race_write_inner(
+        &mut self,
+        addr: u64,
+        len: u64,
+        ordering: Option<MemoryOrdering>,
+        rmw: bool,
+    ) -> Result<(), String> {
+        let (tid, stack) = (self.thread, &self.stack);
+        if let Some(race) = &mut self.race {
+            race.access(tid, addr, len, true, ordering.is_some(), stack)?;
+            match ordering {
+                Some(MemoryOrdering::Release)
+                | Some(MemoryOrdering::AcquireRelease)
+                | Some(MemoryOrdering::SequentiallyConsistent) => race.release(tid, addr, rmw),
+                Some(_) if !rmw => {
+                    race.releases.remove(&addr);
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,327 @@
+use super::{
+    memory::Memory, ops::int, race::RaceDetector, sandbox::Syscall, stats::Stats, Coverage,
+    Debugger, LLVMIRInterpreter, Profiler,
+};
+use llvm_ir::{instruction::Call, ConstantRef};
+use std::{
+    fs,
+    io::{self, Read},
+    rc::Rc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Something the program learns that it can't work out by itself.
+#[derive(Clone, PartialEq)]
+enum Event {
+    /// The thread the scheduler picked to run next.
+    Schedule(usize),
+    /// What an external function such as `getchar` or `time` gave the program.
+    Input(String, Vec<u8>),
+}
+
+/// Logs a program's nondeterministic inputs, or feeds those of an earlier run back to it so that
+/// it runs exactly the same way again.
+pub struct Recorder {
+    /// Where to write the log once the program ends.
+    path: Option<String>,
+    events: Vec<Event>,
+    /// How many events the program has been through. Once it has been through all of them, new
+    /// events are added.
+    pos: usize,
+}
+
+impl Recorder {
+    /// A recorder that writes the log to `path` once the program ends.
+    pub fn record(path: String) -> Recorder {
+        Recorder {
+            path: Some(path),
+            ..Recorder::in_memory()
+        }
+    }
+
+    /// A recorder that replays the log written to `path` by an earlier run. The program must be
+    /// run with the same options as it was then.
+    pub fn replay(path: &str) -> Result<Recorder, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("Can't read recording '{}': {}", path, err))?;
+        let mut events = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            let event = match words.as_slice() {
+                ["schedule", tid] => tid.parse().ok().map(Event::Schedule),
+                ["input", what] => Some(Event::Input(what.to_string(), Vec::new())),
+                ["input", what, data] => {
+                    unhex(data).map(|data| Event::Input(what.to_string(), data))
+                }
+                _ => None,
+            };
+            match event {
+                Some(event) => events.push(event),
+                None => return Err(format!("Invalid recording '{}' at line {}", path, i + 1)),
+            }
+        }
+        Ok(Recorder {
+            path: None,
+            events,
+            pos: 0,
+        })
+    }
+
+    /// A recorder that only keeps the log for the debugger, which runs the program again to go
+    /// back in time.
+    pub(super) fn in_memory() -> Recorder {
+        Recorder {
+            path: None,
+            events: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    /// The next event of the log, if the program hasn't been through all of them.
+    fn replayed(&mut self) -> Option<Event> {
+        let event = self.events.get(self.pos)?.clone();
+        self.pos += 1;
+        Some(event)
+    }
+
+    fn push(&mut self, event: Event) {
+        self.events.push(event);
+        self.pos += 1;
+    }
+
+    /// Go back to the start of the log, for the program to run again.
+    pub(super) fn rewind(&mut self) {
+        self.pos = 0;
+    }
+
+    fn write(&self) -> Result<(), String> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut log = String::new();
+        for event in &self.events {
+            match event {
+                Event::Schedule(tid) => log.push_str(&format!("schedule {}\n", tid)),
+                Event::Input(what, data) => {
+                    log.push_str(format!("input {} {}", what, hex(data)).trim_end());
+                    log.push('\n');
+                }
+            }
+        }
+        fs::write(path, log).map_err(|err| format!("Can't write recording to '{}': {}", path, err))
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn diverged(event: &Event, wanted: &str) -> String {
+    let recorded = match event {
+        Event::Schedule(tid) => format!("thread {} being scheduled", tid),
+        Event::Input(what, _) => format!("input from {}", what),
+    };
+    format!(
+        "Replay diverged: the program wanted {}, but the recording has {}",
+        wanted, recorded
+    )
+}
+
+impl LLVMIRInterpreter {
+    pub fn set_recorder(&mut self, recorder: Option<Recorder>) {
+        self.recorder = recorder;
+    }
+
+    /// Pick the thread to run next, or take the recorded pick when replaying. The scheduler
+    /// picks either way, so that a run carrying on from a replay goes on as the original would.
+    pub(super) fn pick_thread(&mut self, runnable: &[usize], cur: usize) -> Result<usize, String> {
+        let tid = self.scheduler.pick(runnable, cur);
+        let recorder = match &mut self.recorder {
+            Some(recorder) => recorder,
+            None => return Ok(tid),
+        };
+        match recorder.replayed() {
+            Some(Event::Schedule(tid)) if runnable.contains(&tid) => Ok(tid),
+            Some(Event::Schedule(tid)) => Err(format!(
+                "Replay diverged: the recording has thread {} scheduled, but it can't run",
+                tid
+            )),
+            Some(event) => Err(diverged(&event, "to pick a thread to run")),
+            None => {
+                recorder.push(Event::Schedule(tid));
+                Ok(tid)
+            }
+        }
+    }
+
+    /// An input to the program from `what`: what `read` gives, or what it gave when the run being
+    /// replayed was recorded.
+    pub(super) fn input(
+        &mut self,
+        what: &str,
+        read: impl FnOnce() -> Vec<u8>,
+    ) -> Result<Vec<u8>, String> {
+        let recorder = match &mut self.recorder {
+            Some(recorder) => recorder,
+            None => return Ok(read()),
+        };
+        match recorder.replayed() {
+            Some(Event::Input(recorded, data)) if recorded == what => Ok(data),
+            Some(event) => Err(diverged(&event, &format!("input from {}", what))),
+            None => {
+                let data = read();
+                recorder.push(Event::Input(what.to_owned(), data.clone()));
+                Ok(data)
+            }
+        }
+    }
+
+    /// Whether the debugger is running the program again through what it has done before, which
+    /// mustn't be done to the world outside the program twice.
+    pub(super) fn replaying(&self) -> bool {
+        self.debugger.as_ref().is_some_and(Debugger::replaying)
+    }
+
+    /// Write the log of the program's inputs, if `--record` is on.
+    pub fn write_recording(&self) -> Result<(), String> {
+        match &self.recorder {
+            Some(recorder) => recorder.write(),
+            None => Ok(()),
+        }
+    }
+
+    /// Call an external function whose result depends on the world outside the program.
+    pub(super) fn call_input(
+        &mut self,
+        func_name: &str,
+        call: &Call,
+    ) -> Result<Option<ConstantRef>, String> {
+        match func_name {
+            "getchar" => {
+                let byte = self.input(func_name, || {
+                    let mut byte = [0];
+                    match io::stdin().read(&mut byte) {
+                        Ok(1) => byte.to_vec(),
+                        _ => Vec::new(),
+                    }
+                })?;
+                let c = byte.first().map_or(u32::MAX as u64, |&b| b as u64);
+                Ok(Some(ConstantRef::new(int(32, c))))
+            }
+            "time" | "clock_gettime" | "gettimeofday" => {
+                if let Err(errno) = self.syscall(Syscall::Clock) {
+                    let bits = if func_name == "time" { 64 } else { 32 };
+                    return self.fail(bits, errno);
+                }
+                self.call_clock(func_name, call)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn call_clock(&mut self, func_name: &str, call: &Call) -> Result<Option<ConstantRef>, String> {
+        let args = call
+            .arguments
+            .iter()
+            .map(|(arg, _)| self.get_int_op(arg))
+            .collect::<Result<Vec<_>, _>>()?;
+        match func_name {
+            "time" => {
+                let now = self.input(func_name, || {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH);
+                    now.map_or(0, |now| now.as_secs()).to_le_bytes().to_vec()
+                })?;
+                if now.len() != 8 {
+                    return Err("Invalid time in the recording".to_owned());
+                }
+                let now = now.iter().rev().fold(0, |acc, &b| acc << 8 | b as u64);
+                if args[0] != 0 {
+                    self.memory.write(args[0], &now.to_le_bytes())?;
+                }
+                Ok(Some(ConstantRef::new(int(64, now))))
+            }
+            // Both fill in a pair of 64-bit numbers: seconds, and nanoseconds or microseconds.
+            "clock_gettime" => {
+                let clock = args[0] as i32;
+                let now = self.input(func_name, || {
+                    let mut now = libc::timespec {
+                        tv_sec: 0,
+                        tv_nsec: 0,
+                    };
+                    match unsafe { libc::clock_gettime(clock, &mut now) } {
+                        0 => [now.tv_sec.to_le_bytes(), now.tv_nsec.to_le_bytes()].concat(),
+                        _ => Vec::new(),
+                    }
+                })?;
+                match now.len() {
+                    0 => self.fail(32, libc::EINVAL),
+                    16 => {
+                        self.memory.write(args[1], &now)?;
+                        Ok(Some(ConstantRef::new(int(32, 0))))
+                    }
+                    _ => Err("Invalid time in the recording".to_owned()),
+                }
+            }
+            "gettimeofday" => {
+                let now = self.input(func_name, || {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH);
+                    let (secs, micros) =
+                        now.map_or((0, 0), |now| (now.as_secs(), now.subsec_micros() as u64));
+                    [secs.to_le_bytes(), micros.to_le_bytes()].concat()
+                })?;
+                if now.len() != 16 {
+                    return Err("Invalid time in the recording".to_owned());
+                }
+                if args[0] != 0 {
+                    self.memory.write(args[0], &now)?;
+                }
+                Ok(Some(ConstantRef::new(int(32, 0))))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Throw away everything the program has done, for it to run again from the start. Its
+    /// inputs are replayed from the recording.
+    pub(super) fn restart(&mut self) {
+        self.callstack.clear();
+        self.vars.clear();
+        self.gl_vars.clear();
+        self.memory = Memory::new();
+        self.memory.set_checked(self.checked);
+        self.fn_ptrs.clear();
+        self.allocas.clear();
+        self.alloca_stack.clear();
+        self.stack_used = 0;
+        self.threads.clear();
+        self.thread = 0;
+        self.mutexes.clear();
+        self.files = self.files.restarted();
+        self.errno.clear();
+        self.scheduler = self.scheduler.restarted();
+        if self.race.is_some() {
+            self.race = Some(RaceDetector::new());
+        }
+        self.stack = Rc::new(Vec::new());
+        self.pc = (0, 0);
+        self.origins.clear();
+        self.exit_status = None;
+        if self.stats.is_some() {
+            self.stats = Some(Stats::default());
+        }
+        self.profiler = self.profiler.as_ref().map(Profiler::restarted);
+        self.coverage = self.coverage.as_ref().map(Coverage::restarted);
+        self.restart_limits();
+        if let Some(recorder) = &mut self.recorder {
+            recorder.rewind();
+        }
+    }
+}
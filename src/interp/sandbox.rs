@@ -0,0 +1,330 @@
+use super::{
+    memory::{ptr, AllocKind},
+    ops::{get_int, int},
+    snapshot::{Reader, Writer},
+    LLVMIRInterpreter,
+};
+use llvm_ir::{instruction::Call, ConstantRef};
+use std::{
+    collections::HashMap,
+    env,
+    ffi::CString,
+    fs, io,
+    os::unix::process::ExitStatusExt,
+    path::{Path, PathBuf},
+    process::Command,
+    ptr::null,
+};
+
+/// What a sandboxed program may do on the host. Whatever else it asks the host for fails with an
+/// errno, as it would on a system that forbade it.
+pub struct Sandbox {
+    /// The directories the program may open host files under.
+    dirs: Vec<PathBuf>,
+    /// The environment variables the program sees.
+    env: HashMap<String, String>,
+    clock: bool,
+}
+
+impl Sandbox {
+    /// A sandbox that lets the program open files under `dirs`, see the environment variables in
+    /// `env`, given as `NAME` to pass bcvm's own on or as `NAME=value`, and read the clock if
+    /// `clock`.
+    pub fn new(dirs: &[String], env: &[String], clock: bool) -> Result<Sandbox, String> {
+        let dirs = dirs
+            .iter()
+            .map(|dir| {
+                fs::canonicalize(dir).map_err(|err| format!("Can't allow '{}': {}", dir, err))
+            })
+            .collect::<Result<_, _>>()?;
+        let env = env
+            .iter()
+            .filter_map(|var| match var.find('=') {
+                Some(i) => Some((var[..i].to_owned(), var[i + 1..].to_owned())),
+                None => Some((var.clone(), env::var(var).ok()?)),
+            })
+            .collect();
+        Ok(Sandbox { dirs, env, clock })
+    }
+
+    /// Where `path` leads, with the links in it followed, if that's under one of the directories
+    /// the program may open files under.
+    fn resolve(&self, path: &Path) -> Option<PathBuf> {
+        let path = match path.canonicalize() {
+            Ok(path) => path,
+            // The file may be about to be created.
+            Err(_) => {
+                let (dir, name) = (path.parent()?, path.file_name()?);
+                let dir = if dir.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    dir
+                };
+                dir.canonicalize().ok()?.join(name)
+            }
+        };
+        Some(path).filter(|path| self.dirs.iter().any(|dir| path.starts_with(dir)))
+    }
+}
+
+/// Something the program asks of the host that may be denied.
+pub(super) enum Syscall {
+    Clock,
+    Spawn,
+}
+
+impl LLVMIRInterpreter {
+    /// Only let the program do what `sandbox` allows on the host, or anything if it's `None`.
+    pub fn set_sandbox(&mut self, sandbox: Option<Sandbox>) {
+        self.sandbox = sandbox;
+    }
+
+    /// Let the program run other programs, which it can't by default, sandboxed or not.
+    pub fn set_allow_spawn(&mut self, allowed: bool) {
+        self.allow_spawn = allowed;
+    }
+
+    /// Check that the program may make `syscall`, or fail with the errno it gets.
+    pub(super) fn syscall(&self, syscall: Syscall) -> Result<(), i32> {
+        let allowed = match (syscall, &self.sandbox) {
+            (Syscall::Clock, Some(sandbox)) => sandbox.clock,
+            (Syscall::Clock, None) => true,
+            (Syscall::Spawn, _) => self.allow_spawn,
+        };
+        match allowed {
+            true => Ok(()),
+            false => Err(libc::EPERM),
+        }
+    }
+
+    /// Where to open the host file at `path`, and whether a link there mustn't be followed, or
+    /// the errno the program gets if the sandbox doesn't let it. In a sandbox the links in `path`
+    /// are resolved up front, as one left dangling at the end of it could have `O_CREAT` create a
+    /// file outside of the sandbox.
+    pub(super) fn open_path(&self, path: PathBuf) -> Result<(PathBuf, bool), i32> {
+        match &self.sandbox {
+            Some(sandbox) => sandbox
+                .resolve(&path)
+                .map(|path| (path, true))
+                .ok_or(libc::EACCES),
+            None => Ok((path, false)),
+        }
+    }
+
+    /// Where the running thread's `errno` is.
+    fn errno_location(&mut self) -> u64 {
+        if let Some(&addr) = self.errno.get(&self.thread) {
+            return addr;
+        }
+        let kind = AllocKind::Global("errno".to_owned());
+        let addr = self.memory.alloc(4, 4, kind, &self.stack);
+        self.errno.insert(self.thread, addr);
+        addr
+    }
+
+    pub(super) fn set_errno(&mut self, errno: i32) -> Result<(), String> {
+        let addr = self.errno_location();
+        self.memory.write(addr, &errno.to_le_bytes())
+    }
+
+    /// Fail as a C library function does, setting `errno` and returning -1 in `bits` bits.
+    pub(super) fn fail(&mut self, bits: u32, errno: i32) -> Result<Option<ConstantRef>, String> {
+        self.set_errno(errno)?;
+        Ok(Some(ConstantRef::new(int(bits, u64::MAX))))
+    }
+
+    pub(super) fn save_errno(&self, out: &mut Writer) {
+        out.u64(self.errno.len() as u64);
+        for (tid, addr) in &self.errno {
+            out.u64(*tid as u64);
+            out.u64(*addr);
+        }
+    }
+
+    pub(super) fn load_errno(&mut self, input: &mut Reader) -> Result<(), String> {
+        self.errno = (0..input.u64()?)
+            .map(|_| Ok((input.usize()?, input.u64()?)))
+            .collect::<Result<_, String>>()?;
+        Ok(())
+    }
+
+    /// The null-terminated array of strings at `addr`, as `execv` takes.
+    fn read_argv(&self, addr: u64) -> Result<Vec<CString>, String> {
+        let mut argv = Vec::new();
+        for i in 0.. {
+            let bytes = self.memory.read(addr + 8 * i, 8)?;
+            let arg = bytes.iter().rev().fold(0, |acc, &b| acc << 8 | b as u64);
+            if arg == 0 {
+                break;
+            }
+            argv.push(self.c_string(arg)?);
+        }
+        Ok(argv)
+    }
+
+    fn c_string(&self, addr: u64) -> Result<CString, String> {
+        let string = self.memory.read_c_string(addr)?;
+        Ok(CString::new(string.chars().map(|c| c as u8).collect::<Vec<_>>()).unwrap())
+    }
+
+    /// Where a copy of the value of the environment variable named at `name` is, or 0 if the
+    /// program can't see it.
+    fn getenv(&mut self, name: u64) -> Result<u64, String> {
+        let name = self.memory.read_c_string(name)?;
+        let value = match &self.sandbox {
+            Some(sandbox) => sandbox.env.get(&name).cloned(),
+            None => env::var(&name).ok(),
+        };
+        // The value has a byte in front so that an empty value isn't taken for none.
+        let value = value.map_or(Vec::new(), |value| [b"=", value.as_bytes()].concat());
+        let value = self.input("getenv", || value)?;
+        if value.is_empty() {
+            return Ok(0);
+        }
+        // The byte in front makes room for the null terminator.
+        let kind = AllocKind::Global(format!("getenv(\"{}\")", name));
+        let addr = self.memory.alloc(value.len() as u64, 1, kind, &self.stack);
+        self.memory.write(addr, &value[1..])?;
+        Ok(addr)
+    }
+
+    /// Call a C library function that asks the host for something other than files or the time.
+    pub(super) fn call_host(
+        &mut self,
+        func_name: &str,
+        call: &Call,
+    ) -> Result<Option<ConstantRef>, String> {
+        let args = call
+            .arguments
+            .iter()
+            .map(|(arg, _)| self.eval_op(arg))
+            .collect::<Result<Vec<_>, _>>()?;
+        let arg = |i: usize| get_int(&args[i]).map(|(_, val)| val);
+        let last_errno = || io::Error::last_os_error().raw_os_error().unwrap_or(0);
+        match func_name {
+            "__errno_location" => return Ok(Some(ptr(self.errno_location()))),
+            "getenv" => return self.getenv(arg(0)?).map(|addr| Some(ptr(addr))),
+            _ => {}
+        }
+        // `system(NULL)` asks whether there's a shell to run commands with.
+        if func_name == "system" && arg(0)? == 0 {
+            let allowed = self.syscall(Syscall::Spawn).is_ok();
+            return Ok(Some(ConstantRef::new(int(32, allowed as u64))));
+        }
+        if let Err(errno) = self.syscall(Syscall::Spawn) {
+            return self.fail(32, errno);
+        }
+        let spawned = match func_name {
+            "system" => {
+                let command = self.c_string(arg(0)?)?;
+                self.spawn(func_name, || {
+                    let status = Command::new("/bin/sh")
+                        .arg("-c")
+                        .arg(command.to_string_lossy().as_ref())
+                        .status();
+                    match status {
+                        Ok(status) => Ok((status.into_raw() as u64, 0)),
+                        Err(err) => Err(err.raw_os_error().unwrap_or(libc::EIO)),
+                    }
+                })?
+            }
+            "fork" => self.spawn(func_name, || match unsafe { libc::fork() } {
+                -1 => Err(last_errno()),
+                pid => Ok((pid as u64, 0)),
+            })?,
+            "execv" | "execvp" => {
+                let file = self.c_string(arg(0)?)?;
+                let argv = self.read_argv(arg(1)?)?;
+                let mut argv = argv.iter().map(|arg| arg.as_ptr()).collect::<Vec<_>>();
+                argv.push(null());
+                // Only returns if the program couldn't be run.
+                self.spawn(func_name, || {
+                    unsafe {
+                        match func_name {
+                            "execv" => libc::execv(file.as_ptr(), argv.as_ptr()),
+                            _ => libc::execvp(file.as_ptr(), argv.as_ptr()),
+                        };
+                    }
+                    Err(last_errno())
+                })?
+            }
+            "wait" | "waitpid" => {
+                let (pid, status_addr, options) = match func_name {
+                    "wait" => (-1, arg(0)?, 0),
+                    _ => (arg(0)? as i32, arg(1)?, arg(2)? as i32),
+                };
+                let waited = self.spawn(func_name, || {
+                    let mut status = 0;
+                    match unsafe { libc::waitpid(pid, &mut status, options) } {
+                        -1 => Err(last_errno()),
+                        pid => Ok((pid as u64, status)),
+                    }
+                })?;
+                if let (Ok((_, status)), true) = (waited, status_addr != 0) {
+                    self.memory.write(status_addr, &status.to_le_bytes())?;
+                }
+                waited
+            }
+            _ => unreachable!(),
+        };
+        match spawned {
+            Ok((ret, _)) => Ok(Some(ConstantRef::new(int(32, ret)))),
+            Err(errno) => self.fail(32, errno),
+        }
+    }
+
+    /// Start, replace or wait for a process with `spawn`, which gives a result and a status or an
+    /// errno. What it gave is recorded like an input, so that a replay doesn't do it again.
+    fn spawn(
+        &mut self,
+        func_name: &str,
+        spawn: impl FnOnce() -> Result<(u64, i32), i32>,
+    ) -> Result<Result<(u64, i32), i32>, String> {
+        let data = self.input(func_name, || match spawn() {
+            Ok((ret, status)) => [&ret.to_le_bytes()[..], &status.to_le_bytes()].concat(),
+            Err(errno) => errno.to_le_bytes().to_vec(),
+        })?;
+        let word = |bytes: &[u8]| bytes.iter().rev().fold(0, |acc, &b| acc << 8 | b as u64);
+        match data.len() {
+            12 => Ok(Ok((word(&data[..8]), word(&data[8..]) as i32))),
+            4 => Ok(Err(word(&data) as i32)),
+            _ => Err(format!("Invalid result of {} in the recording", func_name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::tempdir;
+
+    #[test]
+    fn resolve() {
+        let root = tempdir().unwrap();
+        let root = root.path().canonicalize().unwrap();
+        let (inside, outside) = (root.join("inside"), root.join("outside"));
+        fs::create_dir_all(inside.join("sub")).unwrap();
+        fs::create_dir(&outside).unwrap();
+        fs::write(inside.join("file"), "").unwrap();
+        fs::write(outside.join("file"), "").unwrap();
+        symlink(inside.join("file"), inside.join("to_inside")).unwrap();
+        symlink(outside.join("file"), inside.join("to_outside")).unwrap();
+        symlink(outside.join("new"), inside.join("dangling")).unwrap();
+        symlink(&outside, inside.join("dir_outside")).unwrap();
+
+        let sandbox = Sandbox::new(&[inside.to_str().unwrap().to_owned()], &[], false).unwrap();
+        let resolve = |path: &str| sandbox.resolve(&inside.join(path));
+        assert_eq!(resolve("file"), Some(inside.join("file")));
+        assert_eq!(resolve("sub/../file"), Some(inside.join("file")));
+        assert_eq!(resolve("to_inside"), Some(inside.join("file")));
+        assert_eq!(resolve("new"), Some(inside.join("new")));
+        assert_eq!(resolve("../outside/file"), None);
+        assert_eq!(resolve("to_outside"), None);
+        assert_eq!(resolve("dir_outside/file"), None);
+        assert_eq!(resolve("dir_outside/new"), None);
+        assert_eq!(resolve("missing/new"), None);
+        // A dangling link resolves to itself, to be opened without following it.
+        assert_eq!(resolve("dangling"), Some(inside.join("dangling")));
+    }
+}
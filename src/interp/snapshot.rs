@@ -0,0 +1,674 @@
+use super::{
+    debugger::{parse_break_at, BreakAt},
+    memory::Memory,
+    threads::{Scheduler, Thread},
+    BbReturn, Frame, LLVMIRInterpreter,
+};
+use llvm_ir::{
+    constant::Float, instruction::Call, name::Name, BasicBlock, Constant, ConstantRef, Function,
+    HasDebugLoc, Instruction, Module, Operand, Type, TypeRef,
+};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs,
+    hash::{Hash, Hasher},
+    rc::Rc,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+const MAGIC: &[u8] = b"bcvm snapshot 1\n";
+
+static SIGNALLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_: libc::c_int) {
+    SIGNALLED.store(true, Ordering::Relaxed);
+}
+
+/// When to take a snapshot of the program, and where to write it.
+pub struct SnapshotTrigger {
+    path: String,
+    /// Take the snapshot when the program gets here.
+    at: Option<BreakAt>,
+    /// Take the snapshot once the program has executed this many instructions.
+    after: Option<u64>,
+    executed: u64,
+    last_line: Option<u32>,
+    taken: bool,
+}
+
+impl SnapshotTrigger {
+    /// Write a snapshot to `path` once the program gets to `at`, a location as taken by the
+    /// debugger's `break`, or once it has executed `after` instructions, whichever comes first.
+    /// A snapshot is also written whenever the process gets `SIGUSR1`.
+    pub fn new(
+        path: String,
+        at: Option<&str>,
+        after: Option<u64>,
+    ) -> Result<SnapshotTrigger, String> {
+        unsafe {
+            libc::signal(
+                libc::SIGUSR1,
+                on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            );
+        }
+        Ok(SnapshotTrigger {
+            path,
+            at: at.map(parse_break_at).transpose()?,
+            after,
+            executed: 0,
+            last_line: None,
+            taken: false,
+        })
+    }
+}
+
+/// Serialises the interpreter's state into a snapshot.
+pub(super) struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub(super) fn u64(&mut self, val: u64) {
+        self.buf.extend(val.to_le_bytes());
+    }
+
+    pub(super) fn bool(&mut self, val: bool) {
+        self.buf.push(val as u8);
+    }
+
+    pub(super) fn bytes(&mut self, bytes: &[u8]) {
+        self.u64(bytes.len() as u64);
+        self.buf.extend(bytes);
+    }
+
+    pub(super) fn str(&mut self, s: &str) {
+        self.bytes(s.as_bytes());
+    }
+
+    pub(super) fn strings(&mut self, strings: &[String]) {
+        self.u64(strings.len() as u64);
+        for s in strings {
+            self.str(s);
+        }
+    }
+
+    pub(super) fn name(&mut self, name: &Name) {
+        match name {
+            Name::Name(name) => {
+                self.bool(true);
+                self.str(name);
+            }
+            Name::Number(n) => {
+                self.bool(false);
+                self.u64(*n as u64);
+            }
+        }
+    }
+
+    fn ty(&mut self, ty: &TypeRef) -> Result<(), String> {
+        match ty.as_ref() {
+            Type::VoidType => self.u64(0),
+            Type::IntegerType { bits } => {
+                self.u64(1);
+                self.u64(*bits as u64);
+            }
+            Type::PointerType {
+                pointee_type,
+                addr_space,
+            } => {
+                self.u64(2);
+                self.ty(pointee_type)?;
+                self.u64(*addr_space as u64);
+            }
+            Type::FPType(fp) => {
+                self.u64(3);
+                self.u64(FP_TYPES.iter().position(|t| t == fp).unwrap() as u64);
+            }
+            Type::FuncType {
+                result_type,
+                param_types,
+                is_var_arg,
+            } => {
+                self.u64(4);
+                self.ty(result_type)?;
+                self.types(param_types)?;
+                self.bool(*is_var_arg);
+            }
+            Type::VectorType {
+                element_type,
+                num_elements,
+                scalable,
+            } => {
+                self.u64(5);
+                self.ty(element_type)?;
+                self.u64(*num_elements as u64);
+                self.bool(*scalable);
+            }
+            Type::ArrayType {
+                element_type,
+                num_elements,
+            } => {
+                self.u64(6);
+                self.ty(element_type)?;
+                self.u64(*num_elements as u64);
+            }
+            Type::StructType {
+                element_types,
+                is_packed,
+            } => {
+                self.u64(7);
+                self.types(element_types)?;
+                self.bool(*is_packed);
+            }
+            Type::NamedStructType { name } => {
+                self.u64(8);
+                self.str(name);
+            }
+            _ => return Err(format!("Can't take a snapshot of a value of type {}", ty)),
+        }
+        Ok(())
+    }
+
+    fn types(&mut self, types: &[TypeRef]) -> Result<(), String> {
+        self.u64(types.len() as u64);
+        types.iter().try_for_each(|ty| self.ty(ty))
+    }
+
+    fn constant(&mut self, con: &Constant) -> Result<(), String> {
+        match con {
+            Constant::Int { bits, value } => {
+                self.u64(0);
+                self.u64(*bits as u64);
+                self.u64(*value);
+            }
+            Constant::Float(Float::Single(val)) => {
+                self.u64(1);
+                self.u64(val.to_bits() as u64);
+            }
+            Constant::Float(Float::Double(val)) => {
+                self.u64(2);
+                self.u64(val.to_bits());
+            }
+            Constant::Null(ty) => {
+                self.u64(3);
+                self.ty(ty)?;
+            }
+            Constant::AggregateZero(ty) => {
+                self.u64(4);
+                self.ty(ty)?;
+            }
+            Constant::Struct {
+                name,
+                values,
+                is_packed,
+            } => {
+                self.u64(5);
+                self.bool(name.is_some());
+                self.str(name.as_deref().unwrap_or(""));
+                self.constants(values)?;
+                self.bool(*is_packed);
+            }
+            Constant::Array {
+                element_type,
+                elements,
+            } => {
+                self.u64(6);
+                self.ty(element_type)?;
+                self.constants(elements)?;
+            }
+            Constant::Vector(elements) => {
+                self.u64(7);
+                self.constants(elements)?;
+            }
+            Constant::Undef(ty) => {
+                self.u64(8);
+                self.ty(ty)?;
+            }
+            Constant::Poison(ty) => {
+                self.u64(9);
+                self.ty(ty)?;
+            }
+            Constant::GlobalReference { name, ty } => {
+                self.u64(10);
+                self.name(name);
+                self.ty(ty)?;
+            }
+            _ => return Err(format!("Can't take a snapshot of the value {}", con)),
+        }
+        Ok(())
+    }
+
+    fn constants(&mut self, cons: &[ConstantRef]) -> Result<(), String> {
+        self.u64(cons.len() as u64);
+        cons.iter().try_for_each(|con| self.constant(con))
+    }
+
+    pub(super) fn operand(&mut self, op: &Operand) -> Result<(), String> {
+        match op {
+            Operand::ConstantOperand(con) => self.constant(con),
+            _ => Err(format!("Can't take a snapshot of the operand {}", op)),
+        }
+    }
+
+    pub(super) fn opt_operand(&mut self, op: &Option<Operand>) -> Result<(), String> {
+        self.bool(op.is_some());
+        op.iter().try_for_each(|op| self.operand(op))
+    }
+
+    pub(super) fn vars(&mut self, vars: &HashMap<Name, Operand>) -> Result<(), String> {
+        self.u64(vars.len() as u64);
+        for (name, val) in vars {
+            self.name(name);
+            self.operand(val)?;
+        }
+        Ok(())
+    }
+
+    pub(super) fn frames(&mut self, frames: &[Frame]) {
+        self.u64(frames.len() as u64);
+        for (func, bb, inst, dest) in frames {
+            self.str(func);
+            self.name(bb);
+            self.u64(*inst as u64);
+            self.bool(dest.is_some());
+            dest.iter().for_each(|dest| self.name(dest));
+        }
+    }
+
+    pub(super) fn addrs(&mut self, addrs: &[u64]) {
+        self.u64(addrs.len() as u64);
+        addrs.iter().for_each(|&addr| self.u64(addr));
+    }
+
+    /// What a thread that isn't running does once it's scheduled again.
+    pub(super) fn next(&mut self, next: &Option<BbReturn>) -> Result<(), String> {
+        match next {
+            None => self.u64(0),
+            Some(BbReturn::Resume(r)) => {
+                self.u64(1);
+                self.opt_operand(r)?;
+            }
+            // The call is read back from where the thread's innermost frame continues.
+            Some(BbReturn::Call(_)) => self.u64(2),
+            Some(BbReturn::Yield) => self.u64(3),
+            Some(BbReturn::Return(r)) => {
+                self.u64(4);
+                self.opt_operand(r)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads back what a [Writer] wrote, for `module`.
+pub(super) struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    module: &'a Module,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], String> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos.saturating_add(len))
+            .ok_or_else(|| "The snapshot is truncated".to_owned())?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub(super) fn u64(&mut self) -> Result<u64, String> {
+        let bytes = self.take(8)?;
+        Ok(bytes.iter().rev().fold(0, |acc, &b| acc << 8 | b as u64))
+    }
+
+    pub(super) fn usize(&mut self) -> Result<usize, String> {
+        Ok(self.u64()? as usize)
+    }
+
+    pub(super) fn bool(&mut self) -> Result<bool, String> {
+        Ok(self.take(1)?[0] != 0)
+    }
+
+    pub(super) fn bytes(&mut self) -> Result<Vec<u8>, String> {
+        let len = self.usize()?;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    pub(super) fn string(&mut self) -> Result<String, String> {
+        String::from_utf8(self.bytes()?).map_err(|_| "The snapshot is corrupt".to_owned())
+    }
+
+    pub(super) fn strings(&mut self) -> Result<Vec<String>, String> {
+        (0..self.u64()?).map(|_| self.string()).collect()
+    }
+
+    pub(super) fn name(&mut self) -> Result<Name, String> {
+        match self.bool()? {
+            true => Ok(Name::from(self.string()?)),
+            false => Ok(Name::Number(self.usize()?)),
+        }
+    }
+
+    fn ty(&mut self) -> Result<TypeRef, String> {
+        let types = &self.module.types;
+        let ty = match self.u64()? {
+            0 => types.void(),
+            1 => types.int(self.u64()? as u32),
+            2 => {
+                let pointee = self.ty()?;
+                types.pointer_in_addr_space(pointee, self.u64()? as u32)
+            }
+            3 => match FP_TYPES.get(self.usize()?) {
+                Some(fp) => types.fp(*fp),
+                None => return Err("The snapshot is corrupt".to_owned()),
+            },
+            4 => {
+                let result = self.ty()?;
+                let params = self.types()?;
+                types.func_type(result, params, self.bool()?)
+            }
+            5 => {
+                let element = self.ty()?;
+                let len = self.usize()?;
+                types.vector_of(element, len, self.bool()?)
+            }
+            6 => {
+                let element = self.ty()?;
+                types.array_of(element, self.usize()?)
+            }
+            7 => {
+                let elements = self.types()?;
+                types.struct_of(elements, self.bool()?)
+            }
+            8 => types.named_struct(&self.string()?),
+            _ => return Err("The snapshot is corrupt".to_owned()),
+        };
+        Ok(ty)
+    }
+
+    fn types(&mut self) -> Result<Vec<TypeRef>, String> {
+        (0..self.u64()?).map(|_| self.ty()).collect()
+    }
+
+    fn constant(&mut self) -> Result<ConstantRef, String> {
+        let con = match self.u64()? {
+            0 => {
+                let bits = self.u64()? as u32;
+                Constant::Int {
+                    bits,
+                    value: self.u64()?,
+                }
+            }
+            1 => Constant::Float(Float::Single(f32::from_bits(self.u64()? as u32))),
+            2 => Constant::Float(Float::Double(f64::from_bits(self.u64()?))),
+            3 => Constant::Null(self.ty()?),
+            4 => Constant::AggregateZero(self.ty()?),
+            5 => {
+                let named = self.bool()?;
+                let name = self.string()?;
+                Constant::Struct {
+                    name: Some(name).filter(|_| named),
+                    values: self.constants()?,
+                    is_packed: self.bool()?,
+                }
+            }
+            6 => Constant::Array {
+                element_type: self.ty()?,
+                elements: self.constants()?,
+            },
+            7 => Constant::Vector(self.constants()?),
+            8 => Constant::Undef(self.ty()?),
+            9 => Constant::Poison(self.ty()?),
+            10 => Constant::GlobalReference {
+                name: self.name()?,
+                ty: self.ty()?,
+            },
+            _ => return Err("The snapshot is corrupt".to_owned()),
+        };
+        Ok(ConstantRef::new(con))
+    }
+
+    fn constants(&mut self) -> Result<Vec<ConstantRef>, String> {
+        (0..self.u64()?).map(|_| self.constant()).collect()
+    }
+
+    pub(super) fn operand(&mut self) -> Result<Operand, String> {
+        Ok(Operand::ConstantOperand(self.constant()?))
+    }
+
+    pub(super) fn opt_operand(&mut self) -> Result<Option<Operand>, String> {
+        match self.bool()? {
+            true => Ok(Some(self.operand()?)),
+            false => Ok(None),
+        }
+    }
+
+    pub(super) fn vars(&mut self) -> Result<HashMap<Name, Operand>, String> {
+        (0..self.u64()?)
+            .map(|_| Ok((self.name()?, self.operand()?)))
+            .collect()
+    }
+
+    pub(super) fn frames(&mut self) -> Result<Vec<Frame>, String> {
+        (0..self.u64()?)
+            .map(|_| {
+                let func = self.string()?;
+                let bb = self.name()?;
+                let inst = self.usize()?;
+                let dest = match self.bool()? {
+                    true => Some(self.name()?),
+                    false => None,
+                };
+                Ok((func, bb, inst, dest))
+            })
+            .collect()
+    }
+
+    pub(super) fn addrs(&mut self) -> Result<Vec<u64>, String> {
+        (0..self.u64()?).map(|_| self.u64()).collect()
+    }
+
+    /// What a thread does once it's scheduled, given the thread's frames.
+    pub(super) fn next(&mut self, frames: &[Frame]) -> Result<Option<BbReturn>, String> {
+        let next = match self.u64()? {
+            0 => None,
+            1 => Some(BbReturn::Resume(self.opt_operand()?)),
+            2 => Some(BbReturn::Call(self.blocked_call(frames)?)),
+            3 => Some(BbReturn::Yield),
+            4 => Some(BbReturn::Return(self.opt_operand()?)),
+            _ => return Err("The snapshot is corrupt".to_owned()),
+        };
+        Ok(next)
+    }
+
+    /// The call a blocked thread retries: the one its innermost frame continues after.
+    fn blocked_call(&self, frames: &[Frame]) -> Result<Call, String> {
+        let (func, bb, inst, _) = frames.last().ok_or("The snapshot is corrupt")?;
+        let inst = self
+            .module
+            .get_func_by_name(func)
+            .and_then(|func| func.basic_blocks.iter().find(|block| block.name == *bb))
+            .and_then(|bb| bb.instrs.get(inst.wrapping_sub(1)));
+        match inst {
+            Some(Instruction::Call(call)) => Ok(call.clone()),
+            _ => Err("The snapshot doesn't match the program".to_owned()),
+        }
+    }
+}
+
+const FP_TYPES: [llvm_ir::types::FPType; 7] = {
+    use llvm_ir::types::FPType::*;
+    [Half, BFloat, Single, Double, FP128, X86_FP80, PPC_FP128]
+};
+
+/// Identifies the program a snapshot was taken of, so that it isn't restored into another.
+fn fingerprint(module: &Module) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for func in &module.functions {
+        func.name.hash(&mut hasher);
+        for bb in &func.basic_blocks {
+            bb.name.hash(&mut hasher);
+            bb.instrs.len().hash(&mut hasher);
+        }
+    }
+    for gl_var in &module.global_vars {
+        gl_var.name.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+impl LLVMIRInterpreter {
+    pub fn set_snapshot_trigger(&mut self, trigger: Option<SnapshotTrigger>) {
+        self.snapshot_trigger = trigger;
+    }
+
+    /// Resume the program from the snapshot at `path` instead of starting it from `main`.
+    pub fn read_snapshot(&mut self, path: &str) -> Result<(), String> {
+        let snapshot =
+            fs::read(path).map_err(|err| format!("Can't read snapshot '{}': {}", path, err))?;
+        self.restore = Some(Rc::new(snapshot));
+        Ok(())
+    }
+
+    /// Take a snapshot before the instruction at `index` in `bb` if it's time to.
+    pub(super) fn snapshot_hook(
+        &mut self,
+        func: &Function,
+        bb: &BasicBlock,
+        index: usize,
+        frames: &[Frame],
+    ) -> Result<(), String> {
+        let source_name = self.source_name(&func.name).to_owned();
+        let trigger = self.snapshot_trigger.as_mut().unwrap();
+        trigger.executed += 1;
+        let due = !trigger.taken
+            && (trigger.after == Some(trigger.executed)
+                || trigger
+                    .at
+                    .as_ref()
+                    .is_some_and(|at| at.hit(func, &source_name, bb, index, trigger.last_line)));
+        let loc = match bb.instrs.get(index) {
+            Some(inst) => inst.get_debug_loc(),
+            None => bb.term.get_debug_loc(),
+        };
+        trigger.last_line = loc.as_ref().map(|loc| loc.line);
+        if due || SIGNALLED.swap(false, Ordering::Relaxed) {
+            trigger.taken = true;
+            let path = trigger.path.clone();
+            self.save_snapshot(&path, &bb.name, index, frames)?;
+        }
+        Ok(())
+    }
+
+    /// Write the program's state to `path`, for it to be resumed before the instruction at
+    /// `index` in `bb` of the running function.
+    pub(super) fn save_snapshot(
+        &mut self,
+        path: &str,
+        bb: &Name,
+        index: usize,
+        frames: &[Frame],
+    ) -> Result<(), String> {
+        if self.race.is_some() {
+            return Err("Snapshots can't be taken with --race".to_owned());
+        }
+        let mut frames = frames.to_vec();
+        frames.push((self.stack.last().unwrap().clone(), bb.clone(), index, None));
+        let snapshot = self.with_suspended(&mut frames, LLVMIRInterpreter::write_snapshot);
+        fs::write(path, snapshot?)
+            .map_err(|err| format!("Can't write snapshot to '{}': {}", path, err))
+    }
+
+    fn write_snapshot(&self) -> Result<Vec<u8>, String> {
+        let mut out = Writer { buf: Vec::new() };
+        out.buf.extend(MAGIC);
+        out.u64(fingerprint(&self.module));
+        out.bool(self.checked);
+        out.u64(self.gl_vars.len() as u64);
+        for (name, addr) in &self.gl_vars {
+            out.name(name);
+            out.u64(*addr);
+        }
+        out.u64(self.fn_ptrs.len() as u64);
+        for (addr, func) in &self.fn_ptrs {
+            out.u64(*addr);
+            out.str(func);
+        }
+        self.memory.save(&mut out);
+        out.u64(self.mutexes.len() as u64);
+        for (mutex, owner) in &self.mutexes {
+            out.u64(*mutex);
+            out.u64(*owner as u64);
+        }
+        self.scheduler.save(&mut out);
+        out.u64(self.thread as u64);
+        out.u64(self.threads.len() as u64);
+        for thread in &self.threads {
+            thread.save(&mut out)?;
+        }
+        self.files.save(&mut out)?;
+        self.save_errno(&mut out);
+        Ok(out.buf)
+    }
+
+    /// Put the program back in the state `snapshot` was taken in. Returns what the running
+    /// thread does next.
+    pub(super) fn restore_snapshot(
+        &mut self,
+        snapshot: &[u8],
+        frames: &mut Vec<Frame>,
+    ) -> Result<BbReturn, String> {
+        let module = Rc::clone(&self.module);
+        let mut input = Reader {
+            bytes: snapshot,
+            pos: 0,
+            module: &module,
+        };
+        if input.take(MAGIC.len()).ok() != Some(MAGIC) {
+            return Err("Not a bcvm snapshot".to_owned());
+        }
+        if input.u64()? != fingerprint(&module) {
+            return Err("The snapshot was taken of another program".to_owned());
+        }
+        if input.bool()? != self.checked {
+            return Err(
+                "The snapshot must be restored with --memcheck if and only if it was taken with it"
+                    .to_owned(),
+            );
+        }
+        if self.race.is_some() {
+            return Err("Snapshots can't be restored with --race".to_owned());
+        }
+        self.gl_vars = (0..input.u64()?)
+            .map(|_| Ok((input.name()?, input.u64()?)))
+            .collect::<Result<_, String>>()?;
+        self.fn_ptrs = (0..input.u64()?)
+            .map(|_| Ok((input.u64()?, input.string()?)))
+            .collect::<Result<_, String>>()?;
+        self.memory = Memory::load(&mut input)?;
+        self.mutexes = (0..input.u64()?)
+            .map(|_| Ok((input.u64()?, input.usize()?)))
+            .collect::<Result<_, String>>()?;
+        self.scheduler = Scheduler::load(&mut input)?;
+        self.thread = input.usize()?;
+        self.threads = (0..input.u64()?)
+            .map(|_| Thread::load(&mut input))
+            .collect::<Result<_, String>>()?;
+        if self.thread >= self.threads.len() {
+            return Err("The snapshot is corrupt".to_owned());
+        }
+        self.files.load(&mut input)?;
+        self.load_errno(&mut input)?;
+
+        if let Some(stats) = &mut self.stats {
+            for (tid, thread) in self.threads.iter().enumerate() {
+                for func in thread.stack().iter() {
+                    stats.enter(tid, func);
+                }
+            }
+        }
+        self.resume_running(frames)
+            .ok_or_else(|| "The snapshot is corrupt".to_owned())
+    }
+}
@@ -0,0 +1,166 @@
+use super::LLVMIRInterpreter;
+use llvm_ir::{Instruction, Terminator};
+use std::collections::HashMap;
+
+fn opcode(inst: &Instruction) -> &'static str {
+    macro_rules! opcodes {
+        ($($variant:ident => $name:literal),*) => {
+            match inst {
+                $(Instruction::$variant(_) => $name,)*
+            }
+        };
+    }
+    opcodes!(
+        Add => "add", Sub => "sub", Mul => "mul", UDiv => "udiv", SDiv => "sdiv", URem => "urem",
+        SRem => "srem", And => "and", Or => "or", Xor => "xor", Shl => "shl", LShr => "lshr",
+        AShr => "ashr", FAdd => "fadd", FSub => "fsub", FMul => "fmul", FDiv => "fdiv",
+        FRem => "frem", FNeg => "fneg", ExtractElement => "extractelement",
+        InsertElement => "insertelement", ShuffleVector => "shufflevector",
+        ExtractValue => "extractvalue", InsertValue => "insertvalue", Alloca => "alloca",
+        Load => "load", Store => "store", Fence => "fence", CmpXchg => "cmpxchg",
+        AtomicRMW => "atomicrmw", GetElementPtr => "getelementptr", Trunc => "trunc",
+        ZExt => "zext", SExt => "sext", FPTrunc => "fptrunc", FPExt => "fpext",
+        FPToUI => "fptoui", FPToSI => "fptosi", UIToFP => "uitofp", SIToFP => "sitofp",
+        PtrToInt => "ptrtoint", IntToPtr => "inttoptr", BitCast => "bitcast",
+        AddrSpaceCast => "addrspacecast", ICmp => "icmp", FCmp => "fcmp", Phi => "phi",
+        Select => "select", Freeze => "freeze", Call => "call", VAArg => "va_arg",
+        LandingPad => "landingpad", CatchPad => "catchpad", CleanupPad => "cleanuppad"
+    )
+}
+
+fn term_opcode(term: &Terminator) -> &'static str {
+    match term {
+        Terminator::Ret(_) => "ret",
+        Terminator::Br(_) | Terminator::CondBr(_) => "br",
+        Terminator::Switch(_) => "switch",
+        Terminator::IndirectBr(_) => "indirectbr",
+        Terminator::Invoke(_) => "invoke",
+        Terminator::Resume(_) => "resume",
+        Terminator::Unreachable(_) => "unreachable",
+        Terminator::CleanupRet(_) => "cleanupret",
+        Terminator::CatchRet(_) => "catchret",
+        Terminator::CatchSwitch(_) => "catchswitch",
+        Terminator::CallBr(_) => "callbr",
+    }
+}
+
+#[derive(Clone, Default)]
+struct FuncStats {
+    calls: u64,
+    /// Instructions executed by the function and everything it called.
+    inclusive: u64,
+    /// Instructions executed by the function itself.
+    exclusive: u64,
+}
+
+#[derive(Clone, Default)]
+struct ThreadStats {
+    executed: u64,
+    /// The functions on the thread's stack, with how many instructions the thread had executed
+    /// when each was entered.
+    frames: Vec<(String, u64)>,
+}
+
+/// Counts of what a program executed, for `--stats`.
+#[derive(Clone, Default)]
+pub(super) struct Stats {
+    total: u64,
+    opcodes: HashMap<&'static str, u64>,
+    funcs: HashMap<String, FuncStats>,
+    threads: Vec<ThreadStats>,
+}
+
+impl Stats {
+    fn thread(&mut self, tid: usize) -> &mut ThreadStats {
+        if tid >= self.threads.len() {
+            self.threads.resize_with(tid + 1, ThreadStats::default);
+        }
+        &mut self.threads[tid]
+    }
+
+    pub(super) fn enter(&mut self, tid: usize, func: &str) {
+        self.funcs.entry(func.to_owned()).or_default().calls += 1;
+        let thread = self.thread(tid);
+        let executed = thread.executed;
+        thread.frames.push((func.to_owned(), executed));
+    }
+
+    pub(super) fn leave(&mut self, tid: usize) {
+        let thread = self.thread(tid);
+        let (func, entered) = thread.frames.pop().unwrap();
+        // The instructions of a recursive call are already part of its outermost call's.
+        if thread.frames.iter().all(|(f, _)| *f != func) {
+            let executed = thread.executed - entered;
+            self.funcs.get_mut(&func).unwrap().inclusive += executed;
+        }
+    }
+
+    /// Leave every function on a thread's stack, when it finishes.
+    pub(super) fn leave_all(&mut self, tid: usize) {
+        while !self.thread(tid).frames.is_empty() {
+            self.leave(tid);
+        }
+    }
+
+    fn count(&mut self, tid: usize, opcode: &'static str) {
+        self.total += 1;
+        *self.opcodes.entry(opcode).or_default() += 1;
+        self.thread(tid).executed += 1;
+        if let Some((func, _)) = self.threads[tid].frames.last() {
+            self.funcs.get_mut(func).unwrap().exclusive += 1;
+        }
+    }
+
+    fn report(&self, peak_stack: u64, peak_heap: u64) -> String {
+        // Functions still running count what they've executed so far, without leaving them.
+        let mut left = self.clone();
+        for tid in 0..left.threads.len() {
+            left.leave_all(tid);
+        }
+        let mut report = format!("Instructions executed: {}\nBy opcode:", self.total);
+        let mut opcodes = self.opcodes.iter().collect::<Vec<_>>();
+        opcodes.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (opcode, count) in opcodes {
+            report.push_str(&format!("\n    {:<16}{:>12}", opcode, count));
+        }
+        report.push_str(&format!(
+            "\nBy function:\n    {:<24}{:>12}{:>12}{:>12}",
+            "function", "calls", "inclusive", "exclusive"
+        ));
+        let mut funcs = left.funcs.iter().collect::<Vec<_>>();
+        funcs.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(b.0)));
+        for (func, stats) in funcs {
+            report.push_str(&format!(
+                "\n    {:<24}{:>12}{:>12}{:>12}",
+                func, stats.calls, stats.inclusive, stats.exclusive
+            ));
+        }
+        report.push_str(&format!(
+            "\nPeak stack usage: {} bytes\nPeak heap usage: {} bytes",
+            peak_stack, peak_heap
+        ));
+        report
+    }
+}
+
+impl LLVMIRInterpreter {
+    pub(super) fn count_inst(&mut self, inst: &Instruction) {
+        if let Some(stats) = &mut self.stats {
+            stats.count(self.thread, opcode(inst));
+        }
+        self.profile_tick();
+    }
+
+    pub(super) fn count_term(&mut self, term: &Terminator) {
+        if let Some(stats) = &mut self.stats {
+            stats.count(self.thread, term_opcode(term));
+        }
+        self.profile_tick();
+    }
+
+    /// The statistics collected so far, if `--stats` is on.
+    pub fn stats(&self) -> Option<String> {
+        let (peak_stack, peak_heap) = self.memory.peak_usage();
+        Some(self.stats.as_ref()?.report(peak_stack, peak_heap))
+    }
+}
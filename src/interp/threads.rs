@@ -0,0 +1,554 @@
+use super::{
+    memory::ptr,
+    ops::int,
+    snapshot::{Reader, Writer},
+    BbReturn, Frame, LLVMIRInterpreter,
+};
+use llvm_ir::{instruction::Call, name, ConstantRef, Operand, Operand::ConstantOperand};
+use std::{collections::HashMap, mem, rc::Rc};
+
+const EBUSY: u64 = 16;
+
+/// How the next thread to run is picked whenever the running one is preempted or blocks.
+#[derive(Clone, Copy)]
+pub enum Policy {
+    /// Threads take turns in the order they were created.
+    RoundRobin,
+    /// A pseudo-random runnable thread is picked from a generator seeded with the given value.
+    Random(u64),
+}
+
+pub struct Scheduler {
+    /// The policy as it was given, to start over from when the program is run again.
+    initial: Policy,
+    policy: Policy,
+    quantum: u64,
+    left: u64,
+}
+
+impl Scheduler {
+    /// A scheduler that preempts the running thread every `quantum` instructions.
+    pub fn new(policy: Policy, quantum: u64) -> Scheduler {
+        let quantum = quantum.max(1);
+        Scheduler {
+            initial: policy,
+            policy,
+            quantum,
+            left: quantum,
+        }
+    }
+
+    /// A scheduler that will make the same picks as this one did from the start.
+    pub(super) fn restarted(&self) -> Scheduler {
+        Scheduler::new(self.initial, self.quantum)
+    }
+
+    pub(super) fn save(&self, out: &mut Writer) {
+        for policy in [self.initial, self.policy] {
+            match policy {
+                Policy::RoundRobin => out.bool(false),
+                Policy::Random(state) => {
+                    out.bool(true);
+                    out.u64(state);
+                }
+            }
+        }
+        out.u64(self.quantum);
+        out.u64(self.left);
+    }
+
+    pub(super) fn load(input: &mut Reader) -> Result<Scheduler, String> {
+        let mut policy = || -> Result<Policy, String> {
+            match input.bool()? {
+                false => Ok(Policy::RoundRobin),
+                true => Ok(Policy::Random(input.u64()?)),
+            }
+        };
+        Ok(Scheduler {
+            initial: policy()?,
+            policy: policy()?,
+            quantum: input.u64()?,
+            left: input.u64()?,
+        })
+    }
+
+    pub(super) fn pick(&mut self, runnable: &[usize], cur: usize) -> usize {
+        self.left = self.quantum;
+        match &mut self.policy {
+            Policy::RoundRobin => *runnable
+                .iter()
+                .find(|&&tid| tid > cur)
+                .unwrap_or(&runnable[0]),
+            Policy::Random(state) => {
+                // splitmix64
+                *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+                let mut z = *state;
+                z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+                z ^= z >> 31;
+                runnable[(z % runnable.len() as u64) as usize]
+            }
+        }
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Scheduler {
+        Scheduler::new(Policy::RoundRobin, 1)
+    }
+}
+
+/// Why a thread can't currently make progress. A blocked thread retries the call that blocked it
+/// once the condition may have changed.
+#[derive(Clone, Copy, PartialEq)]
+enum Wait {
+    Join(usize),
+    Mutex(u64),
+    Cond(u64),
+}
+
+pub(super) enum ThreadOp {
+    Done(Option<Operand>),
+    Block,
+    Exit(Option<Operand>),
+}
+
+/// The state of a thread that isn't running. The running thread's state lives in the
+/// interpreter itself.
+#[derive(Default)]
+pub(super) struct Thread {
+    vars: HashMap<name::Name, Operand>,
+    callstack: Vec<HashMap<name::Name, Operand>>,
+    allocas: Vec<u64>,
+    alloca_stack: Vec<Vec<u64>>,
+    stack_used: u64,
+    frames: Vec<Frame>,
+    stack: Rc<Vec<String>>,
+    /// What the thread does once it's scheduled again; `None` once it has finished.
+    next: Option<BbReturn>,
+    wait: Option<Wait>,
+    /// Set when a condition variable the thread waits on has been signalled.
+    woken: bool,
+    result: Option<Operand>,
+}
+
+impl Thread {
+    pub(super) fn new() -> Thread {
+        Thread {
+            next: Some(BbReturn::Resume(None)),
+            ..Thread::default()
+        }
+    }
+
+    pub(super) fn stack(&self) -> &Rc<Vec<String>> {
+        &self.stack
+    }
+
+    /// The values the thread holds while it isn't running.
+    pub(super) fn operands(&self) -> impl Iterator<Item = &Operand> {
+        let next = match &self.next {
+            Some(BbReturn::Return(op) | BbReturn::Resume(op)) => op.as_ref(),
+            _ => None,
+        };
+        self.vars
+            .values()
+            .chain(self.callstack.iter().flat_map(|vars| vars.values()))
+            .chain(next)
+            .chain(self.result.as_ref())
+    }
+
+    pub(super) fn save(&self, out: &mut Writer) -> Result<(), String> {
+        out.vars(&self.vars)?;
+        out.u64(self.callstack.len() as u64);
+        for vars in &self.callstack {
+            out.vars(vars)?;
+        }
+        out.addrs(&self.allocas);
+        out.u64(self.alloca_stack.len() as u64);
+        for allocas in &self.alloca_stack {
+            out.addrs(allocas);
+        }
+        out.u64(self.stack_used);
+        out.frames(&self.frames);
+        out.strings(&self.stack);
+        out.next(&self.next)?;
+        match self.wait {
+            None => out.u64(0),
+            Some(Wait::Join(tid)) => {
+                out.u64(1);
+                out.u64(tid as u64);
+            }
+            Some(Wait::Mutex(mutex)) => {
+                out.u64(2);
+                out.u64(mutex);
+            }
+            Some(Wait::Cond(cond)) => {
+                out.u64(3);
+                out.u64(cond);
+            }
+        }
+        out.bool(self.woken);
+        out.opt_operand(&self.result)
+    }
+
+    pub(super) fn load(input: &mut Reader) -> Result<Thread, String> {
+        let vars = input.vars()?;
+        let callstack = (0..input.u64()?)
+            .map(|_| input.vars())
+            .collect::<Result<_, _>>()?;
+        let allocas = input.addrs()?;
+        let alloca_stack = (0..input.u64()?)
+            .map(|_| input.addrs())
+            .collect::<Result<_, _>>()?;
+        let stack_used = input.u64()?;
+        let frames = input.frames()?;
+        let stack = Rc::new(input.strings()?);
+        let next = input.next(&frames)?;
+        let wait = match input.u64()? {
+            0 => None,
+            1 => Some(Wait::Join(input.usize()?)),
+            2 => Some(Wait::Mutex(input.u64()?)),
+            3 => Some(Wait::Cond(input.u64()?)),
+            _ => return Err("The snapshot is corrupt".to_owned()),
+        };
+        Ok(Thread {
+            vars,
+            callstack,
+            allocas,
+            alloca_stack,
+            stack_used,
+            frames,
+            stack,
+            next,
+            wait,
+            woken: input.bool()?,
+            result: input.opt_operand()?,
+        })
+    }
+}
+
+impl LLVMIRInterpreter {
+    /// Whether the running thread has used up its quantum. Programs with a single thread are
+    /// never preempted.
+    pub(super) fn preempt(&mut self) -> bool {
+        if self.threads.len() < 2 {
+            return false;
+        }
+        if self.scheduler.left == 0 {
+            return true;
+        }
+        self.scheduler.left -= 1;
+        false
+    }
+
+    fn swap_state(&mut self, tid: usize, frames: &mut Vec<Frame>) {
+        let thread = &mut self.threads[tid];
+        mem::swap(&mut self.vars, &mut thread.vars);
+        mem::swap(&mut self.callstack, &mut thread.callstack);
+        mem::swap(&mut self.allocas, &mut thread.allocas);
+        mem::swap(&mut self.alloca_stack, &mut thread.alloca_stack);
+        mem::swap(&mut self.stack_used, &mut thread.stack_used);
+        mem::swap(frames, &mut thread.frames);
+        mem::swap(&mut self.stack, &mut thread.stack);
+    }
+
+    /// Call `f` with the running thread's state put away as if it had been preempted, to continue
+    /// with the innermost of `frames`.
+    pub(super) fn with_suspended<T>(
+        &mut self,
+        frames: &mut Vec<Frame>,
+        f: impl FnOnce(&Self) -> T,
+    ) -> T {
+        let cur = self.thread;
+        let next = self.threads[cur].next.replace(BbReturn::Resume(None));
+        self.swap_state(cur, frames);
+        let result = f(self);
+        self.swap_state(cur, frames);
+        self.threads[cur].next = next;
+        result
+    }
+
+    /// Switch to the state of the thread that's to run, when none is running yet. Returns what it
+    /// does next.
+    pub(super) fn resume_running(&mut self, frames: &mut Vec<Frame>) -> Option<BbReturn> {
+        let tid = self.thread;
+        self.swap_state(tid, frames);
+        self.threads[tid].next.take()
+    }
+
+    /// Suspend the running thread, which continues with `next` when it's scheduled again, and
+    /// switch to the thread picked by the scheduler. Returns `None` once every thread has
+    /// finished.
+    pub(super) fn schedule(
+        &mut self,
+        next: Option<BbReturn>,
+        frames: &mut Vec<Frame>,
+    ) -> Result<Option<BbReturn>, String> {
+        let cur = self.thread;
+        self.threads[cur].next = next;
+        self.swap_state(cur, frames);
+
+        if self.threads.iter().all(|thread| thread.next.is_none()) {
+            return Ok(None);
+        }
+        let runnable = (0..self.threads.len())
+            .filter(|&tid| {
+                let thread = &self.threads[tid];
+                thread.next.is_some()
+                    && match thread.wait {
+                        None => true,
+                        Some(Wait::Join(other)) => self.threads[other].next.is_none(),
+                        Some(Wait::Mutex(addr)) => !self.mutexes.contains_key(&addr),
+                        Some(Wait::Cond(_)) => false,
+                    }
+            })
+            .collect::<Vec<_>>();
+        if runnable.is_empty() {
+            self.swap_state(cur, frames);
+            return Err("Deadlock: every thread is blocked".to_owned());
+        }
+
+        let tid = match self.pick_thread(&runnable, cur) {
+            Ok(tid) => tid,
+            Err(err) => {
+                self.swap_state(cur, frames);
+                return Err(err);
+            }
+        };
+        self.thread = tid;
+        self.threads[tid].wait = None;
+        self.swap_state(tid, frames);
+        Ok(self.threads[tid].next.take())
+    }
+
+    /// The threads that haven't finished yet.
+    pub(super) fn live_threads(&self) -> Vec<usize> {
+        (0..self.threads.len())
+            .filter(|&tid| tid == self.thread || self.threads[tid].next.is_some())
+            .collect()
+    }
+
+    /// Discard the running thread's stack and record its result.
+    pub(super) fn finish_thread(&mut self, result: Option<Operand>, frames: &mut Vec<Frame>) {
+        self.free_allocas(0);
+        for allocas in mem::take(&mut self.alloca_stack) {
+            self.allocas = allocas;
+            self.free_allocas(0);
+        }
+        self.stack_used = 0;
+        self.callstack.clear();
+        self.vars.clear();
+        self.stack = Rc::new(Vec::new());
+        frames.clear();
+        if let Some(stats) = &mut self.stats {
+            stats.leave_all(self.thread);
+        }
+        if let Some(profiler) = &mut self.profiler {
+            profiler.finish_thread(self.thread);
+        }
+        self.threads[self.thread].result = result;
+    }
+
+    fn lock_mutex(&mut self, mutex: u64) {
+        self.mutexes.insert(mutex, self.thread);
+        if let Some(race) = &mut self.race {
+            race.lock(self.thread, mutex);
+        }
+    }
+
+    fn unlock_mutex(&mut self, mutex: u64) -> Result<(), String> {
+        match self.mutexes.remove(&mutex) {
+            Some(owner) if owner == self.thread => {}
+            _ => {
+                return Err(format!(
+                    "Thread {} unlocked mutex {:#x} which it doesn't hold",
+                    self.thread, mutex
+                ))
+            }
+        }
+        if let Some(race) = &mut self.race {
+            race.unlock(self.thread, mutex);
+        }
+        Ok(())
+    }
+
+    pub(super) fn call_pthread(
+        &mut self,
+        func_name: &str,
+        call: &Call,
+    ) -> Result<ThreadOp, String> {
+        self.check_external_args(func_name, call)?;
+        let args = call
+            .arguments
+            .iter()
+            .map(|(arg, _)| self.get_int_op(arg))
+            .collect::<Result<Vec<_>, _>>()?;
+        let arg = |i: usize| args[i];
+        let cur = self.thread;
+        let ret = |val: u64| Ok(ThreadOp::Done(Some(ConstantOperand(ptr(val)))));
+        let ret_int = |val: u64| {
+            Ok(ThreadOp::Done(Some(ConstantOperand(ConstantRef::new(
+                int(32, val),
+            )))))
+        };
+
+        match func_name {
+            "pthread_create" => {
+                let func = match self.fn_ptrs.get(&arg(2)) {
+                    Some(func) => func.clone(),
+                    None => return Err(format!("Invalid thread start routine {:#x}", arg(2))),
+                };
+                self.cover_entry(&func);
+                let func = self.module.get_func_by_name(&func).unwrap();
+                let mut thread = Thread::new();
+                if let Some(par) = func.parameters.first() {
+                    thread
+                        .vars
+                        .insert(par.name.clone(), ConstantOperand(ptr(arg(3))));
+                }
+                thread.frames.push((
+                    func.name.clone(),
+                    func.basic_blocks[0].name.clone(),
+                    0,
+                    None,
+                ));
+                thread.stack = Rc::new(vec![func.name.clone()]);
+                let tid = self.threads.len() as u64;
+                self.threads.push(thread);
+                if let Some(stats) = &mut self.stats {
+                    stats.enter(tid as usize, &func.name);
+                }
+                if let Some(race) = &mut self.race {
+                    race.fork(cur, tid as usize);
+                }
+                self.memory.write(arg(0), &tid.to_le_bytes())?;
+                ret_int(0)
+            }
+            "pthread_join" => {
+                let tid = arg(0) as usize;
+                if tid >= self.threads.len() || tid == cur {
+                    return Err(format!("Invalid join of thread {}", tid));
+                }
+                if self.threads[tid].next.is_some() {
+                    self.threads[cur].wait = Some(Wait::Join(tid));
+                    return Ok(ThreadOp::Block);
+                }
+                if arg(1) != 0 {
+                    let result = match &self.threads[tid].result {
+                        Some(result) => self.get_int_op(result)?,
+                        None => 0,
+                    };
+                    self.memory.write(arg(1), &result.to_le_bytes())?;
+                }
+                if let Some(race) = &mut self.race {
+                    race.join(cur, tid);
+                }
+                ret_int(0)
+            }
+            "pthread_exit" => Ok(ThreadOp::Exit(Some(ConstantOperand(ptr(arg(0)))))),
+            "pthread_self" => ret(cur as u64),
+            "pthread_equal" => ret_int((arg(0) == arg(1)).into()),
+            "pthread_mutex_lock" => {
+                if self.mutexes.contains_key(&arg(0)) {
+                    self.threads[cur].wait = Some(Wait::Mutex(arg(0)));
+                    return Ok(ThreadOp::Block);
+                }
+                self.lock_mutex(arg(0));
+                ret_int(0)
+            }
+            "pthread_mutex_trylock" => {
+                if self.mutexes.contains_key(&arg(0)) {
+                    return ret_int(EBUSY);
+                }
+                self.lock_mutex(arg(0));
+                ret_int(0)
+            }
+            "pthread_mutex_unlock" => {
+                self.unlock_mutex(arg(0))?;
+                ret_int(0)
+            }
+            "pthread_cond_wait" => {
+                let (cond, mutex) = (arg(0), arg(1));
+                if !self.threads[cur].woken {
+                    self.unlock_mutex(mutex)?;
+                    self.threads[cur].wait = Some(Wait::Cond(cond));
+                    return Ok(ThreadOp::Block);
+                }
+                if self.mutexes.contains_key(&mutex) {
+                    self.threads[cur].wait = Some(Wait::Mutex(mutex));
+                    return Ok(ThreadOp::Block);
+                }
+                self.threads[cur].woken = false;
+                self.lock_mutex(mutex);
+                ret_int(0)
+            }
+            "pthread_cond_signal" | "pthread_cond_broadcast" => {
+                let cond = arg(0);
+                for thread in self.threads.iter_mut() {
+                    if thread.wait == Some(Wait::Cond(cond)) {
+                        thread.wait = None;
+                        thread.woken = true;
+                        if func_name == "pthread_cond_signal" {
+                            break;
+                        }
+                    }
+                }
+                ret_int(0)
+            }
+            "pthread_mutex_init"
+            | "pthread_mutex_destroy"
+            | "pthread_cond_init"
+            | "pthread_cond_destroy"
+            | "pthread_attr_init"
+            | "pthread_attr_destroy"
+            | "pthread_mutexattr_init"
+            | "pthread_mutexattr_destroy"
+            | "pthread_detach" => ret_int(0),
+            _ => Err(format!("Unsupported function {}", func_name)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn picks(scheduler: &mut Scheduler, runnable: &[usize], n: usize) -> Vec<usize> {
+        (0..n).map(|_| scheduler.pick(runnable, 0)).collect()
+    }
+
+    #[test]
+    fn round_robin() {
+        let mut scheduler = Scheduler::new(Policy::RoundRobin, 1);
+        assert_eq!(scheduler.pick(&[0, 2, 5], 0), 2);
+        assert_eq!(scheduler.pick(&[0, 2, 5], 2), 5);
+        assert_eq!(scheduler.pick(&[0, 2, 5], 5), 0);
+    }
+
+    #[test]
+    fn random_is_splitmix64() {
+        // splitmix64 seeded with 0 gives 0xe220a8397b1dcdaf, 0x6e789e6aa1b965f4,
+        // 0x06c45d188009454f and 0xf88bb8a8724c81ec first.
+        let mut scheduler = Scheduler::new(Policy::Random(0), 1);
+        assert_eq!(
+            picks(&mut scheduler, &[0, 1, 2, 3, 4, 5, 6], 4),
+            [2, 1, 2, 4]
+        );
+    }
+
+    #[test]
+    fn random_repeats_from_the_seed() {
+        let runnable = [1, 3, 4, 8];
+        let mut scheduler = Scheduler::new(Policy::Random(5), 1);
+        let first = picks(&mut scheduler, &runnable, 32);
+        assert_eq!(picks(&mut scheduler.restarted(), &runnable, 32), first);
+        assert_eq!(
+            picks(&mut Scheduler::new(Policy::Random(5), 1), &runnable, 32),
+            first
+        );
+        assert_ne!(
+            picks(&mut Scheduler::new(Policy::Random(6), 1), &runnable, 32),
+            first
+        );
+    }
+}
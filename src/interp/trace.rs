@@ -0,0 +1,198 @@
+use super::{debuginfo::format_loc, operands, without_debugloc, Frame, LLVMIRInterpreter};
+use llvm_ir::{
+    constant::Constant, name::Name, HasDebugLoc, Instruction, Operand, Terminator, Type,
+};
+use std::{fmt::Display, rc::Rc};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+/// Logs every executed instruction to stderr, with the values of its operands and result.
+pub struct Trace {
+    format: TraceFormat,
+    /// If not empty, only instructions in these functions are logged.
+    funcs: Vec<String>,
+    /// How many more instructions may be logged.
+    left: Option<u64>,
+}
+
+impl Trace {
+    pub fn new(format: TraceFormat, funcs: Vec<String>, limit: Option<u64>) -> Trace {
+        Trace {
+            format,
+            funcs,
+            left: limit,
+        }
+    }
+}
+
+/// A value as it's shown to users. Pointers are integers to the interpreter, so they need their
+/// type to be told apart.
+pub(super) fn value_string(val: &Constant, ty: &Type) -> String {
+    match (ty, val) {
+        (Type::PointerType { .. }, Constant::Int { value, .. }) => format!("ptr {:#x}", value),
+        _ => val.to_string(),
+    }
+}
+
+pub(super) fn json_string(s: &str) -> String {
+    let mut json = String::from("\"");
+    for ch in s.chars() {
+        match ch {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            ch if (ch as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", ch as u32)),
+            ch => json.push(ch),
+        }
+    }
+    json.push('"');
+    json
+}
+
+impl LLVMIRInterpreter {
+    fn tracing(&mut self, func: &str) -> bool {
+        if self.replaying() {
+            return false;
+        }
+        let trace = match &mut self.trace {
+            Some(trace) => trace,
+            None => return false,
+        };
+        if !trace.funcs.is_empty() && !trace.funcs.iter().any(|f| f == func) {
+            return false;
+        }
+        trace.left != Some(0)
+    }
+
+    fn trace_value(&self, op: &Operand) -> String {
+        match self.eval_op(op) {
+            Ok(val) => value_string(&val, &self.module.type_of(op)),
+            Err(_) => "metadata".to_owned(),
+        }
+    }
+
+    /// Log an instruction that has just been executed.
+    pub(super) fn trace_inst(
+        &mut self,
+        func: &str,
+        block: &Name,
+        index: usize,
+        inst: &Instruction,
+    ) {
+        let result = match (inst, inst.try_get_result()) {
+            (_, None) => None,
+            // llvm-ir can't work out the type of an `extractvalue` from a named struct, so use the
+            // type of the value instead.
+            (Instruction::ExtractValue(_), Some(dest)) => self.vars.get(dest).cloned(),
+            (_, Some(dest)) => Some(Operand::LocalOperand {
+                name: dest.clone(),
+                ty: self.module.type_of(inst),
+            }),
+        };
+        let ops = operands(inst);
+        self.trace(func, block, index, inst, &ops, result.as_ref());
+    }
+
+    /// Log the call the innermost of `frames` is continuing after, now that it has returned
+    /// `result`.
+    pub(super) fn trace_call(&mut self, frames: &[Frame], result: Option<&Operand>) {
+        let (func, block, index, _) = match frames.last() {
+            Some(frame) => frame,
+            None => return,
+        };
+        let module = Rc::clone(&self.module);
+        let inst = module
+            .get_func_by_name(func)
+            .and_then(|f| f.basic_blocks.iter().find(|bb| bb.name == *block))
+            .map(|bb| &bb.instrs[index - 1]);
+        if let Some(inst) = inst {
+            let ops = operands(inst);
+            self.trace(func, block, index - 1, inst, &ops, result);
+        }
+    }
+
+    /// Log a terminator that is about to be executed.
+    pub(super) fn trace_term(&mut self, func: &str, block: &Name, index: usize, term: &Terminator) {
+        let ops = match term {
+            Terminator::Ret(ret) => ret.return_operand.iter().collect(),
+            Terminator::CondBr(condbr) => vec![&condbr.condition],
+            Terminator::Switch(switch) => vec![&switch.operand],
+            _ => Vec::new(),
+        };
+        self.trace(func, block, index, term, &ops, None);
+    }
+
+    fn trace(
+        &mut self,
+        func: &str,
+        block: &Name,
+        index: usize,
+        inst: &(impl Display + HasDebugLoc),
+        ops: &[&Operand],
+        result: Option<&Operand>,
+    ) {
+        if !self.tracing(func) {
+            return;
+        }
+        let text = inst.to_string();
+        let text = without_debugloc(&text);
+        let loc = inst.get_debug_loc().as_ref();
+        let ops = ops
+            .iter()
+            .map(|op| self.trace_value(op))
+            .collect::<Vec<_>>();
+        let result = result.map(|result| self.trace_value(result));
+        let line = match self.trace.as_ref().unwrap().format {
+            TraceFormat::Text => {
+                let mut line = format!(
+                    "[{}] @{} {} #{}: {}",
+                    self.thread, func, block, index, text
+                );
+                if !ops.is_empty() {
+                    line.push_str(&format!(" ({})", ops.join(", ")));
+                }
+                if let Some(result) = result {
+                    line.push_str(&format!(" -> {}", result));
+                }
+                if let Some(loc) = loc {
+                    line.push_str(&format!(" at {} in {}", format_loc(loc), self.source_name(func)));
+                }
+                line
+            }
+            TraceFormat::Json => format!(
+                "{{\"thread\":{},\"func\":{},\"block\":{},\"index\":{},\"inst\":{},\"operands\":[{}],\"result\":{},\"source\":{}}}",
+                self.thread,
+                json_string(func),
+                json_string(&block.to_string()),
+                index,
+                json_string(text),
+                ops.iter().map(|op| json_string(op)).collect::<Vec<_>>().join(","),
+                result.map_or("null".to_owned(), |result| json_string(&result)),
+                loc.map_or("null".to_owned(), |loc| format!(
+                    "{{\"file\":{},\"line\":{},\"col\":{},\"func\":{}}}",
+                    json_string(&loc.filename),
+                    loc.line,
+                    loc.col.map_or("null".to_owned(), |col| col.to_string()),
+                    json_string(self.source_name(func))
+                ))
+            ),
+        };
+        eprintln!("{}", line);
+
+        let trace = self.trace.as_mut().unwrap();
+        if let Some(left) = &mut trace.left {
+            *left -= 1;
+            if *left == 0 {
+                match trace.format {
+                    TraceFormat::Text => eprintln!("(trace limit reached)"),
+                    TraceFormat::Json => eprintln!("{{\"limit_reached\":true}}"),
+                }
+            }
+        }
+    }
+}
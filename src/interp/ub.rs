@@ -0,0 +1,166 @@
+use super::{
+    flags::{EXACT, NSW, NUW},
+    ops::{self, get_int, sext, vector_lanes},
+    BinOps, LLVMIRInterpreter,
+};
+use llvm_ir::{constant::Constant, ConstantRef};
+
+/// What to do when an instruction produces poison because it overflowed, shifted by too much or
+/// divided inexactly. Immediate undefined behaviour such as division by zero is always an error.
+#[derive(Clone, Copy, PartialEq)]
+pub enum UbAction {
+    Trap,
+    Poison,
+}
+
+enum Ub {
+    Immediate(String),
+    Poison(String),
+}
+
+fn check(op: BinOps, flags: u8, con0: &Constant, con1: &Constant) -> Option<Ub> {
+    let name = match op {
+        BinOps::Add => "add",
+        BinOps::Sub => "sub",
+        BinOps::Mul => "mul",
+        BinOps::Shl => "shl",
+        BinOps::UDiv => "udiv",
+        BinOps::SDiv => "sdiv",
+        BinOps::URem => "urem",
+        BinOps::SRem => "srem",
+        BinOps::LShr => "lshr",
+        BinOps::AShr => "ashr",
+        _ => return None,
+    };
+    let divides = matches!(
+        op,
+        BinOps::UDiv | BinOps::SDiv | BinOps::URem | BinOps::SRem
+    );
+    match con1 {
+        Constant::Poison(_) if divides => {
+            return Some(Ub::Immediate(format!("Division by poison in {}", name)));
+        }
+        Constant::Undef(_) if divides => {
+            return Some(Ub::Immediate(format!("Division by undef in {}", name)));
+        }
+        _ => {}
+    }
+    if ops::propagate(&[con0, con1], None).is_some() {
+        return None;
+    }
+
+    // Values we can't handle are reported by the operation itself.
+    let ((bits, op0), (_, op1)) = match (get_int(con0), get_int(con1)) {
+        (Ok(int0), Ok(int1)) => (int0, int1),
+        _ => return None,
+    };
+    let (sop0, sop1) = (sext(bits, op0) as i128, sext(bits, op1) as i128);
+    let (smin, smax) = (-(1i128 << (bits - 1)), (1i128 << (bits - 1)) - 1);
+    let umax = ops::mask(bits) as u128;
+    let in_signed = |val: i128| smin <= val && val <= smax;
+    let (uop0, uop1) = (op0 as u128, op1 as u128);
+    match op {
+        _ if divides && op1 == 0 => {
+            return Some(Ub::Immediate(format!("Division by zero in {}", name)));
+        }
+        BinOps::SDiv | BinOps::SRem if sop0 == smin && sop1 == -1 => {
+            return Some(Ub::Immediate(format!(
+                "Signed overflow in {}: {} / -1",
+                name, sop0
+            )));
+        }
+        BinOps::Shl | BinOps::LShr | BinOps::AShr if op1 >= u64::from(bits) => {
+            return Some(Ub::Poison(format!(
+                "Shift amount {} is too large for i{} in {}",
+                op1, bits, name
+            )));
+        }
+        _ => {}
+    }
+
+    let (signed, unsigned) = match op {
+        BinOps::Add => (in_signed(sop0 + sop1), uop0 + uop1 <= umax),
+        BinOps::Sub => (in_signed(sop0 - sop1), uop0 >= uop1),
+        BinOps::Mul => (
+            sop0.checked_mul(sop1).is_some_and(in_signed),
+            uop0.checked_mul(uop1).is_some_and(|val| val <= umax),
+        ),
+        // `shl nsw` requires the bits shifted out to all equal the resulting sign bit.
+        BinOps::Shl => (
+            sext(bits, op0 << op1) as i128 >> op1 == sop0,
+            uop0 << op1 <= umax,
+        ),
+        _ => (true, true),
+    };
+    let exact = match op {
+        BinOps::UDiv => op0 % op1 == 0,
+        BinOps::SDiv => sop0 % sop1 == 0,
+        BinOps::LShr | BinOps::AShr => op0 & ops::mask(op1 as u32) == 0,
+        _ => true,
+    };
+    let wrapping = matches!(op, BinOps::Add | BinOps::Sub | BinOps::Mul | BinOps::Shl);
+    let signed_operands = matches!(op, BinOps::SDiv | BinOps::AShr);
+    if wrapping && flags & NSW != 0 && !signed {
+        Some(Ub::Poison(format!(
+            "Signed overflow in {} nsw: {} and {}",
+            name, sop0, sop1
+        )))
+    } else if wrapping && flags & NUW != 0 && !unsigned {
+        Some(Ub::Poison(format!(
+            "Unsigned overflow in {} nuw: {} and {}",
+            name, op0, op1
+        )))
+    } else if flags & EXACT != 0 && !exact {
+        let (op0, op1) = match signed_operands {
+            true => (sop0.to_string(), sop1.to_string()),
+            false => (op0.to_string(), op1.to_string()),
+        };
+        Some(Ub::Poison(format!(
+            "Inexact result in {} exact: {} and {}",
+            name, op0, op1
+        )))
+    } else {
+        None
+    }
+}
+
+impl LLVMIRInterpreter {
+    /// The `nsw`, `nuw` and `exact` flags of the current instruction.
+    fn inst_flags(&self) -> u8 {
+        let (bb, inst) = self.pc;
+        match (self.stack.last(), &self.wrap_flags) {
+            (Some(func), Some(flags)) => flags.get(func).map_or(0, |blocks| blocks[bb][inst]),
+            _ => 0,
+        }
+    }
+
+    /// Apply an integer binary operation, checking for the undefined behaviour that comes with
+    /// it.
+    pub(super) fn checked_int_bin_op(
+        &self,
+        con0: &Constant,
+        con1: &Constant,
+        op: BinOps,
+    ) -> Result<Constant, String> {
+        let flags = self.inst_flags();
+        let lane = |con0: &Constant, con1: &Constant| match check(op, flags, con0, con1) {
+            None => ops::int_bin_op(con0, con1, op),
+            Some(Ub::Poison(_)) if self.ub == UbAction::Poison => {
+                Ok(Constant::Poison(self.module.types.int(get_int(con0)?.0)))
+            }
+            Some(Ub::Poison(msg)) | Some(Ub::Immediate(msg)) => {
+                Err(format!("{}\n  at {}", msg, self.location()))
+            }
+        };
+        match (vector_lanes(con0), vector_lanes(con1)) {
+            (Some(lanes0), Some(lanes1)) => Ok(Constant::Vector(
+                lanes0
+                    .iter()
+                    .zip(lanes1.iter())
+                    .map(|(lane0, lane1)| lane(lane0, lane1).map(ConstantRef::new))
+                    .collect::<Result<_, _>>()?,
+            )),
+            _ => lane(con0, con1),
+        }
+    }
+}
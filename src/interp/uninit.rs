@@ -0,0 +1,84 @@
+use super::{operands, ops, LLVMIRInterpreter};
+use llvm_ir::{constant::Constant, ConstantRef, Instruction, Operand};
+use std::{collections::HashSet, rc::Rc};
+
+/// How many origins are kept before any are dropped.
+const MIN_ORIGINS: usize = 1024;
+
+// In checked mode, `poison` that comes from undefined memory remembers where that memory came
+// from, so that using it can be reported in terms of the allocation it was never written to.
+// Values are told apart by the address of their `ConstantRef`s, which are kept alive here so that
+// the address can't be reused.
+impl LLVMIRInterpreter {
+    pub(super) fn origin_of(&self, con: &Constant) -> Option<Rc<String>> {
+        let (_, origin) = self.origins.get(&(con as *const Constant as usize))?;
+        Some(Rc::clone(origin))
+    }
+
+    pub(super) fn set_origin(&mut self, con: &ConstantRef, origin: Rc<String>) {
+        if self.origins.len() >= self.origins_limit {
+            self.prune_origins();
+        }
+        let key = con.as_ref() as *const Constant as usize;
+        self.origins.insert(key, (con.clone(), origin));
+    }
+
+    /// Drop the origins of values that no variable of any thread holds any more.
+    fn prune_origins(&mut self) {
+        let live: HashSet<usize> = self
+            .vars
+            .values()
+            .chain(self.callstack.iter().flat_map(|vars| vars.values()))
+            .chain(self.threads.iter().flat_map(|thread| thread.operands()))
+            .filter_map(|op| match op {
+                Operand::ConstantOperand(con) => Some(con.as_ref() as *const Constant as usize),
+                _ => None,
+            })
+            .collect();
+        self.origins.retain(|key, _| live.contains(key));
+        self.origins_limit = (self.origins.len() * 2).max(MIN_ORIGINS);
+    }
+
+    /// Mark `len` bytes at `addr` of the new allocation at `base` as undefined.
+    pub(super) fn undefine_alloc(&mut self, base: u64, addr: u64, len: u64) {
+        if let Some(what) = self.memory.describe(base) {
+            let origin = Rc::new(format!("{} made by {}", what, self.location()));
+            self.memory.undefine(addr, len, &origin);
+        }
+    }
+
+    /// Give `poison` computed by `inst` the origin of the first operand it came from.
+    pub(super) fn propagate_origin(&mut self, inst: &Instruction) {
+        let val = match inst.try_get_result().and_then(|dest| self.vars.get(dest)) {
+            Some(Operand::ConstantOperand(val)) => val.clone(),
+            _ => return,
+        };
+        if !ops::is_poison(&val) || self.origin_of(&val).is_some() {
+            return;
+        }
+        let origin = operands(inst)
+            .into_iter()
+            .find_map(|op| self.origin_of(self.eval_op(op).ok()?.as_ref()));
+        if let Some(origin) = origin {
+            self.set_origin(&val, origin);
+        }
+    }
+
+    /// The error for `poison` reaching somewhere it mustn't. `what` says where, e.g. "used as an
+    /// address".
+    pub(super) fn poison_error(&self, con: &Constant, what: &str) -> String {
+        match self.origin_of(con) {
+            Some(origin) => self.uninit_error(what, &origin),
+            None => format!("Poison value {}\n  at {}", what, self.location()),
+        }
+    }
+
+    pub(super) fn uninit_error(&self, what: &str, origin: &str) -> String {
+        format!(
+            "Uninitialised value {}\n  at {}\n  the memory is a {}",
+            what,
+            self.location(),
+            origin
+        )
+    }
+}
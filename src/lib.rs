@@ -0,0 +1,20 @@
+//! An interpreter for LLVM IR, for running programs compiled to bitcode under bcvm's checks, or
+//! embedding them in other programs.
+//!
+//! ```no_run
+//! let mut lii = bcvm::LLVMIRInterpreter::from_bc_path("program.bc")?;
+//! lii.set_fuel(Some(1_000_000));
+//! while let Err(err) = lii.interpret() {
+//!     if !lii.suspended() {
+//!         return Err(err);
+//!     }
+//!     lii.add_fuel(1_000_000);
+//! }
+//! # Ok::<(), String>(())
+//! ```
+
+mod interp;
+pub use interp::{
+    Coverage, Debugger, FileSystem, LLVMIRInterpreter, Policy, ProfileFormat, Profiler, Recorder,
+    Sandbox, Scheduler, SnapshotTrigger, Trace, TraceFormat, UbAction,
+};
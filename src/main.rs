@@ -1,23 +1,393 @@
-mod interp;
-use interp::LLVMIRInterpreter;
+use bcvm::{
+    Coverage, Debugger, FileSystem, LLVMIRInterpreter, Policy, ProfileFormat, Profiler, Recorder,
+    Sandbox, Scheduler, SnapshotTrigger, Trace, TraceFormat, UbAction,
+};
 
-use llvm_ir::Module;
-use std::env;
+use std::{collections::HashMap, env, process, time::Duration};
+
+const USAGE: &str = "Usage: bcvm [options] <file.bc>
+       bcvm dap            serve the Debug Adapter Protocol on stdin and stdout
+
+Options:
+    --sched=rr|random   how threads are scheduled (default: rr)
+    --seed=<n>          seed for the random scheduler
+    --quantum=<n>       instructions a thread runs before it's preempted (default: 1)
+    --race              report data races between threads
+    --memcheck          report memory errors in detail, and leaks
+    --ub=trap|poison    whether overflowing `nsw`/`nuw` arithmetic, inexact `exact` division and
+                        oversized shifts stop the program or produce poison (default: trap)
+    --trace[=text|json] log every executed instruction to stderr (default: text)
+    --trace-func=<name> only trace instructions in these functions (comma separated, repeatable)
+    --trace-limit=<n>   stop tracing after this many instructions
+    --stats             print instruction counts and peak memory usage to stderr at exit
+    --profile=<file>    write the instructions executed under each call stack to a file, or to
+                        stdout if it's -
+    --profile-format=folded|chrome
+                        write the profile as folded stacks for flame graphs, or as Chrome trace
+                        events (default: folded)
+    --coverage=<file>   write which lines and branches ran as an lcov tracefile, or to stdout if
+                        it's -; lines are only known for programs compiled with debug info
+    --coverage-summary  print the blocks, edges and lines each function covered to stderr at exit
+    --debug             stop before the first instruction and take debugger commands from stdin
+    --gdb=<port>        wait for gdb or lldb to connect to this port on localhost and let it
+                        control the program with the GDB remote serial protocol
+    --record=<file>     write the program's inputs and thread switches to a file
+    --replay=<file>     run the program again exactly as it ran when the file was recorded, given
+                        the same options
+    --snapshot=<file>   write the program's state to a file when it gets to --snapshot-at or
+                        --snapshot-after, or when bcvm gets SIGUSR1
+    --snapshot-at=<where>
+                        take the snapshot here, given as to the debugger's `break`
+    --snapshot-after=<n>
+                        take the snapshot after this many instructions
+    --restore=<file>    resume the program from a snapshot instead of starting it
+    --max-instructions=<n>
+                        stop the program after it has executed this many instructions
+    --max-call-depth=<n>
+                        stop the program when a thread has this many calls on its stack
+    --max-heap=<bytes>  stop the program when it has more than this much allocated on the heap
+    --stack-size=<bytes>|unlimited
+                        how much stack each thread has for its calls and allocas (default: 8 MiB)
+    --timeout=<time>    stop the program after it has run for this long, in seconds unless it
+                        ends in ms, s, m or h; may be fractional
+    --fs=host|memory    whether the files the program opens are the host's or kept in memory
+                        (default: host)
+    --fs-root=<dir>     only let the program open host files under this directory, which it sees
+                        as /
+    --sandbox           deny the program the host's files, environment and clock, except as the
+                        options below allow; what's denied fails with an errno
+    --allow-dir=<dir>   let the sandboxed program open files under this directory (repeatable)
+    --allow-env=<name>[=<value>]
+                        let the sandboxed program see this environment variable, or give it this
+                        value (repeatable)
+    --allow-clock       let the sandboxed program read the clock
+    --allow-spawn       let the program run other programs, which it can't by default, sandboxed
+                        or not";
+
+struct Options {
+    path: String,
+    scheduler: Scheduler,
+    race: bool,
+    memcheck: bool,
+    ub: UbAction,
+    trace: Option<Trace>,
+    stats: bool,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    debug: bool,
+    gdb: Option<u16>,
+    record: Option<String>,
+    replay: Option<String>,
+    snapshot: Option<SnapshotTrigger>,
+    restore: Option<String>,
+    max_instructions: Option<u64>,
+    max_call_depth: Option<u64>,
+    max_heap: Option<u64>,
+    stack_size: Option<Option<u64>>,
+    timeout: Option<Duration>,
+    fs: FileSystem,
+    sandbox: Option<Sandbox>,
+    allow_spawn: bool,
+}
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    match create_module(&args[1]) {
-        Ok(module) => {
-            let mut lii = LLVMIRInterpreter::new(module);
-            match lii.interpret() {
-                Ok(_) => {}
-                Err(str) => println!("{}", str),
+    if args.len() == 2 && args[1] == "dap" {
+        dap();
+        return;
+    }
+    let options = match parse_args(&args[1..]) {
+        Ok(options) => options,
+        Err(error_message) => {
+            eprintln!("{}\n{}", error_message, USAGE);
+            process::exit(1);
+        }
+    };
+    match LLVMIRInterpreter::from_bc_path(&options.path) {
+        Ok(mut lii) => {
+            lii.set_scheduler(options.scheduler);
+            lii.set_race_detection(options.race);
+            lii.set_memory_check(options.memcheck);
+            lii.set_ub_action(options.ub);
+            lii.set_trace(options.trace);
+            lii.set_stats(options.stats);
+            lii.set_profiler(options.profiler);
+            lii.set_coverage(options.coverage);
+            match (options.record, options.replay) {
+                (Some(path), _) => lii.set_recorder(Some(Recorder::record(path))),
+                (_, Some(path)) => match Recorder::replay(&path) {
+                    Ok(recorder) => lii.set_recorder(Some(recorder)),
+                    Err(str) => {
+                        eprintln!("{}", str);
+                        process::exit(1);
+                    }
+                },
+                (None, None) => {}
+            }
+            lii.set_snapshot_trigger(options.snapshot);
+            lii.set_fuel(options.max_instructions);
+            lii.set_max_call_depth(options.max_call_depth.map(|depth| depth as usize));
+            lii.set_max_heap(options.max_heap);
+            if let Some(stack_size) = options.stack_size {
+                lii.set_stack_size(stack_size);
+            }
+            lii.set_timeout(options.timeout);
+            lii.set_file_system(options.fs);
+            lii.set_sandbox(options.sandbox);
+            lii.set_allow_spawn(options.allow_spawn);
+            if let Some(path) = options.restore {
+                if let Err(str) = lii.read_snapshot(&path) {
+                    eprintln!("{}", str);
+                    process::exit(1);
+                }
+            }
+            if let Some(port) = options.gdb {
+                match Debugger::gdb(port) {
+                    Ok(debugger) => lii.set_debugger(Some(debugger)),
+                    Err(str) => {
+                        eprintln!("{}", str);
+                        process::exit(1);
+                    }
+                }
+            } else if options.debug {
+                lii.set_debugger(Some(Debugger::new()));
+            }
+            let result = lii.interpret();
+            lii.end_debugging(&result);
+            if let Some(stats) = lii.stats() {
+                eprintln!("{}", stats);
+            }
+            // A failed run is worth replaying too.
+            let recorded = lii.write_recording();
+            let result = result
+                .and(lii.write_profile())
+                .and(lii.write_coverage())
+                .and(recorded);
+            match result {
+                Ok(_) => process::exit(lii.exit_status().unwrap_or(0)),
+                Err(str) => {
+                    eprintln!("{}", str);
+                    process::exit(1);
+                }
             }
         }
         Err(error_message) => println!("{}", error_message),
     };
 }
 
-fn create_module(path_s: &str) -> Result<Module, String> {
-    Module::from_bc_path(path_s)
+/// Debug a program that an editor launches through the Debug Adapter Protocol.
+fn dap() {
+    let (debugger, path) = match Debugger::dap() {
+        Ok(launch) => launch,
+        Err(str) => {
+            eprintln!("{}", str);
+            process::exit(1);
+        }
+    };
+    let mut lii = match LLVMIRInterpreter::from_bc_path(&path) {
+        Ok(lii) => lii,
+        Err(str) => {
+            eprintln!("{}", str);
+            process::exit(1);
+        }
+    };
+    lii.set_debugger(Some(debugger));
+    let result = lii.interpret();
+    lii.end_debugging(&result);
+    if result.is_err() {
+        process::exit(1);
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut path = None;
+    let mut random = false;
+    let mut seed = 0;
+    let mut quantum = 1;
+    let mut race = false;
+    let mut memcheck = false;
+    let mut ub = UbAction::Trap;
+    let mut trace = None;
+    let mut trace_funcs = Vec::new();
+    let mut trace_limit = None;
+    let mut stats = false;
+    let mut profile = None;
+    let mut profile_format = ProfileFormat::Folded;
+    let mut coverage = None;
+    let mut coverage_summary = false;
+    let mut debug = false;
+    let mut gdb = None;
+    let mut record = None;
+    let mut replay = None;
+    let mut snapshot = None;
+    let mut snapshot_at = None;
+    let mut snapshot_after = None;
+    let mut restore = None;
+    let mut max_instructions = None;
+    let mut max_call_depth = None;
+    let mut max_heap = None;
+    let mut stack_size = None;
+    let mut timeout = None;
+    let mut memory_fs = false;
+    let mut fs_root = None;
+    let mut sandbox = false;
+    let mut allow_dirs = Vec::new();
+    let mut allow_env = Vec::new();
+    let mut allow_clock = false;
+    let mut allow_spawn = false;
+    for arg in args {
+        let (flag, val) = match arg.find('=') {
+            Some(i) => (&arg[..i], &arg[i + 1..]),
+            None => (arg.as_str(), ""),
+        };
+        match flag {
+            "--sched" => match val {
+                "rr" => random = false,
+                "random" => random = true,
+                _ => return Err(format!("Unknown scheduler '{}'", val)),
+            },
+            "--seed" => seed = parse_num(flag, val)?,
+            "--quantum" => quantum = parse_num(flag, val)?,
+            "--race" => race = true,
+            "--memcheck" => memcheck = true,
+            "--ub" => match val {
+                "trap" => ub = UbAction::Trap,
+                "poison" => ub = UbAction::Poison,
+                _ => return Err(format!("Unknown action '{}' for --ub", val)),
+            },
+            "--trace" => match val {
+                "" | "text" => trace = Some(TraceFormat::Text),
+                "json" => trace = Some(TraceFormat::Json),
+                _ => return Err(format!("Unknown format '{}' for --trace", val)),
+            },
+            "--trace-func" => trace_funcs.extend(val.split(',').map(str::to_owned)),
+            "--trace-limit" => trace_limit = Some(parse_num(flag, val)?),
+            "--stats" => stats = true,
+            "--profile" => profile = Some(val.to_owned()),
+            "--coverage" => coverage = Some(val.to_owned()),
+            "--coverage-summary" => coverage_summary = true,
+            "--debug" => debug = true,
+            "--gdb" => match val.parse() {
+                Ok(port) => gdb = Some(port),
+                Err(_) => return Err(format!("Invalid port '{}' for --gdb", val)),
+            },
+            "--record" => record = Some(val.to_owned()),
+            "--replay" => replay = Some(val.to_owned()),
+            "--snapshot" => snapshot = Some(val.to_owned()),
+            "--snapshot-at" => snapshot_at = Some(val),
+            "--snapshot-after" => snapshot_after = Some(parse_num(flag, val)?),
+            "--restore" => restore = Some(val.to_owned()),
+            "--max-instructions" => max_instructions = Some(parse_num(flag, val)?),
+            "--max-call-depth" => max_call_depth = Some(parse_num(flag, val)?),
+            "--max-heap" => max_heap = Some(parse_num(flag, val)?),
+            "--stack-size" => {
+                stack_size = match val {
+                    "unlimited" => Some(None),
+                    _ => Some(Some(parse_num(flag, val)?)),
+                }
+            }
+            "--timeout" => timeout = Some(parse_duration(flag, val)?),
+            "--fs" => match val {
+                "host" => memory_fs = false,
+                "memory" => memory_fs = true,
+                _ => return Err(format!("Unknown file system '{}'", val)),
+            },
+            "--fs-root" => fs_root = Some(val.to_owned()),
+            "--sandbox" => sandbox = true,
+            "--allow-dir" => allow_dirs.push(val.to_owned()),
+            "--allow-env" => allow_env.push(val.to_owned()),
+            "--allow-clock" => allow_clock = true,
+            "--allow-spawn" => allow_spawn = true,
+            "--profile-format" => match val {
+                "folded" => profile_format = ProfileFormat::Folded,
+                "chrome" => profile_format = ProfileFormat::Chrome,
+                _ => return Err(format!("Unknown format '{}' for --profile", val)),
+            },
+            _ if flag.starts_with("--") => return Err(format!("Unknown option '{}'", arg)),
+            _ if path.is_none() => path = Some(arg.clone()),
+            _ => return Err(format!("Unexpected argument '{}'", arg)),
+        }
+    }
+    if record.is_some() && replay.is_some() {
+        return Err("--record and --replay can't be used together".to_owned());
+    }
+    let snapshot = match snapshot {
+        Some(path) => Some(SnapshotTrigger::new(path, snapshot_at, snapshot_after)?),
+        None if snapshot_at.is_some() || snapshot_after.is_some() => {
+            return Err("--snapshot-at and --snapshot-after need --snapshot".to_owned())
+        }
+        None => None,
+    };
+    let fs = match (memory_fs, fs_root) {
+        (false, root) => FileSystem::Host(root),
+        (true, None) => FileSystem::Memory(HashMap::new()),
+        (true, Some(_)) => return Err("--fs-root can't be used with --fs=memory".to_owned()),
+    };
+    let sandbox = match sandbox {
+        true => Some(Sandbox::new(&allow_dirs, &allow_env, allow_clock)?),
+        false if !allow_dirs.is_empty() || !allow_env.is_empty() || allow_clock => {
+            return Err("--allow-dir, --allow-env and --allow-clock need --sandbox".to_owned())
+        }
+        false => None,
+    };
+    let policy = match random {
+        true => Policy::Random(seed),
+        false => Policy::RoundRobin,
+    };
+    match path {
+        Some(path) => Ok(Options {
+            path,
+            scheduler: Scheduler::new(policy, quantum),
+            race,
+            memcheck,
+            ub,
+            trace: trace.map(|format| Trace::new(format, trace_funcs, trace_limit)),
+            stats,
+            profiler: profile.map(|path| Profiler::new(profile_format, path)),
+            coverage: match (coverage, coverage_summary) {
+                (None, false) => None,
+                (path, summary) => Some(Coverage::new(path, summary)),
+            },
+            debug,
+            gdb,
+            record,
+            replay,
+            snapshot,
+            restore,
+            max_instructions,
+            max_call_depth,
+            max_heap,
+            stack_size,
+            timeout,
+            fs,
+            sandbox,
+            allow_spawn,
+        }),
+        None => Err("No input file".to_owned()),
+    }
+}
+
+fn parse_num(flag: &str, val: &str) -> Result<u64, String> {
+    val.parse()
+        .map_err(|_| format!("Invalid value '{}' for {}", val, flag))
+}
+
+// e.g. `10`, `2.5s`, `500ms` or `1m`
+fn parse_duration(flag: &str, val: &str) -> Result<Duration, String> {
+    let (num, unit) = match val.find(|ch: char| ch.is_ascii_alphabetic()) {
+        Some(i) => val.split_at(i),
+        None => (val, "s"),
+    };
+    let scale = match unit {
+        "ms" => 0.001,
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        _ => 0.0,
+    };
+    num.parse::<f64>()
+        .ok()
+        .filter(|_| scale > 0.0)
+        .and_then(|num| Duration::try_from_secs_f64(num * scale).ok())
+        .ok_or_else(|| format!("Invalid value '{}' for {}", val, flag))
 }